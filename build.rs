@@ -0,0 +1,99 @@
+//! Packs the initial ramdisk.
+//!
+//! Everything in `initrd/` plus the prebuilt programs of `src/programs`
+//! (as `bin/<name>`) is written to a USTAR archive in `OUT_DIR`, which the
+//! kernel embeds and unpacks into the root filesystem at boot.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const BLOCK_SIZE: usize = 512;
+const TYPE_REGULAR: u8 = b'0';
+const TYPE_DIRECTORY: u8 = b'5';
+
+fn main() -> io::Result<()> {
+    let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let initrd = root.join("initrd");
+    let programs = root.join("src").join("programs");
+    println!("cargo:rerun-if-changed={}", initrd.display());
+    println!("cargo:rerun-if-changed={}", programs.display());
+
+    let mut archive = Vec::new();
+    if initrd.is_dir() {
+        add_directory(&mut archive, &initrd, "")?;
+    }
+
+    append_entry(&mut archive, "bin/", TYPE_DIRECTORY, 0o755, &[]);
+    for path in sorted_entries(&programs)? {
+        if path.extension().map_or(false, |extension| extension == "elf") {
+            let name = path.file_stem().unwrap().to_str().unwrap();
+            let data = fs::read(&path)?;
+            append_entry(&mut archive, &format!("bin/{}", name), TYPE_REGULAR, 0o755, &data);
+        }
+    }
+
+    // The end of the archive is marked by two zero blocks
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("initrd.tar"), archive)
+}
+
+fn sorted_entries(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(directory)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+/// Adds the contents of `directory`, `prefix` is its path in the archive
+fn add_directory(archive: &mut Vec<u8>, directory: &Path, prefix: &str) -> io::Result<()> {
+    for path in sorted_entries(directory)? {
+        let name = format!("{}{}", prefix, path.file_name().unwrap().to_str().unwrap());
+        if path.is_dir() {
+            let name = format!("{}/", name);
+            append_entry(archive, &name, TYPE_DIRECTORY, 0o755, &[]);
+            add_directory(archive, &path, &name)?;
+        } else {
+            append_entry(archive, &name, TYPE_REGULAR, 0o644, &fs::read(&path)?);
+        }
+    }
+    Ok(())
+}
+
+/// Writes `value` as a NUL terminated octal number filling `field`
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    assert_eq!(digits.len(), field.len() - 1, "{} doesn't fit the header", value);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+fn append_entry(archive: &mut Vec<u8>, name: &str, type_flag: u8, mode: u64, data: &[u8]) {
+    assert!(name.len() <= 100, "{} is too long for a tar header", name);
+
+    let mut header = [0; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], mode);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], data.len() as u64);
+    // No timestamps, the archive only changes when its contents do
+    write_octal(&mut header[136..148], 0);
+    header[156] = type_flag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    write_octal(&mut header[148..155], u64::from(checksum));
+    header[155] = b' ';
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+    archive.resize(archive.len() + padding, 0);
+}
@@ -0,0 +1,142 @@
+//! ACPI tables the firmware left in memory.
+//!
+//! The RSDP is looked for where the BIOS puts it, in the first KiB of the
+//! EBDA or in 0xE0000 to 0xFFFFF. It points to the RSDT, or on ACPI 2.0
+//! and later to the XSDT, which list the other tables. Tables are only
+//! found and checked here, what's in them is up to their users.
+
+use alloc::vec::Vec;
+use core::slice;
+
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Segment of the EBDA in the BIOS data area
+const EBDA_POINTER: u64 = 0x40E;
+const BIOS_AREA_START: u64 = 0xE_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+/// Size of the RSDP of ACPI 1.0, later versions add to it
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+/// Size of the header every table starts with
+pub const HEADER_SIZE: usize = 36;
+
+/// Physical memory through the mapping the bootloader made
+fn physical(address: u64, length: usize) -> &'static [u8] {
+    let virt = memory::phys_to_virt(PhysAddr::new(address));
+    unsafe { slice::from_raw_parts(virt.as_ptr(), length) }
+}
+
+/// Tables, like the RSDP, are valid when their bytes add up to 0
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+pub fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+/// Address of the RSDP, which is on a 16 byte boundary
+fn search_rsdp(start: u64, end: u64) -> Option<u64> {
+    (start..end).step_by(16).find(|&address| {
+        let rsdp = physical(address, RSDP_V1_SIZE);
+        &rsdp[..8] == RSDP_SIGNATURE && checksum_ok(rsdp)
+    })
+}
+
+fn find_rsdp() -> Option<&'static [u8]> {
+    let ebda_pointer = physical(EBDA_POINTER, 2);
+    let ebda = u64::from(u16::from_le_bytes([ebda_pointer[0], ebda_pointer[1]])) << 4;
+    let address = if ebda != 0 {
+        search_rsdp(ebda, ebda + 1024)
+    } else {
+        None
+    };
+    let address = address.or_else(|| search_rsdp(BIOS_AREA_START, BIOS_AREA_END))?;
+
+    // Revision 2 and later have a 64-bit XSDT address, with a checksum of
+    // their own over the whole structure
+    if physical(address, RSDP_V1_SIZE)[15] >= 2 {
+        let rsdp = physical(address, RSDP_V2_SIZE);
+        if checksum_ok(rsdp) {
+            return Some(rsdp);
+        }
+    }
+    Some(physical(address, RSDP_V1_SIZE))
+}
+
+/// A table whose header is at `address`, `None` if its checksum is wrong
+fn table_at(address: u64) -> Option<&'static [u8]> {
+    let length = read_u32(physical(address, HEADER_SIZE), 4) as usize;
+    if length < HEADER_SIZE {
+        return None;
+    }
+    let table = physical(address, length);
+    if checksum_ok(table) {
+        Some(table)
+    } else {
+        None
+    }
+}
+
+/// Addresses of the tables the RSDT or XSDT lists
+fn table_addresses() -> Vec<u64> {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => return Vec::new(),
+    };
+
+    let xsdt = if rsdp.len() == RSDP_V2_SIZE {
+        read_u64(rsdp, 24)
+    } else {
+        0
+    };
+    let (root, entry_size) = if xsdt != 0 {
+        (xsdt, 8)
+    } else {
+        (u64::from(read_u32(rsdp, 16)), 4)
+    };
+    let root = match table_at(root) {
+        Some(root) => root,
+        None => return Vec::new(),
+    };
+
+    root[HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(|entry| {
+            if entry_size == 8 {
+                read_u64(entry, 0)
+            } else {
+                u64::from(read_u32(entry, 0))
+            }
+        })
+        .collect()
+}
+
+/// The first table with `signature`, header included
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    table_addresses()
+        .into_iter()
+        .filter(|&address| &physical(address, 4)[..] == signature)
+        .filter_map(table_at)
+        .next()
+}
+
+/// Signatures of the tables the firmware provides
+pub fn tables() -> Vec<[u8; 4]> {
+    table_addresses()
+        .into_iter()
+        .filter_map(table_at)
+        .map(|table| [table[0], table[1], table[2], table[3]])
+        .collect()
+}
@@ -0,0 +1,409 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{Page, PageTable, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::lock_class;
+use crate::memory;
+use crate::sync::IrqSpinLock;
+
+/// Level 4 entry holding all the user space mappings, every address space
+/// gets its own subtree there and shares the kernel's other entries
+const USER_P4_INDEX: usize = 64;
+pub const USER_SPACE_START: u64 = (USER_P4_INDEX as u64) << 39;
+pub const USER_SPACE_END: u64 = ((USER_P4_INDEX as u64) + 1) << 39;
+/// `mmap` without an address hands out memory from here upwards
+const MMAP_START: u64 = USER_SPACE_START + (1 << 36);
+
+/// Marks read only pages that get their own frame on the first write
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/// Marks pages of shared memory, `fork` doesn't make them copy-on-write
+pub const SHARED: PageTableFlags = PageTableFlags::BIT_10;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MapError {
+    FrameAllocationFailed,
+    PageAlreadyMapped,
+    /// The page is outside of the user space region
+    NotUserAddress,
+}
+
+/// A set of page tables for a user program.
+///
+/// The kernel half is shared with the page tables the bootloader set up, so
+/// kernel code keeps working after switching to it, only the pages in
+/// `USER_SPACE_START..USER_SPACE_END` belong to the address space. They are
+/// freed together with it.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    // Page table changes are done through the physical memory mapping
+    lock: IrqSpinLock<()>,
+    next_mmap: AtomicU64,
+}
+
+pub fn is_user_address(addr: VirtAddr) -> bool {
+    addr.as_u64() >= USER_SPACE_START && addr.as_u64() < USER_SPACE_END
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let level_4_frame =
+            memory::allocate_zeroed_frame().ok_or(MapError::FrameAllocationFailed)?;
+
+        unsafe {
+            let kernel = memory::page_table_at(kernel_level_4_frame());
+            let table = memory::page_table_at(level_4_frame);
+            for (index, entry) in kernel.iter().enumerate() {
+                if index != USER_P4_INDEX {
+                    table[index] = entry.clone();
+                }
+            }
+        }
+
+        Ok(AddressSpace {
+            level_4_frame,
+            lock: IrqSpinLock::with_class((), lock_class!("AddressSpace")),
+            next_mmap: AtomicU64::new(MMAP_START),
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Loads the address space into CR3
+    pub unsafe fn activate(&self) {
+        activate_level_4_frame(self.level_4_frame);
+    }
+
+    /// Maps a user page to the given frame, the frame is owned by the
+    /// address space afterwards
+    pub fn map_page(
+        &self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        let _lock = self.lock.lock();
+        let entry = self.leaf_entry(page, true)?;
+        if !entry.is_unused() {
+            return Err(MapError::PageAlreadyMapped);
+        }
+
+        entry.set_frame(frame, user_flags(flags));
+        flush_if_active(self, page);
+        Ok(())
+    }
+
+    /// Maps a user page to a new zeroed frame
+    pub fn map_new_page(&self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapError> {
+        let frame = memory::allocate_zeroed_frame().ok_or(MapError::FrameAllocationFailed)?;
+        if let Err(error) = self.map_page(page, frame, flags) {
+            memory::deallocate_frame(frame);
+            return Err(error);
+        }
+
+        Ok(frame)
+    }
+
+    /// Removes a mapping and returns the frame, the caller owns it afterwards
+    pub fn unmap_page(&self, page: Page) -> Option<PhysFrame> {
+        let _lock = self.lock.lock();
+        let entry = self.leaf_entry(page, false).ok()?;
+        let frame = entry.frame().ok()?;
+        entry.set_unused();
+        flush_if_active(self, page);
+        Some(frame)
+    }
+
+    pub fn update_flags(&self, page: Page, flags: PageTableFlags) -> Result<(), MapError> {
+        let _lock = self.lock.lock();
+        let entry = self.leaf_entry(page, false)?;
+        let frame = entry.frame().map_err(|_| MapError::NotUserAddress)?;
+        entry.set_frame(frame, user_flags(flags));
+        flush_if_active(self, page);
+        Ok(())
+    }
+
+    /// Returns the physical address and flags a user address is mapped to
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        let _lock = self.lock.lock();
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let entry = self.leaf_entry(page, false).ok()?;
+        let frame = entry.frame().ok()?;
+        let offset = addr.as_u64() - page.start_address().as_u64();
+        Some((frame.start_address() + offset, entry.flags()))
+    }
+
+    /// Copies `data` into the address space, the pages have to be mapped.
+    /// Works whether the address space is active or not.
+    pub fn write_bytes(&self, addr: VirtAddr, data: &[u8]) -> Result<(), MapError> {
+        let mut written = 0;
+        while written < data.len() {
+            let current = addr + written;
+            let (phys, _) = self.translate(current).ok_or(MapError::NotUserAddress)?;
+            let in_page = 4096 - (current.as_u64() % 4096) as usize;
+            let count = core::cmp::min(in_page, data.len() - written);
+            unsafe {
+                let target: *mut u8 = memory::phys_to_virt(phys).as_mut_ptr();
+                target.copy_from_nonoverlapping(data[written..].as_ptr(), count);
+            }
+            written += count;
+        }
+
+        Ok(())
+    }
+
+    /// Copies from the address space into `buffer`, the pages have to be mapped
+    pub fn read_bytes(&self, addr: VirtAddr, buffer: &mut [u8]) -> Result<(), MapError> {
+        let mut read = 0;
+        while read < buffer.len() {
+            let current = addr + read;
+            let (phys, _) = self.translate(current).ok_or(MapError::NotUserAddress)?;
+            let in_page = 4096 - (current.as_u64() % 4096) as usize;
+            let count = core::cmp::min(in_page, buffer.len() - read);
+            unsafe {
+                let source: *const u8 = memory::phys_to_virt(phys).as_ptr();
+                source.copy_to_nonoverlapping(buffer[read..].as_mut_ptr(), count);
+            }
+            read += count;
+        }
+
+        Ok(())
+    }
+
+    /// Reserves `size` bytes of address space for an anonymous mapping,
+    /// nothing is mapped there yet
+    pub fn reserve_region(&self, size: usize) -> Result<VirtAddr, MapError> {
+        let size = (size as u64)
+            .checked_add(4095)
+            .ok_or(MapError::NotUserAddress)?
+            & !4095;
+        let mut start = self.next_mmap.load(Ordering::Relaxed);
+        loop {
+            if size > USER_SPACE_END - start {
+                return Err(MapError::NotUserAddress);
+            }
+            match self.next_mmap.compare_exchange_weak(
+                start,
+                start + size,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(VirtAddr::new(start)),
+                Err(current) => start = current,
+            }
+        }
+    }
+
+    /// Gives back a region from `reserve_region` that couldn't be mapped,
+    /// unless another one was reserved after it in the meantime
+    pub fn release_region(&self, start: VirtAddr, size: usize) {
+        let end = start.as_u64() + ((size as u64 + 4095) & !4095);
+        let _ = self.next_mmap.compare_exchange(
+            end,
+            start.as_u64(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Creates a copy of the address space for `fork`. The frames are
+    /// shared copy-on-write, writable pages are made read only in both.
+    /// Shared memory stays shared.
+    pub fn fork(&self) -> Result<AddressSpace, MapError> {
+        let child = AddressSpace::new()?;
+        child
+            .next_mmap
+            .store(self.next_mmap.load(Ordering::Relaxed), Ordering::Relaxed);
+
+        let _lock = self.lock.lock();
+        let level_4 = unsafe { memory::page_table_at(self.level_4_frame) };
+        let level_3_frame = match level_4[USER_P4_INDEX].frame() {
+            Ok(frame) => frame,
+            Err(_) => return Ok(child),
+        };
+
+        let level_3 = unsafe { memory::page_table_at(level_3_frame) };
+        for (i3, entry_3) in level_3.iter().enumerate() {
+            let level_2 = match entry_3.frame() {
+                Ok(frame) => unsafe { memory::page_table_at(frame) },
+                Err(_) => continue,
+            };
+            for (i2, entry_2) in level_2.iter().enumerate() {
+                let level_1 = match entry_2.frame() {
+                    Ok(frame) => unsafe { memory::page_table_at(frame) },
+                    Err(_) => continue,
+                };
+                for (i1, entry) in level_1.iter_mut().enumerate() {
+                    let frame = match entry.frame() {
+                        Ok(frame) => frame,
+                        Err(_) => continue,
+                    };
+
+                    let mut flags = entry.flags();
+                    if flags.contains(PageTableFlags::WRITABLE) && !flags.contains(SHARED) {
+                        flags.remove(PageTableFlags::WRITABLE);
+                        flags.insert(COPY_ON_WRITE);
+                        entry.set_flags(flags);
+                    }
+
+                    let addr = USER_SPACE_START
+                        | ((i3 as u64) << 30)
+                        | ((i2 as u64) << 21)
+                        | ((i1 as u64) << 12);
+                    let page = Page::containing_address(VirtAddr::new(addr));
+                    memory::share_frame(frame);
+                    child.leaf_entry(page, true)?.set_frame(frame, flags);
+                }
+            }
+        }
+
+        // Our writable pages just became read only
+        if Cr3::read().0 == self.level_4_frame {
+            x86_64::instructions::tlb::flush_all();
+        }
+        Ok(child)
+    }
+
+    /// Gives a copy-on-write page a writable frame of its own, returns
+    /// `false` if the page isn't copy-on-write
+    pub fn resolve_copy_on_write(&self, page: Page) -> Result<bool, MapError> {
+        let _lock = self.lock.lock();
+        let entry = self.leaf_entry(page, false)?;
+        let mut flags = entry.flags();
+        if !flags.contains(COPY_ON_WRITE) {
+            return Ok(false);
+        }
+
+        flags.remove(COPY_ON_WRITE);
+        flags.insert(PageTableFlags::WRITABLE);
+        let frame = entry.frame().map_err(|_| MapError::NotUserAddress)?;
+        if memory::frame_ref_count(frame) == 1 {
+            // Everybody else already made their copy
+            entry.set_flags(flags);
+        } else {
+            let copy = memory::allocate_frame().ok_or(MapError::FrameAllocationFailed)?;
+            unsafe {
+                let source: *const u8 = memory::phys_to_virt(frame.start_address()).as_ptr();
+                let target: *mut u8 = memory::phys_to_virt(copy.start_address()).as_mut_ptr();
+                target.copy_from_nonoverlapping(source, 4096);
+            }
+            entry.set_frame(copy, flags);
+            memory::deallocate_frame(frame);
+        }
+
+        flush_if_active(self, page);
+        Ok(true)
+    }
+
+    /// Walks the page tables down to the level 1 entry of `page`, creating
+    /// the intermediate tables if `create` is set
+    fn leaf_entry(&self, page: Page, create: bool) -> Result<&mut PageTableEntry, MapError> {
+        if !is_user_address(page.start_address()) {
+            return Err(MapError::NotUserAddress);
+        }
+
+        let mut table = unsafe { memory::page_table_at(self.level_4_frame) };
+        let indices = [page.p4_index(), page.p3_index(), page.p2_index()];
+        for &index in indices.iter() {
+            let entry = &mut table[index];
+            if entry.is_unused() {
+                if !create {
+                    return Err(MapError::NotUserAddress);
+                }
+
+                let frame =
+                    memory::allocate_zeroed_frame().ok_or(MapError::FrameAllocationFailed)?;
+                entry.set_frame(frame, parent_flags());
+            }
+
+            let frame = entry.frame().map_err(|_| MapError::PageAlreadyMapped)?;
+            table = unsafe { memory::page_table_at(frame) };
+        }
+
+        Ok(&mut table[page.p1_index()])
+    }
+}
+
+fn user_flags(flags: PageTableFlags) -> PageTableFlags {
+    flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE
+}
+
+fn parent_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE
+}
+
+fn flush_if_active(space: &AddressSpace, page: Page) {
+    if Cr3::read().0 == space.level_4_frame {
+        x86_64::instructions::tlb::flush(page.start_address());
+    }
+}
+
+/// Frees every table of a subtree and the frames mapped by it
+unsafe fn free_table(table: &PageTable, level: usize) {
+    for entry in table.iter() {
+        if let Ok(frame) = entry.frame() {
+            if level > 1 {
+                free_table(memory::page_table_at(frame), level - 1);
+            }
+            memory::deallocate_frame(frame);
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(
+            Cr3::read().0 != self.level_4_frame,
+            "Dropping the active address space"
+        );
+
+        unsafe {
+            let level_4 = memory::page_table_at(self.level_4_frame);
+            if let Ok(level_3_frame) = level_4[USER_P4_INDEX].frame() {
+                free_table(memory::page_table_at(level_3_frame), 3);
+                memory::deallocate_frame(level_3_frame);
+            }
+        }
+        memory::deallocate_frame(self.level_4_frame);
+    }
+}
+
+static KERNEL_LEVEL_4_FRAME: IrqSpinLock<Option<PhysFrame>> = IrqSpinLock::new(None);
+
+/// Remembers the page tables set up by the bootloader, kernel threads
+/// run on them
+pub fn init() {
+    let (frame, _) = Cr3::read();
+    let table = unsafe { memory::page_table_at(frame) };
+    assert!(
+        table[USER_P4_INDEX].is_unused(),
+        "The user space P4 entry is used by the kernel"
+    );
+
+    *KERNEL_LEVEL_4_FRAME.lock() = Some(frame);
+}
+
+pub fn kernel_level_4_frame() -> PhysFrame {
+    let frame = *KERNEL_LEVEL_4_FRAME.lock();
+    frame.expect("Address spaces aren't initialized")
+}
+
+/// Loads the level 4 table into CR3 unless it's already active
+pub unsafe fn activate_level_4_frame(frame: PhysFrame) {
+    let (active, _) = Cr3::read();
+    if active != frame {
+        Cr3::write(frame, Cr3Flags::empty());
+    }
+}
+
+/// Switches back to the kernel's page tables, does nothing before `init`
+pub unsafe fn activate_kernel() {
+    let frame = *KERNEL_LEVEL_4_FRAME.lock();
+    if let Some(frame) = frame {
+        activate_level_4_frame(frame);
+    }
+}
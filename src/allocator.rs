@@ -0,0 +1,72 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use linked_list_allocator::LockedHeap;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+
+#[global_allocator]
+static ALLOCATOR: IrqSafeHeap = IrqSafeHeap(LockedHeap::empty());
+
+/// Bytes handed out by the heap, without the allocator's own overhead
+static HEAP_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Wraps the heap so that it's never locked while interrupts are enabled.
+///
+/// Interrupt handlers (e.g. the scheduler running from the timer interrupt)
+/// allocate, if an interrupt arrives while the interrupted code holds the heap
+/// lock the handler would spin forever.
+struct IrqSafeHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for IrqSafeHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = x86_64::instructions::interrupts::without_interrupts(|| self.0.alloc(layout));
+        if !ptr.is_null() {
+            HEAP_USED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        x86_64::instructions::interrupts::without_interrupts(|| self.0.dealloc(ptr, layout));
+        HEAP_USED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+/// Bytes of the heap that are allocated
+pub fn heap_used() -> usize {
+    HEAP_USED.load(Ordering::Relaxed)
+}
+
+/// Maps the heap pages and hands them to the allocator, must be called once
+/// before anything from the `alloc` crate is used.
+pub fn init_heap(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError> {
+    let page_range = {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    for page in page_range {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+
+    unsafe {
+        ALLOCATOR.0.lock().init(HEAP_START, HEAP_SIZE);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! The local APIC of the CPU.
+//!
+//! Legacy IRQs keep coming from the PICs, which the local APIC passes on
+//! through LINT0 in virtual wire mode. It's only turned on for the
+//! interrupts devices send as MSI messages, which go straight to it and
+//! need an EOI there rather than at the PICs.
+
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+use crate::memory;
+use crate::println;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS: u64 = 0xF_FFFF_F000;
+/// CPUID leaf 1 sets it in EDX if there's a local APIC
+const CPUID_APIC: u32 = 1 << 9;
+
+// Registers at offsets from the base
+const ID: usize = 0x20;
+const VERSION: usize = 0x30;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xB0;
+const SPURIOUS: usize = 0xF0;
+const LVT_LINT0: usize = 0x350;
+const LVT_LINT1: usize = 0x360;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const DELIVERY_NMI: u32 = 0x400;
+const DELIVERY_EXTINT: u32 = 0x700;
+
+/// Interrupts the local APIC drops rather than delivering arrive here, they
+/// don't get an EOI
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Where the registers are mapped, 0 until `init`
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+fn read(register: usize) -> u32 {
+    let base = BASE.load(Ordering::Acquire);
+    unsafe { ptr::read_volatile((base as usize + register) as *const u32) }
+}
+
+fn write(register: usize, value: u32) {
+    let base = BASE.load(Ordering::Acquire);
+    unsafe { ptr::write_volatile((base as usize + register) as *mut u32, value) }
+}
+
+/// `true` once `init` turned the local APIC on
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+/// The ID MSI messages are addressed to
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+pub fn version() -> u8 {
+    read(VERSION) as u8
+}
+
+/// Tells the local APIC the interrupt it delivered was handled
+pub fn end_of_interrupt() {
+    if is_enabled() {
+        write(EOI, 0);
+    }
+}
+
+/// Turns the local APIC on, the PICs stay in charge of the legacy IRQs.
+/// Needs the kernel page tables, the registers are mapped uncached.
+pub fn init() {
+    if unsafe { __cpuid(1) }.edx & CPUID_APIC == 0 {
+        return;
+    }
+
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { base_msr.read() };
+    let virt = match memory::map_mmio(PhysAddr::new(base & APIC_BASE_ADDRESS), 4096) {
+        Ok(virt) => virt,
+        Err(error) => {
+            println!("apic: can't map the registers: {:?}", error);
+            return;
+        }
+    };
+    unsafe { base_msr.write(base | APIC_BASE_ENABLE) };
+    BASE.store(virt.as_u64(), Ordering::Release);
+
+    // The PICs are wired to LINT0 and NMIs to LINT1
+    write(LVT_LINT0, DELIVERY_EXTINT);
+    write(LVT_LINT1, DELIVERY_NMI);
+    write(TASK_PRIORITY, 0);
+    write(SPURIOUS, SPURIOUS_ENABLE | u32::from(SPURIOUS_VECTOR));
+    println!("apic: local APIC {} version {:#x}", id(), version());
+}
@@ -0,0 +1,593 @@
+//! SATA disks on AHCI controllers, transferred with DMA.
+//!
+//! `init` registers a PCI driver for AHCI controllers, which registers
+//! the disks on their ports as `/dev/sda`, `/dev/sdb` and so on. Each port
+//! gets a frame holding its command list, the area the disk posts its
+//! FISes to and a command table, plus a DMA buffer of frames the data goes
+//! through.
+//! Commands only use the first slot of the command list, one at a time per
+//! port and without NCQ.
+//!
+//! A thread that issued a command sleeps until the controller interrupts,
+//! with MSI or on its PCI line, or the command times out. The registers
+//! are mapped uncached where the rest of the physical memory is.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use x86_64::structures::paging::PhysFrame;
+
+use lazy_static::lazy_static;
+
+use super::ata::{self, AtaError};
+use super::{check_request, BlockDevice, BlockError, BlockResult, SECTOR_SIZE};
+use crate::interrupts;
+use crate::lock_class;
+use crate::memory;
+use crate::pci::{self, msi};
+use crate::println;
+use crate::sync::{IrqSpinLock, Mutex, WaitQueue};
+use crate::time;
+
+/// The registers are behind BAR 5
+const ABAR: usize = 5;
+
+// Registers of the controller
+const HBA_CAPABILITIES: usize = 0x00;
+const HBA_CONTROL: usize = 0x04;
+const HBA_INTERRUPT_STATUS: usize = 0x08;
+const HBA_PORTS_IMPLEMENTED: usize = 0x0C;
+const CAPABILITY_64BIT: u32 = 1 << 31;
+const CONTROL_INTERRUPT_ENABLE: u32 = 1 << 1;
+const CONTROL_AHCI_ENABLE: u32 = 1 << 31;
+
+// Registers of a port, at offsets from its registers
+const PORT_REGISTERS: usize = 0x100;
+const PORT_REGISTERS_SIZE: usize = 0x80;
+const PORT_COMMAND_LIST: usize = 0x00;
+const PORT_COMMAND_LIST_HIGH: usize = 0x04;
+const PORT_FIS: usize = 0x08;
+const PORT_FIS_HIGH: usize = 0x0C;
+const PORT_INTERRUPT_STATUS: usize = 0x10;
+const PORT_INTERRUPT_ENABLE: usize = 0x14;
+const PORT_COMMAND: usize = 0x18;
+const PORT_TASK_FILE: usize = 0x20;
+const PORT_SIGNATURE: usize = 0x24;
+const PORT_SATA_STATUS: usize = 0x28;
+const PORT_SATA_ERROR: usize = 0x30;
+const PORT_COMMAND_ISSUE: usize = 0x38;
+
+// Bits of the command register of a port
+const COMMAND_START: u32 = 1 << 0;
+const COMMAND_FIS_RECEIVE: u32 = 1 << 4;
+const COMMAND_FIS_RUNNING: u32 = 1 << 14;
+const COMMAND_LIST_RUNNING: u32 = 1 << 15;
+
+// Interrupts of a port: a register FIS or PIO setup FIS arrived, and the
+// errors
+const INTERRUPT_DEVICE_TO_HOST: u32 = 1 << 0;
+const INTERRUPT_PIO_SETUP: u32 = 1 << 1;
+const INTERRUPT_ERRORS: u32 = 0xF << 27;
+
+/// A device is present and the link is up
+const SATA_STATUS_ACTIVE: u32 = 3;
+/// Signature of a disk, packet devices have their own
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+const CMD_READ_DMA_EXT: u8 = 0x25;
+const CMD_WRITE_DMA_EXT: u8 = 0x35;
+const CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+/// Register FIS sent to the device
+const FIS_HOST_TO_DEVICE: u8 = 0x27;
+
+// Where the structures are in the frame of a port, with the alignment
+// they need
+const PAGE_SIZE: usize = 4096;
+const COMMAND_LIST: usize = 0;
+const RECEIVED_FIS: usize = 1024;
+const COMMAND_TABLE: usize = 1280;
+const PRDT: usize = COMMAND_TABLE + 0x80;
+
+/// Frames of the DMA buffer of a port, one region of the PRDT each
+const DMA_FRAMES: usize = 16;
+/// Sectors of one command
+const MAX_SECTORS: usize = DMA_FRAMES * PAGE_SIZE / SECTOR_SIZE;
+/// Checks of a polled command before giving up
+const POLL_LIMIT: usize = 10_000_000;
+/// How long an interrupt driven command may take, enough for a disk to
+/// spin up
+const COMMAND_TIMEOUT_MS: u64 = 10_000;
+
+/// Memory mapped registers
+#[derive(Copy, Clone)]
+struct Registers(*mut u8);
+
+unsafe impl Send for Registers {}
+unsafe impl Sync for Registers {}
+
+impl Registers {
+    fn read(self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.0.add(offset) as *const u32) }
+    }
+
+    fn write(self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile(self.0.add(offset) as *mut u32, value) }
+    }
+
+    fn port(self, number: u8) -> Registers {
+        let offset = PORT_REGISTERS + usize::from(number) * PORT_REGISTERS_SIZE;
+        Registers(unsafe { self.0.add(offset) })
+    }
+
+    /// Waits until `bits` of `offset` are clear
+    fn wait_clear(self, offset: usize, bits: u32) -> Result<(), AtaError> {
+        for _ in 0..POLL_LIMIT {
+            if self.read(offset) & bits == 0 {
+                return Ok(());
+            }
+        }
+        Err(AtaError::Timeout)
+    }
+}
+
+struct Controller {
+    registers: Registers,
+    disks: Vec<Arc<AhciDisk>>,
+    interrupt_driven: bool,
+}
+
+lazy_static! {
+    static ref CONTROLLERS: IrqSpinLock<Vec<Controller>> =
+        IrqSpinLock::with_class(Vec::new(), lock_class!("AHCI_CONTROLLERS"));
+}
+
+/// Registered for the PCI lines of the controllers, they may be shared
+/// with other devices
+fn interrupt() {
+    for controller in CONTROLLERS.lock().iter() {
+        let pending = controller.registers.read(HBA_INTERRUPT_STATUS);
+        for number in (0..32).filter(|number| pending & 1 << number != 0) {
+            let port = controller.registers.port(number);
+            let status = port.read(PORT_INTERRUPT_STATUS);
+            port.write(PORT_INTERRUPT_STATUS, status);
+            if let Some(disk) = controller.disks.iter().find(|disk| disk.number == number) {
+                disk.events.fetch_or(status, Ordering::SeqCst);
+                disk.waiters.wake_all();
+            }
+        }
+        controller.registers.write(HBA_INTERRUPT_STATUS, pending);
+    }
+}
+
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Writes `value` into DMA memory the controller reads
+fn write_u32(base: *mut u8, offset: usize, value: u32) {
+    unsafe { ptr::write_volatile(base.add(offset) as *mut u32, value) }
+}
+
+/// A disk on a port of a controller
+pub struct AhciDisk {
+    name: String,
+    number: u8,
+    registers: Registers,
+    /// Command list, received FISes and the command table
+    memory: PhysFrame,
+    dma: Vec<PhysFrame>,
+    model: String,
+    serial: String,
+    sectors: u64,
+    /// Commands complete with an interrupt, otherwise they're polled
+    interrupt_driven: bool,
+    /// Interrupt status bits the handler saw
+    events: AtomicU32,
+    waiters: WaitQueue,
+    /// One command at a time
+    lock: Mutex<()>,
+}
+
+impl fmt::Debug for AhciDisk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AhciDisk")
+            .field("name", &self.name)
+            .field("port", &self.number)
+            .field("model", &self.model)
+            .field("sectors", &self.sectors)
+            .finish()
+    }
+}
+
+impl AhciDisk {
+    /// Its name in `/dev`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Number of the port of the controller
+    pub fn port(&self) -> u8 {
+        self.number
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    pub fn is_interrupt_driven(&self) -> bool {
+        self.interrupt_driven
+    }
+
+    /// Stops the port from processing the command list
+    fn stop(&self) -> Result<(), AtaError> {
+        let port = self.registers;
+        port.write(PORT_COMMAND, port.read(PORT_COMMAND) & !COMMAND_START);
+        port.wait_clear(PORT_COMMAND, COMMAND_LIST_RUNNING)?;
+        port.write(PORT_COMMAND, port.read(PORT_COMMAND) & !COMMAND_FIS_RECEIVE);
+        port.wait_clear(PORT_COMMAND, COMMAND_FIS_RUNNING)
+    }
+
+    fn start(&self) -> Result<(), AtaError> {
+        let port = self.registers;
+        port.wait_clear(PORT_COMMAND, COMMAND_LIST_RUNNING)?;
+        port.write(PORT_COMMAND, port.read(PORT_COMMAND) | COMMAND_FIS_RECEIVE);
+        port.write(PORT_COMMAND, port.read(PORT_COMMAND) | COMMAND_START);
+        Ok(())
+    }
+
+    /// Points the port at its memory and starts it
+    fn set_up(&self) -> Result<(), AtaError> {
+        let port = self.registers;
+        self.stop()?;
+        let memory = self.memory.start_address().as_u64();
+        let command_list = memory + COMMAND_LIST as u64;
+        let received_fis = memory + RECEIVED_FIS as u64;
+        port.write(PORT_COMMAND_LIST, command_list as u32);
+        port.write(PORT_COMMAND_LIST_HIGH, (command_list >> 32) as u32);
+        port.write(PORT_FIS, received_fis as u32);
+        port.write(PORT_FIS_HIGH, (received_fis >> 32) as u32);
+
+        port.write(PORT_SATA_ERROR, !0);
+        port.write(PORT_INTERRUPT_STATUS, !0);
+        port.write(
+            PORT_INTERRUPT_ENABLE,
+            INTERRUPT_DEVICE_TO_HOST | INTERRUPT_PIO_SETUP | INTERRUPT_ERRORS,
+        );
+        self.start()
+    }
+
+    /// Runs `command` in the first slot, `bytes` of data go through the
+    /// DMA buffer
+    fn run(
+        &self,
+        command: u8,
+        sector: u64,
+        count: usize,
+        bytes: usize,
+        write: bool,
+    ) -> Result<(), AtaError> {
+        let memory = frame_ptr(self.memory);
+        let table = self.memory.start_address().as_u64() + COMMAND_TABLE as u64;
+        let regions = (bytes + PAGE_SIZE - 1) / PAGE_SIZE;
+
+        // The header: a FIS of 5 dwords, the direction and the PRDT length
+        let flags = 5 | (write as u32) << 6 | (regions as u32) << 16;
+        write_u32(memory, COMMAND_LIST, flags);
+        write_u32(memory, COMMAND_LIST + 4, 0);
+        write_u32(memory, COMMAND_LIST + 8, table as u32);
+        write_u32(memory, COMMAND_LIST + 12, (table >> 32) as u32);
+
+        let mut fis = [0; 20];
+        fis[0] = FIS_HOST_TO_DEVICE;
+        // It's a command, not a control register update
+        fis[1] = 0x80;
+        fis[2] = command;
+        fis[4] = sector as u8;
+        fis[5] = (sector >> 8) as u8;
+        fis[6] = (sector >> 16) as u8;
+        // LBA mode
+        fis[7] = 0x40;
+        fis[8] = (sector >> 24) as u8;
+        fis[9] = (sector >> 32) as u8;
+        fis[10] = (sector >> 40) as u8;
+        fis[12] = count as u8;
+        fis[13] = (count >> 8) as u8;
+        for (index, dword) in fis.chunks(4).enumerate() {
+            let value = u32::from_le_bytes([dword[0], dword[1], dword[2], dword[3]]);
+            write_u32(memory, COMMAND_TABLE + index * 4, value);
+        }
+
+        for (index, frame) in self.dma.iter().take(regions).enumerate() {
+            let address = frame.start_address().as_u64();
+            let length = (bytes - index * PAGE_SIZE).min(PAGE_SIZE);
+            let entry = PRDT + index * 16;
+            write_u32(memory, entry, address as u32);
+            write_u32(memory, entry + 4, (address >> 32) as u32);
+            write_u32(memory, entry + 8, 0);
+            write_u32(memory, entry + 12, length as u32 - 1);
+        }
+
+        let port = self.registers;
+        self.events.store(0, Ordering::SeqCst);
+        port.write(PORT_INTERRUPT_STATUS, !0);
+        port.write(PORT_COMMAND_ISSUE, 1);
+        self.wait()
+    }
+
+    /// Waits for the command in the first slot to complete
+    fn wait(&self) -> Result<(), AtaError> {
+        let port = self.registers;
+        let done = || {
+            let events = self.events.load(Ordering::SeqCst) | port.read(PORT_INTERRUPT_STATUS);
+            events & INTERRUPT_ERRORS != 0 || port.read(PORT_COMMAND_ISSUE) & 1 == 0
+        };
+
+        let interrupts_enabled = x86_64::instructions::interrupts::are_enabled();
+        if self.interrupt_driven && interrupts_enabled {
+            let deadline = time::ticks() + time::ms_to_ticks(COMMAND_TIMEOUT_MS);
+            if !self.waiters.wait_until_deadline(done, deadline) {
+                self.recover();
+                return Err(AtaError::Timeout);
+            }
+        } else if !(0..POLL_LIMIT).any(|_| done()) {
+            self.recover();
+            return Err(AtaError::Timeout);
+        }
+
+        let events = self.events.swap(0, Ordering::SeqCst) | port.read(PORT_INTERRUPT_STATUS);
+        let task_file = port.read(PORT_TASK_FILE);
+        if events & INTERRUPT_ERRORS != 0 || task_file & 1 != 0 {
+            self.recover();
+            return Err(AtaError::decode(task_file as u8, (task_file >> 8) as u8, false));
+        }
+        Ok(())
+    }
+
+    /// Stops the port of a disk that won't be used and frees its memory. If
+    /// the port doesn't stop it may still write there, the memory is kept.
+    fn release(self) {
+        if let Err(error) = self.stop() {
+            println!("ahci: port {} doesn't stop: {:?}", self.number, error);
+            return;
+        }
+        let port = self.registers;
+        port.write(PORT_INTERRUPT_ENABLE, 0);
+        let pointers = [PORT_COMMAND_LIST, PORT_COMMAND_LIST_HIGH, PORT_FIS, PORT_FIS_HIGH];
+        for &register in pointers.iter() {
+            port.write(register, 0);
+        }
+
+        memory::deallocate_frame(self.memory);
+        for frame in self.dma {
+            memory::deallocate_frame(frame);
+        }
+    }
+
+    /// Restarts the port after an error, that clears the command
+    fn recover(&self) {
+        if self.set_up().is_err() {
+            println!("{}: the port doesn't restart", self.name);
+        }
+    }
+
+    fn identify(&mut self) -> Result<(), AtaError> {
+        const LBA48_SUPPORTED: u64 = 1 << 10;
+
+        self.run(CMD_IDENTIFY, 0, 0, SECTOR_SIZE, false)?;
+        let mut data = [0; SECTOR_SIZE];
+        self.copy_from_dma(&mut data);
+        if ata::identify_word(&data, 83) & LBA48_SUPPORTED == 0 {
+            return Err(AtaError::Aborted);
+        }
+
+        self.model = ata::identify_string(&data[54..94]);
+        self.serial = ata::identify_string(&data[20..40]);
+        self.sectors = (100..104).fold(0, |sectors, index| {
+            sectors | ata::identify_word(&data, index) << ((index - 100) * 16)
+        });
+        Ok(())
+    }
+
+    fn copy_from_dma(&self, buffer: &mut [u8]) {
+        for (chunk, frame) in buffer.chunks_mut(PAGE_SIZE).zip(self.dma.iter()) {
+            let source = frame_ptr(*frame);
+            unsafe { ptr::copy_nonoverlapping(source, chunk.as_mut_ptr(), chunk.len()) };
+        }
+    }
+
+    fn copy_to_dma(&self, buffer: &[u8]) {
+        for (chunk, frame) in buffer.chunks(PAGE_SIZE).zip(self.dma.iter()) {
+            let target = frame_ptr(*frame);
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), target, chunk.len()) };
+        }
+    }
+
+    /// Logs a failed command and turns it into a block error
+    fn failed(&self, error: AtaError) -> BlockError {
+        println!("{}: {:?}", self.name, error);
+        BlockError::Io
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        check_request(self, block, buffer.len())?;
+        let _lock = self.lock.lock();
+        let mut sector = block;
+        for chunk in buffer.chunks_mut(MAX_SECTORS * SECTOR_SIZE) {
+            let count = chunk.len() / SECTOR_SIZE;
+            self.run(CMD_READ_DMA_EXT, sector, count, chunk.len(), false)
+                .map_err(|error| self.failed(error))?;
+            self.copy_from_dma(chunk);
+            sector += count as u64;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> BlockResult<()> {
+        check_request(self, block, buffer.len())?;
+        let _lock = self.lock.lock();
+        let mut sector = block;
+        for chunk in buffer.chunks(MAX_SECTORS * SECTOR_SIZE) {
+            let count = chunk.len() / SECTOR_SIZE;
+            self.copy_to_dma(chunk);
+            self.run(CMD_WRITE_DMA_EXT, sector, count, chunk.len(), true)
+                .map_err(|error| self.failed(error))?;
+            sector += count as u64;
+        }
+
+        // The disk may keep the data in its cache
+        self.run(CMD_FLUSH_CACHE_EXT, 0, 0, 0, false)
+            .map_err(|error| self.failed(error))
+    }
+}
+
+/// Allocates the memory of a port, `None` if there aren't enough frames or
+/// the controller can't reach them
+fn allocate_port_memory(addresses_64bit: bool) -> Option<(PhysFrame, Vec<PhysFrame>)> {
+    let mut frames = Vec::new();
+    for _ in 0..=DMA_FRAMES {
+        match memory::allocate_zeroed_frame() {
+            Some(frame) if addresses_64bit || frame.start_address().as_u64() >> 32 == 0 => {
+                frames.push(frame)
+            }
+            other => {
+                frames.extend(other);
+                for frame in frames {
+                    memory::deallocate_frame(frame);
+                }
+                return None;
+            }
+        }
+    }
+    let memory = frames.remove(0);
+    Some((memory, frames))
+}
+
+/// Sets up the disks on the ports of a controller, their names start with
+/// `sd` followed by `letter`, which is counted up
+fn probe(device: &pci::Device, registers: Registers, letter: &mut u8) -> Controller {
+    let address = device.address;
+    address.enable(pci::COMMAND_MEMORY_SPACE | pci::COMMAND_BUS_MASTER);
+    let command = address.read_u16(pci::COMMAND);
+    address.write_u16(pci::COMMAND, command & !pci::COMMAND_INTERRUPT_DISABLE);
+
+    registers.write(HBA_CONTROL, registers.read(HBA_CONTROL) | CONTROL_AHCI_ENABLE);
+    let addresses_64bit = registers.read(HBA_CAPABILITIES) & CAPABILITY_64BIT != 0;
+    // MSI if there's a local APIC to send it to, the shared line otherwise
+    let interrupt_driven = msi::enable(device, interrupt).is_ok()
+        || interrupts::register_irq_handler(address.interrupt_line(), interrupt);
+
+    let mut disks = Vec::new();
+    let implemented = registers.read(HBA_PORTS_IMPLEMENTED);
+    for number in (0..32).filter(|number| implemented & 1 << number != 0) {
+        let port = registers.port(number);
+        if port.read(PORT_SATA_STATUS) & 0xF != SATA_STATUS_ACTIVE
+            || port.read(PORT_SIGNATURE) != SIGNATURE_ATA
+        {
+            continue;
+        }
+        let (memory, dma) = match allocate_port_memory(addresses_64bit) {
+            Some(memory) => memory,
+            None => {
+                println!("ahci: no memory for port {}", number);
+                continue;
+            }
+        };
+
+        let mut disk = AhciDisk {
+            name: format!("sd{}", *letter as char),
+            number,
+            registers: port,
+            memory,
+            dma,
+            model: String::new(),
+            serial: String::new(),
+            sectors: 0,
+            interrupt_driven: false,
+            events: AtomicU32::new(0),
+            waiters: WaitQueue::new(),
+            lock: Mutex::new(()),
+        };
+        // Polled until the controller interrupts
+        if let Err(error) = disk.set_up().and_then(|_| disk.identify()) {
+            println!("ahci: port {}: {:?}", number, error);
+            disk.release();
+            continue;
+        }
+        disk.interrupt_driven = interrupt_driven;
+        println!(
+            "{}: {} on port {}, {} sectors",
+            disk.name, disk.model, number, disk.sectors
+        );
+        *letter += 1;
+        disks.push(Arc::new(disk));
+    }
+
+    Controller {
+        registers,
+        disks,
+        interrupt_driven,
+    }
+}
+
+static DRIVER: pci::Driver = pci::Driver {
+    name: "ahci",
+    // Mass storage, SATA, AHCI
+    ids: &[pci::DeviceId::Interface(0x01, 0x06, 0x01)],
+    probe: set_up_controller,
+};
+
+/// Sets up a controller the PCI bus found and registers its disks
+fn set_up_controller(device: &pci::Device) -> bool {
+    let registers = match device.map_bar(ABAR) {
+        Some(registers) => Registers(registers.as_mut_ptr()),
+        None => {
+            println!("ahci: {} has no registers", device.address);
+            return false;
+        }
+    };
+    // Disks of earlier controllers come first
+    let mut letter = b'a' + disks().len() as u8;
+    let controller = probe(device, registers, &mut letter);
+    for disk in controller.disks.iter() {
+        super::register(&disk.name, disk.clone()).expect("Failed to register a disk");
+    }
+
+    // The handler has to know the controller before it interrupts
+    let interrupt_driven = controller.interrupt_driven;
+    CONTROLLERS.lock().push(controller);
+    if interrupt_driven {
+        let control = registers.read(HBA_CONTROL);
+        registers.write(HBA_CONTROL, control | CONTROL_INTERRUPT_ENABLE);
+    }
+    true
+}
+
+/// Sets up the AHCI controllers and registers their disks
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+/// The disks `init` found
+pub fn disks() -> Vec<Arc<AhciDisk>> {
+    CONTROLLERS
+        .lock()
+        .iter()
+        .flat_map(|controller| controller.disks.iter().cloned())
+        .collect()
+}
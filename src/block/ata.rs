@@ -0,0 +1,646 @@
+//! ATA and ATAPI drives on the two IDE channels, transferred with PIO.
+//!
+//! Each channel has a master and a slave drive and raises its own IRQ, 14
+//! for the primary and 15 for the secondary channel. `init` probes the
+//! four drives with IDENTIFY and registers the ones that answer as
+//! `/dev/hda` to `/dev/hdd`. Hard disks are read and written with 28-bit
+//! LBA, and with 48-bit LBA past the first 128 GiB if they support it.
+//! ATAPI drives like CD-ROMs are read-only, their capacity is read once at
+//! boot.
+//!
+//! A thread that issued a command sleeps until the drive raises its IRQ,
+//! the command times out if it doesn't. With interrupts disabled the status
+//! is polled instead.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use lazy_static::lazy_static;
+
+use super::{check_request, BlockDevice, BlockError, BlockResult, SECTOR_SIZE};
+use crate::interrupts;
+use crate::lock_class;
+use crate::println;
+use crate::sync::{IrqSpinLock, Mutex, WaitQueue};
+use crate::time;
+
+// Registers at offsets from the I/O base of a channel
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const FEATURES: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7;
+const COMMAND: u16 = 7;
+
+// Status bits
+const STATUS_ERROR: u8 = 0x01;
+const STATUS_DATA_REQUEST: u8 = 0x08;
+const STATUS_DEVICE_FAULT: u8 = 0x20;
+const STATUS_BUSY: u8 = 0x80;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_PACKET: u8 = 0xA0;
+const CMD_IDENTIFY_PACKET: u8 = 0xA1;
+const CMD_FLUSH_CACHE: u8 = 0xE7;
+const CMD_FLUSH_CACHE_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+// SCSI commands sent in ATAPI packets
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+
+/// Block size of CD-ROMs
+const ATAPI_BLOCK_SIZE: usize = 2048;
+/// Sectors of one command. It's the most LBA28 commands can transfer, they
+/// take a sector count of 0 as 256.
+const MAX_SECTORS: u64 = 256;
+/// First sector that needs 48-bit LBA
+const LBA28_LIMIT: u64 = 1 << 28;
+/// Status reads before giving up on a drive that stays busy
+const POLL_LIMIT: usize = 1_000_000;
+/// How long a step of a command may take to interrupt, enough for a disk
+/// to spin up
+const INTERRUPT_TIMEOUT_MS: u64 = 10_000;
+
+/// Why a command failed
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AtaError {
+    /// The drive stayed busy or never asked for data
+    Timeout,
+    DeviceFault,
+    AddressMarkNotFound,
+    Track0NotFound,
+    /// The drive doesn't support the command or its arguments
+    Aborted,
+    MediaChangeRequest,
+    /// The sector doesn't exist
+    IdNotFound,
+    MediaChanged,
+    /// The data couldn't be corrected
+    Uncorrectable,
+    BadBlock,
+    /// An ATAPI drive without a medium
+    NotReady,
+    /// The error bit without a reason
+    Unknown,
+}
+
+impl AtaError {
+    /// Decodes the status register and, if the error bit is set, the error
+    /// register. ATAPI drives put a sense key into the upper bits of the
+    /// error register.
+    pub(super) fn decode(status: u8, error: u8, atapi: bool) -> AtaError {
+        const SENSE_NOT_READY: u8 = 2;
+
+        if status & STATUS_DEVICE_FAULT != 0 {
+            return AtaError::DeviceFault;
+        }
+        if atapi && error >> 4 == SENSE_NOT_READY {
+            return AtaError::NotReady;
+        }
+
+        let reasons = [
+            (0x80, AtaError::BadBlock),
+            (0x40, AtaError::Uncorrectable),
+            (0x20, AtaError::MediaChanged),
+            (0x10, AtaError::IdNotFound),
+            (0x08, AtaError::MediaChangeRequest),
+            (0x04, AtaError::Aborted),
+            (0x02, AtaError::Track0NotFound),
+            (0x01, AtaError::AddressMarkNotFound),
+        ];
+        let error = if atapi { error & 0x0F } else { error };
+        reasons
+            .iter()
+            .find(|&&(bit, _)| error & bit != 0)
+            .map_or(AtaError::Unknown, |&(_, reason)| reason)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DriveKind {
+    /// A hard disk
+    Ata,
+    /// A packet device like a CD-ROM
+    Atapi,
+}
+
+struct Channel {
+    base: u16,
+    /// Alternate status when read, device control when written
+    control: u16,
+    irq: u8,
+    /// Set by the interrupt handler, cleared before a command is issued
+    interrupted: AtomicBool,
+    waiters: WaitQueue,
+    /// Only one drive of a channel runs a command at a time
+    lock: Mutex<()>,
+}
+
+lazy_static! {
+    static ref CHANNELS: [Channel; 2] = [
+        Channel::new(0x1F0, 0x3F6, 14),
+        Channel::new(0x170, 0x376, 15),
+    ];
+    static ref DRIVES: IrqSpinLock<Vec<Arc<AtaDrive>>> =
+        IrqSpinLock::with_class(Vec::new(), lock_class!("ATA_DRIVES"));
+}
+
+impl Channel {
+    fn new(base: u16, control: u16, irq: u8) -> Channel {
+        Channel {
+            base,
+            control,
+            irq,
+            interrupted: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// Reads the status without acknowledging an interrupt
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    /// Selects the master or the slave, it takes 400ns to switch
+    fn select(&self, slave: bool, bits: u8) {
+        self.write(DRIVE, 0xA0 | (slave as u8) << 4 | bits);
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    /// Clears the interrupt flag and issues `command`
+    fn issue(&self, command: u8) {
+        self.interrupted.store(false, Ordering::SeqCst);
+        self.write(COMMAND, command);
+    }
+
+    /// Waits until the drive isn't busy, returns the status
+    fn poll(&self) -> Result<u8, AtaError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(AtaError::Timeout)
+    }
+
+    /// Waits until the drive is done with the current step of a command,
+    /// returns the status
+    fn wait(&self) -> Result<u8, AtaError> {
+        if x86_64::instructions::interrupts::are_enabled() {
+            let interrupted = &self.interrupted;
+            let deadline = time::ticks() + time::ms_to_ticks(INTERRUPT_TIMEOUT_MS);
+            let raised = self
+                .waiters
+                .wait_until_deadline(|| interrupted.swap(false, Ordering::SeqCst), deadline);
+            if !raised {
+                return Err(AtaError::Timeout);
+            }
+        }
+        self.poll()
+    }
+
+    /// Like `wait`, fails if the drive reports an error
+    fn wait_ok(&self, atapi: bool) -> Result<u8, AtaError> {
+        let status = self.wait()?;
+        self.check(status, atapi)
+    }
+
+    fn check(&self, status: u8, atapi: bool) -> Result<u8, AtaError> {
+        if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 {
+            return Err(AtaError::decode(status, self.read(ERROR), atapi));
+        }
+        Ok(status)
+    }
+
+    /// Waits for the drive to ask for data without an interrupt, like
+    /// before the first sector of a write
+    fn wait_data_request(&self, atapi: bool) -> Result<(), AtaError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.check(self.poll()?, atapi)?;
+            if status & STATUS_DATA_REQUEST != 0 {
+                return Ok(());
+            }
+        }
+        Err(AtaError::Timeout)
+    }
+
+    fn read_data(&self, buffer: &mut [u8]) {
+        let mut data: Port<u16> = Port::new(self.base + DATA);
+        for word in buffer.chunks_mut(2) {
+            let value = unsafe { data.read() };
+            word[0] = value as u8;
+            word[1] = (value >> 8) as u8;
+        }
+    }
+
+    fn write_data(&self, buffer: &[u8]) {
+        let mut data: Port<u16> = Port::new(self.base + DATA);
+        for word in buffer.chunks(2) {
+            unsafe { data.write(u16::from(word[0]) | u16::from(word[1]) << 8) };
+        }
+    }
+}
+
+/// Called by the interrupt handler of the channel's IRQ
+pub(crate) fn interrupt(channel: usize) {
+    let channel = &CHANNELS[channel];
+    // Reading the status acknowledges the interrupt
+    channel.read(STATUS);
+    channel.interrupted.store(true, Ordering::SeqCst);
+    channel.waiters.wake_all();
+}
+
+/// What IDENTIFY tells about a drive
+struct Identity {
+    kind: DriveKind,
+    model: String,
+    serial: String,
+    lba48: bool,
+    sectors: u64,
+}
+
+/// An ASCII string of IDENTIFY data, its bytes are swapped in each word
+pub(super) fn identify_string(data: &[u8]) -> String {
+    let mut text = String::new();
+    for word in data.chunks(2) {
+        text.push(word[1] as char);
+        text.push(word[0] as char);
+    }
+    String::from(text.trim())
+}
+
+pub(super) fn identify_word(data: &[u8], index: usize) -> u64 {
+    u64::from(data[index * 2]) | u64::from(data[index * 2 + 1]) << 8
+}
+
+/// Sends IDENTIFY to a drive, `None` if there's no drive or it's neither
+/// ATA nor ATAPI
+fn identify(channel: &Channel, slave: bool) -> Option<Identity> {
+    const LBA_SUPPORTED: u64 = 1 << 9;
+    const LBA48_SUPPORTED: u64 = 1 << 10;
+
+    let _lock = channel.lock.lock();
+    channel.select(slave, 0);
+    // Nothing is connected to a floating bus
+    if channel.alternate_status() == 0xFF {
+        return None;
+    }
+    for &register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH].iter() {
+        channel.write(register, 0);
+    }
+    channel.issue(CMD_IDENTIFY);
+    if channel.alternate_status() == 0 {
+        return None;
+    }
+
+    // A packet device aborts IDENTIFY and leaves its signature
+    let mut kind = DriveKind::Ata;
+    let status = channel.poll().ok()?;
+    if status & STATUS_ERROR != 0 || channel.read(LBA_MID) != 0 || channel.read(LBA_HIGH) != 0 {
+        if (channel.read(LBA_MID), channel.read(LBA_HIGH)) != (0x14, 0xEB) {
+            return None;
+        }
+        kind = DriveKind::Atapi;
+        channel.issue(CMD_IDENTIFY_PACKET);
+    }
+    channel.wait_data_request(kind == DriveKind::Atapi).ok()?;
+    let mut data = [0; SECTOR_SIZE];
+    channel.read_data(&mut data);
+    channel.interrupted.store(false, Ordering::SeqCst);
+
+    let lba48 = kind == DriveKind::Ata && identify_word(&data, 83) & LBA48_SUPPORTED != 0;
+    let sectors = if kind == DriveKind::Atapi {
+        0
+    } else if lba48 {
+        (100..104).fold(0, |sectors, index| {
+            sectors | identify_word(&data, index) << ((index - 100) * 16)
+        })
+    } else if identify_word(&data, 49) & LBA_SUPPORTED != 0 {
+        identify_word(&data, 60) | identify_word(&data, 61) << 16
+    } else {
+        // Only addressed by cylinder, head and sector
+        return None;
+    };
+
+    Some(Identity {
+        kind,
+        model: identify_string(&data[54..94]),
+        serial: identify_string(&data[20..40]),
+        lba48,
+        sectors,
+    })
+}
+
+/// A drive on one of the channels
+pub struct AtaDrive {
+    channel: &'static Channel,
+    slave: bool,
+    name: String,
+    identity: Identity,
+    /// Sectors of a disk, blocks of 2048 bytes of a packet device
+    block_count: u64,
+}
+
+impl fmt::Debug for AtaDrive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AtaDrive")
+            .field("name", &self.name)
+            .field("kind", &self.identity.kind)
+            .field("model", &self.identity.model)
+            .field("block_count", &self.block_count)
+            .finish()
+    }
+}
+
+impl AtaDrive {
+    /// Its name in `/dev`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> DriveKind {
+        self.identity.kind
+    }
+
+    pub fn model(&self) -> &str {
+        &self.identity.model
+    }
+
+    pub fn serial(&self) -> &str {
+        &self.identity.serial
+    }
+
+    pub fn supports_lba48(&self) -> bool {
+        self.identity.lba48
+    }
+
+    /// Selects the drive and sets up the sector count and address of a
+    /// transfer of up to `MAX_SECTORS`, returns whether it's a 48-bit one
+    fn set_address(&self, sector: u64, count: u64) -> bool {
+        let channel = self.channel;
+        let lba48 = sector + count > LBA28_LIMIT;
+        if lba48 {
+            channel.select(self.slave, 0x40);
+            // The high bytes go first, into the same registers
+            channel.write(SECTOR_COUNT, (count >> 8) as u8);
+            channel.write(LBA_LOW, (sector >> 24) as u8);
+            channel.write(LBA_MID, (sector >> 32) as u8);
+            channel.write(LBA_HIGH, (sector >> 40) as u8);
+        } else {
+            channel.select(self.slave, 0x40 | (sector >> 24) as u8 & 0x0F);
+        }
+        // 256 becomes 0, which is what LBA28 wants
+        channel.write(SECTOR_COUNT, count as u8);
+        channel.write(LBA_LOW, sector as u8);
+        channel.write(LBA_MID, (sector >> 8) as u8);
+        channel.write(LBA_HIGH, (sector >> 16) as u8);
+        lba48
+    }
+
+    fn check_lba48(&self, sector: u64, count: u64) -> Result<(), AtaError> {
+        if sector + count > LBA28_LIMIT && !self.identity.lba48 {
+            return Err(AtaError::IdNotFound);
+        }
+        Ok(())
+    }
+
+    fn read_sectors(&self, mut sector: u64, buffer: &mut [u8]) -> Result<(), AtaError> {
+        let channel = self.channel;
+        let _lock = channel.lock.lock();
+        for chunk in buffer.chunks_mut(MAX_SECTORS as usize * SECTOR_SIZE) {
+            let count = (chunk.len() / SECTOR_SIZE) as u64;
+            self.check_lba48(sector, count)?;
+            let lba48 = self.set_address(sector, count);
+            channel.issue(if lba48 {
+                CMD_READ_SECTORS_EXT
+            } else {
+                CMD_READ_SECTORS
+            });
+            for data in chunk.chunks_mut(SECTOR_SIZE) {
+                let status = channel.wait_ok(false)?;
+                if status & STATUS_DATA_REQUEST == 0 {
+                    return Err(AtaError::Unknown);
+                }
+                channel.read_data(data);
+            }
+            sector += count;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, mut sector: u64, buffer: &[u8]) -> Result<(), AtaError> {
+        let channel = self.channel;
+        let _lock = channel.lock.lock();
+        let mut lba48 = false;
+        for chunk in buffer.chunks(MAX_SECTORS as usize * SECTOR_SIZE) {
+            let count = (chunk.len() / SECTOR_SIZE) as u64;
+            self.check_lba48(sector, count)?;
+            let chunk_lba48 = self.set_address(sector, count);
+            lba48 |= chunk_lba48;
+            channel.issue(if chunk_lba48 {
+                CMD_WRITE_SECTORS_EXT
+            } else {
+                CMD_WRITE_SECTORS
+            });
+            // The drive interrupts once it took a sector
+            for data in chunk.chunks(SECTOR_SIZE) {
+                channel.wait_data_request(false)?;
+                channel.write_data(data);
+                channel.wait_ok(false)?;
+            }
+            sector += count;
+        }
+
+        // The drive may keep the data in its cache
+        channel.select(self.slave, 0x40);
+        channel.issue(if lba48 {
+            CMD_FLUSH_CACHE_EXT
+        } else {
+            CMD_FLUSH_CACHE
+        });
+        channel.wait_ok(false)?;
+        Ok(())
+    }
+
+    /// Sends a SCSI command to a packet device, the data it returns goes
+    /// into `buffer`
+    fn packet(&self, command: &[u8; 12], buffer: &mut [u8]) -> Result<(), AtaError> {
+        let channel = self.channel;
+        let _lock = channel.lock.lock();
+        channel.select(self.slave, 0);
+        // PIO, at most a block per data request
+        channel.write(FEATURES, 0);
+        channel.write(LBA_MID, ATAPI_BLOCK_SIZE as u8);
+        channel.write(LBA_HIGH, (ATAPI_BLOCK_SIZE >> 8) as u8);
+        channel.issue(CMD_PACKET);
+        channel.wait_data_request(true)?;
+        // Only the data of the command interrupts
+        channel.interrupted.store(false, Ordering::SeqCst);
+        channel.write_data(command);
+
+        let mut done = 0;
+        loop {
+            let status = channel.wait_ok(true)?;
+            if status & STATUS_DATA_REQUEST == 0 {
+                break;
+            }
+            let length =
+                usize::from(channel.read(LBA_MID)) | usize::from(channel.read(LBA_HIGH)) << 8;
+            // Nothing to transfer would ask for data forever
+            if length == 0 || done + length > buffer.len() {
+                return Err(AtaError::Unknown);
+            }
+            channel.read_data(&mut buffer[done..done + length]);
+            done += length;
+        }
+
+        if done != buffer.len() {
+            return Err(AtaError::Unknown);
+        }
+        Ok(())
+    }
+
+    /// Number of blocks of the medium in a packet device
+    fn read_capacity(&self) -> Result<u64, AtaError> {
+        let mut command = [0; 12];
+        command[0] = SCSI_READ_CAPACITY;
+        let mut capacity = [0; 8];
+        self.packet(&command, &mut capacity)?;
+
+        let last = u32::from_be_bytes([capacity[0], capacity[1], capacity[2], capacity[3]]);
+        let block_size = u32::from_be_bytes([capacity[4], capacity[5], capacity[6], capacity[7]]);
+        if block_size as usize != ATAPI_BLOCK_SIZE {
+            return Err(AtaError::Unknown);
+        }
+        Ok(u64::from(last) + 1)
+    }
+
+    fn read_packet_blocks(&self, mut block: u64, buffer: &mut [u8]) -> Result<(), AtaError> {
+        // Blocks of one READ (10)
+        const MAX_BLOCKS: usize = 32;
+
+        for chunk in buffer.chunks_mut(MAX_BLOCKS * ATAPI_BLOCK_SIZE) {
+            let count = chunk.len() / ATAPI_BLOCK_SIZE;
+            let mut command = [0; 12];
+            command[0] = SCSI_READ_10;
+            command[2..6].copy_from_slice(&(block as u32).to_be_bytes());
+            command[7..9].copy_from_slice(&(count as u16).to_be_bytes());
+            self.packet(&command, chunk)?;
+            block += count as u64;
+        }
+        Ok(())
+    }
+
+    /// Logs a failed command and turns it into a block error
+    fn failed(&self, error: AtaError) -> BlockError {
+        println!("{}: {:?}", self.name, error);
+        BlockError::Io
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn block_size(&self) -> usize {
+        match self.identity.kind {
+            DriveKind::Ata => SECTOR_SIZE,
+            DriveKind::Atapi => ATAPI_BLOCK_SIZE,
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.identity.kind == DriveKind::Atapi
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        check_request(self, block, buffer.len())?;
+        let result = match self.identity.kind {
+            DriveKind::Ata => self.read_sectors(block, buffer),
+            DriveKind::Atapi => self.read_packet_blocks(block, buffer),
+        };
+        result.map_err(|error| self.failed(error))
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> BlockResult<()> {
+        check_request(self, block, buffer.len())?;
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        self.write_sectors(block, buffer).map_err(|error| self.failed(error))
+    }
+}
+
+/// Probes the drives on both channels and registers the ones found as
+/// disks
+pub fn init() {
+    for channel in CHANNELS.iter() {
+        // Clears nIEN, the drives interrupt when they're done
+        unsafe { Port::new(channel.control).write(0u8) };
+        interrupts::enable_irq(channel.irq);
+    }
+
+    let names = ["hda", "hdb", "hdc", "hdd"];
+    for (index, name) in names.iter().enumerate() {
+        let channel = &CHANNELS[index / 2];
+        let slave = index % 2 == 1;
+        let identity = match identify(channel, slave) {
+            Some(identity) => identity,
+            None => continue,
+        };
+
+        let mut drive = AtaDrive {
+            channel,
+            slave,
+            name: String::from(*name),
+            block_count: identity.sectors,
+            identity,
+        };
+        if drive.kind() == DriveKind::Atapi {
+            drive.block_count = drive.read_capacity().unwrap_or(0);
+        }
+        println!(
+            "{}: {:?} {}, {} blocks of {} bytes",
+            name,
+            drive.kind(),
+            drive.model(),
+            drive.block_count,
+            drive.block_size()
+        );
+
+        let drive = Arc::new(drive);
+        super::register(name, drive.clone()).expect("Failed to register a drive");
+        DRIVES.lock().push(drive);
+    }
+}
+
+/// The drives `init` found
+pub fn drives() -> Vec<Arc<AtaDrive>> {
+    DRIVES.lock().clone()
+}
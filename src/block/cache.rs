@@ -0,0 +1,140 @@
+//! Buffer cache of a block device.
+//!
+//! Keeps the most recently used blocks in memory. It doesn't talk to the
+//! device itself: the caller reads what's missing, and writes dirty
+//! buffers back when they're evicted or the cache is synced.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+pub struct Buffer {
+    pub data: Vec<u8>,
+    /// Changed since it was read or last written back
+    pub dirty: bool,
+    used: u64,
+}
+
+pub struct BufferCache {
+    capacity: usize,
+    buffers: BTreeMap<u64, Buffer>,
+    /// Blocks by when they were last used, the first is the least recently
+    /// used one
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl BufferCache {
+    /// Creates a cache that holds up to `capacity` blocks, at least one
+    pub fn new(capacity: usize) -> BufferCache {
+        BufferCache {
+            capacity: capacity.max(1),
+            buffers: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.buffers.len() >= self.capacity
+    }
+
+    pub fn contains(&self, block: u64) -> bool {
+        self.buffers.contains_key(&block)
+    }
+
+    /// The block `insert` evicts next
+    pub fn least_recently_used(&self) -> Option<u64> {
+        self.lru.values().next().cloned()
+    }
+
+    /// Returns the cached `block` without using it
+    pub fn peek(&self, block: u64) -> Option<&Buffer> {
+        self.buffers.get(&block)
+    }
+
+    /// Returns the cached `block` and makes it the most recently used one
+    pub fn get(&mut self, block: u64) -> Option<&mut Buffer> {
+        let now = self.tick();
+        let buffer = self.buffers.get_mut(&block)?;
+        self.lru.remove(&buffer.used);
+        self.lru.insert(now, block);
+        buffer.used = now;
+        Some(buffer)
+    }
+
+    /// Caches `data` as `block` in place of what was there. Returns the
+    /// least recently used block and its buffer if one had to go for it.
+    pub fn insert(&mut self, block: u64, data: Vec<u8>, dirty: bool) -> Option<(u64, Buffer)> {
+        let evicted = match self.least_recently_used() {
+            Some(oldest) if self.is_full() && !self.contains(block) => {
+                self.remove(oldest).map(|buffer| (oldest, buffer))
+            }
+            _ => None,
+        };
+
+        let used = self.tick();
+        if let Some(old) = self.buffers.insert(block, Buffer { data, dirty, used }) {
+            self.lru.remove(&old.used);
+        }
+        self.lru.insert(used, block);
+        evicted
+    }
+
+    pub fn remove(&mut self, block: u64) -> Option<Buffer> {
+        let buffer = self.buffers.remove(&block)?;
+        self.lru.remove(&buffer.used);
+        Some(buffer)
+    }
+
+    /// The cached blocks from `start` up to `end`, without using them
+    pub fn range_mut(
+        &mut self,
+        start: u64,
+        end: u64,
+    ) -> impl Iterator<Item = (&u64, &mut Buffer)> {
+        self.buffers.range_mut(start..end)
+    }
+
+    /// Marks `block` as written back
+    pub fn mark_clean(&mut self, block: u64) {
+        if let Some(buffer) = self.buffers.get_mut(&block) {
+            buffer.dirty = false;
+        }
+    }
+
+    /// Empties the cache, dirty buffers included
+    pub fn clear(&mut self) {
+        self.buffers.clear();
+        self.lru.clear();
+    }
+
+    /// The dirty blocks, in order
+    pub fn dirty_blocks(&self) -> Vec<u64> {
+        self.buffers
+            .iter()
+            .filter(|(_, buffer)| buffer.dirty)
+            .map(|(&block, _)| block)
+            .collect()
+    }
+
+    pub fn dirty_count(&self) -> usize {
+        self.buffers.values().filter(|buffer| buffer.dirty).count()
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
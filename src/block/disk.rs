@@ -0,0 +1,251 @@
+//! Disks, what filesystems see of block devices.
+//!
+//! A disk puts a driver's device behind a buffer cache. Small requests are
+//! served from the cache and written later, large ones go to the device
+//! directly so they don't push everything else out. Misses and write-backs
+//! reach the driver sorted and merged by a request queue.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::cache::BufferCache;
+use super::queue::RequestQueue;
+use super::{check_request, BlockDevice, BlockError, BlockResult, IoStats};
+use crate::sync::Mutex;
+
+/// Memory the cache of a disk uses at most
+pub const DEFAULT_CACHE_BYTES: usize = 32 * 1024;
+
+pub struct Disk {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    state: Mutex<State>,
+}
+
+struct State {
+    cache: BufferCache,
+    stats: IoStats,
+}
+
+impl Disk {
+    pub fn new(name: &str, device: Arc<dyn BlockDevice>) -> Disk {
+        Disk::with_cache_size(name, device, DEFAULT_CACHE_BYTES)
+    }
+
+    /// Creates a disk that caches up to `bytes` of `device`, at least a
+    /// block
+    pub fn with_cache_size(name: &str, device: Arc<dyn BlockDevice>, bytes: usize) -> Disk {
+        let capacity = bytes / device.block_size();
+        Disk {
+            name: String::from(name),
+            device,
+            state: Mutex::new(State {
+                cache: BufferCache::new(capacity),
+                stats: IoStats::default(),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The driver's device under the cache
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> IoStats {
+        self.state.lock().stats
+    }
+
+    /// Blocks the cache holds at most
+    pub fn cache_capacity(&self) -> usize {
+        self.state.lock().cache.capacity()
+    }
+
+    pub fn cached_blocks(&self) -> usize {
+        self.state.lock().cache.len()
+    }
+
+    pub fn dirty_blocks(&self) -> usize {
+        self.state.lock().cache.dirty_count()
+    }
+
+    /// Writes the dirty blocks to the device, without waiting for the
+    /// device to put them on the medium
+    pub fn write_back(&self) -> BlockResult<()> {
+        self.state.lock().write_back(&*self.device)
+    }
+
+    /// Writes the dirty blocks back and empties the cache, for when the
+    /// device was written without going through the disk
+    pub fn invalidate(&self) -> BlockResult<()> {
+        let mut state = self.state.lock();
+        state.write_back(&*self.device)?;
+        state.cache.clear();
+        Ok(())
+    }
+
+    /// Requests of more blocks than this bypass the cache
+    fn is_large(state: &State, count: u64) -> bool {
+        count as usize > state.cache.capacity() / 2
+    }
+}
+
+impl State {
+    /// Writes all dirty blocks at once
+    fn write_back(&mut self, device: &dyn BlockDevice) -> BlockResult<()> {
+        let dirty = self.cache.dirty_blocks();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+
+        let mut queue = RequestQueue::new();
+        for &block in dirty.iter() {
+            let buffer = self.cache.peek(block).expect("A dirty block isn't cached");
+            queue.write(block, buffer.data.clone());
+        }
+        queue.run(device, &mut self.stats)?;
+        for &block in dirty.iter() {
+            self.cache.mark_clean(block);
+        }
+        self.stats.written_back += dirty.len() as u64;
+        Ok(())
+    }
+
+    /// Caches `block`, writing back first if a dirty block would be evicted
+    /// for it
+    fn insert(
+        &mut self,
+        device: &dyn BlockDevice,
+        block: u64,
+        data: Vec<u8>,
+        dirty: bool,
+    ) -> BlockResult<()> {
+        if self.cache.is_full() && !self.cache.contains(block) {
+            let oldest = self.cache.least_recently_used();
+            if oldest.and_then(|oldest| self.cache.peek(oldest)).map_or(false, |old| old.dirty) {
+                self.write_back(device)?;
+            }
+        }
+        self.cache.insert(block, data, dirty);
+        Ok(())
+    }
+}
+
+impl Drop for Disk {
+    fn drop(&mut self) {
+        // Nobody is left to report the error to
+        let _ = self.write_back();
+    }
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        let count = check_request(self, block, buffer.len())?;
+        let block_size = self.block_size();
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        if Disk::is_large(state, count) {
+            self.device.read_blocks(block, buffer)?;
+            state.stats.reads += 1;
+            state.stats.blocks_read += count;
+            state.stats.misses += count;
+            // Dirty blocks are newer than what the device has
+            for (&cached, cached_buffer) in state.cache.range_mut(block, block + count) {
+                let at = (cached - block) as usize * block_size;
+                buffer[at..at + block_size].copy_from_slice(&cached_buffer.data);
+            }
+            return Ok(());
+        }
+
+        let mut queue = RequestQueue::new();
+        for index in 0..count {
+            let at = index as usize * block_size;
+            match state.cache.get(block + index) {
+                Some(cached) => {
+                    buffer[at..at + block_size].copy_from_slice(&cached.data);
+                    state.stats.hits += 1;
+                }
+                None => {
+                    queue.read(block + index, 1, block_size);
+                    state.stats.misses += 1;
+                }
+            }
+        }
+
+        if queue.is_empty() {
+            return Ok(());
+        }
+        for request in queue.run(&*self.device, &mut state.stats)? {
+            let at = (request.block - block) as usize * block_size;
+            buffer[at..at + block_size].copy_from_slice(&request.data);
+            state.insert(&*self.device, request.block, request.data, false)?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> BlockResult<()> {
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        let count = check_request(self, block, buffer.len())?;
+        let block_size = self.block_size();
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        if Disk::is_large(state, count) {
+            self.device.write_blocks(block, buffer)?;
+            state.stats.writes += 1;
+            state.stats.blocks_written += count;
+            state.stats.misses += count;
+            // What's cached is now what the device has
+            for (&cached, cached_buffer) in state.cache.range_mut(block, block + count) {
+                let at = (cached - block) as usize * block_size;
+                cached_buffer.data.copy_from_slice(&buffer[at..at + block_size]);
+                cached_buffer.dirty = false;
+            }
+            return Ok(());
+        }
+
+        for index in 0..count {
+            let at = index as usize * block_size;
+            let data = &buffer[at..at + block_size];
+            match state.cache.get(block + index) {
+                Some(cached) => {
+                    cached.data.copy_from_slice(data);
+                    cached.dirty = true;
+                    state.stats.hits += 1;
+                }
+                None => {
+                    // Whole blocks are written, there's nothing to read first
+                    state.insert(&*self.device, block + index, data.to_vec(), true)?;
+                    state.stats.misses += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the dirty blocks back and flushes the device
+    fn flush(&self) -> BlockResult<()> {
+        let mut state = self.state.lock();
+        state.write_back(&*self.device)?;
+        self.device.flush()?;
+        state.stats.flushes += 1;
+        Ok(())
+    }
+}
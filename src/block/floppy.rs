@@ -0,0 +1,622 @@
+//! 3.5" 1.44 MB floppy disks in the first drive of an 82077AA controller.
+//!
+//! The type of the drive comes from the CMOS. Sectors are addressed by
+//! cylinder, head and sector and moved by channel 2 of the ISA DMA
+//! controller, through a frame below 16 MiB that holds 8 sectors. The
+//! controller raises IRQ 6 when a reset, seek or transfer is done; a
+//! thread that issued one of those sleeps until it comes, or until a
+//! timeout, so commands need interrupts enabled.
+//!
+//! Failed transfers are retried after recalibrating the drive, or after
+//! resetting the controller if it stopped answering. The motor is started
+//! by the first transfer and turned off after a few seconds without one.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::cmp;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::PhysFrame;
+
+use super::{check_request, BlockDevice, BlockError, BlockResult, SECTOR_SIZE};
+use crate::interrupts;
+use crate::lock_class;
+use crate::memory;
+use crate::println;
+use crate::sync::{IrqSpinLock, Mutex};
+use crate::task;
+use crate::time;
+
+// Controller registers
+const DIGITAL_OUTPUT: u16 = 0x3F2;
+const MAIN_STATUS: u16 = 0x3F4;
+const FIFO: u16 = 0x3F5;
+/// Configuration control when written, digital input when read
+const CONFIGURATION_CONTROL: u16 = 0x3F7;
+
+// Digital output bits, drive 0 is selected while they're all clear
+const DOR_NOT_RESET: u8 = 0x04;
+const DOR_IRQ_DMA: u8 = 0x08;
+const DOR_MOTOR_0: u8 = 0x10;
+
+// Main status bits
+/// Set when the controller sends the next byte, clear when it expects one
+const MSR_DATA_IN: u8 = 0x40;
+const MSR_READY: u8 = 0x80;
+
+const CMD_SPECIFY: u8 = 0x03;
+const CMD_WRITE_DATA: u8 = 0x05;
+const CMD_READ_DATA: u8 = 0x06;
+const CMD_RECALIBRATE: u8 = 0x07;
+const CMD_SENSE_INTERRUPT: u8 = 0x08;
+const CMD_SEEK: u8 = 0x0F;
+const CMD_VERSION: u8 = 0x10;
+/// Double density, sent along with reads and writes
+const CMD_MFM: u8 = 0x40;
+
+/// What VERSION returns on an 82077AA
+const VERSION_82077AA: u8 = 0x90;
+
+// Status register 0
+const ST0_INTERRUPT_CODE: u8 = 0xC0;
+const ST0_EQUIPMENT_CHECK: u8 = 0x10;
+// Status register 1
+const ST1_MISSING_ADDRESS_MARK: u8 = 0x01;
+const ST1_NOT_WRITABLE: u8 = 0x02;
+const ST1_NO_DATA: u8 = 0x04;
+const ST1_OVERRUN: u8 = 0x10;
+const ST1_DATA_ERROR: u8 = 0x20;
+// Status register 2
+const ST2_MISSING_DATA_MARK: u8 = 0x01;
+const ST2_DATA_ERROR_IN_DATA: u8 = 0x20;
+
+// Channel 2 of the first ISA DMA controller
+const DMA_ADDRESS: u16 = 0x04;
+const DMA_COUNT: u16 = 0x05;
+const DMA_MASK: u16 = 0x0A;
+const DMA_MODE: u16 = 0x0B;
+const DMA_FLIP_FLOP: u16 = 0x0C;
+const DMA_PAGE: u16 = 0x81;
+const DMA_CHANNEL: u8 = 2;
+const DMA_MASK_ON: u8 = 0x04;
+/// Single transfers from the device into memory
+const DMA_MODE_TO_MEMORY: u8 = 0x44;
+/// Single transfers from memory to the device
+const DMA_MODE_FROM_MEMORY: u8 = 0x48;
+/// The ISA DMA controller only reaches the first 16 MiB
+const DMA_LIMIT: u64 = 16 << 20;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Types of the first drive in the high nibble, the second in the low one
+const CMOS_FLOPPY_TYPES: u8 = 0x10;
+const DRIVE_TYPE_1440K: u8 = 4;
+
+const FLOPPY_IRQ: u8 = 6;
+
+const CYLINDERS: u64 = 80;
+const HEADS: u64 = 2;
+const SECTORS_PER_TRACK: u64 = 18;
+/// Sector size as the controller encodes it, 128 << 2
+const SECTOR_SIZE_CODE: u8 = 2;
+/// Gap between sectors of a 1.44 MB disk
+const GAP_LENGTH: u8 = 0x1B;
+/// Sectors that fit into the DMA frame
+const SECTORS_PER_TRANSFER: u64 = 8;
+
+/// Attempts of a transfer before it fails
+const ATTEMPTS: usize = 3;
+/// Status reads before giving up on a byte of a command
+const POLL_LIMIT: usize = 100_000;
+/// How long a command may take to interrupt, a few turns of the disk
+const INTERRUPT_TIMEOUT_MS: u64 = 2000;
+const MOTOR_SPIN_UP_MS: u64 = 300;
+const HEAD_SETTLE_MS: u64 = 15;
+const MOTOR_IDLE_MS: u64 = 3000;
+
+/// Set by the interrupt handler, cleared before a command is issued
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+static DRIVE: IrqSpinLock<Option<Arc<FloppyDrive>>> =
+    IrqSpinLock::with_class(None, lock_class!("FLOPPY"));
+
+/// Why a command failed
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FloppyError {
+    /// The controller didn't take or send a byte, or didn't interrupt
+    Timeout,
+    /// The head didn't get to the cylinder
+    SeekFailed,
+    WriteProtected,
+    /// The sector wasn't found on the track
+    NoData,
+    MissingAddressMark,
+    /// The CRC of an ID field or of the data didn't match
+    DataError,
+    /// The DMA controller didn't keep up
+    Overrun,
+    /// The drive isn't there or isn't ready
+    EquipmentCheck,
+    /// An abnormal termination without a reason
+    Unknown,
+}
+
+impl FloppyError {
+    /// Decodes the status registers of the result of a read or write,
+    /// `None` if it ended normally
+    fn decode(st0: u8, st1: u8, st2: u8) -> Option<FloppyError> {
+        if st0 & ST0_INTERRUPT_CODE == 0 {
+            return None;
+        }
+        if st0 & ST0_EQUIPMENT_CHECK != 0 {
+            return Some(FloppyError::EquipmentCheck);
+        }
+
+        let reasons = [
+            (st1 & ST1_NOT_WRITABLE, FloppyError::WriteProtected),
+            (st1 & ST1_OVERRUN, FloppyError::Overrun),
+            (st1 & ST1_DATA_ERROR, FloppyError::DataError),
+            (st2 & ST2_DATA_ERROR_IN_DATA, FloppyError::DataError),
+            (st1 & ST1_NO_DATA, FloppyError::NoData),
+            (st1 & ST1_MISSING_ADDRESS_MARK, FloppyError::MissingAddressMark),
+            (st2 & ST2_MISSING_DATA_MARK, FloppyError::MissingAddressMark),
+        ];
+        let reason = reasons.iter().find(|&&(bits, _)| bits != 0);
+        Some(reason.map_or(FloppyError::Unknown, |&(_, reason)| reason))
+    }
+}
+
+/// Cylinder, head and sector of a block
+fn chs(block: u64) -> (u8, u8, u8) {
+    let cylinder = block / (HEADS * SECTORS_PER_TRACK);
+    let head = block / SECTORS_PER_TRACK % HEADS;
+    let sector = block % SECTORS_PER_TRACK + 1;
+    (cylinder as u8, head as u8, sector as u8)
+}
+
+fn read_port(port: u16) -> u8 {
+    unsafe { Port::new(port).read() }
+}
+
+fn write_port(port: u16, value: u8) {
+    unsafe { Port::new(port).write(value) }
+}
+
+/// Sends a byte of a command once the controller expects one
+fn send(byte: u8) -> Result<(), FloppyError> {
+    for _ in 0..POLL_LIMIT {
+        if read_port(MAIN_STATUS) & (MSR_READY | MSR_DATA_IN) == MSR_READY {
+            write_port(FIFO, byte);
+            return Ok(());
+        }
+    }
+    Err(FloppyError::Timeout)
+}
+
+/// Receives a byte of a result once the controller has one
+fn receive() -> Result<u8, FloppyError> {
+    for _ in 0..POLL_LIMIT {
+        if read_port(MAIN_STATUS) & (MSR_READY | MSR_DATA_IN) == MSR_READY | MSR_DATA_IN {
+            return Ok(read_port(FIFO));
+        }
+    }
+    Err(FloppyError::Timeout)
+}
+
+/// Clears the interrupt flag and sends `bytes`
+fn issue(bytes: &[u8]) -> Result<(), FloppyError> {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    bytes.iter().try_for_each(|&byte| send(byte))
+}
+
+/// Sleeps until the controller interrupts
+fn wait_interrupt() -> Result<(), FloppyError> {
+    let deadline = time::ticks() + time::ms_to_ticks(INTERRUPT_TIMEOUT_MS);
+    while !INTERRUPTED.swap(false, Ordering::SeqCst) {
+        if !x86_64::instructions::interrupts::are_enabled() || time::ticks() >= deadline {
+            return Err(FloppyError::Timeout);
+        }
+        task::sleep_ticks(1);
+    }
+    Ok(())
+}
+
+/// Acknowledges an interrupt, returns status register 0 and the cylinder
+/// the head is on
+fn sense_interrupt() -> Result<(u8, u8), FloppyError> {
+    send(CMD_SENSE_INTERRUPT)?;
+    Ok((receive()?, receive()?))
+}
+
+/// Programs channel 2 to move `length` bytes between the device and
+/// `address`, which mustn't cross a 64 KiB boundary
+fn set_up_dma(address: u64, length: usize, to_memory: bool) {
+    let count = (length - 1) as u16;
+    let mode = if to_memory {
+        DMA_MODE_TO_MEMORY
+    } else {
+        DMA_MODE_FROM_MEMORY
+    };
+
+    write_port(DMA_MASK, DMA_MASK_ON | DMA_CHANNEL);
+    // The 16-bit registers take the low byte first after a reset of the
+    // flip-flop
+    write_port(DMA_FLIP_FLOP, 0xFF);
+    write_port(DMA_ADDRESS, address as u8);
+    write_port(DMA_ADDRESS, (address >> 8) as u8);
+    write_port(DMA_PAGE, (address >> 16) as u8);
+    write_port(DMA_FLIP_FLOP, 0xFF);
+    write_port(DMA_COUNT, count as u8);
+    write_port(DMA_COUNT, (count >> 8) as u8);
+    write_port(DMA_MODE, mode | DMA_CHANNEL);
+    write_port(DMA_MASK, DMA_CHANNEL);
+}
+
+/// Called on every interrupt of IRQ 6
+fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+struct State {
+    motor_on: bool,
+    /// Tick of the last transfer, the motor is turned off a while after it
+    last_used: u64,
+    /// Cylinder the head is on, `None` until the drive is recalibrated
+    cylinder: Option<u8>,
+}
+
+/// The first floppy drive
+pub struct FloppyDrive {
+    name: String,
+    version: u8,
+    /// Sectors are moved through this frame
+    dma: PhysFrame,
+    /// Only one command runs at a time
+    state: Mutex<State>,
+}
+
+impl FloppyDrive {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// What the controller answered to VERSION
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn motor_on(&self) -> bool {
+        self.state.lock().motor_on
+    }
+
+    fn dma_buffer(&self) -> *mut u8 {
+        memory::phys_to_virt(self.dma.start_address()).as_mut_ptr()
+    }
+
+    fn digital_output(&self, motor_on: bool) {
+        let motor = if motor_on { DOR_MOTOR_0 } else { 0 };
+        write_port(DIGITAL_OUTPUT, DOR_NOT_RESET | DOR_IRQ_DMA | motor);
+    }
+
+    /// Resets the controller and sets it up for 1.44 MB disks
+    fn reset(&self, state: &mut State) -> Result<(), FloppyError> {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        write_port(DIGITAL_OUTPUT, 0);
+        self.digital_output(state.motor_on);
+        wait_interrupt()?;
+        // One for each of the drives the controller could have
+        for _ in 0..4 {
+            sense_interrupt()?;
+        }
+
+        // 500 kbit/s, step rate of 3 ms, head unload time of 240 ms, head
+        // load time of 4 ms and DMA
+        write_port(CONFIGURATION_CONTROL, 0);
+        issue(&[CMD_SPECIFY, 0xDF, 0x02])?;
+        state.cylinder = None;
+        Ok(())
+    }
+
+    fn start_motor(&self, state: &mut State) {
+        if !state.motor_on {
+            state.motor_on = true;
+            self.digital_output(true);
+            task::sleep_ms(MOTOR_SPIN_UP_MS);
+        }
+        state.last_used = time::ticks();
+    }
+
+    /// Turns the motor off if it hasn't been used for a while and no
+    /// command is running
+    fn stop_idle_motor(&self) {
+        if let Some(mut state) = self.state.try_lock() {
+            let idle = time::ticks() - state.last_used;
+            if state.motor_on && idle >= time::ms_to_ticks(MOTOR_IDLE_MS) {
+                state.motor_on = false;
+                self.digital_output(false);
+            }
+        }
+    }
+
+    /// Moves the head back to cylinder 0. A drive steps at most 77 times,
+    /// which may not be enough for the 80 cylinders, so it's tried twice.
+    fn recalibrate(&self, state: &mut State) -> Result<(), FloppyError> {
+        state.cylinder = None;
+        for _ in 0..2 {
+            issue(&[CMD_RECALIBRATE, 0])?;
+            wait_interrupt()?;
+            let (st0, cylinder) = sense_interrupt()?;
+            if st0 & ST0_INTERRUPT_CODE == 0 && cylinder == 0 {
+                state.cylinder = Some(0);
+                return Ok(());
+            }
+        }
+        Err(FloppyError::SeekFailed)
+    }
+
+    fn seek(&self, state: &mut State, cylinder: u8, head: u8) -> Result<(), FloppyError> {
+        if state.cylinder == Some(cylinder) {
+            return Ok(());
+        }
+
+        state.cylinder = None;
+        issue(&[CMD_SEEK, head << 2, cylinder])?;
+        wait_interrupt()?;
+        let (st0, reached) = sense_interrupt()?;
+        if st0 & ST0_INTERRUPT_CODE != 0 || reached != cylinder {
+            return Err(FloppyError::SeekFailed);
+        }
+        state.cylinder = Some(cylinder);
+        task::sleep_ms(HEAD_SETTLE_MS);
+        Ok(())
+    }
+
+    /// Reads or writes `count` sectors from `block` on, which are on one
+    /// track, through the DMA frame
+    fn transfer(
+        &self,
+        state: &mut State,
+        block: u64,
+        count: u64,
+        write: bool,
+    ) -> Result<(), FloppyError> {
+        let (cylinder, head, sector) = chs(block);
+        self.seek(state, cylinder, head)?;
+
+        let length = count as usize * SECTOR_SIZE;
+        set_up_dma(self.dma.start_address().as_u64(), length, !write);
+        let command = CMD_MFM | if write { CMD_WRITE_DATA } else { CMD_READ_DATA };
+        // The DMA controller ends the transfer after the last sector
+        issue(&[
+            command,
+            head << 2,
+            cylinder,
+            head,
+            sector,
+            SECTOR_SIZE_CODE,
+            SECTORS_PER_TRACK as u8,
+            GAP_LENGTH,
+            0xFF,
+        ])?;
+        wait_interrupt()?;
+
+        let mut result = [0; 7];
+        for byte in result.iter_mut() {
+            *byte = receive()?;
+        }
+        match FloppyError::decode(result[0], result[1], result[2]) {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs a transfer up to `ATTEMPTS` times, getting the drive back into
+    /// a known state after each failure
+    fn transfer_with_retries(
+        &self,
+        state: &mut State,
+        block: u64,
+        count: u64,
+        write: bool,
+    ) -> Result<(), FloppyError> {
+        let mut error = FloppyError::Unknown;
+        for _ in 0..ATTEMPTS {
+            if state.cylinder.is_none() {
+                if let Err(failed) = self.recalibrate(state) {
+                    error = failed;
+                    let _ = self.reset(state);
+                    continue;
+                }
+            }
+            match self.transfer(state, block, count, write) {
+                Ok(()) => return Ok(()),
+                Err(FloppyError::WriteProtected) => return Err(FloppyError::WriteProtected),
+                Err(FloppyError::Timeout) => {
+                    error = FloppyError::Timeout;
+                    let _ = self.reset(state);
+                }
+                Err(failed) => {
+                    error = failed;
+                    state.cylinder = None;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// Calls `f` with the first block, the sector count and the offset into
+    /// the buffer of each transfer, none of which crosses a track
+    fn for_each_transfer<F>(&self, block: u64, length: usize, mut f: F) -> Result<(), FloppyError>
+    where
+        F: FnMut(&mut State, u64, u64, usize) -> Result<(), FloppyError>,
+    {
+        let mut state = self.state.lock();
+        self.start_motor(&mut state);
+
+        let end = block + (length / SECTOR_SIZE) as u64;
+        let mut current = block;
+        let result = loop {
+            if current == end {
+                break Ok(());
+            }
+            let track_left = SECTORS_PER_TRACK - current % SECTORS_PER_TRACK;
+            let count = cmp::min(cmp::min(end - current, track_left), SECTORS_PER_TRANSFER);
+            let offset = (current - block) as usize * SECTOR_SIZE;
+            if let Err(error) = f(&mut state, current, count, offset) {
+                break Err(error);
+            }
+            current += count;
+        };
+        state.last_used = time::ticks();
+        result
+    }
+
+    fn read_sectors(&self, block: u64, buffer: &mut [u8]) -> Result<(), FloppyError> {
+        let dma = self.dma_buffer();
+        self.for_each_transfer(block, buffer.len(), |state, block, count, offset| {
+            self.transfer_with_retries(state, block, count, false)?;
+            let length = count as usize * SECTOR_SIZE;
+            let sectors = unsafe { core::slice::from_raw_parts(dma, length) };
+            buffer[offset..offset + length].copy_from_slice(sectors);
+            Ok(())
+        })
+    }
+
+    fn write_sectors(&self, block: u64, buffer: &[u8]) -> Result<(), FloppyError> {
+        let dma = self.dma_buffer();
+        self.for_each_transfer(block, buffer.len(), |state, block, count, offset| {
+            let length = count as usize * SECTOR_SIZE;
+            let sectors = unsafe { core::slice::from_raw_parts_mut(dma, length) };
+            sectors.copy_from_slice(&buffer[offset..offset + length]);
+            self.transfer_with_retries(state, block, count, true)
+        })
+    }
+
+    /// Logs a failed command and turns it into a block error
+    fn failed(&self, error: FloppyError) -> BlockError {
+        println!("{}: {:?}", self.name, error);
+        match error {
+            FloppyError::WriteProtected => BlockError::ReadOnly,
+            _ => BlockError::Io,
+        }
+    }
+}
+
+impl BlockDevice for FloppyDrive {
+    fn block_count(&self) -> u64 {
+        CYLINDERS * HEADS * SECTORS_PER_TRACK
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        check_request(self, block, buffer.len())?;
+        self.read_sectors(block, buffer).map_err(|error| self.failed(error))
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> BlockResult<()> {
+        check_request(self, block, buffer.len())?;
+        self.write_sectors(block, buffer).map_err(|error| self.failed(error))
+    }
+}
+
+/// Type of the first drive the BIOS found
+fn drive_type() -> u8 {
+    write_port(CMOS_ADDRESS, CMOS_FLOPPY_TYPES);
+    read_port(CMOS_DATA) >> 4
+}
+
+/// A DMA frame the ISA DMA controller can reach
+fn allocate_dma_frame() -> Option<PhysFrame> {
+    let frame = memory::allocate_zeroed_frame()?;
+    if frame.start_address().as_u64() + 4096 > DMA_LIMIT {
+        memory::deallocate_frame(frame);
+        return None;
+    }
+    Some(frame)
+}
+
+/// Resets the controller, checks that there's a disk in the first drive and
+/// registers it as `/dev/fd0`
+pub fn init() {
+    let name = "fd0";
+    match drive_type() {
+        DRIVE_TYPE_1440K => {}
+        0 => return,
+        other => {
+            println!("{}: drive type {} isn't supported", name, other);
+            return;
+        }
+    }
+
+    let dma = match allocate_dma_frame() {
+        Some(frame) => frame,
+        None => {
+            println!("{}: no memory for DMA below 16 MiB", name);
+            return;
+        }
+    };
+    if !interrupts::register_irq_handler(FLOPPY_IRQ, interrupt) {
+        println!("{}: IRQ {} is taken", name, FLOPPY_IRQ);
+        memory::deallocate_frame(dma);
+        return;
+    }
+
+    let mut drive = FloppyDrive {
+        name: String::from(name),
+        version: 0,
+        dma,
+        state: Mutex::new(State {
+            motor_on: false,
+            last_used: 0,
+            cylinder: None,
+        }),
+    };
+    let probed = {
+        let mut state = drive.state.lock();
+        drive
+            .reset(&mut state)
+            .and_then(|()| send(CMD_VERSION))
+            .and_then(|()| receive())
+    };
+    drive.version = match probed {
+        Ok(version) => version,
+        Err(error) => {
+            println!("{}: the controller doesn't answer: {:?}", name, error);
+            memory::deallocate_frame(dma);
+            return;
+        }
+    };
+
+    // Reading the boot sector tells whether there's a disk
+    let mut sector = [0; SECTOR_SIZE];
+    if let Err(error) = drive.read_sectors(0, &mut sector) {
+        println!("{}: no disk: {:?}", name, error);
+        memory::deallocate_frame(dma);
+        return;
+    }
+    let controller = if drive.version == VERSION_82077AA {
+        "82077AA"
+    } else {
+        "8272A"
+    };
+    println!(
+        "{}: 1.44 MB floppy on an {}, {} blocks of {} bytes",
+        name,
+        controller,
+        drive.block_count(),
+        SECTOR_SIZE
+    );
+
+    let drive = Arc::new(drive);
+    super::register(name, drive.clone()).expect("Failed to register the floppy drive");
+    *DRIVE.lock() = Some(drive.clone());
+    task::spawn("floppy-motor", move || loop {
+        task::sleep_ms(MOTOR_IDLE_MS / 3);
+        drive.stop_idle_motor();
+    });
+}
+
+/// The drive `init` found
+pub fn drive() -> Option<Arc<FloppyDrive>> {
+    DRIVE.lock().clone()
+}
@@ -0,0 +1,208 @@
+//! Block devices.
+//!
+//! A block device is an array of fixed size blocks that's read and written
+//! whole blocks at a time. Filesystems keep their data on one and don't
+//! care whether it's a disk or memory.
+//!
+//! Drivers register their devices here rather than with devfs, so that
+//! filesystems get them as disks with a buffer cache in front.
+
+pub mod ahci;
+pub mod ata;
+pub mod cache;
+pub mod disk;
+pub mod floppy;
+pub mod partition;
+pub mod queue;
+pub mod ram;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use lazy_static::lazy_static;
+
+pub use disk::Disk;
+
+use crate::fs::devfs;
+use crate::fs::{FsError, FsResult};
+use crate::lock_class;
+use crate::sync::IrqSpinLock;
+use crate::task;
+
+/// Block size of most disks
+pub const SECTOR_SIZE: usize = 512;
+
+pub type BlockResult<T> = Result<T, BlockError>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BlockError {
+    /// The request goes past the last block
+    OutOfRange,
+    /// The buffer isn't a whole number of blocks
+    BadBuffer,
+    ReadOnly,
+    /// The device reported an error
+    Io,
+}
+
+/// What a disk did since it was created
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct IoStats {
+    /// Transfers issued to the driver
+    pub reads: u64,
+    pub writes: u64,
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+    /// Requests that went along with the transfer before them
+    pub merged: u64,
+    /// Blocks requested that were cached and that weren't
+    pub hits: u64,
+    pub misses: u64,
+    /// Dirty blocks written to the driver
+    pub written_back: u64,
+    pub flushes: u64,
+}
+
+impl From<BlockError> for FsError {
+    fn from(error: BlockError) -> FsError {
+        match error {
+            BlockError::ReadOnly => FsError::ReadOnly,
+            _ => FsError::Io,
+        }
+    }
+}
+
+pub trait BlockDevice: Send + Sync {
+    /// Bytes per block
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64;
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads `buffer.len() / block_size()` blocks starting at `block`
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> BlockResult<()>;
+
+    /// Writes `buffer.len() / block_size()` blocks starting at `block`
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> BlockResult<()>;
+
+    /// Returns once everything written is on the medium
+    fn flush(&self) -> BlockResult<()> {
+        Ok(())
+    }
+}
+
+/// Checks a request of `length` bytes from `block` on, returns the number
+/// of blocks
+pub fn check_request(device: &dyn BlockDevice, block: u64, length: usize) -> BlockResult<u64> {
+    let block_size = device.block_size();
+    if length % block_size != 0 {
+        return Err(BlockError::BadBuffer);
+    }
+
+    let count = (length / block_size) as u64;
+    match block.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Reads bytes at any position of a device
+pub fn read_at(device: &dyn BlockDevice, position: u64, buffer: &mut [u8]) -> BlockResult<()> {
+    let block_size = device.block_size();
+    let mut done = 0;
+    let mut bounce = Vec::new();
+    while done < buffer.len() {
+        let at = position + done as u64;
+        let block = at / block_size as u64;
+        let in_block = (at % block_size as u64) as usize;
+        let rest = buffer.len() - done;
+        if in_block == 0 && rest >= block_size {
+            let whole = rest / block_size * block_size;
+            device.read_blocks(block, &mut buffer[done..done + whole])?;
+            done += whole;
+        } else {
+            bounce.resize(block_size, 0);
+            device.read_blocks(block, &mut bounce)?;
+            let chunk = rest.min(block_size - in_block);
+            buffer[done..done + chunk].copy_from_slice(&bounce[in_block..in_block + chunk]);
+            done += chunk;
+        }
+    }
+    Ok(())
+}
+
+/// Writes bytes at any position of a device, partial blocks are read first
+pub fn write_at(device: &dyn BlockDevice, position: u64, buffer: &[u8]) -> BlockResult<()> {
+    let block_size = device.block_size();
+    let mut done = 0;
+    let mut bounce = Vec::new();
+    while done < buffer.len() {
+        let at = position + done as u64;
+        let block = at / block_size as u64;
+        let in_block = (at % block_size as u64) as usize;
+        let rest = buffer.len() - done;
+        if in_block == 0 && rest >= block_size {
+            let whole = rest / block_size * block_size;
+            device.write_blocks(block, &buffer[done..done + whole])?;
+            done += whole;
+        } else {
+            bounce.resize(block_size, 0);
+            device.read_blocks(block, &mut bounce)?;
+            let chunk = rest.min(block_size - in_block);
+            bounce[in_block..in_block + chunk].copy_from_slice(&buffer[done..done + chunk]);
+            device.write_blocks(block, &bounce)?;
+            done += chunk;
+        }
+    }
+    Ok(())
+}
+
+/// How often dirty blocks are written back
+const WRITE_BACK_INTERVAL_MS: u64 = 5000;
+
+lazy_static! {
+    static ref DISKS: IrqSpinLock<Vec<Arc<Disk>>> =
+        IrqSpinLock::with_class(Vec::new(), lock_class!("DISKS"));
+}
+
+/// Puts `device` behind a cache and makes it available as `/dev/<name>`
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) -> FsResult<Arc<Disk>> {
+    let disk = Arc::new(Disk::new(name, device));
+    devfs::register_block(name, disk.clone())?;
+    DISKS.lock().push(disk.clone());
+    Ok(disk)
+}
+
+/// The registered disks, in the order they were registered
+pub fn disks() -> Vec<Arc<Disk>> {
+    DISKS.lock().clone()
+}
+
+/// Writes the dirty blocks of every disk back and flushes them, returns
+/// the first error
+pub fn sync_all() -> BlockResult<()> {
+    let mut result = Ok(());
+    for disk in disks() {
+        let synced = disk.flush();
+        if result.is_ok() {
+            result = synced;
+        }
+    }
+    result
+}
+
+/// Starts the task that writes dirty blocks back every few seconds
+pub fn init() {
+    task::spawn("writeback", || loop {
+        task::sleep_ms(WRITE_BACK_INTERVAL_MS);
+        for disk in disks() {
+            // A failed write-back leaves the blocks dirty for the next try
+            let _ = disk.write_back();
+        }
+    });
+}
@@ -0,0 +1,568 @@
+//! MBR and GPT partition tables.
+//!
+//! Each partition of a disk becomes a block device of its own, registered
+//! in devfs after the disk with its number appended, like `sda1`. MBR
+//! primary partitions are numbered 1 to 4 and the logical ones in the
+//! extended partition from 5 on; GPT partitions are numbered by their
+//! entry. Filesystems the partition type promises are mounted under `/mnt`.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use lazy_static::lazy_static;
+
+use super::{check_request, read_at, BlockDevice, BlockError, BlockResult};
+use crate::fs::devfs;
+use crate::fs::ext2::Ext2Fs;
+use crate::fs::fat::FatFs;
+use crate::fs::{self, FileSystem, FsResult};
+use crate::lock_class;
+use crate::println;
+use crate::sync::IrqSpinLock;
+
+const MBR_SIZE: usize = 512;
+const MBR_ENTRIES: usize = 446;
+const MBR_SIGNATURE: usize = 510;
+const MBR_ENTRY_SIZE: usize = 16;
+
+// MBR partition types
+pub const MBR_FAT12: u8 = 0x01;
+pub const MBR_FAT16_SMALL: u8 = 0x04;
+pub const MBR_EXTENDED_CHS: u8 = 0x05;
+pub const MBR_FAT16: u8 = 0x06;
+pub const MBR_FAT32_CHS: u8 = 0x0B;
+pub const MBR_FAT32: u8 = 0x0C;
+pub const MBR_FAT16_LBA: u8 = 0x0E;
+pub const MBR_EXTENDED: u8 = 0x0F;
+pub const MBR_LINUX: u8 = 0x83;
+pub const MBR_LINUX_EXTENDED: u8 = 0x85;
+/// Covers the disk so that MBR tools leave a GPT alone
+pub const MBR_PROTECTIVE: u8 = 0xEE;
+
+/// Logical partitions followed at most, in case the chain loops
+const MAX_LOGICAL: u32 = 64;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_ENTRY_SIZE: usize = 128;
+/// Bytes of entries read at most
+const GPT_MAX_ENTRIES_SIZE: usize = 64 * 1024;
+
+/// A GUID as it's stored, the first three fields little endian
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const fn new(a: u32, b: u16, c: u16, d: [u8; 8]) -> Guid {
+        Guid([
+            a as u8,
+            (a >> 8) as u8,
+            (a >> 16) as u8,
+            (a >> 24) as u8,
+            b as u8,
+            (b >> 8) as u8,
+            c as u8,
+            (c >> 8) as u8,
+            d[0],
+            d[1],
+            d[2],
+            d[3],
+            d[4],
+            d[5],
+            d[6],
+            d[7],
+        ])
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&byte| byte == 0)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            read_u32(bytes, 0),
+            read_u16(bytes, 4),
+            read_u16(bytes, 6)
+        )?;
+        for (index, byte) in bytes[8..].iter().enumerate() {
+            if index == 2 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+// GPT partition types
+pub const GPT_EFI_SYSTEM: Guid = Guid::new(
+    0xC12A_7328,
+    0xF81F,
+    0x11D2,
+    [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+);
+pub const GPT_BASIC_DATA: Guid = Guid::new(
+    0xEBD0_A0A2,
+    0xB9E5,
+    0x4433,
+    [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
+);
+pub const GPT_LINUX_FILESYSTEM: Guid = Guid::new(
+    0x0FC6_3DAF,
+    0x8483,
+    0x4772,
+    [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PartitionType {
+    Mbr(u8),
+    Gpt(Guid),
+}
+
+/// A partition as the table describes it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Entry {
+    pub number: u32,
+    /// First block on the disk
+    pub start: u64,
+    pub block_count: u64,
+    pub kind: PartitionType,
+    /// The name of a GPT partition, MBR partitions have none
+    pub label: String,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TableError {
+    Io(BlockError),
+    /// Partitions outside the disk or overlapping, or a broken chain of
+    /// logical partitions
+    Invalid,
+    /// Neither GPT header with its entries has the right CRC32
+    BadChecksum,
+}
+
+impl From<BlockError> for TableError {
+    fn from(error: BlockError) -> TableError {
+        TableError::Io(error)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from(data[offset]) | u16::from(data[offset + 1]) << 8
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(data, offset)) | u32::from(read_u16(data, offset + 2)) << 16
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from(read_u32(data, offset)) | u64::from(read_u32(data, offset + 4)) << 32
+}
+
+/// The CRC32 GPT uses, the one of zlib and Ethernet
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Reads the first 512 bytes of `block`
+fn read_mbr(device: &dyn BlockDevice, block: u64) -> Result<Vec<u8>, TableError> {
+    let mut sector = vec![0; device.block_size().max(MBR_SIZE)];
+    device.read_blocks(block, &mut sector[..device.block_size()])?;
+    sector.truncate(MBR_SIZE);
+    Ok(sector)
+}
+
+fn is_extended(kind: u8) -> bool {
+    kind == MBR_EXTENDED_CHS || kind == MBR_EXTENDED || kind == MBR_LINUX_EXTENDED
+}
+
+/// The type, start and length of the entry `index` of an MBR or EBR
+fn mbr_entry(sector: &[u8], index: usize) -> (u8, u64, u64) {
+    let entry = &sector[MBR_ENTRIES + index * MBR_ENTRY_SIZE..];
+    (entry[4], u64::from(read_u32(entry, 8)), u64::from(read_u32(entry, 12)))
+}
+
+/// Reads the partition table of `device`, an empty list if it has none
+pub fn read_table(device: &dyn BlockDevice) -> Result<Vec<Entry>, TableError> {
+    if device.block_count() == 0 {
+        return Ok(Vec::new());
+    }
+    let mbr = read_mbr(device, 0)?;
+    if read_u16(&mbr, MBR_SIGNATURE) != 0xAA55 {
+        return Ok(Vec::new());
+    }
+    // Boot sectors of filesystems have the signature too, but not the
+    // boot flags of a partition table
+    let flags_valid = (0..4).all(|index| {
+        let status = mbr[MBR_ENTRIES + index * MBR_ENTRY_SIZE];
+        status == 0 || status == 0x80
+    });
+    if !flags_valid {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = Vec::new();
+    for index in 0..4 {
+        let (kind, start, block_count) = mbr_entry(&mbr, index);
+        if kind == 0 || block_count == 0 {
+            continue;
+        }
+        if kind == MBR_PROTECTIVE {
+            return read_gpt(device);
+        }
+
+        if is_extended(kind) {
+            check_range(device, start, block_count)?;
+            read_logical(device, start, block_count, &mut entries)?;
+        } else {
+            entries.push(Entry {
+                number: index as u32 + 1,
+                start,
+                block_count,
+                kind: PartitionType::Mbr(kind),
+                label: String::new(),
+            });
+        }
+    }
+    check_entries(device, &mut entries)?;
+    Ok(entries)
+}
+
+fn check_range(device: &dyn BlockDevice, start: u64, block_count: u64) -> Result<(), TableError> {
+    match start.checked_add(block_count) {
+        Some(end) if end <= device.block_count() => Ok(()),
+        _ => Err(TableError::Invalid),
+    }
+}
+
+/// Follows the chain of EBRs in the extended partition at `start`, each
+/// has a logical partition relative to itself and a link to the next
+/// relative to the extended partition
+fn read_logical(
+    device: &dyn BlockDevice,
+    start: u64,
+    block_count: u64,
+    entries: &mut Vec<Entry>,
+) -> Result<(), TableError> {
+    let mut ebr = start;
+    for number in 5..5 + MAX_LOGICAL {
+        let sector = read_mbr(device, ebr)?;
+        if read_u16(&sector, MBR_SIGNATURE) != 0xAA55 {
+            return Err(TableError::Invalid);
+        }
+
+        let (kind, relative, count) = mbr_entry(&sector, 0);
+        if kind != 0 && count != 0 {
+            let first = ebr + relative;
+            if first + count > start + block_count {
+                return Err(TableError::Invalid);
+            }
+            entries.push(Entry {
+                number,
+                start: first,
+                block_count: count,
+                kind: PartitionType::Mbr(kind),
+                label: String::new(),
+            });
+        }
+
+        let (next_kind, next, _) = mbr_entry(&sector, 1);
+        if !is_extended(next_kind) {
+            return Ok(());
+        }
+        // Later EBRs are further on, which also stops loops
+        if next == 0 || start + next <= ebr || next >= block_count {
+            return Err(TableError::Invalid);
+        }
+        ebr = start + next;
+    }
+    Err(TableError::Invalid)
+}
+
+/// Checks that the partitions are on the disk and don't overlap, sorts
+/// them by number
+fn check_entries(device: &dyn BlockDevice, entries: &mut Vec<Entry>) -> Result<(), TableError> {
+    for entry in entries.iter() {
+        check_range(device, entry.start, entry.block_count)?;
+    }
+    entries.sort_by_key(|entry| entry.start);
+    for pair in entries.windows(2) {
+        if pair[0].start + pair[0].block_count > pair[1].start {
+            return Err(TableError::Invalid);
+        }
+    }
+    entries.sort_by_key(|entry| entry.number);
+    Ok(())
+}
+
+/// Reads the primary GPT, or the backup at the end of the disk if the
+/// primary is damaged
+fn read_gpt(device: &dyn BlockDevice) -> Result<Vec<Entry>, TableError> {
+    let primary = read_gpt_at(device, 1);
+    match primary {
+        Err(TableError::Io(_)) | Ok(_) => primary,
+        Err(_) => read_gpt_at(device, device.block_count() - 1).or(primary),
+    }
+}
+
+/// Reads the GPT header at `block` and the entries it points to
+fn read_gpt_at(device: &dyn BlockDevice, block: u64) -> Result<Vec<Entry>, TableError> {
+    let block_size = device.block_size();
+    let mut header = vec![0; block_size];
+    device.read_blocks(block, &mut header)?;
+    if block_size < GPT_HEADER_SIZE || &header[..8] != GPT_SIGNATURE {
+        return Err(TableError::Invalid);
+    }
+
+    let header_size = read_u32(&header, 12) as usize;
+    if header_size < GPT_HEADER_SIZE || header_size > block_size {
+        return Err(TableError::Invalid);
+    }
+    let checksum = read_u32(&header, 16);
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&header[..header_size]) != checksum {
+        return Err(TableError::BadChecksum);
+    }
+
+    let first_usable = read_u64(&header, 40);
+    let last_usable = read_u64(&header, 48);
+    let entries_start = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    let entries_size = entry_count.saturating_mul(entry_size);
+    if read_u64(&header, 24) != block
+        || first_usable > last_usable
+        || last_usable >= device.block_count()
+        || entry_size < GPT_ENTRY_SIZE
+        || entry_size % 8 != 0
+        || entries_size > GPT_MAX_ENTRIES_SIZE
+    {
+        return Err(TableError::Invalid);
+    }
+
+    let mut table = vec![0; entries_size];
+    let position = entries_start
+        .checked_mul(block_size as u64)
+        .ok_or(TableError::Invalid)?;
+    check_range(
+        device,
+        entries_start,
+        ((entries_size + block_size - 1) / block_size) as u64,
+    )?;
+    read_at(device, position, &mut table)?;
+    if crc32(&table) != read_u32(&header, 88) {
+        return Err(TableError::BadChecksum);
+    }
+
+    let mut entries = Vec::new();
+    for (index, entry) in table.chunks(entry_size).enumerate() {
+        let mut kind = [0; 16];
+        kind.copy_from_slice(&entry[..16]);
+        let kind = Guid(kind);
+        if kind.is_zero() {
+            continue;
+        }
+
+        let first = read_u64(entry, 32);
+        let last = read_u64(entry, 40);
+        if first > last || first < first_usable || last > last_usable {
+            return Err(TableError::Invalid);
+        }
+        let name = (0..36)
+            .map(|character| read_u16(entry, 56 + character * 2))
+            .take_while(|&unit| unit != 0);
+        let label = core::char::decode_utf16(name)
+            .map(|character| character.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+            .collect();
+        entries.push(Entry {
+            number: index as u32 + 1,
+            start: first,
+            block_count: last - first + 1,
+            kind: PartitionType::Gpt(kind),
+            label,
+        });
+    }
+    check_entries(device, &mut entries)?;
+    Ok(entries)
+}
+
+/// A range of the blocks of a disk
+pub struct Partition {
+    name: String,
+    disk: Arc<dyn BlockDevice>,
+    entry: Entry,
+}
+
+impl Partition {
+    pub fn new(name: &str, disk: Arc<dyn BlockDevice>, entry: Entry) -> Partition {
+        Partition {
+            name: String::from(name),
+            disk,
+            entry,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn number(&self) -> u32 {
+        self.entry.number
+    }
+
+    /// First block on the disk
+    pub fn start(&self) -> u64 {
+        self.entry.start
+    }
+
+    pub fn kind(&self) -> PartitionType {
+        self.entry.kind
+    }
+
+    pub fn label(&self) -> &str {
+        &self.entry.label
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.entry.block_count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.disk.is_read_only()
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        check_request(self, block, buffer.len())?;
+        self.disk.read_blocks(self.entry.start + block, buffer)
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> BlockResult<()> {
+        check_request(self, block, buffer.len())?;
+        self.disk.write_blocks(self.entry.start + block, buffer)
+    }
+
+    fn flush(&self) -> BlockResult<()> {
+        self.disk.flush()
+    }
+}
+
+lazy_static! {
+    static ref PARTITIONS: IrqSpinLock<Vec<Arc<Partition>>> =
+        IrqSpinLock::with_class(Vec::new(), lock_class!("PARTITIONS"));
+}
+
+/// Reads the partition table of the disk `name` and registers its
+/// partitions in devfs. A `p` goes between names ending in a digit and
+/// the number, like `ram0p1`.
+pub fn scan(name: &str, disk: Arc<dyn BlockDevice>) -> Result<Vec<Arc<Partition>>, TableError> {
+    let separator = match name.chars().last() {
+        Some(last) if last.is_ascii_digit() => "p",
+        _ => "",
+    };
+
+    let mut partitions = Vec::new();
+    for entry in read_table(&*disk)? {
+        let name = format!("{}{}{}", name, separator, entry.number);
+        let partition = Arc::new(Partition::new(&name, disk.clone(), entry));
+        if devfs::register_block(&name, partition.clone()).is_err() {
+            // Scanned before
+            continue;
+        }
+        PARTITIONS.lock().push(partition.clone());
+        partitions.push(partition);
+    }
+    Ok(partitions)
+}
+
+/// The filesystem the type of `partition` promises, opened on it, `None`
+/// for types without one
+fn open_filesystem(partition: &Arc<Partition>) -> FsResult<Option<Arc<dyn FileSystem>>> {
+    let device: Arc<dyn BlockDevice> = partition.clone();
+    let fs: Arc<dyn FileSystem> = match partition.kind() {
+        PartitionType::Mbr(MBR_FAT12)
+        | PartitionType::Mbr(MBR_FAT16_SMALL)
+        | PartitionType::Mbr(MBR_FAT16)
+        | PartitionType::Mbr(MBR_FAT32_CHS)
+        | PartitionType::Mbr(MBR_FAT32)
+        | PartitionType::Mbr(MBR_FAT16_LBA) => FatFs::new(device)?,
+        PartitionType::Gpt(kind) if kind == GPT_EFI_SYSTEM || kind == GPT_BASIC_DATA => {
+            FatFs::new(device)?
+        }
+        PartitionType::Mbr(MBR_LINUX) => Ext2Fs::new(device)?,
+        PartitionType::Gpt(kind) if kind == GPT_LINUX_FILESYSTEM => Ext2Fs::new(device)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(fs))
+}
+
+/// Mounts the filesystem on `partition` on `/mnt/<name>` if its type says
+/// which one it is, returns where
+pub fn mount(partition: &Arc<Partition>) -> FsResult<Option<String>> {
+    let fs = match open_filesystem(partition)? {
+        Some(fs) => fs,
+        None => return Ok(None),
+    };
+    let path = format!("/mnt/{}", partition.name());
+    fs::create_dir_all(&path)?;
+    fs::mount(&path, fs)?;
+    Ok(Some(path))
+}
+
+/// The partitions `scan` found
+pub fn partitions() -> Vec<Arc<Partition>> {
+    PARTITIONS.lock().clone()
+}
+
+/// Scans the registered disks for partitions and mounts the filesystems
+/// on them
+pub fn init() {
+    for disk in super::disks() {
+        let partitions = match scan(disk.name(), disk.clone()) {
+            Ok(partitions) => partitions,
+            Err(error) => {
+                println!("{}: bad partition table: {:?}", disk.name(), error);
+                continue;
+            }
+        };
+        for partition in partitions {
+            match mount(&partition) {
+                Ok(Some(path)) => println!("{}: mounted on {}", partition.name(), path),
+                Ok(None) => {}
+                Err(error) => println!("{}: failed to mount: {:?}", partition.name(), error),
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Request queue of a block device.
+//!
+//! Requests are collected first and then issued together: sorted by block
+//! so the disk sweeps across once, with neighbouring requests in the same
+//! direction merged into one transfer.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{check_request, BlockDevice, BlockError, BlockResult, IoStats};
+
+/// Bytes a merged transfer moves at most
+pub const MAX_MERGED_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Request {
+    pub direction: Direction,
+    pub block: u64,
+    /// Whole blocks, reads fill it in
+    pub data: Vec<u8>,
+}
+
+/// Requests waiting to be issued, they mustn't overlap
+#[derive(Debug, Default)]
+pub struct RequestQueue {
+    requests: Vec<Request>,
+}
+
+impl RequestQueue {
+    pub fn new() -> RequestQueue {
+        RequestQueue {
+            requests: Vec::new(),
+        }
+    }
+
+    /// Queues a read of `count` blocks of `block_size` bytes from `block` on
+    pub fn read(&mut self, block: u64, count: usize, block_size: usize) {
+        self.push(Direction::Read, block, vec![0; count * block_size]);
+    }
+
+    /// Queues a write of `data` from `block` on
+    pub fn write(&mut self, block: u64, data: Vec<u8>) {
+        self.push(Direction::Write, block, data);
+    }
+
+    fn push(&mut self, direction: Direction, block: u64, data: Vec<u8>) {
+        if !data.is_empty() {
+            self.requests.push(Request {
+                direction,
+                block,
+                data,
+            });
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Issues the requests to `device` in order of block and returns them
+    /// in that order, with the data of the reads. Stops at the first error.
+    pub fn run(
+        mut self,
+        device: &dyn BlockDevice,
+        stats: &mut IoStats,
+    ) -> BlockResult<Vec<Request>> {
+        let block_size = device.block_size();
+        if self.requests.iter().any(|request| request.data.len() % block_size != 0) {
+            return Err(BlockError::BadBuffer);
+        }
+
+        self.requests.sort_by_key(|request| request.block);
+        let mut start = 0;
+        while start < self.requests.len() {
+            let first = &self.requests[start];
+            let mut length = first.data.len();
+            let mut end = start + 1;
+            while end < self.requests.len() {
+                let next = &self.requests[end];
+                let contiguous = next.block == first.block + (length / block_size) as u64;
+                if next.direction != first.direction
+                    || !contiguous
+                    || length + next.data.len() > MAX_MERGED_BYTES
+                {
+                    break;
+                }
+                length += next.data.len();
+                end += 1;
+            }
+
+            issue(device, &mut self.requests[start..end], length, stats)?;
+            start = end;
+        }
+        Ok(self.requests)
+    }
+}
+
+/// Transfers contiguous requests in the same direction at once
+fn issue(
+    device: &dyn BlockDevice,
+    group: &mut [Request],
+    length: usize,
+    stats: &mut IoStats,
+) -> BlockResult<()> {
+    let block = group[0].block;
+    let count = check_request(device, block, length)?;
+    stats.merged += group.len() as u64 - 1;
+    match group[0].direction {
+        Direction::Read => {
+            if group.len() == 1 {
+                device.read_blocks(block, &mut group[0].data)?;
+            } else {
+                let mut buffer = vec![0; length];
+                device.read_blocks(block, &mut buffer)?;
+                let mut at = 0;
+                for request in group.iter_mut() {
+                    let end = at + request.data.len();
+                    request.data.copy_from_slice(&buffer[at..end]);
+                    at = end;
+                }
+            }
+            stats.reads += 1;
+            stats.blocks_read += count;
+        }
+        Direction::Write => {
+            if group.len() == 1 {
+                device.write_blocks(block, &group[0].data)?;
+            } else {
+                let mut buffer = Vec::with_capacity(length);
+                for request in group.iter() {
+                    buffer.extend_from_slice(&request.data);
+                }
+                device.write_blocks(block, &buffer)?;
+            }
+            stats.writes += 1;
+            stats.blocks_written += count;
+        }
+    }
+    Ok(())
+}
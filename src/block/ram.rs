@@ -0,0 +1,151 @@
+//! Block device in memory.
+//!
+//! The contents live in frames from the frame allocator, a page is only
+//! allocated once something other than zeros is written to it, so a large
+//! disk that's mostly empty costs little.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::ptr;
+
+use x86_64::structures::paging::PhysFrame;
+
+use super::{check_request, BlockDevice, BlockError, BlockResult, SECTOR_SIZE};
+use crate::lock_class;
+use crate::memory;
+use crate::sync::IrqSpinLock;
+
+const PAGE_SIZE: usize = 4096;
+
+pub struct RamDisk {
+    block_count: u64,
+    read_only: bool,
+    /// Pages by index, missing ones are zero
+    pages: IrqSpinLock<BTreeMap<u64, PhysFrame>>,
+}
+
+impl RamDisk {
+    fn empty(size: u64) -> RamDisk {
+        RamDisk {
+            block_count: (size + SECTOR_SIZE as u64 - 1) / SECTOR_SIZE as u64,
+            read_only: false,
+            pages: IrqSpinLock::with_class(BTreeMap::new(), lock_class!("RAM_DISK")),
+        }
+    }
+
+    /// Creates a zeroed disk of `size` bytes rounded up to whole sectors
+    pub fn new(size: u64) -> Arc<RamDisk> {
+        Arc::new(RamDisk::empty(size))
+    }
+
+    /// Creates a disk holding a copy of `image`, the last sector is padded
+    /// with zeros. Fails if there aren't enough frames.
+    pub fn with_contents(image: &[u8], read_only: bool) -> BlockResult<Arc<RamDisk>> {
+        let mut disk = RamDisk::empty(image.len() as u64);
+        let mut last = [0; SECTOR_SIZE];
+        for (index, chunk) in image.chunks(SECTOR_SIZE).enumerate() {
+            let data = if chunk.len() == SECTOR_SIZE {
+                chunk
+            } else {
+                last[..chunk.len()].copy_from_slice(chunk);
+                &last[..]
+            };
+            disk.write_blocks(index as u64, data)?;
+        }
+
+        disk.read_only = read_only;
+        Ok(Arc::new(disk))
+    }
+
+    /// Bytes of memory the contents use
+    pub fn used_bytes(&self) -> u64 {
+        (self.pages.lock().len() * PAGE_SIZE) as u64
+    }
+}
+
+fn page_ptr(frame: PhysFrame) -> *mut u8 {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+impl Drop for RamDisk {
+    fn drop(&mut self) {
+        let pages = core::mem::replace(&mut *self.pages.lock(), BTreeMap::new());
+        for frame in pages.values() {
+            memory::deallocate_frame(*frame);
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_blocks(&self, block: u64, buffer: &mut [u8]) -> BlockResult<()> {
+        check_request(self, block, buffer.len())?;
+        let pages = self.pages.lock();
+        let start = block as usize * SECTOR_SIZE;
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = start + done;
+            let in_page = position % PAGE_SIZE;
+            let chunk = (buffer.len() - done).min(PAGE_SIZE - in_page);
+            let target = &mut buffer[done..done + chunk];
+            match pages.get(&((position / PAGE_SIZE) as u64)) {
+                Some(frame) => unsafe {
+                    let page = page_ptr(*frame).add(in_page);
+                    ptr::copy_nonoverlapping(page, target.as_mut_ptr(), chunk);
+                },
+                None => {
+                    for byte in target.iter_mut() {
+                        *byte = 0;
+                    }
+                }
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, block: u64, buffer: &[u8]) -> BlockResult<()> {
+        check_request(self, block, buffer.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+
+        let mut pages = self.pages.lock();
+        let start = block as usize * SECTOR_SIZE;
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = start + done;
+            let index = (position / PAGE_SIZE) as u64;
+            let in_page = position % PAGE_SIZE;
+            let chunk = (buffer.len() - done).min(PAGE_SIZE - in_page);
+            let source = &buffer[done..done + chunk];
+
+            let frame = match pages.get(&index) {
+                Some(frame) => *frame,
+                // Zeros written to a missing page are already there
+                None if source.iter().all(|&byte| byte == 0) => {
+                    done += chunk;
+                    continue;
+                }
+                None => {
+                    let frame = memory::allocate_zeroed_frame().ok_or(BlockError::Io)?;
+                    pages.insert(index, frame);
+                    frame
+                }
+            };
+            unsafe {
+                let page = page_ptr(frame).add(in_page);
+                ptr::copy_nonoverlapping(source.as_ptr(), page, chunk);
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+}
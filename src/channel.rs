@@ -0,0 +1,232 @@
+//! Message channels.
+//!
+//! A channel is a bounded queue of byte messages that keeps the message
+//! boundaries. Any number of threads and processes may send and receive:
+//! `send` blocks while the queue is full and `receive` while it's empty.
+//! Every channel is registered under a `ChannelId` handle so that a process
+//! can open one that the kernel or another process created, it goes away
+//! with the last reference. Processes use channels as files, `write` sends
+//! a message and `read` receives one.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+
+use crate::file::{File, FileResult};
+use crate::lock_class;
+use crate::signal;
+use crate::sync::{IrqSpinLock, WaitQueue};
+use crate::syscall::SyscallError;
+
+/// Longest message a channel accepts
+pub const MAX_MESSAGE_SIZE: usize = 4096;
+/// Most messages a channel can queue
+pub const MAX_CAPACITY: usize = 256;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ChannelId(u64);
+
+impl ChannelId {
+    fn new() -> ChannelId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ChannelId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(id: u64) -> ChannelId {
+        ChannelId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ChannelId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChannelError {
+    /// The queue is full, only returned by `try_send`
+    Full,
+    /// The queue is empty, only returned by `try_receive`
+    Empty,
+    /// The message is longer than `MAX_MESSAGE_SIZE` or than the buffer
+    /// it's received into
+    MessageTooLong,
+    /// A signal for the calling process came first
+    Interrupted,
+}
+
+impl From<ChannelError> for SyscallError {
+    fn from(error: ChannelError) -> SyscallError {
+        match error {
+            ChannelError::Full | ChannelError::Empty => SyscallError::WouldBlock,
+            ChannelError::MessageTooLong => SyscallError::MessageTooLong,
+            ChannelError::Interrupted => SyscallError::Interrupted,
+        }
+    }
+}
+
+lazy_static! {
+    static ref CHANNELS: IrqSpinLock<BTreeMap<ChannelId, Weak<Channel>>> =
+        IrqSpinLock::with_class(BTreeMap::new(), lock_class!("CHANNELS"));
+}
+
+pub struct Channel {
+    id: ChannelId,
+    capacity: usize,
+    messages: IrqSpinLock<VecDeque<Vec<u8>>>,
+    /// Woken up when a message arrives
+    receivers: WaitQueue,
+    /// Woken up when a message is taken out
+    senders: WaitQueue,
+}
+
+/// Retries `attempt` until it fails with something else than `would_block`
+/// or a signal interrupts the caller
+fn blocking<T, F>(
+    queue: &WaitQueue,
+    would_block: ChannelError,
+    mut attempt: F,
+) -> Result<T, ChannelError>
+where
+    F: FnMut() -> Result<T, ChannelError>,
+{
+    let mut result = Err(would_block);
+    queue.wait_until(|| {
+        result = attempt();
+        match result {
+            Err(error) if error == would_block => signal::interrupted(),
+            _ => true,
+        }
+    });
+
+    match result {
+        Err(error) if error == would_block => Err(ChannelError::Interrupted),
+        result => result,
+    }
+}
+
+impl Channel {
+    /// Creates a channel holding up to `capacity` messages, which has to
+    /// be between 1 and `MAX_CAPACITY`
+    pub fn new(capacity: usize) -> Arc<Channel> {
+        assert!(capacity > 0 && capacity <= MAX_CAPACITY, "Invalid channel capacity");
+        let channel = Arc::new(Channel {
+            id: ChannelId::new(),
+            capacity,
+            messages: IrqSpinLock::with_class(VecDeque::new(), lock_class!("CHANNEL")),
+            receivers: WaitQueue::new(),
+            senders: WaitQueue::new(),
+        });
+
+        CHANNELS
+            .lock()
+            .insert(channel.id, Arc::downgrade(&channel));
+        channel
+    }
+
+    /// Looks up a channel by its handle
+    pub fn open(id: ChannelId) -> Option<Arc<Channel>> {
+        CHANNELS.lock().get(&id)?.upgrade()
+    }
+
+    pub fn id(&self) -> ChannelId {
+        self.id
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of queued messages
+    pub fn len(&self) -> usize {
+        self.messages.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn try_send(&self, message: &[u8]) -> Result<(), ChannelError> {
+        if message.len() > MAX_MESSAGE_SIZE {
+            return Err(ChannelError::MessageTooLong);
+        }
+
+        let message = message.to_vec();
+        {
+            let mut messages = self.messages.lock();
+            if messages.len() == self.capacity {
+                return Err(ChannelError::Full);
+            }
+            messages.push_back(message);
+        }
+        self.receivers.wake_all();
+        Ok(())
+    }
+
+    /// Blocks while the queue is full
+    pub fn send(&self, message: &[u8]) -> Result<(), ChannelError> {
+        blocking(&self.senders, ChannelError::Full, || self.try_send(message))
+    }
+
+    pub fn try_receive(&self) -> Result<Vec<u8>, ChannelError> {
+        let message = self
+            .messages
+            .lock()
+            .pop_front()
+            .ok_or(ChannelError::Empty)?;
+        self.senders.wake_all();
+        Ok(message)
+    }
+
+    /// Blocks while the queue is empty
+    pub fn receive(&self) -> Result<Vec<u8>, ChannelError> {
+        blocking(&self.receivers, ChannelError::Empty, || self.try_receive())
+    }
+
+    /// Copies the next message into `buffer`, it stays queued if it doesn't fit
+    fn try_receive_into(&self, buffer: &mut [u8]) -> Result<usize, ChannelError> {
+        let message = {
+            let mut messages = self.messages.lock();
+            let size = messages.front().ok_or(ChannelError::Empty)?.len();
+            if size > buffer.len() {
+                return Err(ChannelError::MessageTooLong);
+            }
+            messages.pop_front().expect("The message disappeared")
+        };
+
+        buffer[..message.len()].copy_from_slice(&message);
+        self.senders.wake_all();
+        Ok(message.len())
+    }
+}
+
+impl File for Channel {
+    /// Receives one message
+    fn read(&self, buffer: &mut [u8]) -> FileResult<usize> {
+        blocking(&self.receivers, ChannelError::Empty, || {
+            self.try_receive_into(buffer)
+        })
+        .map_err(SyscallError::from)
+    }
+
+    /// Sends `buffer` as one message
+    fn write(&self, buffer: &[u8]) -> FileResult<usize> {
+        self.send(buffer)?;
+        Ok(buffer.len())
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        CHANNELS.lock().remove(&self.id);
+    }
+}
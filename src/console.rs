@@ -0,0 +1,94 @@
+//! The text console user programs talk to: output goes to the screen and
+//! the serial port, input comes from the keyboard. Ctrl+C sends `SIGINT`
+//! to the foreground process.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+
+use crate::lock_class;
+use crate::process::{self, Pid};
+use crate::signal;
+use crate::sync::{IrqSpinLock, WaitQueue};
+use crate::{print, serial_print};
+
+// Typed characters are dropped when nobody reads them
+const INPUT_CAPACITY: usize = 256;
+
+lazy_static! {
+    static ref INPUT: IrqSpinLock<VecDeque<u8>> =
+        IrqSpinLock::with_class(VecDeque::new(), lock_class!("CONSOLE_INPUT"));
+    static ref INPUT_WAITERS: WaitQueue = WaitQueue::new();
+}
+
+/// Process that gets Ctrl+C, 0 if there's none
+static FOREGROUND: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_foreground(pid: Option<Pid>) {
+    FOREGROUND.store(pid.map_or(0, Pid::as_u64), Ordering::Relaxed);
+}
+
+pub fn foreground() -> Option<Pid> {
+    match FOREGROUND.load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(Pid::from_u64(pid)),
+    }
+}
+
+/// Called by the keyboard interrupt handler for Ctrl+C
+pub fn interrupt() {
+    if let Some(pid) = foreground() {
+        // It may have exited already
+        let _ = process::send_signal(pid, signal::SIGINT);
+    }
+}
+
+/// Called by the keyboard interrupt handler for every typed character
+pub fn push_input(character: char) {
+    let mut bytes = [0; 4];
+    {
+        let mut input = INPUT.lock();
+        for &byte in character.encode_utf8(&mut bytes).as_bytes() {
+            if input.len() < INPUT_CAPACITY {
+                input.push_back(byte);
+            }
+        }
+    }
+
+    INPUT_WAITERS.wake_all();
+}
+
+/// Blocks until there's input and reads as much of it as fits into
+/// `buffer`. Returns `None` if a signal for the calling process came first.
+pub fn read(buffer: &mut [u8]) -> Option<usize> {
+    if buffer.is_empty() {
+        return Some(0);
+    }
+
+    INPUT_WAITERS.wait_until(|| {
+        let ready = !INPUT.lock().is_empty();
+        ready || signal::interrupted()
+    });
+
+    let mut input = INPUT.lock();
+    if input.is_empty() {
+        return None;
+    }
+    let mut count = 0;
+    while count < buffer.len() {
+        match input.pop_front() {
+            Some(byte) => buffer[count] = byte,
+            None => break,
+        }
+        count += 1;
+    }
+    Some(count)
+}
+
+pub fn write(bytes: &[u8]) {
+    let text = String::from_utf8_lossy(bytes);
+    print!("{}", text);
+    serial_print!("{}", text);
+}
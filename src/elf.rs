@@ -0,0 +1,341 @@
+//! Loading ELF64 executables into an address space.
+//!
+//! Only static x86_64 executables are supported: the `PT_LOAD` segments are
+//! mapped with the permissions they ask for and everything else is ignored.
+
+use alloc::vec::Vec;
+use core::cmp;
+
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::address_space::{self, AddressSpace, MapError};
+use crate::user;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3E;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// Auxiliary vector entry types
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    /// Not a 64 bit little endian x86_64 file
+    UnsupportedFormat,
+    NotExecutable,
+    BadProgramHeader,
+    /// A segment lies outside of the file or of user space
+    BadSegment,
+    /// Arguments and environment don't fit on the stack
+    ArgumentsTooLong,
+    Map(MapError),
+}
+
+impl From<MapError> for ElfError {
+    fn from(error: MapError) -> ElfError {
+        ElfError::Map(error)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+}
+
+/// A validated ELF file
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    program_header_offset: u64,
+    program_header_count: u16,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from(data[offset]) | (u16::from(data[offset + 1]) << 8)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(data, offset)) | (u32::from(read_u16(data, offset + 2)) << 16)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from(read_u32(data, offset)) | (u64::from(read_u32(data, offset + 4)) << 32)
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<ElfFile<'a>, ElfError> {
+        if data.len() < ELF_HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64
+            || data[5] != ELFDATA2LSB
+            || data[6] != EV_CURRENT
+            || read_u16(data, 18) != EM_X86_64
+        {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if read_u16(data, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+
+        let program_header_offset = read_u64(data, 32);
+        let program_header_size = read_u16(data, 54);
+        let program_header_count = read_u16(data, 56);
+        let table_size = u64::from(program_header_count) * PROGRAM_HEADER_SIZE as u64;
+        let table_end = program_header_offset.checked_add(table_size);
+        if usize::from(program_header_size) != PROGRAM_HEADER_SIZE
+            || table_end.map_or(true, |end| end > data.len() as u64)
+        {
+            return Err(ElfError::BadProgramHeader);
+        }
+
+        let file = ElfFile {
+            data,
+            entry: read_u64(data, 24),
+            program_header_offset,
+            program_header_count,
+        };
+        if file.entry < address_space::USER_SPACE_START
+            || file.entry >= address_space::USER_SPACE_END
+        {
+            return Err(ElfError::NotExecutable);
+        }
+
+        for header in file.program_headers() {
+            if header.kind == PT_LOAD {
+                file.check_segment(&header)?;
+            }
+        }
+
+        Ok(file)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..usize::from(self.program_header_count)).map(move |index| {
+            let offset = self.program_header_offset as usize + index * PROGRAM_HEADER_SIZE;
+            let data = self.data;
+            ProgramHeader {
+                kind: read_u32(data, offset),
+                flags: read_u32(data, offset + 4),
+                offset: read_u64(data, offset + 8),
+                virtual_address: read_u64(data, offset + 16),
+                file_size: read_u64(data, offset + 32),
+                memory_size: read_u64(data, offset + 40),
+            }
+        })
+    }
+
+    fn check_segment(&self, header: &ProgramHeader) -> Result<(), ElfError> {
+        let file_end = header.offset.checked_add(header.file_size);
+        let memory_end = header.virtual_address.checked_add(header.memory_size);
+        let valid = header.file_size <= header.memory_size
+            && file_end.map_or(false, |end| end <= self.data.len() as u64)
+            && header.virtual_address >= address_space::USER_SPACE_START
+            && memory_end.map_or(false, |end| end <= address_space::USER_SPACE_END);
+
+        if valid {
+            Ok(())
+        } else {
+            Err(ElfError::BadSegment)
+        }
+    }
+
+    /// Where the program headers end up in memory, for `AT_PHDR`
+    fn program_headers_address(&self) -> Option<u64> {
+        self.program_headers()
+            .filter(|header| header.kind == PT_LOAD)
+            .find(|header| {
+                header.offset <= self.program_header_offset
+                    && self.program_header_offset < header.offset + header.file_size
+            })
+            .map(|header| header.virtual_address + self.program_header_offset - header.offset)
+    }
+}
+
+fn segment_flags(header: &ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    if header.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if header.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Maps the pages of a segment, pages shared with a previous segment get
+/// the permissions of both
+fn map_segment(space: &AddressSpace, header: &ProgramHeader) -> Result<(), ElfError> {
+    if header.memory_size == 0 {
+        return Ok(());
+    }
+
+    let flags = segment_flags(header);
+    let start = VirtAddr::new(header.virtual_address);
+    let first: Page<Size4KiB> = Page::containing_address(start);
+    let last = Page::containing_address(start + (header.memory_size - 1));
+    for page in Page::range_inclusive(first, last) {
+        match space.translate(page.start_address()) {
+            Some((_, existing)) => {
+                let mut merged = existing | flags;
+                if !(existing & flags).contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                space.update_flags(page, merged)?;
+            }
+            None => {
+                space.map_new_page(page, flags)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps and fills the `PT_LOAD` segments, the BSS part of a segment is zeroed
+pub fn load(space: &AddressSpace, file: &ElfFile) -> Result<(), ElfError> {
+    const ZEROS: [u8; 512] = [0; 512];
+
+    for header in file.program_headers() {
+        if header.kind != PT_LOAD {
+            continue;
+        }
+
+        map_segment(space, &header)?;
+        let start = header.offset as usize;
+        let contents = &file.data[start..start + header.file_size as usize];
+        space.write_bytes(VirtAddr::new(header.virtual_address), contents)?;
+
+        // The pages could be shared with the file contents of another segment
+        let mut zeroed = header.file_size;
+        while zeroed < header.memory_size {
+            let count = cmp::min(ZEROS.len() as u64, header.memory_size - zeroed);
+            let addr = VirtAddr::new(header.virtual_address + zeroed);
+            space.write_bytes(addr, &ZEROS[..count as usize])?;
+            zeroed += count;
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the initial stack the System V ABI expects at the entry point:
+/// `argc`, the `argv` and `envp` pointers, the auxiliary vector and the
+/// strings they point to. Returns the stack pointer.
+pub fn setup_stack(
+    space: &AddressSpace,
+    file: &ElfFile,
+    stack_top: VirtAddr,
+    stack_size: usize,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, ElfError> {
+    let top = stack_top.as_u64();
+    let bottom = top - stack_size as u64;
+
+    // The strings go at the top
+    let mut strings = Vec::new();
+    let mut string_offsets = Vec::new();
+    for string in argv.iter().chain(envp.iter()) {
+        string_offsets.push(strings.len() as u64);
+        strings.extend_from_slice(string.as_bytes());
+        strings.push(0);
+    }
+    let strings_start = (top - strings.len() as u64) & !0xF;
+    let string_address = |index: usize| strings_start + string_offsets[index];
+
+    let mut auxv = Vec::new();
+    if let Some(address) = file.program_headers_address() {
+        auxv.push((AT_PHDR, address));
+    }
+    auxv.push((AT_PHENT, PROGRAM_HEADER_SIZE as u64));
+    auxv.push((AT_PHNUM, u64::from(file.program_header_count)));
+    auxv.push((AT_PAGESZ, 4096));
+    auxv.push((AT_ENTRY, file.entry));
+    auxv.push((AT_NULL, 0));
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend((0..argv.len()).map(&string_address));
+    words.push(0);
+    words.extend((argv.len()..argv.len() + envp.len()).map(&string_address));
+    words.push(0);
+    for &(kind, value) in auxv.iter() {
+        words.push(kind);
+        words.push(value);
+    }
+    // `rsp` has to be 16 byte aligned at the entry point
+    if words.len() % 2 != 0 {
+        words.push(0);
+    }
+
+    let stack_pointer = strings_start - (words.len() * 8) as u64;
+    if stack_pointer < bottom {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+
+    let mut bytes = Vec::with_capacity(words.len() * 8);
+    for word in words {
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+    space.write_bytes(VirtAddr::new(stack_pointer), &bytes)?;
+    space.write_bytes(VirtAddr::new(strings_start), &strings)?;
+
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+/// Creates an address space with the program and its stack, returns it
+/// with the entry point and initial stack pointer
+pub fn load_executable(
+    data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(AddressSpace, VirtAddr, VirtAddr), ElfError> {
+    let file = ElfFile::parse(data)?;
+    let space = AddressSpace::new()?;
+    load(&space, &file)?;
+
+    let stack_top = VirtAddr::new(user::USER_STACK_TOP);
+    user::map_region(
+        &space,
+        stack_top - user::USER_STACK_SIZE as u64,
+        user::USER_STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+    let stack_pointer = setup_stack(
+        &space,
+        &file,
+        stack_top,
+        user::USER_STACK_SIZE,
+        argv,
+        envp,
+    )?;
+
+    Ok((space, VirtAddr::new(file.entry), stack_pointer))
+}
@@ -0,0 +1,129 @@
+//! Open files and the file descriptor table of a process.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::console;
+use crate::fs::{DirEntry, Metadata, SeekFrom};
+use crate::syscall::SyscallError;
+
+pub type FileResult<T> = Result<T, SyscallError>;
+
+/// Anything a file descriptor can refer to. Descriptors duplicated with
+/// `dup` or `fork` share the same `File`.
+pub trait File: Send + Sync {
+    fn read(&self, _buffer: &mut [u8]) -> FileResult<usize> {
+        Err(SyscallError::BadFileDescriptor)
+    }
+
+    fn write(&self, _buffer: &[u8]) -> FileResult<usize> {
+        Err(SyscallError::BadFileDescriptor)
+    }
+
+    /// Moves the position of a file that has one, returns the new one
+    fn seek(&self, _from: SeekFrom) -> FileResult<u64> {
+        Err(SyscallError::IllegalSeek)
+    }
+
+    /// Only files of a filesystem have metadata
+    fn metadata(&self) -> FileResult<Metadata> {
+        Err(SyscallError::InvalidArgument)
+    }
+
+    /// Changes the size of a regular file
+    fn set_len(&self, _size: u64) -> FileResult<()> {
+        Err(SyscallError::InvalidArgument)
+    }
+
+    /// Returns the next entry of a directory, `None` after the last one
+    fn read_dir(&self) -> FileResult<Option<DirEntry>> {
+        Err(SyscallError::NotADirectory)
+    }
+}
+
+/// Keyboard input and screen output
+pub struct Console;
+
+impl File for Console {
+    fn read(&self, buffer: &mut [u8]) -> FileResult<usize> {
+        console::read(buffer).ok_or(SyscallError::Interrupted)
+    }
+
+    fn write(&self, buffer: &[u8]) -> FileResult<usize> {
+        console::write(buffer);
+        Ok(buffer.len())
+    }
+}
+
+// Upper limit of open files per process
+const MAX_FILES: usize = 64;
+
+#[derive(Clone, Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl FileTable {
+    pub fn new() -> FileTable {
+        FileTable { files: Vec::new() }
+    }
+
+    /// A table with the console open as stdin, stdout and stderr
+    pub fn with_console() -> FileTable {
+        let console: Arc<dyn File> = Arc::new(Console);
+        FileTable {
+            files: vec![Some(console.clone()), Some(console.clone()), Some(console)],
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> FileResult<Arc<dyn File>> {
+        self.files
+            .get(fd)
+            .and_then(|file| file.clone())
+            .ok_or(SyscallError::BadFileDescriptor)
+    }
+
+    /// Opens `file` at the lowest free descriptor
+    pub fn insert(&mut self, file: Arc<dyn File>) -> FileResult<usize> {
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None => self.files.len(),
+        };
+        self.insert_at(fd, file)?;
+        Ok(fd)
+    }
+
+    /// Opens `file` at `fd`, closing what was open there
+    pub fn insert_at(&mut self, fd: usize, file: Arc<dyn File>) -> FileResult<()> {
+        if fd >= MAX_FILES {
+            return Err(SyscallError::TooManyFiles);
+        }
+
+        if fd >= self.files.len() {
+            self.files.resize(fd + 1, None);
+        }
+        self.files[fd] = Some(file);
+        Ok(())
+    }
+
+    pub fn close(&mut self, fd: usize) -> FileResult<Arc<dyn File>> {
+        self.files
+            .get_mut(fd)
+            .and_then(Option::take)
+            .ok_or(SyscallError::BadFileDescriptor)
+    }
+
+    pub fn close_all(&mut self) {
+        self.files.clear();
+    }
+
+    /// Number of open descriptors
+    pub fn len(&self) -> usize {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,429 @@
+//! Device files.
+//!
+//! Drivers register their devices by name, a `/` in the name puts the
+//! device in a directory like `input/mouse0`. Directories exist as long as
+//! there are devices in them. The filesystem mounted on `/dev` shows what's
+//! registered at the time of the lookup, a device that's unregistered
+//! while it's open keeps working for the open file.
+//!
+//! Character devices are streams without a position, block devices are
+//! read and written at any offset below their size.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use lazy_static::lazy_static;
+
+use super::path;
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, Metadata, Timestamp};
+use crate::block::{self, BlockDevice};
+use crate::console;
+use crate::input::{self, InputQueue};
+use crate::lock_class;
+use crate::serial;
+use crate::sync::IrqSpinLock;
+use crate::time;
+use crate::vga_driver::VGA_WRITER;
+
+const ROOT_INODE: u64 = 1;
+
+/// A device that's read and written as a stream of bytes
+pub trait CharDevice: Send + Sync {
+    /// Blocks until there's something to read unless the device never has
+    /// anything more, then it returns 0
+    fn read(&self, _buffer: &mut [u8]) -> FsResult<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn write(&self, _buffer: &[u8]) -> FsResult<usize> {
+        Err(FsError::NotSupported)
+    }
+}
+
+#[derive(Clone)]
+enum Node {
+    Directory,
+    Char(Arc<dyn CharDevice>),
+    Block(Arc<dyn BlockDevice>),
+}
+
+impl Node {
+    fn file_type(&self) -> FileType {
+        match self {
+            Node::Directory => FileType::Directory,
+            Node::Char(_) => FileType::CharDevice,
+            Node::Block(_) => FileType::BlockDevice,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Entry {
+    inode: u64,
+    node: Node,
+    registered: Timestamp,
+}
+
+struct Registry {
+    /// By path below `/dev`, without a leading `/`
+    entries: BTreeMap<String, Entry>,
+    next_inode: u64,
+}
+
+lazy_static! {
+    static ref REGISTRY: IrqSpinLock<Registry> = IrqSpinLock::with_class(
+        Registry {
+            entries: BTreeMap::new(),
+            next_inode: ROOT_INODE + 1,
+        },
+        lock_class!("DEVICES")
+    );
+}
+
+/// Returns `name` with empty components removed, fails for `.` and `..`
+fn check_name(name: &str) -> FsResult<String> {
+    let mut checked = String::new();
+    for component in path::components(name) {
+        if component == "." || component == ".." {
+            return Err(FsError::InvalidArgument);
+        }
+        if component.len() > path::MAX_NAME {
+            return Err(FsError::NameTooLong);
+        }
+        if !checked.is_empty() {
+            checked.push('/');
+        }
+        checked.push_str(component);
+    }
+
+    if checked.is_empty() {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok(checked)
+}
+
+/// The directory `name` is in, empty for the root
+fn parent(name: &str) -> &str {
+    name.rfind('/').map_or("", |index| &name[..index])
+}
+
+fn register(name: &str, node: Node) -> FsResult<()> {
+    let name = check_name(name)?;
+    let mut registry = REGISTRY.lock();
+    if registry.entries.contains_key(&name) {
+        return Err(FsError::AlreadyExists);
+    }
+
+    // The directories above it, from the top down
+    let mut directories = Vec::new();
+    let mut directory = parent(&name);
+    while !directory.is_empty() {
+        directories.push(directory);
+        directory = parent(directory);
+    }
+    for &directory in directories.iter().rev() {
+        match registry.entries.get(directory) {
+            Some(entry) if entry.node.file_type() != FileType::Directory => {
+                return Err(FsError::NotADirectory);
+            }
+            _ => {}
+        }
+    }
+
+    let now = time::uptime_ms();
+    for &directory in directories.iter().rev() {
+        if !registry.entries.contains_key(directory) {
+            let inode = registry.next_inode;
+            registry.next_inode += 1;
+            let entry = Entry {
+                inode,
+                node: Node::Directory,
+                registered: now,
+            };
+            registry.entries.insert(String::from(directory), entry);
+        }
+    }
+    let inode = registry.next_inode;
+    registry.next_inode += 1;
+    let entry = Entry {
+        inode,
+        node,
+        registered: now,
+    };
+    registry.entries.insert(name, entry);
+    Ok(())
+}
+
+/// Makes a character device available as `/dev/<name>`
+pub fn register_char(name: &str, device: Arc<dyn CharDevice>) -> FsResult<()> {
+    register(name, Node::Char(device))
+}
+
+/// Makes a block device available as `/dev/<name>`
+pub fn register_block(name: &str, device: Arc<dyn BlockDevice>) -> FsResult<()> {
+    register(name, Node::Block(device))
+}
+
+/// Removes the device `name`, and the directories above it if they're
+/// empty then
+pub fn unregister(name: &str) -> FsResult<()> {
+    let name = check_name(name)?;
+    let mut registry = REGISTRY.lock();
+    match registry.entries.get(&name) {
+        Some(entry) if entry.node.file_type() == FileType::Directory => {
+            return Err(FsError::IsADirectory);
+        }
+        Some(_) => {}
+        None => return Err(FsError::NotFound),
+    }
+    registry.entries.remove(&name);
+
+    let mut directory = String::from(parent(&name));
+    while !directory.is_empty() {
+        let prefix = directory.clone() + "/";
+        let in_use = registry
+            .entries
+            .range::<String, _>(&prefix..)
+            .next()
+            .map_or(false, |(name, _)| name.starts_with(&prefix));
+        if in_use {
+            break;
+        }
+        registry.entries.remove(&directory);
+        directory = String::from(parent(&directory));
+    }
+    Ok(())
+}
+
+/// Returns the block device `name`, for mounting the filesystem on it
+pub fn block_device(name: &str) -> FsResult<Arc<dyn BlockDevice>> {
+    let name = check_name(name)?;
+    match REGISTRY.lock().entries.get(&name) {
+        Some(Entry {
+            node: Node::Block(device),
+            ..
+        }) => Ok(device.clone()),
+        Some(_) => Err(FsError::InvalidArgument),
+        None => Err(FsError::NotFound),
+    }
+}
+
+/// Names of the registered devices, sorted
+pub fn devices() -> Vec<String> {
+    REGISTRY
+        .lock()
+        .entries
+        .iter()
+        .filter(|(_, entry)| entry.node.file_type() != FileType::Directory)
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+pub struct DevFs;
+
+impl DevFs {
+    pub fn new() -> Arc<DevFs> {
+        Arc::new(DevFs)
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(DevInode {
+            name: String::new(),
+            entry: Entry {
+                inode: ROOT_INODE,
+                node: Node::Directory,
+                registered: 0,
+            },
+        })
+    }
+}
+
+/// A directory or device, holding on to the device while it's in use
+struct DevInode {
+    /// Empty for the root
+    name: String,
+    entry: Entry,
+}
+
+impl Inode for DevInode {
+    fn metadata(&self) -> FsResult<Metadata> {
+        let size = match &self.entry.node {
+            Node::Block(device) => device.block_count() * device.block_size() as u64,
+            _ => 0,
+        };
+
+        Ok(Metadata {
+            inode: self.entry.inode,
+            size,
+            file_type: self.entry.node.file_type(),
+            links: 1,
+            accessed: self.entry.registered,
+            modified: self.entry.registered,
+            changed: self.entry.registered,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        match &self.entry.node {
+            Node::Directory => Err(FsError::IsADirectory),
+            Node::Char(device) => device.read(buffer),
+            Node::Block(device) => {
+                let size = device.block_count() * device.block_size() as u64;
+                if offset >= size {
+                    return Ok(0);
+                }
+                let length = (size - offset).min(buffer.len() as u64) as usize;
+                block::read_at(&**device, offset, &mut buffer[..length])?;
+                Ok(length)
+            }
+        }
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> FsResult<usize> {
+        match &self.entry.node {
+            Node::Directory => Err(FsError::IsADirectory),
+            Node::Char(device) => device.write(buffer),
+            Node::Block(device) => {
+                let size = device.block_count() * device.block_size() as u64;
+                if offset >= size && !buffer.is_empty() {
+                    return Err(FsError::NoSpace);
+                }
+                let length = (size - offset.min(size)).min(buffer.len() as u64) as usize;
+                block::write_at(&**device, offset, &buffer[..length])?;
+                Ok(length)
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        if self.entry.node.file_type() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let name = if self.name.is_empty() {
+            String::from(name)
+        } else {
+            self.name.clone() + "/" + name
+        };
+        let entry = REGISTRY
+            .lock()
+            .entries
+            .get(&name)
+            .cloned()
+            .ok_or(FsError::NotFound)?;
+        Ok(Arc::new(DevInode { name, entry }))
+    }
+
+    fn read_dir(&self, index: usize) -> FsResult<Option<DirEntry>> {
+        if self.entry.node.file_type() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let registry = REGISTRY.lock();
+        let entry = registry
+            .entries
+            .iter()
+            .filter(|(name, _)| parent(name) == self.name)
+            .nth(index)
+            .map(|(name, entry)| DirEntry {
+                inode: entry.inode,
+                file_type: entry.node.file_type(),
+                name: String::from(&name[name.rfind('/').map_or(0, |index| index + 1)..]),
+            });
+        Ok(entry)
+    }
+}
+
+/// Reads nothing and swallows what's written
+struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buffer: &mut [u8]) -> FsResult<usize> {
+        Ok(0)
+    }
+
+    fn write(&self, buffer: &[u8]) -> FsResult<usize> {
+        Ok(buffer.len())
+    }
+}
+
+/// Reads zeros and swallows what's written
+struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buffer: &mut [u8]) -> FsResult<usize> {
+        for byte in buffer.iter_mut() {
+            *byte = 0;
+        }
+        Ok(buffer.len())
+    }
+
+    fn write(&self, buffer: &[u8]) -> FsResult<usize> {
+        Ok(buffer.len())
+    }
+}
+
+/// The VGA text screen, reading gives the typed characters
+struct Console;
+
+impl CharDevice for Console {
+    fn read(&self, buffer: &mut [u8]) -> FsResult<usize> {
+        console::read(buffer).ok_or(FsError::Interrupted)
+    }
+
+    fn write(&self, buffer: &[u8]) -> FsResult<usize> {
+        VGA_WRITER.lock().print(buffer);
+        Ok(buffer.len())
+    }
+}
+
+/// The first serial port
+struct Serial;
+
+impl CharDevice for Serial {
+    fn read(&self, buffer: &mut [u8]) -> FsResult<usize> {
+        input::SERIAL.read(buffer).ok_or(FsError::Interrupted)
+    }
+
+    fn write(&self, buffer: &[u8]) -> FsResult<usize> {
+        serial::write(buffer);
+        Ok(buffer.len())
+    }
+}
+
+/// Raw input of a PS/2 device
+struct Input(&'static InputQueue);
+
+impl CharDevice for Input {
+    fn read(&self, buffer: &mut [u8]) -> FsResult<usize> {
+        self.0.read(buffer).ok_or(FsError::Interrupted)
+    }
+}
+
+/// Registers the devices that are always there and mounts devfs on `/dev`
+pub fn init() {
+    let devices: [(&str, Arc<dyn CharDevice>); 6] = [
+        ("null", Arc::new(Null)),
+        ("zero", Arc::new(Zero)),
+        ("console", Arc::new(Console)),
+        ("ttyS0", Arc::new(Serial)),
+        // Scan codes of set 1
+        ("input/keyboard0", Arc::new(Input(&input::KEYBOARD))),
+        // Packets of 4 bytes: buttons, x and y movement and the wheel
+        ("input/mouse0", Arc::new(Input(&input::MOUSE))),
+    ];
+    for (name, device) in devices.iter() {
+        register_char(name, device.clone()).expect("Failed to register a device");
+    }
+
+    super::create_dir_all("/dev").expect("Failed to create /dev");
+    super::mount("/dev", DevFs::new()).expect("Failed to mount devfs");
+}
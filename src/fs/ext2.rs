@@ -0,0 +1,558 @@
+//! Second extended filesystem, read-only.
+//!
+//! The superblock is 1024 bytes into the device, the group descriptors
+//! start in the block after it. Each block group has a table of inodes,
+//! inode `n` is entry `(n - 1) % inodes_per_group` of group
+//! `(n - 1) / inodes_per_group`. An inode maps the blocks of a file with 12
+//! direct pointers followed by a singly, a doubly and a triply indirect one,
+//! a 0 pointer is a hole that reads as zeros.
+//!
+//! A directory is a file of variable length entries that never cross a
+//! block. Hashed directory indexes hide in entries that look unused, so
+//! directories are read linearly. `.` and `..` are left out of listings
+//! like on the other filesystems. Symbolic link targets of up to 60 bytes
+//! are stored in the block pointers of the inode.
+//!
+//! Files that are neither regular, directories nor symbolic links show up
+//! as empty regular files. Nothing is ever written, a filesystem that
+//! needs features beyond the directory entry file types isn't mounted.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::path::MAX_PATH;
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, Metadata, Timestamp};
+use crate::block::{self, BlockDevice};
+
+const SUPERBLOCK_POSITION: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+/// Size of a group descriptor
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+/// Inode size of revision 0 filesystems
+const OLD_INODE_SIZE: u32 = 128;
+/// Bytes of an inode that are read, the rest are extensions
+const INODE_SIZE: usize = 128;
+const ROOT_INODE: u32 = 2;
+
+/// Directory entries have a file type byte
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+
+const DIRECT_BLOCKS: usize = 12;
+/// Block pointers in an inode, the direct ones and the three indirect ones
+const BLOCK_POINTERS: usize = DIRECT_BLOCKS + 3;
+/// Longest symbolic link target stored in the inode
+const FAST_LINK_SIZE: u64 = (BLOCK_POINTERS * 4) as u64;
+
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_REGULAR: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xA000;
+
+/// Header of a directory entry before the name
+const DIR_ENTRY_HEADER: usize = 8;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from(data[offset]) | u16::from(data[offset + 1]) << 8
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(data, offset)) | u32::from(read_u16(data, offset + 2)) << 16
+}
+
+fn timestamp(seconds: u32) -> Timestamp {
+    u64::from(seconds) * 1000
+}
+
+/// What's needed of the superblock and the group descriptors
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: u32,
+    block_count: u32,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: u32,
+    /// First block of the inode table of each block group
+    inode_tables: Vec<u32>,
+    /// Directory entries have a file type byte instead of a 16 bit name
+    /// length
+    entry_types: bool,
+    label: String,
+}
+
+impl Volume {
+    fn open(device: Arc<dyn BlockDevice>) -> FsResult<Volume> {
+        let mut superblock = vec![0; SUPERBLOCK_SIZE];
+        block::read_at(&*device, SUPERBLOCK_POSITION, &mut superblock)?;
+        if read_u16(&superblock, 56) != MAGIC {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let inode_count = read_u32(&superblock, 0);
+        let block_count = read_u32(&superblock, 4);
+        let first_data_block = read_u32(&superblock, 20);
+        let log_block_size = read_u32(&superblock, 24);
+        let blocks_per_group = read_u32(&superblock, 32);
+        let inodes_per_group = read_u32(&superblock, 40);
+        let revision = read_u32(&superblock, 76);
+        let (inode_size, incompat) = if revision == 0 {
+            (OLD_INODE_SIZE, 0)
+        } else {
+            (
+                u32::from(read_u16(&superblock, 88)),
+                read_u32(&superblock, 96),
+            )
+        };
+        if incompat & !SUPPORTED_INCOMPAT != 0 {
+            return Err(FsError::NotSupported);
+        }
+
+        // Blocks go from 1 KiB to 64 KiB
+        if log_block_size > 6 {
+            return Err(FsError::Io);
+        }
+        let block_size = 1024 << log_block_size;
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_count == 0
+            || first_data_block >= block_count
+            || !inode_size.is_power_of_two()
+            || inode_size < OLD_INODE_SIZE
+            || inode_size > block_size
+            || u64::from(block_count) * u64::from(block_size)
+                > device.block_count() * device.block_size() as u64
+        {
+            return Err(FsError::Io);
+        }
+
+        let group_count = (inode_count - 1) / inodes_per_group + 1;
+        let block_groups = (block_count - first_data_block - 1) / blocks_per_group + 1;
+        if group_count != block_groups {
+            return Err(FsError::Io);
+        }
+
+        let mut descriptors = vec![0; group_count as usize * GROUP_DESCRIPTOR_SIZE];
+        let position = u64::from(first_data_block + 1) * u64::from(block_size);
+        block::read_at(&*device, position, &mut descriptors)?;
+        let table_blocks =
+            (u64::from(inodes_per_group) * u64::from(inode_size) - 1) / u64::from(block_size) + 1;
+        let inode_tables = descriptors
+            .chunks(GROUP_DESCRIPTOR_SIZE)
+            .map(|descriptor| read_u32(descriptor, 8))
+            .collect::<Vec<_>>();
+        if inode_tables
+            .iter()
+            .any(|&table| table == 0 || u64::from(table) + table_blocks > u64::from(block_count))
+        {
+            return Err(FsError::Io);
+        }
+
+        let label = superblock[120..136]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .cloned()
+            .collect::<Vec<_>>();
+        Ok(Volume {
+            device,
+            block_size,
+            block_count,
+            inode_count,
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+            entry_types: incompat & INCOMPAT_FILETYPE != 0,
+            label: String::from_utf8_lossy(&label).into_owned(),
+        })
+    }
+
+    fn block_position(&self, block: u32) -> u64 {
+        u64::from(block) * u64::from(self.block_size)
+    }
+
+    /// Reads `buffer.len()` bytes from `offset` on in `block`
+    fn read_block(&self, block: u32, offset: usize, buffer: &mut [u8]) -> FsResult<()> {
+        if block >= self.block_count {
+            return Err(FsError::Io);
+        }
+        block::read_at(
+            &*self.device,
+            self.block_position(block) + offset as u64,
+            buffer,
+        )?;
+        Ok(())
+    }
+
+    fn read_inode(&self, number: u32) -> FsResult<RawInode> {
+        if number == 0 || number > self.inode_count {
+            return Err(FsError::Io);
+        }
+
+        let group = (number - 1) / self.inodes_per_group;
+        let index = (number - 1) % self.inodes_per_group;
+        let position = self.block_position(self.inode_tables[group as usize])
+            + u64::from(index) * u64::from(self.inode_size);
+        let mut data = [0; INODE_SIZE];
+        block::read_at(&*self.device, position, &mut data)?;
+
+        let mode = read_u16(&data, 0);
+        let mut size = u64::from(read_u32(&data, 4));
+        // The high half of the size of a regular file, it's the directory
+        // ACL otherwise
+        if mode & MODE_TYPE_MASK == MODE_REGULAR {
+            size |= u64::from(read_u32(&data, 108)) << 32;
+        }
+        let mut blocks = [0; BLOCK_POINTERS];
+        for (index, block) in blocks.iter_mut().enumerate() {
+            *block = read_u32(&data, 40 + index * 4);
+        }
+
+        Ok(RawInode {
+            number,
+            mode,
+            size,
+            accessed: read_u32(&data, 8),
+            changed: read_u32(&data, 12),
+            modified: read_u32(&data, 16),
+            links: read_u16(&data, 26),
+            sectors: read_u32(&data, 28),
+            attribute_block: read_u32(&data, 104),
+            blocks,
+        })
+    }
+
+    /// Returns the device block of block `index` of a file, 0 for a hole
+    fn map_block(&self, inode: &RawInode, index: u64) -> FsResult<u32> {
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(inode.blocks[index as usize]);
+        }
+
+        let per_block = u64::from(self.block_size / 4);
+        let mut index = index - DIRECT_BLOCKS as u64;
+        // Blocks an entry of the pointer block at the current level maps
+        let mut span = 1;
+        for level in 0..3 {
+            span *= per_block;
+            if index >= span {
+                index -= span;
+                continue;
+            }
+
+            let mut block = inode.blocks[DIRECT_BLOCKS + level];
+            for _ in 0..=level {
+                if block == 0 {
+                    return Ok(0);
+                }
+                span /= per_block;
+                let mut entry = [0; 4];
+                self.read_block(block, (index / span) as usize * 4, &mut entry)?;
+                block = read_u32(&entry, 0);
+                index %= span;
+            }
+            return Ok(block);
+        }
+        Err(FsError::Io)
+    }
+
+    /// Reads from `offset` on in the data blocks of a file
+    fn read_data(&self, inode: &RawInode, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+
+        let length = (inode.size - offset).min(buffer.len() as u64) as usize;
+        let block_size = u64::from(self.block_size);
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let in_block = (position % block_size) as usize;
+            let chunk = (length - done).min(self.block_size as usize - in_block);
+            let target = &mut buffer[done..done + chunk];
+            match self.map_block(inode, position / block_size)? {
+                0 => {
+                    for byte in target.iter_mut() {
+                        *byte = 0;
+                    }
+                }
+                block => self.read_block(block, in_block, target)?,
+            }
+            done += chunk;
+        }
+        Ok(length)
+    }
+
+    /// Calls `found` with the inode number, the type byte and the name of
+    /// the entries of a directory until it returns `Some`
+    fn find_entry<T, F>(&self, directory: &RawInode, mut found: F) -> FsResult<Option<T>>
+    where
+        F: FnMut(u32, u8, &[u8]) -> Option<T>,
+    {
+        let block_size = self.block_size as usize;
+        let mut data = vec![0; block_size];
+        let mut offset = 0;
+        while offset < directory.size {
+            let length = self.read_data(directory, offset, &mut data)?;
+            if length < block_size {
+                return Err(FsError::Io);
+            }
+
+            let mut position = 0;
+            while position < block_size {
+                if block_size - position < DIR_ENTRY_HEADER {
+                    return Err(FsError::Io);
+                }
+                let entry = &data[position..];
+                let inode = read_u32(entry, 0);
+                let length = read_u16(entry, 4) as usize;
+                let (name_length, file_type) = if self.entry_types {
+                    (entry[6] as usize, entry[7])
+                } else {
+                    (read_u16(entry, 6) as usize, 0)
+                };
+                if length < DIR_ENTRY_HEADER
+                    || length % 4 != 0
+                    || length > block_size - position
+                    || DIR_ENTRY_HEADER + name_length > length
+                {
+                    return Err(FsError::Io);
+                }
+
+                let name = &entry[DIR_ENTRY_HEADER..DIR_ENTRY_HEADER + name_length];
+                if inode != 0 && name != b"." && name != b".." {
+                    if let Some(result) = found(inode, file_type, name) {
+                        return Ok(Some(result));
+                    }
+                }
+                position += length;
+            }
+            offset += block_size as u64;
+        }
+        Ok(None)
+    }
+}
+
+/// An inode as it's stored
+#[derive(Clone)]
+struct RawInode {
+    number: u32,
+    mode: u16,
+    size: u64,
+    accessed: u32,
+    changed: u32,
+    modified: u32,
+    links: u16,
+    /// 512 byte sectors in use, including the extended attribute block
+    sectors: u32,
+    attribute_block: u32,
+    blocks: [u32; BLOCK_POINTERS],
+}
+
+impl RawInode {
+    fn file_type(&self) -> FileType {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            _ => FileType::Regular,
+        }
+    }
+
+    /// Size of regular files and directories, special files are empty
+    fn data_size(&self) -> u64 {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY | MODE_REGULAR | MODE_SYMLINK => self.size,
+            _ => 0,
+        }
+    }
+
+    /// Returns `true` for a symbolic link with the target in the block
+    /// pointers
+    fn is_fast_link(&self, block_size: u32) -> bool {
+        let attribute_sectors = if self.attribute_block == 0 {
+            0
+        } else {
+            block_size / 512
+        };
+        self.file_type() == FileType::Symlink
+            && self.size <= FAST_LINK_SIZE
+            && self.sectors == attribute_sectors
+    }
+}
+
+/// Converts the file type byte of a directory entry
+fn entry_type(file_type: u8) -> FileType {
+    match file_type {
+        2 => FileType::Directory,
+        7 => FileType::Symlink,
+        _ => FileType::Regular,
+    }
+}
+
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+    root: Arc<Ext2Inode>,
+}
+
+impl Ext2Fs {
+    /// Opens the ext2 filesystem on `device`, it's always read-only
+    pub fn new(device: Arc<dyn BlockDevice>) -> FsResult<Arc<Ext2Fs>> {
+        let volume = Arc::new(Volume::open(device)?);
+        let root = Ext2Inode::load(&volume, ROOT_INODE)?;
+        if root.inode.file_type() != FileType::Directory {
+            return Err(FsError::Io);
+        }
+        Ok(Arc::new(Ext2Fs { volume, root }))
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.volume.block_size
+    }
+
+    /// The volume name, it's empty if there's none
+    pub fn label(&self) -> &str {
+        &self.volume.label
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Nothing changes, the inode is read once when it's looked up
+struct Ext2Inode {
+    volume: Arc<Volume>,
+    inode: RawInode,
+}
+
+impl Ext2Inode {
+    fn load(volume: &Arc<Volume>, number: u32) -> FsResult<Arc<Ext2Inode>> {
+        Ok(Arc::new(Ext2Inode {
+            volume: volume.clone(),
+            inode: volume.read_inode(number)?,
+        }))
+    }
+
+    /// The target of a symbolic link stored in the inode
+    fn fast_link(&self) -> Vec<u8> {
+        let mut target = Vec::new();
+        for block in self.inode.blocks.iter() {
+            target.extend_from_slice(&[
+                *block as u8,
+                (*block >> 8) as u8,
+                (*block >> 16) as u8,
+                (*block >> 24) as u8,
+            ]);
+        }
+        target.truncate(self.inode.size as usize);
+        target
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> FsResult<Metadata> {
+        Ok(Metadata {
+            inode: u64::from(self.inode.number),
+            size: self.inode.data_size(),
+            file_type: self.inode.file_type(),
+            links: u32::from(self.inode.links),
+            accessed: timestamp(self.inode.accessed),
+            modified: timestamp(self.inode.modified),
+            changed: timestamp(self.inode.changed),
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        match self.inode.file_type() {
+            FileType::Directory => Err(FsError::IsADirectory),
+            _ if self.inode.data_size() == 0 => Ok(0),
+            _ if self.inode.is_fast_link(self.volume.block_size) => {
+                let target = self.fast_link();
+                if offset >= target.len() as u64 {
+                    return Ok(0);
+                }
+                let length = (target.len() - offset as usize).min(buffer.len());
+                buffer[..length]
+                    .copy_from_slice(&target[offset as usize..offset as usize + length]);
+                Ok(length)
+            }
+            _ => self.volume.read_data(&self.inode, offset, buffer),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        if self.inode.file_type() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let number = self
+            .volume
+            .find_entry(&self.inode, |inode, _, entry_name| {
+                if entry_name == name.as_bytes() {
+                    Some(inode)
+                } else {
+                    None
+                }
+            })?
+            .ok_or(FsError::NotFound)?;
+        Ok(Ext2Inode::load(&self.volume, number)?)
+    }
+
+    fn read_dir(&self, index: usize) -> FsResult<Option<DirEntry>> {
+        if self.inode.file_type() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+
+        let mut skip = index;
+        let entry = self
+            .volume
+            .find_entry(&self.inode, |inode, file_type, name| {
+                if skip > 0 {
+                    skip -= 1;
+                    return None;
+                }
+                Some((inode, file_type, String::from_utf8_lossy(name).into_owned()))
+            })?;
+
+        match entry {
+            Some((inode, file_type, name)) => {
+                let file_type = if self.volume.entry_types {
+                    entry_type(file_type)
+                } else {
+                    self.volume.read_inode(inode)?.file_type()
+                };
+                Ok(Some(DirEntry {
+                    inode: u64::from(inode),
+                    file_type,
+                    name,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn read_link(&self) -> FsResult<String> {
+        if self.inode.file_type() != FileType::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+        if self.inode.size > MAX_PATH as u64 {
+            return Err(FsError::Io);
+        }
+
+        let target = if self.inode.is_fast_link(self.volume.block_size) {
+            self.fast_link()
+        } else {
+            let mut target = vec![0; self.inode.size as usize];
+            let length = self.volume.read_data(&self.inode, 0, &mut target)?;
+            target.truncate(length);
+            target
+        };
+        String::from_utf8(target).map_err(|_| FsError::Io)
+    }
+}
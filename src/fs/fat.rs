@@ -0,0 +1,2074 @@
+//! FAT12, FAT16 and FAT32 filesystems.
+//!
+//! The FAT type follows from the number of clusters as the specification
+//! says, except that a 0 in the FAT12/16 FAT size field always means FAT32.
+//! Long names (VFAT) are read and written, a name that is its own 8.3 name
+//! only gets a short entry. Names are compared ignoring ASCII case.
+//!
+//! FAT has no inodes, a file is its short directory entry. The inode number
+//! is the position of that entry on the device, the root directory is 1.
+//! Files that are in use are kept track of in memory, one that's removed
+//! while open keeps its clusters until the last handle is dropped.
+//!
+//! There's no clock, new entries are dated 1980-01-01 and changes keep the
+//! dates that are stored. Every copy of the FAT is written. One sector of
+//! the FAT is cached, it's written back before an operation returns, the
+//! FAT32 free cluster count only by `sync` and when the filesystem is
+//! dropped. `check` looks for the problems `fsck.fat` finds on a device
+//! that isn't mounted.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::char;
+
+use super::path::MAX_NAME;
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, Metadata, Timestamp};
+use crate::block::{self, BlockDevice};
+use crate::sync::Mutex;
+use crate::time;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Bits a FAT entry takes
+    fn bits(self) -> u32 {
+        match self {
+            FatType::Fat12 => 12,
+            FatType::Fat16 => 16,
+            FatType::Fat32 => 32,
+        }
+    }
+
+    /// Entries from this one on end a chain
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+
+    /// What's written to end a chain
+    fn end_marker(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// Marks a cluster that can't be used
+    fn bad_cluster(self) -> u32 {
+        self.end_of_chain() - 1
+    }
+}
+
+/// Largest file FAT can store
+pub const MAX_FILE_SIZE: u64 = 0xFFFF_FFFF;
+
+const ROOT_INODE: u64 = 1;
+
+/// FAT12 and FAT16 volumes have fewer clusters than these
+const MAX_FAT12_CLUSTERS: u32 = 4085;
+const MAX_FAT16_CLUSTERS: u32 = 65525;
+const MAX_FAT32_CLUSTERS: u32 = 0x0FFF_FFF5;
+
+const ENTRY_SIZE: usize = 32;
+/// Largest number of entries in a directory
+const MAX_DIRECTORY_ENTRIES: usize = 65536;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+const ATTR_SYSTEM: u8 = 0x04;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// First name byte of a free entry, all entries after one starting with 0
+/// are free as well
+const FREE: u8 = 0xE5;
+/// Stands for 0xE5 as the first byte of a short name
+const KANJI_E5: u8 = 0x05;
+/// Set in the order of the last long name entry, it comes first on disk
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// UTF-16 units of a name in one long name entry, and where they are
+const LONG_NAME_UNITS: usize = 13;
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Longest long name, in UTF-16 units
+const MAX_LONG_NAME: usize = 255;
+/// Case flags Windows NT keeps in short entries
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+/// 1980-01-01, the first day a FAT date can hold
+const FIRST_DATE: u16 = (1 << 5) | 1;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+/// Free count or next free cluster that isn't known
+const FS_INFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from(data[offset]) | u16::from(data[offset + 1]) << 8
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from(read_u16(data, offset)) | u32::from(read_u16(data, offset + 2)) << 16
+}
+
+fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset] = value as u8;
+    data[offset + 1] = (value >> 8) as u8;
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    write_u16(data, offset, value as u16);
+    write_u16(data, offset + 2, (value >> 16) as u16);
+}
+
+/// Where things are on a volume, from the BIOS parameter block
+#[derive(Debug, Clone)]
+struct Layout {
+    fat_type: FatType,
+    bytes_per_sector: u32,
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    fat_count: u32,
+    /// Sectors per copy of the FAT
+    fat_size: u32,
+    /// Entries of the fixed root directory of FAT12 and FAT16
+    root_entries: u32,
+    /// First cluster of the root directory of FAT32
+    root_cluster: u32,
+    /// FAT32 sector with the free cluster count, 0 if there's none
+    fs_info_sector: u32,
+    first_data_sector: u32,
+    cluster_count: u32,
+}
+
+impl Layout {
+    fn parse(sector: &[u8]) -> FsResult<Layout> {
+        if read_u16(sector, 510) != 0xAA55 {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let bytes_per_sector = u32::from(read_u16(sector, 11));
+        let sectors_per_cluster = u32::from(sector[13]);
+        let reserved_sectors = u32::from(read_u16(sector, 14));
+        let fat_count = u32::from(sector[16]);
+        let root_entries = u32::from(read_u16(sector, 17));
+        let fat_size_16 = u32::from(read_u16(sector, 22));
+        let total_sectors = match read_u16(sector, 19) {
+            0 => read_u32(sector, 32),
+            count => u32::from(count),
+        };
+        if !bytes_per_sector.is_power_of_two()
+            || bytes_per_sector < 512
+            || bytes_per_sector > 4096
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+        {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let is_fat32 = fat_size_16 == 0;
+        let fat_size = if is_fat32 {
+            read_u32(sector, 36)
+        } else {
+            fat_size_16
+        };
+        let root_sectors =
+            (root_entries * ENTRY_SIZE as u32 + bytes_per_sector - 1) / bytes_per_sector;
+        let first_data_sector = u64::from(reserved_sectors)
+            + u64::from(fat_count) * u64::from(fat_size)
+            + u64::from(root_sectors);
+        if fat_size == 0 || first_data_sector >= u64::from(total_sectors) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let first_data_sector = first_data_sector as u32;
+        let cluster_count = (total_sectors - first_data_sector) / sectors_per_cluster;
+        let fat_type = if is_fat32 {
+            FatType::Fat32
+        } else if cluster_count < MAX_FAT12_CLUSTERS {
+            FatType::Fat12
+        } else {
+            FatType::Fat16
+        };
+        let valid = match fat_type {
+            FatType::Fat32 => root_entries == 0 && cluster_count < MAX_FAT32_CLUSTERS,
+            _ => root_entries != 0 && cluster_count < MAX_FAT16_CLUSTERS,
+        };
+        // The FAT has to have an entry for each cluster
+        let fat_entries = u64::from(fat_size) * u64::from(bytes_per_sector) * 8
+            / u64::from(fat_type.bits());
+        if !valid || cluster_count == 0 || fat_entries < u64::from(cluster_count) + 2 {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let (root_cluster, fs_info_sector) = if is_fat32 {
+            let fs_info_sector = u32::from(read_u16(sector, 48));
+            let fs_info_sector = if fs_info_sector < reserved_sectors {
+                fs_info_sector
+            } else {
+                0
+            };
+            (read_u32(sector, 44), fs_info_sector)
+        } else {
+            (0, 0)
+        };
+
+        let layout = Layout {
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fat_count,
+            fat_size,
+            root_entries,
+            root_cluster,
+            fs_info_sector,
+            first_data_sector,
+            cluster_count,
+        };
+        if is_fat32 && !layout.is_valid_cluster(root_cluster) {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(layout)
+    }
+
+    fn cluster_size(&self) -> u32 {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    fn sector_position(&self, sector: u32) -> u64 {
+        u64::from(sector) * u64::from(self.bytes_per_sector)
+    }
+
+    /// Position of the fixed root directory of FAT12 and FAT16
+    fn root_position(&self) -> u64 {
+        self.sector_position(self.reserved_sectors + self.fat_count * self.fat_size)
+    }
+
+    fn cluster_position(&self, cluster: u32) -> u64 {
+        let sector = u64::from(self.first_data_sector)
+            + u64::from(cluster - 2) * u64::from(self.sectors_per_cluster);
+        sector * u64::from(self.bytes_per_sector)
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.cluster_count
+    }
+}
+
+/// Milliseconds since 1970-01-01 of a FAT date and time
+fn timestamp(date: u16, time: u16) -> Timestamp {
+    let year = 1980 + u64::from(date >> 9);
+    let month = u64::from((date >> 5) & 0xF).max(1).min(12);
+    let day = u64::from(date & 0x1F).max(1);
+
+    // Days from the civil date, with years starting in March
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = u64::from(time >> 11) * 3600
+        + u64::from((time >> 5) & 0x3F) * 60
+        + u64::from(time & 0x1F) * 2;
+    (days * 86400 + seconds) * 1000
+}
+
+/// Checksum of a short name that its long name entries hold
+fn checksum(short_name: &[u8]) -> u8 {
+    short_name[..11].iter().fold(0u8, |sum, &byte| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte)
+    })
+}
+
+fn entry_attributes(raw: &[u8]) -> u8 {
+    raw[11]
+}
+
+fn is_directory_entry(raw: &[u8]) -> bool {
+    entry_attributes(raw) & ATTR_DIRECTORY != 0
+}
+
+fn entry_size(raw: &[u8]) -> u32 {
+    read_u32(raw, 28)
+}
+
+/// Returns the short name as it's shown, with the case flags applied
+fn short_name_string(raw: &[u8]) -> String {
+    let case = raw[12];
+    let convert = |bytes: &[u8], lower: bool| {
+        bytes
+            .iter()
+            .enumerate()
+            .map(|(index, &byte)| match byte {
+                KANJI_E5 if index == 0 => FREE,
+                byte => byte,
+            })
+            .take_while(|&byte| byte != b' ')
+            .map(|byte| {
+                if lower {
+                    char::from(byte.to_ascii_lowercase())
+                } else {
+                    char::from(byte)
+                }
+            })
+            .collect::<String>()
+    };
+
+    let mut name = convert(&raw[..8], case & LOWER_CASE_BASE != 0);
+    let extension = convert(&raw[8..11], case & LOWER_CASE_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+/// Checks that `name` can be the name of an entry
+fn check_name(name: &str) -> FsResult<()> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidArgument);
+    }
+    if name.len() > MAX_NAME || name.encode_utf16().count() > MAX_LONG_NAME {
+        return Err(FsError::NameTooLong);
+    }
+    let invalid = |c: char| c < ' ' || "\"*/:<>?\\|".contains(c);
+    // Other systems would drop trailing dots and spaces
+    if name.chars().any(invalid) || name.ends_with('.') || name.ends_with(' ') {
+        return Err(FsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// Returns the 8.3 name for `name`, whether characters were dropped or
+/// replaced and whether it's `name` exactly
+fn basis_name(name: &str) -> ([u8; 11], bool, bool) {
+    let (base, extension) = match name.rfind('.') {
+        Some(index) if index > 0 => (&name[..index], &name[index + 1..]),
+        _ => (name, ""),
+    };
+
+    let mut short = [b' '; 11];
+    let mut lossy = false;
+    let mut lower_case = false;
+    let mut convert = |part: &str, field: &mut [u8]| {
+        let mut length = 0;
+        for c in part.chars() {
+            let byte = match c {
+                'A'..='Z' | '0'..='9' => c as u8,
+                'a'..='z' => {
+                    lower_case = true;
+                    c.to_ascii_uppercase() as u8
+                }
+                '!' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' | '-' | '@' | '^' | '_' | '`'
+                | '{' | '}' | '~' => c as u8,
+                ' ' | '.' => {
+                    lossy = true;
+                    continue;
+                }
+                _ => {
+                    lossy = true;
+                    b'_'
+                }
+            };
+            if length == field.len() {
+                lossy = true;
+                break;
+            }
+            field[length] = byte;
+            length += 1;
+        }
+    };
+
+    let (base_field, extension_field) = short.split_at_mut(8);
+    convert(base, base_field);
+    convert(extension, extension_field);
+    if short[0] == b' ' {
+        short[0] = b'_';
+        lossy = true;
+    }
+    (short, lossy, !lossy && !lower_case)
+}
+
+/// Puts a `~n` tail on a basis name
+fn numbered_name(basis: &[u8; 11], number: u32) -> [u8; 11] {
+    let mut tail = [0; 7];
+    let mut digits = 0;
+    let mut rest = number;
+    while rest > 0 {
+        tail[tail.len() - 1 - digits] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        digits += 1;
+    }
+    let tail = &tail[tail.len() - digits..];
+
+    let base_length = basis[..8].iter().position(|&byte| byte == b' ').unwrap_or(8);
+    let kept = base_length.min(8 - 1 - digits);
+    let mut name = *basis;
+    name[kept] = b'~';
+    name[kept + 1..kept + 1 + digits].copy_from_slice(tail);
+    for byte in name[kept + 1 + digits..8].iter_mut() {
+        *byte = b' ';
+    }
+    name
+}
+
+/// The long name entries of `name` in the order they're stored
+fn long_name_slots(name: &str, short_name: &[u8; 11]) -> Vec<[u8; ENTRY_SIZE]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = (units.len() + LONG_NAME_UNITS - 1) / LONG_NAME_UNITS;
+    let sum = checksum(short_name);
+
+    (1..=count)
+        .rev()
+        .map(|order| {
+            let mut slot = [0; ENTRY_SIZE];
+            slot[0] = order as u8 | if order == count { LAST_LONG_ENTRY } else { 0 };
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = sum;
+            for (index, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                let position = (order - 1) * LONG_NAME_UNITS + index;
+                // The name is NUL terminated if there's room and padded
+                let unit = if position < units.len() {
+                    units[position]
+                } else if position == units.len() {
+                    0
+                } else {
+                    0xFFFF
+                };
+                write_u16(&mut slot, offset, unit);
+            }
+            slot
+        })
+        .collect()
+}
+
+fn short_slot(name: &[u8; 11], attributes: u8, first_cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut slot = [0; ENTRY_SIZE];
+    slot[..11].copy_from_slice(name);
+    slot[11] = attributes;
+    write_u16(&mut slot, 16, FIRST_DATE);
+    write_u16(&mut slot, 18, FIRST_DATE);
+    write_u16(&mut slot, 24, FIRST_DATE);
+    set_entry_cluster(&mut slot, first_cluster);
+    slot
+}
+
+fn set_entry_cluster(raw: &mut [u8], cluster: u32) {
+    write_u16(raw, 20, (cluster >> 16) as u16);
+    write_u16(raw, 26, cluster as u16);
+}
+
+/// The `.` and `..` entries at the start of a directory
+fn dot_slots(cluster: u32, parent: u32) -> [[u8; ENTRY_SIZE]; 2] {
+    [
+        short_slot(b".          ", ATTR_DIRECTORY, cluster),
+        short_slot(b"..         ", ATTR_DIRECTORY, parent),
+    ]
+}
+
+/// A file or directory in a directory
+struct Entry {
+    name: String,
+    /// The short entry
+    raw: [u8; ENTRY_SIZE],
+    /// Index of the first long name entry, or of the short one
+    first_slot: usize,
+    slot: usize,
+}
+
+/// Long name entries seen before a short entry
+struct LongName {
+    checksum: u8,
+    first_slot: usize,
+    /// The order the next entry has to have
+    next: u8,
+    /// Entries seen so far
+    entries: usize,
+    units: Vec<u16>,
+}
+
+/// The contents of a directory
+struct Directory {
+    /// First cluster, 0 for the fixed root directory of FAT12 and FAT16
+    cluster: u32,
+    /// The chain, empty for the fixed root directory
+    clusters: Vec<u32>,
+    data: Vec<u8>,
+}
+
+impl Directory {
+    fn slot_count(&self) -> usize {
+        self.data.len() / ENTRY_SIZE
+    }
+
+    fn slot(&self, index: usize) -> &[u8] {
+        &self.data[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE]
+    }
+
+    /// The files and directories, without `.` and `..`, and the number of
+    /// long name entries that don't belong to any
+    fn entries(&self) -> (Vec<Entry>, usize) {
+        let mut entries = Vec::new();
+        let mut orphans = 0;
+        let mut long_name: Option<LongName> = None;
+
+        for index in 0..self.slot_count() {
+            let slot = self.slot(index);
+            match slot[0] {
+                0 => break,
+                FREE => {
+                    orphans += long_name.take().map_or(0, |name| name.entries);
+                    continue;
+                }
+                _ => {}
+            }
+
+            if entry_attributes(slot) & 0x3F == ATTR_LONG_NAME {
+                let order = slot[0] & !LAST_LONG_ENTRY;
+                let continues = match &long_name {
+                    Some(name) => slot[0] & LAST_LONG_ENTRY == 0
+                        && order == name.next
+                        && slot[13] == name.checksum,
+                    None => false,
+                };
+                if !continues {
+                    orphans += long_name.take().map_or(0, |name| name.entries);
+                    if slot[0] & LAST_LONG_ENTRY == 0 || order == 0 || order > 20 {
+                        orphans += 1;
+                        continue;
+                    }
+                    long_name = Some(LongName {
+                        checksum: slot[13],
+                        first_slot: index,
+                        next: order,
+                        entries: 0,
+                        units: vec![0xFFFF; usize::from(order) * LONG_NAME_UNITS],
+                    });
+                }
+
+                let name = long_name.as_mut().expect("Long name disappeared");
+                let start = usize::from(order - 1) * LONG_NAME_UNITS;
+                for (unit, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                    name.units[start + unit] = read_u16(slot, offset);
+                }
+                name.next = order - 1;
+                name.entries += 1;
+                continue;
+            }
+
+            let long_name = long_name.take();
+            // Volume labels and the dot entries
+            if entry_attributes(slot) & ATTR_VOLUME_ID != 0 || slot[0] == b'.' {
+                orphans += long_name.map_or(0, |name| name.entries);
+                continue;
+            }
+
+            let mut raw = [0; ENTRY_SIZE];
+            raw.copy_from_slice(slot);
+            let (name, first_slot) = match long_name {
+                Some(ref long_name)
+                    if long_name.next == 0 && long_name.checksum == checksum(&raw) =>
+                {
+                    let units = long_name
+                        .units
+                        .iter()
+                        .cloned()
+                        .take_while(|&unit| unit != 0 && unit != 0xFFFF);
+                    let name: String = char::decode_utf16(units)
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                        .collect();
+                    (name, long_name.first_slot)
+                }
+                _ => {
+                    orphans += long_name.as_ref().map_or(0, |name| name.entries);
+                    (short_name_string(&raw), index)
+                }
+            };
+            entries.push(Entry {
+                name,
+                raw,
+                first_slot,
+                slot: index,
+            });
+        }
+        (entries, orphans)
+    }
+
+    /// Looks up `name` by its long or its short name
+    fn find(&self, name: &str) -> Option<Entry> {
+        self.entries().0.into_iter().find(|entry| {
+            entry.name.eq_ignore_ascii_case(name)
+                || short_name_string(&entry.raw).eq_ignore_ascii_case(name)
+        })
+    }
+
+    /// Returns `true` if a short entry has the 8.3 name `name`
+    fn has_short_name(&self, name: &[u8; 11]) -> bool {
+        (0..self.slot_count())
+            .map(|index| self.slot(index))
+            .take_while(|slot| slot[0] != 0)
+            .any(|slot| {
+                slot[0] != FREE
+                    && entry_attributes(slot) & 0x3F != ATTR_LONG_NAME
+                    && &slot[..11] == name
+            })
+    }
+
+    /// Returns `true` if there's nothing but `.` and `..`
+    fn is_empty(&self) -> bool {
+        self.entries().0.is_empty()
+    }
+}
+
+/// A file or directory that's in use
+struct Node {
+    inode: u64,
+    /// Position of the short entry, `None` for the root
+    entry: Option<u64>,
+    /// Unlinked, the clusters are freed once the node isn't used anymore
+    removed: bool,
+    directory: bool,
+    first_cluster: u32,
+    size: u32,
+    accessed: Timestamp,
+    modified: Timestamp,
+    /// `FatInode`s referring to the node
+    handles: usize,
+}
+
+/// One sector of the first FAT
+struct CachedSector {
+    /// Counted from the start of the FAT
+    sector: u32,
+    data: Vec<u8>,
+    dirty: bool,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    layout: Layout,
+    read_only: bool,
+    fat_cache: Option<CachedSector>,
+    free_clusters: u32,
+    /// Where the search for a free cluster starts
+    next_free: u32,
+    nodes: BTreeMap<u64, Node>,
+    next_id: u64,
+}
+
+impl Volume {
+    fn open(device: Arc<dyn BlockDevice>, read_only: bool) -> FsResult<Volume> {
+        let mut boot_sector = [0; 512];
+        block::read_at(&*device, 0, &mut boot_sector)?;
+        let layout = Layout::parse(&boot_sector)?;
+        let end = layout.sector_position(layout.first_data_sector)
+            + u64::from(layout.cluster_count) * u64::from(layout.cluster_size());
+        if end > device.block_count() * device.block_size() as u64 {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let mut volume = Volume {
+            device,
+            layout,
+            read_only,
+            fat_cache: None,
+            free_clusters: 0,
+            next_free: 2,
+            nodes: BTreeMap::new(),
+            next_id: 1,
+        };
+        volume.free_clusters = volume.count_free_clusters()?;
+        if let Some((_, next_free)) = volume.read_fs_info()? {
+            if volume.layout.is_valid_cluster(next_free) {
+                volume.next_free = next_free;
+            }
+        }
+        Ok(volume)
+    }
+
+    fn fat_type(&self) -> FatType {
+        self.layout.fat_type
+    }
+
+    fn check_writable(&self) -> FsResult<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn read_bytes(&self, position: u64, buffer: &mut [u8]) -> FsResult<()> {
+        block::read_at(&*self.device, position, buffer).map_err(FsError::from)
+    }
+
+    fn write_bytes(&self, position: u64, buffer: &[u8]) -> FsResult<()> {
+        block::write_at(&*self.device, position, buffer).map_err(FsError::from)
+    }
+
+    /// Returns sector `sector` of the first FAT from the cache
+    fn fat_sector(&mut self, sector: u32) -> FsResult<&mut CachedSector> {
+        if self.fat_cache.as_ref().map(|cached| cached.sector) != Some(sector) {
+            self.flush_fat()?;
+            let mut data = vec![0; self.layout.bytes_per_sector as usize];
+            let position = self.layout.sector_position(self.layout.reserved_sectors + sector);
+            self.read_bytes(position, &mut data)?;
+            self.fat_cache = Some(CachedSector {
+                sector,
+                data,
+                dirty: false,
+            });
+        }
+        Ok(self.fat_cache.as_mut().expect("FAT sector disappeared"))
+    }
+
+    /// Writes the cached FAT sector to every copy of the FAT
+    fn flush_fat(&mut self) -> FsResult<()> {
+        let mut cached = match self.fat_cache.take() {
+            Some(cached) => cached,
+            None => return Ok(()),
+        };
+
+        let mut result = Ok(());
+        if cached.dirty {
+            for copy in 0..self.layout.fat_count {
+                let sector =
+                    self.layout.reserved_sectors + copy * self.layout.fat_size + cached.sector;
+                let position = self.layout.sector_position(sector);
+                result = result.and(self.write_bytes(position, &cached.data));
+            }
+            cached.dirty = result.is_err();
+        }
+        self.fat_cache = Some(cached);
+        result
+    }
+
+    fn read_fat_bytes(&mut self, offset: u32, bytes: &mut [u8]) -> FsResult<()> {
+        let sector_size = self.layout.bytes_per_sector;
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            let offset = offset + index as u32;
+            *byte = self.fat_sector(offset / sector_size)?.data[(offset % sector_size) as usize];
+        }
+        Ok(())
+    }
+
+    fn write_fat_bytes(&mut self, offset: u32, bytes: &[u8]) -> FsResult<()> {
+        let sector_size = self.layout.bytes_per_sector;
+        for (index, &byte) in bytes.iter().enumerate() {
+            let offset = offset + index as u32;
+            let cached = self.fat_sector(offset / sector_size)?;
+            cached.data[(offset % sector_size) as usize] = byte;
+            cached.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn fat_entry(&mut self, cluster: u32) -> FsResult<u32> {
+        let mut bytes = [0; 4];
+        Ok(match self.fat_type() {
+            FatType::Fat12 => {
+                self.read_fat_bytes(cluster + cluster / 2, &mut bytes[..2])?;
+                let value = u32::from(read_u16(&bytes, 0));
+                if cluster & 1 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xFFF
+                }
+            }
+            FatType::Fat16 => {
+                self.read_fat_bytes(cluster * 2, &mut bytes[..2])?;
+                u32::from(read_u16(&bytes, 0))
+            }
+            FatType::Fat32 => {
+                self.read_fat_bytes(cluster * 4, &mut bytes)?;
+                read_u32(&bytes, 0) & 0x0FFF_FFFF
+            }
+        })
+    }
+
+    fn set_fat_entry(&mut self, cluster: u32, value: u32) -> FsResult<()> {
+        let mut bytes = [0; 4];
+        match self.fat_type() {
+            FatType::Fat12 => {
+                let offset = cluster + cluster / 2;
+                self.read_fat_bytes(offset, &mut bytes[..2])?;
+                let old = read_u16(&bytes, 0);
+                let value = value as u16 & 0xFFF;
+                let new = if cluster & 1 == 1 {
+                    (old & 0x000F) | value << 4
+                } else {
+                    (old & 0xF000) | value
+                };
+                write_u16(&mut bytes, 0, new);
+                self.write_fat_bytes(offset, &bytes[..2])
+            }
+            FatType::Fat16 => {
+                write_u16(&mut bytes, 0, value as u16);
+                self.write_fat_bytes(cluster * 2, &bytes[..2])
+            }
+            FatType::Fat32 => {
+                // The top 4 bits are reserved and kept
+                self.read_fat_bytes(cluster * 4, &mut bytes)?;
+                let old = read_u32(&bytes, 0);
+                write_u32(&mut bytes, 0, (old & 0xF000_0000) | (value & 0x0FFF_FFFF));
+                self.write_fat_bytes(cluster * 4, &bytes)
+            }
+        }
+    }
+
+    fn count_free_clusters(&mut self) -> FsResult<u32> {
+        let mut free = 0;
+        for cluster in 2..self.layout.cluster_count + 2 {
+            if self.fat_entry(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    /// Returns the free cluster count and the next free cluster of FAT32
+    fn read_fs_info(&self) -> FsResult<Option<(u32, u32)>> {
+        if self.layout.fs_info_sector == 0 {
+            return Ok(None);
+        }
+
+        let mut sector = [0; 512];
+        self.read_bytes(self.layout.sector_position(self.layout.fs_info_sector), &mut sector)?;
+        if read_u32(&sector, 0) != FS_INFO_LEAD_SIGNATURE
+            || read_u32(&sector, 484) != FS_INFO_SIGNATURE
+            || read_u32(&sector, 508) != FS_INFO_TRAIL_SIGNATURE
+        {
+            return Ok(None);
+        }
+        Ok(Some((read_u32(&sector, 488), read_u32(&sector, 492))))
+    }
+
+    /// Writes the cached FAT sector and the FAT32 free cluster count
+    fn sync(&mut self) -> FsResult<()> {
+        self.flush_fat()?;
+        if self.read_only {
+            return Ok(());
+        }
+
+        if self.read_fs_info()?.is_some() {
+            let mut sector = [0; 512];
+            let position = self.layout.sector_position(self.layout.fs_info_sector);
+            self.read_bytes(position, &mut sector)?;
+            write_u32(&mut sector, 488, self.free_clusters);
+            write_u32(&mut sector, 492, self.next_free);
+            self.write_bytes(position, &sector)?;
+        }
+        self.device.flush()?;
+        Ok(())
+    }
+
+    /// Returns the cluster after `cluster` in its chain, `None` at the end
+    fn next_cluster(&mut self, cluster: u32) -> FsResult<Option<u32>> {
+        let next = self.fat_entry(cluster)?;
+        if next >= self.fat_type().end_of_chain() {
+            return Ok(None);
+        }
+        // Free or bad clusters in a chain
+        if !self.layout.is_valid_cluster(next) {
+            return Err(FsError::Io);
+        }
+        Ok(Some(next))
+    }
+
+    /// Returns cluster number `index` of the chain starting at `first`
+    fn cluster_at(&mut self, first: u32, index: u32) -> FsResult<u32> {
+        let mut cluster = first;
+        for _ in 0..index {
+            // The chain is shorter than the size says
+            cluster = self.next_cluster(cluster)?.ok_or(FsError::Io)?;
+        }
+        Ok(cluster)
+    }
+
+    /// Takes a free cluster, zeroes it and appends it to the chain ending
+    /// at `previous`
+    fn allocate_cluster(&mut self, previous: Option<u32>) -> FsResult<u32> {
+        if self.free_clusters == 0 {
+            return Err(FsError::NoSpace);
+        }
+
+        let mut cluster = self.next_free;
+        for _ in 0..self.layout.cluster_count {
+            if !self.layout.is_valid_cluster(cluster) {
+                cluster = 2;
+            }
+            if self.fat_entry(cluster)? == 0 {
+                let zeros = vec![0; self.layout.cluster_size() as usize];
+                self.write_bytes(self.layout.cluster_position(cluster), &zeros)?;
+                let end = self.fat_type().end_marker();
+                self.set_fat_entry(cluster, end)?;
+                if let Some(previous) = previous {
+                    self.set_fat_entry(previous, cluster)?;
+                }
+                self.free_clusters -= 1;
+                self.next_free = cluster + 1;
+                return Ok(cluster);
+            }
+            cluster += 1;
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Frees the chain starting at `first`
+    fn free_chain(&mut self, first: u32) -> FsResult<()> {
+        let mut cluster = first;
+        // Stops at the end or where the chain is broken
+        for _ in 0..self.layout.cluster_count {
+            if !self.layout.is_valid_cluster(cluster) {
+                break;
+            }
+            let next = self.fat_entry(cluster)?;
+            if next == 0 {
+                break;
+            }
+            self.set_fat_entry(cluster, 0)?;
+            self.free_clusters += 1;
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    /// The value `..` entries store for the directory starting at `cluster`
+    fn parent_value(&self, cluster: u32) -> u32 {
+        if cluster == self.layout.root_cluster {
+            0
+        } else {
+            cluster
+        }
+    }
+
+    fn read_directory(&mut self, cluster: u32) -> FsResult<Directory> {
+        if cluster == 0 && self.fat_type() != FatType::Fat32 {
+            let mut data = vec![0; self.layout.root_entries as usize * ENTRY_SIZE];
+            self.read_bytes(self.layout.root_position(), &mut data)?;
+            return Ok(Directory {
+                cluster,
+                clusters: Vec::new(),
+                data,
+            });
+        }
+
+        // `..` of a directory in the root
+        let cluster = if cluster == 0 {
+            self.layout.root_cluster
+        } else {
+            cluster
+        };
+        let cluster_size = self.layout.cluster_size() as usize;
+        let mut directory = Directory {
+            cluster,
+            clusters: Vec::new(),
+            data: Vec::new(),
+        };
+        let mut next = Some(cluster);
+        while let Some(cluster) = next {
+            if !self.layout.is_valid_cluster(cluster)
+                || directory.data.len() >= MAX_DIRECTORY_ENTRIES * ENTRY_SIZE
+            {
+                return Err(FsError::Io);
+            }
+            let start = directory.data.len();
+            directory.data.resize(start + cluster_size, 0);
+            self.read_bytes(
+                self.layout.cluster_position(cluster),
+                &mut directory.data[start..],
+            )?;
+            directory.clusters.push(cluster);
+            next = self.next_cluster(cluster)?;
+        }
+        Ok(directory)
+    }
+
+    fn slot_position(&self, directory: &Directory, slot: usize) -> u64 {
+        let offset = slot * ENTRY_SIZE;
+        if directory.clusters.is_empty() {
+            return self.layout.root_position() + offset as u64;
+        }
+
+        let cluster_size = self.layout.cluster_size() as usize;
+        let cluster = directory.clusters[offset / cluster_size];
+        self.layout.cluster_position(cluster) + (offset % cluster_size) as u64
+    }
+
+    fn write_slot(&self, directory: &mut Directory, slot: usize, data: &[u8]) -> FsResult<()> {
+        self.write_bytes(self.slot_position(directory, slot), data)?;
+        directory.data[slot * ENTRY_SIZE..slot * ENTRY_SIZE + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Stores `slots` in consecutive free entries of the directory, it
+    /// grows if there aren't enough. Returns the slot of the last one.
+    fn insert_slots(
+        &mut self,
+        directory: &mut Directory,
+        slots: &[[u8; ENTRY_SIZE]],
+    ) -> FsResult<usize> {
+        let mut run = 0;
+        let mut start = None;
+        for index in 0..directory.slot_count() {
+            let first = directory.slot(index)[0];
+            if first == 0 || first == FREE {
+                run += 1;
+                if run == slots.len() {
+                    start = Some(index + 1 - run);
+                    break;
+                }
+            } else {
+                run = 0;
+            }
+        }
+
+        let start = match start {
+            Some(start) => start,
+            None => {
+                // The free entries at the end continue in new clusters
+                let start = directory.slot_count() - run;
+                let cluster_size = self.layout.cluster_size() as usize;
+                while directory.slot_count() < start + slots.len() {
+                    if directory.clusters.is_empty()
+                        || directory.slot_count() >= MAX_DIRECTORY_ENTRIES
+                    {
+                        return Err(FsError::NoSpace);
+                    }
+                    let last = *directory.clusters.last().expect("Directory without clusters");
+                    let cluster = self.allocate_cluster(Some(last))?;
+                    directory.clusters.push(cluster);
+                    let length = directory.data.len();
+                    directory.data.resize(length + cluster_size, 0);
+                }
+                start
+            }
+        };
+
+        for (index, slot) in slots.iter().enumerate() {
+            self.write_slot(directory, start + index, slot)?;
+        }
+        Ok(start + slots.len() - 1)
+    }
+
+    /// Marks the entries of `entry` free
+    fn remove_slots(&self, directory: &mut Directory, entry: &Entry) -> FsResult<()> {
+        for slot in entry.first_slot..=entry.slot {
+            self.write_slot(directory, slot, &[FREE])?;
+        }
+        Ok(())
+    }
+
+    /// Picks the short name for `name` in `directory`, it gets a numeric
+    /// tail if it's lossy or taken. Returns whether long name entries are
+    /// needed too.
+    fn short_name(&self, directory: &Directory, name: &str) -> FsResult<([u8; 11], bool)> {
+        let (basis, lossy, exact) = basis_name(name);
+        if !lossy && !directory.has_short_name(&basis) {
+            return Ok((basis, !exact));
+        }
+
+        (1..1_000_000)
+            .map(|number| numbered_name(&basis, number))
+            .find(|short| !directory.has_short_name(short))
+            .map(|short| (short, true))
+            .ok_or(FsError::AlreadyExists)
+    }
+
+    /// The entries storing `name`, a long name and then the short entry
+    /// based on `raw` with the name put in
+    fn name_slots(
+        &self,
+        directory: &Directory,
+        name: &str,
+        raw: &[u8],
+    ) -> FsResult<Vec<[u8; ENTRY_SIZE]>> {
+        let (short, needs_long_name) = self.short_name(directory, name)?;
+        let mut slots = if needs_long_name {
+            long_name_slots(name, &short)
+        } else {
+            Vec::new()
+        };
+
+        let mut slot = [0; ENTRY_SIZE];
+        slot.copy_from_slice(raw);
+        slot[..11].copy_from_slice(&short);
+        slot[12] = 0;
+        slots.push(slot);
+        Ok(slots)
+    }
+
+    fn entry_cluster(&self, raw: &[u8]) -> u32 {
+        let low = u32::from(read_u16(raw, 26));
+        match self.fat_type() {
+            FatType::Fat32 => low | u32::from(read_u16(raw, 20)) << 16,
+            _ => low,
+        }
+    }
+
+    fn node(&self, id: u64) -> &Node {
+        self.nodes.get(&id).expect("FAT node disappeared")
+    }
+
+    fn node_mut(&mut self, id: u64) -> &mut Node {
+        self.nodes.get_mut(&id).expect("FAT node disappeared")
+    }
+
+    fn insert_node(&mut self, node: Node) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.nodes.insert(id, node);
+        id
+    }
+
+    fn root_node(&mut self) -> u64 {
+        let existing = self
+            .nodes
+            .iter()
+            .find(|(_, node)| node.inode == ROOT_INODE)
+            .map(|(&id, _)| id);
+        existing.unwrap_or_else(|| {
+            let epoch = timestamp(FIRST_DATE, 0);
+            let first_cluster = self.layout.root_cluster;
+            self.insert_node(Node {
+                inode: ROOT_INODE,
+                entry: None,
+                removed: false,
+                directory: true,
+                first_cluster,
+                size: 0,
+                accessed: epoch,
+                modified: epoch,
+                handles: 0,
+            })
+        })
+    }
+
+    /// Returns the node of the entry at `position`
+    fn entry_node(&mut self, position: u64, raw: &[u8]) -> u64 {
+        let existing = self
+            .nodes
+            .iter()
+            .find(|(_, node)| node.entry == Some(position))
+            .map(|(&id, _)| id);
+        existing.unwrap_or_else(|| {
+            let directory = is_directory_entry(raw);
+            let first_cluster = self.entry_cluster(raw);
+            self.insert_node(Node {
+                inode: position,
+                entry: Some(position),
+                removed: false,
+                directory,
+                first_cluster,
+                size: if directory { 0 } else { entry_size(raw) },
+                accessed: timestamp(read_u16(raw, 18), 0),
+                modified: timestamp(read_u16(raw, 24), read_u16(raw, 22)),
+                handles: 0,
+            })
+        })
+    }
+
+    /// The first cluster of a directory node that wasn't removed
+    fn directory_cluster(&self, id: u64) -> FsResult<u32> {
+        let node = self.node(id);
+        if !node.directory {
+            return Err(FsError::NotADirectory);
+        }
+        if node.removed {
+            return Err(FsError::NotFound);
+        }
+        Ok(node.first_cluster)
+    }
+
+    fn regular(&self, id: u64) -> FsResult<&Node> {
+        let node = self.node(id);
+        if node.directory {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(node)
+    }
+
+    /// Writes the size and first cluster of a node to its entry
+    fn update_entry(&mut self, id: u64) -> FsResult<()> {
+        let node = self.node(id);
+        let (position, first_cluster, size) = match node.entry {
+            Some(position) => (position, node.first_cluster, node.size),
+            None => return Ok(()),
+        };
+
+        let mut raw = [0; ENTRY_SIZE];
+        self.read_bytes(position, &mut raw)?;
+        set_entry_cluster(&mut raw, first_cluster);
+        write_u32(&mut raw, 28, size);
+        raw[11] |= ATTR_ARCHIVE;
+        self.write_bytes(position, &raw)
+    }
+
+    /// Forgets a node that isn't used anymore, a removed one's clusters are
+    /// freed
+    fn release(&mut self, id: u64) -> FsResult<()> {
+        let node = self.node(id);
+        if node.handles > 0 {
+            return Ok(());
+        }
+
+        let node = self.nodes.remove(&id).expect("FAT node disappeared");
+        if node.removed && node.first_cluster != 0 {
+            self.free_chain(node.first_cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Removes `entry` from `directory`, its clusters are freed unless it's
+    /// in use
+    fn remove_entry(&mut self, directory: &mut Directory, entry: &Entry) -> FsResult<()> {
+        self.remove_slots(directory, entry)?;
+
+        let position = self.slot_position(directory, entry.slot);
+        let open = self
+            .nodes
+            .values_mut()
+            .find(|node| node.entry == Some(position));
+        match open {
+            Some(node) => {
+                node.entry = None;
+                node.removed = true;
+            }
+            None => {
+                let first_cluster = self.entry_cluster(&entry.raw);
+                if first_cluster != 0 {
+                    self.free_chain(first_cluster)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the directory starting at `cluster` is `ancestor`
+    /// or below it, following the `..` entries
+    fn is_below(&mut self, mut cluster: u32, ancestor: u32) -> FsResult<bool> {
+        for _ in 0..self.layout.cluster_count {
+            if cluster == ancestor {
+                return Ok(true);
+            }
+            if cluster == 0 || cluster == self.layout.root_cluster {
+                return Ok(false);
+            }
+
+            let mut dot_dot = [0; ENTRY_SIZE];
+            let position = self.layout.cluster_position(cluster) + ENTRY_SIZE as u64;
+            self.read_bytes(position, &mut dot_dot)?;
+            cluster = self.entry_cluster(&dot_dot);
+        }
+        Err(FsError::Io)
+    }
+
+    fn read(&mut self, id: u64, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let node = self.regular(id)?;
+        let size = u64::from(node.size);
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let first_cluster = node.first_cluster;
+        let cluster_size = u64::from(self.layout.cluster_size());
+        let count = buffer.len().min((size - offset) as usize);
+        let mut cluster = self.cluster_at(first_cluster, (offset / cluster_size) as u32)?;
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            let in_cluster = position % cluster_size;
+            if done > 0 && in_cluster == 0 {
+                cluster = self.next_cluster(cluster)?.ok_or(FsError::Io)?;
+            }
+            let chunk = (count - done).min((cluster_size - in_cluster) as usize);
+            let start = self.layout.cluster_position(cluster) + in_cluster;
+            self.read_bytes(start, &mut buffer[done..done + chunk])?;
+            done += chunk;
+        }
+        Ok(count)
+    }
+
+    /// Writes `data` at `offset`, which is at most the size, allocating
+    /// clusters as needed. Returns how much was written before an error.
+    fn write_data(&mut self, id: u64, offset: u64, data: &[u8]) -> (usize, FsResult<()>) {
+        let cluster_size = u64::from(self.layout.cluster_size());
+        let mut done = 0;
+        let result = (|| -> FsResult<()> {
+            if self.node(id).first_cluster == 0 {
+                let first = self.allocate_cluster(None)?;
+                self.node_mut(id).first_cluster = first;
+            }
+
+            // The chain is only known to reach the cluster before the one
+            // `offset` is in
+            let first = self.node(id).first_cluster;
+            let index = (offset / cluster_size) as u32;
+            let mut cluster = self.cluster_at(first, index.saturating_sub(1))?;
+            while done < data.len() {
+                let position = offset + done as u64;
+                let in_cluster = position % cluster_size;
+                if (done == 0 && index > 0) || (done > 0 && in_cluster == 0) {
+                    cluster = match self.next_cluster(cluster)? {
+                        Some(next) => next,
+                        None => self.allocate_cluster(Some(cluster))?,
+                    };
+                }
+                let chunk = (data.len() - done).min((cluster_size - in_cluster) as usize);
+                let start = self.layout.cluster_position(cluster) + in_cluster;
+                self.write_bytes(start, &data[done..done + chunk])?;
+                done += chunk;
+            }
+            Ok(())
+        })();
+
+        let node = self.node_mut(id);
+        node.size = node.size.max((offset + done as u64) as u32);
+        let updated = self.update_entry(id);
+        (done, result.and(updated))
+    }
+
+    /// Writes zeros from the end of the file up to `end`
+    fn grow(&mut self, id: u64, end: u64) -> FsResult<()> {
+        let zeros = vec![0; self.layout.cluster_size() as usize];
+        loop {
+            let size = u64::from(self.node(id).size);
+            if size >= end {
+                return Ok(());
+            }
+            let chunk = (end - size).min(zeros.len() as u64) as usize;
+            let (_, result) = self.write_data(id, size, &zeros[..chunk]);
+            result?;
+        }
+    }
+
+    fn write(&mut self, id: u64, offset: u64, buffer: &[u8]) -> FsResult<usize> {
+        self.check_writable()?;
+        self.regular(id)?;
+        let end = offset
+            .checked_add(buffer.len() as u64)
+            .ok_or(FsError::InvalidArgument)?;
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+
+        // FAT has no holes
+        self.grow(id, offset)?;
+        let (count, result) = self.write_data(id, offset, buffer);
+        match result {
+            Err(error) if count == 0 && !buffer.is_empty() => Err(error),
+            _ => Ok(count),
+        }
+    }
+
+    fn truncate(&mut self, id: u64, size: u64) -> FsResult<()> {
+        self.check_writable()?;
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+
+        let node = self.regular(id)?;
+        if size > u64::from(node.size) {
+            return self.grow(id, size);
+        }
+
+        let first_cluster = node.first_cluster;
+        let cluster_size = u64::from(self.layout.cluster_size());
+        let kept = ((size + cluster_size - 1) / cluster_size) as u32;
+        if kept == 0 {
+            if first_cluster != 0 {
+                self.free_chain(first_cluster)?;
+            }
+            self.node_mut(id).first_cluster = 0;
+        } else {
+            let last = self.cluster_at(first_cluster, kept - 1)?;
+            if let Some(next) = self.next_cluster(last)? {
+                let end = self.fat_type().end_marker();
+                self.set_fat_entry(last, end)?;
+                self.free_chain(next)?;
+            }
+        }
+        self.node_mut(id).size = size as u32;
+        self.update_entry(id)
+    }
+
+    fn metadata(&mut self, id: u64) -> FsResult<Metadata> {
+        let node = self.node(id);
+        let (inode, first_cluster, accessed, modified) =
+            (node.inode, node.first_cluster, node.accessed, node.modified);
+        let (size, links) = if node.directory {
+            let directory = self.read_directory(first_cluster)?;
+            let subdirectories = directory
+                .entries()
+                .0
+                .iter()
+                .filter(|entry| is_directory_entry(&entry.raw))
+                .count();
+            (directory.data.len() as u64, 2 + subdirectories as u32)
+        } else {
+            (u64::from(node.size), 1)
+        };
+
+        Ok(Metadata {
+            inode,
+            size,
+            file_type: if self.node(id).directory {
+                FileType::Directory
+            } else {
+                FileType::Regular
+            },
+            links,
+            accessed,
+            modified,
+            changed: modified,
+        })
+    }
+
+    fn lookup(&mut self, id: u64, name: &str) -> FsResult<u64> {
+        let cluster = self.directory_cluster(id)?;
+        let directory = self.read_directory(cluster)?;
+        let entry = directory.find(name).ok_or(FsError::NotFound)?;
+        let position = self.slot_position(&directory, entry.slot);
+        Ok(self.entry_node(position, &entry.raw))
+    }
+
+    fn create(&mut self, id: u64, name: &str, file_type: FileType) -> FsResult<u64> {
+        self.check_writable()?;
+        check_name(name)?;
+        let cluster = self.directory_cluster(id)?;
+        let mut directory = self.read_directory(cluster)?;
+        if directory.find(name).is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (attributes, first_cluster) = match file_type {
+            FileType::Directory => {
+                let first_cluster = self.allocate_cluster(None)?;
+                let dots = dot_slots(first_cluster, self.parent_value(directory.cluster));
+                let position = self.layout.cluster_position(first_cluster);
+                let written = self
+                    .write_bytes(position, &dots[0])
+                    .and_then(|_| self.write_bytes(position + ENTRY_SIZE as u64, &dots[1]));
+                if let Err(error) = written {
+                    self.free_chain(first_cluster)?;
+                    return Err(error);
+                }
+                (ATTR_DIRECTORY, first_cluster)
+            }
+            FileType::Regular => (ATTR_ARCHIVE, 0),
+            FileType::Symlink | FileType::CharDevice | FileType::BlockDevice => {
+                return Err(FsError::NotSupported);
+            }
+        };
+
+        let raw = short_slot(&[b' '; 11], attributes, first_cluster);
+        let inserted = self
+            .name_slots(&directory, name, &raw)
+            .and_then(|slots| {
+                let slot = self.insert_slots(&mut directory, &slots)?;
+                Ok((slot, slots[slots.len() - 1]))
+            });
+        match inserted {
+            Ok((slot, raw)) => {
+                let position = self.slot_position(&directory, slot);
+                Ok(self.entry_node(position, &raw))
+            }
+            Err(error) => {
+                if first_cluster != 0 {
+                    self.free_chain(first_cluster)?;
+                }
+                Err(error)
+            }
+        }
+    }
+
+    fn read_dir(&mut self, id: u64, index: usize) -> FsResult<Option<DirEntry>> {
+        let cluster = self.directory_cluster(id)?;
+        let directory = self.read_directory(cluster)?;
+        let entry = directory.entries().0.into_iter().nth(index);
+        Ok(entry.map(|entry| DirEntry {
+            inode: self.slot_position(&directory, entry.slot),
+            file_type: if is_directory_entry(&entry.raw) {
+                FileType::Directory
+            } else {
+                FileType::Regular
+            },
+            name: entry.name,
+        }))
+    }
+
+    fn unlink(&mut self, id: u64, name: &str) -> FsResult<()> {
+        self.check_writable()?;
+        let cluster = self.directory_cluster(id)?;
+        let mut directory = self.read_directory(cluster)?;
+        let entry = directory.find(name).ok_or(FsError::NotFound)?;
+        if is_directory_entry(&entry.raw) {
+            let first_cluster = self.entry_cluster(&entry.raw);
+            if !self.read_directory(first_cluster)?.is_empty() {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+        self.remove_entry(&mut directory, &entry)
+    }
+
+    fn rename(&mut self, id: u64, name: &str, target_inode: u64, new_name: &str) -> FsResult<()> {
+        self.check_writable()?;
+        check_name(new_name)?;
+        let source_cluster = self.directory_cluster(id)?;
+        let target = self
+            .nodes
+            .iter()
+            .find(|(_, node)| node.inode == target_inode && !node.removed)
+            .map(|(&id, _)| id)
+            .ok_or(FsError::NotFound)?;
+        let target_cluster = self.directory_cluster(target)?;
+
+        let mut source = self.read_directory(source_cluster)?;
+        let entry = source.find(name).ok_or(FsError::NotFound)?;
+        let position = self.slot_position(&source, entry.slot);
+        let is_directory = is_directory_entry(&entry.raw);
+        let first_cluster = self.entry_cluster(&entry.raw);
+        if is_directory && self.is_below(target_cluster, first_cluster)? {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let mut target = self.read_directory(target_cluster)?;
+        if let Some(replaced) = target.find(new_name) {
+            if self.slot_position(&target, replaced.slot) == position {
+                // Only the case changes
+                if name == new_name {
+                    return Ok(());
+                }
+            } else {
+                match (is_directory, is_directory_entry(&replaced.raw)) {
+                    (true, false) => return Err(FsError::NotADirectory),
+                    (false, true) => return Err(FsError::IsADirectory),
+                    (true, true) => {
+                        let replaced_cluster = self.entry_cluster(&replaced.raw);
+                        if !self.read_directory(replaced_cluster)?.is_empty() {
+                            return Err(FsError::DirectoryNotEmpty);
+                        }
+                    }
+                    _ => {}
+                }
+                self.remove_entry(&mut target, &replaced)?;
+            }
+        }
+
+        // Inserted before the old entries are freed, so the same directory
+        // is read again to see them as used
+        let same_directory = source_cluster == target_cluster;
+        let slots = self.name_slots(&target, new_name, &entry.raw)?;
+        let slot = self.insert_slots(&mut target, &slots)?;
+        let new_position = self.slot_position(&target, slot);
+        if same_directory {
+            source = self.read_directory(source_cluster)?;
+        }
+        self.remove_slots(&mut source, &entry)?;
+
+        if is_directory && !same_directory {
+            let mut dot_dot = [0; ENTRY_SIZE];
+            let dot_dot_position = self.layout.cluster_position(first_cluster) + ENTRY_SIZE as u64;
+            self.read_bytes(dot_dot_position, &mut dot_dot)?;
+            set_entry_cluster(&mut dot_dot, self.parent_value(target.cluster));
+            self.write_bytes(dot_dot_position, &dot_dot)?;
+        }
+
+        if let Some(node) = self.nodes.values_mut().find(|node| node.entry == Some(position)) {
+            node.entry = Some(new_position);
+            node.inode = new_position;
+        }
+        Ok(())
+    }
+
+    /// Marks the chain starting at `first` in `used`, returns its length
+    /// or `None` if it's broken or crosses another one
+    fn check_chain(
+        &mut self,
+        first: u32,
+        path: &str,
+        used: &mut [u64],
+        problems: &mut Vec<Problem>,
+    ) -> FsResult<Option<u32>> {
+        let mut cluster = first;
+        let mut count = 0;
+        loop {
+            if !self.layout.is_valid_cluster(cluster) {
+                problems.push(Problem::BrokenChain {
+                    path: String::from(path),
+                    cluster,
+                });
+                return Ok(None);
+            }
+            let (word, bit) = (cluster as usize / 64, cluster % 64);
+            if used[word] & 1 << bit != 0 {
+                problems.push(Problem::CrossLinked {
+                    path: String::from(path),
+                    cluster,
+                });
+                return Ok(None);
+            }
+            used[word] |= 1 << bit;
+            count += 1;
+
+            let next = self.fat_entry(cluster)?;
+            if next >= self.fat_type().end_of_chain() {
+                return Ok(Some(count));
+            }
+            if !self.layout.is_valid_cluster(next) {
+                problems.push(Problem::BrokenChain {
+                    path: String::from(path),
+                    cluster: next,
+                });
+                return Ok(None);
+            }
+            cluster = next;
+        }
+    }
+
+    fn check(&mut self) -> FsResult<Vec<Problem>> {
+        let mut problems = Vec::new();
+        let sector_size = self.layout.bytes_per_sector as usize;
+        let mut first = vec![0; sector_size];
+        let mut copy = vec![0; sector_size];
+        'copies: for index in 1..self.layout.fat_count {
+            for sector in 0..self.layout.fat_size {
+                let start = self.layout.reserved_sectors + sector;
+                self.read_bytes(self.layout.sector_position(start), &mut first)?;
+                let start = start + index * self.layout.fat_size;
+                self.read_bytes(self.layout.sector_position(start), &mut copy)?;
+                if first != copy {
+                    problems.push(Problem::FatCopiesDiffer { copy: index });
+                    continue 'copies;
+                }
+            }
+        }
+
+        let cluster_count = self.layout.cluster_count;
+        let mut used = vec![0u64; (cluster_count as usize + 2 + 63) / 64];
+        let root = self.layout.root_cluster;
+        let mut pending = Vec::new();
+        if root == 0 || self.check_chain(root, "/", &mut used, &mut problems)?.is_some() {
+            pending.push((String::from("/"), root, None));
+        }
+
+        let cluster_size = u64::from(self.layout.cluster_size());
+        while let Some((path, cluster, parent)) = pending.pop() {
+            let directory = self.read_directory(cluster)?;
+            if let Some(parent) = parent {
+                let dot = directory.slot(0);
+                let dot_dot = directory.slot(1);
+                if &dot[..11] != b".          "
+                    || self.entry_cluster(dot) != cluster
+                    || &dot_dot[..11] != b"..         "
+                    || self.entry_cluster(dot_dot) != parent
+                {
+                    problems.push(Problem::BadDotEntries { path: path.clone() });
+                }
+            }
+
+            let (entries, orphans) = directory.entries();
+            if orphans > 0 {
+                problems.push(Problem::OrphanLongNames {
+                    path: path.clone(),
+                    count: orphans,
+                });
+            }
+            for entry in entries {
+                let mut child = path.clone();
+                if child != "/" {
+                    child.push('/');
+                }
+                child.push_str(&entry.name);
+
+                let first_cluster = self.entry_cluster(&entry.raw);
+                let size = entry_size(&entry.raw);
+                if is_directory_entry(&entry.raw) {
+                    let chain = self.check_chain(first_cluster, &child, &mut used, &mut problems)?;
+                    if chain.is_some() {
+                        let parent = self.parent_value(cluster);
+                        pending.push((child, first_cluster, Some(parent)));
+                    }
+                    continue;
+                }
+
+                let needed = ((u64::from(size) + cluster_size - 1) / cluster_size) as u32;
+                let clusters = if first_cluster == 0 {
+                    Some(0)
+                } else {
+                    self.check_chain(first_cluster, &child, &mut used, &mut problems)?
+                };
+                match clusters {
+                    Some(clusters) if clusters != needed => problems.push(Problem::SizeMismatch {
+                        path: child,
+                        size,
+                        clusters,
+                    }),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut lost = 0;
+        let mut free = 0;
+        let bad = self.fat_type().bad_cluster();
+        for cluster in 2..cluster_count + 2 {
+            let (word, bit) = (cluster as usize / 64, cluster % 64);
+            match self.fat_entry(cluster)? {
+                0 => free += 1,
+                value if value != bad && used[word] & 1 << bit == 0 => lost += 1,
+                _ => {}
+            }
+        }
+        if lost > 0 {
+            problems.push(Problem::LostClusters { count: lost });
+        }
+        if let Some((stored, _)) = self.read_fs_info()? {
+            if stored != FS_INFO_UNKNOWN && stored != free {
+                problems.push(Problem::FreeCountMismatch {
+                    stored,
+                    actual: free,
+                });
+            }
+        }
+        Ok(problems)
+    }
+}
+
+impl Drop for Volume {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+/// Something `check` found wrong
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Problem {
+    /// A copy of the FAT differs from the first one
+    FatCopiesDiffer { copy: u32 },
+    /// The chain of `path` reaches a free, bad or out of range cluster
+    BrokenChain { path: String, cluster: u32 },
+    /// `cluster` is in another chain too, or twice in the chain of `path`
+    CrossLinked { path: String, cluster: u32 },
+    /// The chain doesn't have the clusters the size of a file needs
+    SizeMismatch { path: String, size: u32, clusters: u32 },
+    /// `.` or `..` of a directory are missing or point elsewhere
+    BadDotEntries { path: String },
+    /// Long name entries without a short entry that matches them
+    OrphanLongNames { path: String, count: usize },
+    /// Clusters in use that no file refers to
+    LostClusters { count: u32 },
+    /// The free cluster count of the FAT32 FSInfo sector is wrong
+    FreeCountMismatch { stored: u32, actual: u32 },
+}
+
+/// Checks the FAT filesystem on `device`, which isn't mounted or was
+/// synced, without changing it
+pub fn check(device: Arc<dyn BlockDevice>) -> FsResult<Vec<Problem>> {
+    Volume::open(device, true)?.check()
+}
+
+/// Creates an empty FAT filesystem of type `fat_type` on the whole device,
+/// with 2 FATs and the smallest clusters that fit the type. Fails if the
+/// device is too small or too large for it.
+pub fn format(device: &dyn BlockDevice, fat_type: FatType) -> FsResult<()> {
+    let bytes_per_sector = device.block_size().max(512) as u32;
+    let total_sectors = (device.block_count() * device.block_size() as u64
+        / u64::from(bytes_per_sector))
+    .min(u64::from(u32::max_value())) as u32;
+    let fat_count = 2;
+    let (reserved_sectors, root_entries) = match fat_type {
+        FatType::Fat12 if total_sectors <= 2880 => (1, 224),
+        FatType::Fat12 | FatType::Fat16 => (1, 512),
+        FatType::Fat32 => (32, 0),
+    };
+    let root_sectors = (root_entries * ENTRY_SIZE as u32 + bytes_per_sector - 1) / bytes_per_sector;
+
+    let mut geometry = None;
+    let mut sectors_per_cluster = 1;
+    while geometry.is_none() && sectors_per_cluster * bytes_per_sector <= 32768 {
+        // The FAT gets bigger until it holds all the clusters left
+        let mut fat_size = 1;
+        let cluster_count = loop {
+            let used = reserved_sectors + fat_count * fat_size + root_sectors;
+            if used >= total_sectors {
+                return Err(FsError::InvalidArgument);
+            }
+            let cluster_count = (total_sectors - used) / sectors_per_cluster;
+            let fat_bytes = (u64::from(cluster_count) + 2) * u64::from(fat_type.bits()) / 8 + 1;
+            let sector_size = u64::from(bytes_per_sector);
+            let needed = ((fat_bytes + sector_size - 1) / sector_size) as u32;
+            if needed <= fat_size {
+                break cluster_count;
+            }
+            fat_size = needed;
+        };
+
+        let (minimum, maximum) = match fat_type {
+            FatType::Fat12 => (1, MAX_FAT12_CLUSTERS),
+            FatType::Fat16 => (MAX_FAT12_CLUSTERS, MAX_FAT16_CLUSTERS),
+            FatType::Fat32 => (MAX_FAT16_CLUSTERS, MAX_FAT32_CLUSTERS),
+        };
+        if cluster_count < minimum {
+            return Err(FsError::InvalidArgument);
+        }
+        if cluster_count < maximum {
+            geometry = Some((fat_size, cluster_count));
+        } else {
+            sectors_per_cluster *= 2;
+        }
+    }
+    let (fat_size, cluster_count) = geometry.ok_or(FsError::InvalidArgument)?;
+
+    let sector_position = |sector: u32| u64::from(sector) * u64::from(bytes_per_sector);
+    let first_data_sector = reserved_sectors + fat_count * fat_size + root_sectors;
+    let zeros = vec![0; bytes_per_sector as usize];
+    for sector in 0..first_data_sector {
+        block::write_at(device, sector_position(sector), &zeros)?;
+    }
+    if fat_type == FatType::Fat32 {
+        for sector in 0..sectors_per_cluster {
+            block::write_at(device, sector_position(first_data_sector + sector), &zeros)?;
+        }
+    }
+
+    let mut boot = vec![0; bytes_per_sector as usize];
+    boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot[3..11].copy_from_slice(b"HAMDOS  ");
+    write_u16(&mut boot, 11, bytes_per_sector as u16);
+    boot[13] = sectors_per_cluster as u8;
+    write_u16(&mut boot, 14, reserved_sectors as u16);
+    boot[16] = fat_count as u8;
+    write_u16(&mut boot, 17, root_entries as u16);
+    if total_sectors < 0x10000 && fat_type != FatType::Fat32 {
+        write_u16(&mut boot, 19, total_sectors as u16);
+    } else {
+        write_u32(&mut boot, 32, total_sectors);
+    }
+    let media = 0xF8;
+    boot[21] = media;
+    write_u16(&mut boot, 24, 63);
+    write_u16(&mut boot, 26, 255);
+
+    let volume_id = time::ticks() as u32;
+    let extended = match fat_type {
+        FatType::Fat32 => {
+            boot[1] = 0x58;
+            write_u32(&mut boot, 36, fat_size);
+            write_u32(&mut boot, 44, 2);
+            write_u16(&mut boot, 48, 1);
+            write_u16(&mut boot, 50, 6);
+            64
+        }
+        _ => {
+            write_u16(&mut boot, 22, fat_size as u16);
+            36
+        }
+    };
+    boot[extended] = 0x80;
+    boot[extended + 2] = 0x29;
+    write_u32(&mut boot, extended + 3, volume_id);
+    boot[extended + 7..extended + 18].copy_from_slice(b"NO NAME    ");
+    boot[extended + 18..extended + 26].copy_from_slice(match fat_type {
+        FatType::Fat12 => b"FAT12   ",
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    });
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    block::write_at(device, 0, &boot)?;
+
+    let mut fat = vec![0; bytes_per_sector as usize];
+    let end = fat_type.end_marker();
+    match fat_type {
+        FatType::Fat12 => {
+            let entries = 0xF00 | u32::from(media) | end << 12;
+            fat[..3].copy_from_slice(&[entries as u8, (entries >> 8) as u8, (entries >> 16) as u8]);
+        }
+        FatType::Fat16 => {
+            write_u16(&mut fat, 0, 0xFF00 | u16::from(media));
+            write_u16(&mut fat, 2, end as u16);
+        }
+        FatType::Fat32 => {
+            write_u32(&mut fat, 0, 0x0FFF_FF00 | u32::from(media));
+            write_u32(&mut fat, 4, end);
+            // The root directory
+            write_u32(&mut fat, 8, end);
+        }
+    }
+    for copy in 0..fat_count {
+        block::write_at(device, sector_position(reserved_sectors + copy * fat_size), &fat)?;
+    }
+
+    if fat_type == FatType::Fat32 {
+        let mut fs_info = vec![0; bytes_per_sector as usize];
+        write_u32(&mut fs_info, 0, FS_INFO_LEAD_SIGNATURE);
+        write_u32(&mut fs_info, 484, FS_INFO_SIGNATURE);
+        write_u32(&mut fs_info, 488, cluster_count - 1);
+        write_u32(&mut fs_info, 492, 3);
+        write_u32(&mut fs_info, 508, FS_INFO_TRAIL_SIGNATURE);
+        block::write_at(device, sector_position(1), &fs_info)?;
+        // Backups of both
+        block::write_at(device, sector_position(6), &boot)?;
+        block::write_at(device, sector_position(7), &fs_info)?;
+    }
+    device.flush()?;
+    Ok(())
+}
+
+pub struct FatFs {
+    volume: Arc<Mutex<Volume>>,
+    fat_type: FatType,
+}
+
+impl FatFs {
+    /// Opens the FAT filesystem on `device`, it's read-only if the device
+    /// is
+    pub fn new(device: Arc<dyn BlockDevice>) -> FsResult<Arc<FatFs>> {
+        let read_only = device.is_read_only();
+        let volume = Volume::open(device, read_only)?;
+        Ok(Arc::new(FatFs {
+            fat_type: volume.fat_type(),
+            volume: Arc::new(Mutex::new(volume)),
+        }))
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Bytes per cluster, files take whole clusters
+    pub fn cluster_size(&self) -> u32 {
+        self.volume.lock().layout.cluster_size()
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        let volume = self.volume.lock();
+        u64::from(volume.free_clusters) * u64::from(volume.layout.cluster_size())
+    }
+
+    /// Writes everything that's cached to the device
+    pub fn sync(&self) -> FsResult<()> {
+        self.volume.lock().sync()
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &str {
+        "vfat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let mut volume = self.volume.lock();
+        let root = volume.root_node();
+        FatInode::new(&self.volume, &mut volume, root)
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        FatFs::sync(self)
+    }
+}
+
+/// A handle to a node, it keeps the node alive
+struct FatInode {
+    volume: Arc<Mutex<Volume>>,
+    id: u64,
+}
+
+impl FatInode {
+    fn new(volume: &Arc<Mutex<Volume>>, locked: &mut Volume, id: u64) -> Arc<dyn Inode> {
+        locked.node_mut(id).handles += 1;
+        Arc::new(FatInode {
+            volume: volume.clone(),
+            id,
+        })
+    }
+
+    /// Runs `operation` on the volume and writes the FAT back
+    fn with_volume<T, F>(&self, operation: F) -> FsResult<T>
+    where
+        F: FnOnce(&mut Volume) -> FsResult<T>,
+    {
+        let mut volume = self.volume.lock();
+        let result = operation(&mut volume);
+        let flushed = volume.flush_fat();
+        let value = result?;
+        flushed?;
+        Ok(value)
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let mut volume = self.volume.lock();
+        volume.node_mut(self.id).handles -= 1;
+        // Nothing to report the error to, `check` finds the lost clusters
+        let _ = volume.release(self.id).and_then(|_| volume.flush_fat());
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> FsResult<Metadata> {
+        self.with_volume(|volume| volume.metadata(self.id))
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        self.with_volume(|volume| volume.read(self.id, offset, buffer))
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> FsResult<usize> {
+        self.with_volume(|volume| volume.write(self.id, offset, buffer))
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        self.with_volume(|volume| volume.truncate(self.id, size))
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let mut volume = self.volume.lock();
+        let id = volume.lookup(self.id, name)?;
+        Ok(FatInode::new(&self.volume, &mut volume, id))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> FsResult<Arc<dyn Inode>> {
+        let mut volume = self.volume.lock();
+        let result = volume.create(self.id, name, file_type);
+        let flushed = volume.flush_fat();
+        let id = result?;
+        let inode = FatInode::new(&self.volume, &mut volume, id);
+        flushed?;
+        Ok(inode)
+    }
+
+    fn read_dir(&self, index: usize) -> FsResult<Option<DirEntry>> {
+        self.with_volume(|volume| volume.read_dir(self.id, index))
+    }
+
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        self.with_volume(|volume| volume.unlink(self.id, name))
+    }
+
+    fn rename(&self, name: &str, directory: &dyn Inode, new_name: &str) -> FsResult<()> {
+        // Taken before the lock, it locks the volume itself
+        let target = directory.metadata()?.inode;
+        self.with_volume(|volume| volume.rename(self.id, name, target, new_name))
+    }
+}
@@ -0,0 +1,203 @@
+//! Initial ramdisk.
+//!
+//! `build.rs` packs `initrd/` and the programs into a USTAR archive that's
+//! embedded in the kernel, `init` unpacks it into the root filesystem before
+//! the first process starts. Only regular files and directories are
+//! extracted, other entries are skipped. Paths can't leave the directory
+//! the archive is unpacked into.
+
+use alloc::format;
+use alloc::string::String;
+use core::str;
+
+use super::{path, FsError};
+
+/// The archive `build.rs` made
+pub static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InitrdError {
+    /// The archive ends in the middle of an entry
+    Truncated,
+    BadChecksum,
+    /// A header field isn't valid USTAR or a path leaves the target
+    BadHeader,
+    Fs(FsError),
+}
+
+impl From<FsError> for InitrdError {
+    fn from(error: FsError) -> InitrdError {
+        InitrdError::Fs(error)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// Links, devices and so on
+    Other,
+}
+
+#[derive(Debug)]
+pub struct Entry<'a> {
+    /// As stored in the archive, relative to where it's unpacked
+    pub path: String,
+    pub kind: EntryKind,
+    pub data: &'a [u8],
+}
+
+/// Iterates over the entries of a USTAR archive, it stops after the first
+/// error
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+pub fn entries(archive: &[u8]) -> Entries {
+    Entries {
+        archive,
+        offset: 0,
+        done: false,
+    }
+}
+
+/// Parses a NUL or space terminated octal number
+fn octal(field: &[u8]) -> Result<u64, InitrdError> {
+    let digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != 0 && byte != b' ');
+
+    let mut value: u64 = 0;
+    for &digit in digits {
+        if digit < b'0' || digit > b'7' {
+            return Err(InitrdError::BadHeader);
+        }
+        value = value
+            .checked_mul(8)
+            .ok_or(InitrdError::BadHeader)?
+            + u64::from(digit - b'0');
+    }
+    Ok(value)
+}
+
+/// Returns the NUL terminated string in `field`, it may fill the field
+fn string(field: &[u8]) -> Result<&str, InitrdError> {
+    let end = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..end]).map_err(|_| InitrdError::BadHeader)
+}
+
+impl<'a> Entries<'a> {
+    fn parse_next(&mut self) -> Result<Option<Entry<'a>>, InitrdError> {
+        let rest = &self.archive[self.offset..];
+        // The end is marked by zero blocks, but they may be missing
+        if rest.is_empty()
+            || (rest.len() >= BLOCK_SIZE && rest[..BLOCK_SIZE].iter().all(|&byte| byte == 0))
+        {
+            return Ok(None);
+        }
+        if rest.len() < BLOCK_SIZE {
+            return Err(InitrdError::Truncated);
+        }
+
+        let header = &rest[..BLOCK_SIZE];
+        if &header[257..262] != b"ustar" {
+            return Err(InitrdError::BadHeader);
+        }
+        // Summed with the checksum field counted as spaces
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(index, &byte)| match index {
+                148..=155 => u64::from(b' '),
+                _ => u64::from(byte),
+            })
+            .sum();
+        if sum != octal(&header[148..156])? {
+            return Err(InitrdError::BadChecksum);
+        }
+
+        let size = octal(&header[124..136])? as usize;
+        if size > rest.len() - BLOCK_SIZE {
+            return Err(InitrdError::Truncated);
+        }
+        let kind = match header[156] {
+            b'0' | 0 => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            _ => EntryKind::Other,
+        };
+
+        let name = string(&header[..100])?;
+        let prefix = string(&header[345..500])?;
+        let path = if prefix.is_empty() {
+            String::from(name)
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+
+        let padded = (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+        self.offset = (self.offset + BLOCK_SIZE + padded).min(self.archive.len());
+        Ok(Some(Entry {
+            path,
+            kind,
+            data: &rest[BLOCK_SIZE..BLOCK_SIZE + size],
+        }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, InitrdError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let result = self.parse_next();
+        if let Ok(None) | Err(_) = result {
+            self.done = true;
+        }
+        result.transpose()
+    }
+}
+
+/// Extracts `archive` into the directory `target`, missing parent
+/// directories are created and existing files overwritten. Returns the
+/// number of files and directories extracted.
+pub fn unpack(archive: &[u8], target: &str) -> Result<usize, InitrdError> {
+    let target = super::absolute(target)?;
+    let mut count = 0;
+    for entry in entries(archive) {
+        let entry = entry?;
+        if entry.kind == EntryKind::Other {
+            continue;
+        }
+
+        // Absolute paths are taken as relative to the target too
+        let destination = path::normalize(&target, entry.path.trim_start_matches('/'))?;
+        if path::strip_ancestor(&destination, &target).is_none() {
+            return Err(InitrdError::BadHeader);
+        }
+
+        match entry.kind {
+            EntryKind::Directory => super::create_dir_all(&destination)?,
+            _ => {
+                if let Some((parent, _)) = path::split_last(&destination) {
+                    super::create_dir_all(parent)?;
+                }
+                super::write(&destination, entry.data)?;
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Unpacks the embedded archive into the root filesystem
+pub fn init() {
+    unpack(ARCHIVE, "/").expect("Failed to unpack the initrd");
+}
@@ -0,0 +1,606 @@
+//! Virtual file system.
+//!
+//! Concrete filesystems implement `FileSystem` and `Inode` and get mounted
+//! on a directory of the tree, the first one on `/`. Paths are resolved
+//! lexically: a relative path starts at the working directory of the
+//! calling process and `.` and `..` are removed before the path is split at
+//! the deepest mount point below it. The rest is looked up name by name
+//! from the root of that filesystem. A symbolic link found on the way is
+//! replaced by its target and the lookup starts over.
+//!
+//! Opening a path gives an `OpenFile` with its own position. Kernel code
+//! uses it directly, processes get a file descriptor referring to it.
+
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod initrd;
+mod open_file;
+pub mod path;
+pub mod procfs;
+pub mod tmpfs;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use lazy_static::lazy_static;
+
+use crate::block;
+use crate::lock_class;
+use crate::process;
+use crate::sync::IrqSpinLock;
+use crate::syscall::SyscallError;
+
+pub use self::open_file::{OpenFile, SeekFrom, SEEK_CUR, SEEK_END, SEEK_SET};
+
+use self::tmpfs::TmpFs;
+
+/// Open for reading only
+pub const O_RDONLY: u32 = 0;
+/// Open for writing only
+pub const O_WRONLY: u32 = 1;
+/// Open for reading and writing
+pub const O_RDWR: u32 = 2;
+/// Mask of the access mode bits
+pub const O_ACCMODE: u32 = 3;
+/// Create a regular file if the path doesn't exist
+pub const O_CREAT: u32 = 0x40;
+/// Fail if `O_CREAT` finds an existing file
+pub const O_EXCL: u32 = 0x80;
+/// Truncate a regular file opened for writing to 0 bytes
+pub const O_TRUNC: u32 = 0x200;
+/// Every write goes to the end of the file
+pub const O_APPEND: u32 = 0x400;
+/// Fail if the path isn't a directory
+pub const O_DIRECTORY: u32 = 0x10000;
+
+const KNOWN_FLAGS: u32 = O_ACCMODE | O_CREAT | O_EXCL | O_TRUNC | O_APPEND | O_DIRECTORY;
+
+pub type FsResult<T> = Result<T, FsError>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    NameTooLong,
+    /// The filesystem can't be written to
+    ReadOnly,
+    /// The file isn't open for reading or for writing
+    WrongMode,
+    NoSpace,
+    /// A mount point is still in use
+    Busy,
+    /// Renaming across filesystems
+    CrossDevice,
+    /// The filesystem doesn't support the operation
+    NotSupported,
+    InvalidArgument,
+    /// The device failed or the data on it is inconsistent
+    Io,
+    /// Too many symbolic links while resolving a path
+    SymlinkLoop,
+    /// A signal came while waiting for a device
+    Interrupted,
+}
+
+impl From<FsError> for SyscallError {
+    fn from(error: FsError) -> SyscallError {
+        match error {
+            FsError::NotFound => SyscallError::NoSuchFile,
+            FsError::AlreadyExists => SyscallError::FileExists,
+            FsError::NotADirectory => SyscallError::NotADirectory,
+            FsError::IsADirectory => SyscallError::IsADirectory,
+            FsError::DirectoryNotEmpty => SyscallError::DirectoryNotEmpty,
+            FsError::NameTooLong => SyscallError::NameTooLong,
+            FsError::ReadOnly => SyscallError::ReadOnlyFileSystem,
+            FsError::WrongMode => SyscallError::BadFileDescriptor,
+            FsError::NoSpace => SyscallError::NoSpace,
+            FsError::Busy => SyscallError::Busy,
+            FsError::CrossDevice => SyscallError::CrossDevice,
+            FsError::NotSupported => SyscallError::NotSupported,
+            FsError::InvalidArgument => SyscallError::InvalidArgument,
+            FsError::Io => SyscallError::IoError,
+            FsError::SymlinkLoop => SyscallError::SymlinkLoop,
+            FsError::Interrupted => SyscallError::Interrupted,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u32)]
+pub enum FileType {
+    Regular = 1,
+    Directory = 2,
+    Symlink = 3,
+    /// A device that streams bytes, like a terminal. It has no position.
+    CharDevice = 4,
+    BlockDevice = 5,
+}
+
+/// In milliseconds. There's no real time clock, filesystems that only live
+/// in memory count from boot.
+pub type Timestamp = u64;
+
+/// What `stat` returns, it's copied to user code as it is
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(C)]
+pub struct Metadata {
+    /// Unique within the filesystem
+    pub inode: u64,
+    /// In bytes, for directories it's up to the filesystem
+    pub size: u64,
+    pub file_type: FileType,
+    /// Number of directory entries referring to the inode
+    pub links: u32,
+    /// Last time the contents were read
+    pub accessed: Timestamp,
+    /// Last time the contents changed
+    pub modified: Timestamp,
+    /// Last time the contents or the metadata changed
+    pub changed: Timestamp,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirEntry {
+    pub inode: u64,
+    pub file_type: FileType,
+    pub name: String,
+}
+
+/// A file or directory of a mounted filesystem. The VFS checks the file
+/// type before calling the operations, a filesystem only implements the
+/// ones its inodes support.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> FsResult<Metadata>;
+
+    /// Reads from `offset` on, returns 0 at or after the end of the file
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> FsResult<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Writes at `offset`, the file grows if it ends before
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> FsResult<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Changes the size of a regular file, new bytes are zero
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Looks up `name` in a directory
+    fn lookup(&self, _name: &str) -> FsResult<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Creates an empty file or directory called `name` in a directory
+    fn create(&self, _name: &str, _file_type: FileType) -> FsResult<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Returns entry number `index` of a directory, `None` after the last
+    /// one. Creating or removing entries may move the others.
+    fn read_dir(&self, _index: usize) -> FsResult<Option<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Removes the entry `name` from a directory, it may refer to a file or
+    /// to an empty directory. The inode lives on while it's in use.
+    fn unlink(&self, _name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Moves the entry `name` of a directory to `new_name` in `directory`,
+    /// which is on the same filesystem. An existing entry there is replaced
+    /// if it's a file or, when a directory is moved, an empty directory.
+    fn rename(&self, _name: &str, _directory: &dyn Inode, _new_name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Returns the target of a symbolic link
+    fn read_link(&self) -> FsResult<String> {
+        Err(FsError::InvalidArgument)
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    /// Type of the filesystem, like `tmpfs`
+    fn name(&self) -> &str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    /// Writes what the filesystem keeps in memory to its device
+    fn sync(&self) -> FsResult<()> {
+        Ok(())
+    }
+}
+
+/// A filesystem mounted on a directory. Open files hold a reference, it
+/// can't be unmounted while there are any.
+pub struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+impl Mount {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn filesystem(&self) -> &Arc<dyn FileSystem> {
+        &self.fs
+    }
+}
+
+lazy_static! {
+    static ref MOUNTS: IrqSpinLock<Vec<Arc<Mount>>> =
+        IrqSpinLock::with_class(Vec::new(), lock_class!("MOUNTS"));
+}
+
+/// Mounts an empty tmpfs as the root
+pub fn init() {
+    mount("/", TmpFs::new()).expect("Failed to mount the root filesystem");
+}
+
+/// Makes `path` absolute and normalized, relative to the working directory
+/// of the calling process
+pub fn absolute(path: &str) -> FsResult<String> {
+    path::normalize(&process::current_dir(), path)
+}
+
+/// Returns the deepest mount `path` is on and the path below its root
+fn find_mount(path: &str) -> FsResult<(Arc<Mount>, &str)> {
+    let mounts = MOUNTS.lock();
+    mounts
+        .iter()
+        .filter_map(|mount| path::strip_ancestor(path, &mount.path).map(|rest| (mount, rest)))
+        .max_by_key(|(mount, _)| mount.path.len())
+        .map(|(mount, rest)| (mount.clone(), rest))
+        .ok_or(FsError::NotFound)
+}
+
+/// Most symbolic links followed while resolving one path
+const MAX_LINKS: usize = 40;
+
+enum Walk {
+    Found(Arc<Mount>, Arc<dyn Inode>),
+    /// The path with the first symbolic link on it replaced
+    Link(String),
+}
+
+/// Looks up a normalized absolute path, stops at the first symbolic link
+/// unless it's the last name and `follow` isn't set
+fn walk(path: &str, follow: bool) -> FsResult<Walk> {
+    let (mount, rest) = find_mount(path)?;
+    let names: Vec<&str> = path::components(rest).collect();
+    let mut inode = mount.fs.root();
+    for (index, name) in names.iter().enumerate() {
+        inode = inode.lookup(name)?;
+        if (index + 1 == names.len() && !follow)
+            || inode.metadata()?.file_type != FileType::Symlink
+        {
+            continue;
+        }
+
+        // A relative target starts at the directory of the link
+        let mut directory = mount.path.clone();
+        for name in &names[..index] {
+            if directory != "/" {
+                directory.push('/');
+            }
+            directory.push_str(name);
+        }
+        let target = path::normalize(&directory, &inode.read_link()?)?;
+        let remaining = names[index + 1..].join("/");
+        if remaining.is_empty() {
+            return Ok(Walk::Link(target));
+        }
+        return Ok(Walk::Link(path::normalize(&target, &remaining)?));
+    }
+    Ok(Walk::Found(mount, inode))
+}
+
+/// Looks up a normalized absolute path, following symbolic links on the way
+/// and, if `follow` is set, the one at the end
+fn resolve_links(path: &str, follow: bool) -> FsResult<(Arc<Mount>, Arc<dyn Inode>)> {
+    let mut path = String::from(path);
+    for _ in 0..=MAX_LINKS {
+        match walk(&path, follow)? {
+            Walk::Found(mount, inode) => return Ok((mount, inode)),
+            Walk::Link(target) => path = target,
+        }
+    }
+    Err(FsError::SymlinkLoop)
+}
+
+/// Looks up a normalized absolute path
+fn resolve(path: &str) -> FsResult<(Arc<Mount>, Arc<dyn Inode>)> {
+    resolve_links(path, true)
+}
+
+/// Creates a file or directory at a normalized absolute path
+fn create(path: &str, file_type: FileType) -> FsResult<(Arc<Mount>, Arc<dyn Inode>)> {
+    // The root always exists
+    let (parent, name) = path::split_last(path).ok_or(FsError::AlreadyExists)?;
+    let (mount, directory) = resolve(parent)?;
+    let inode = directory.create(name, file_type)?;
+    Ok((mount, inode))
+}
+
+/// Mounts `fs` on the directory `path`, the first filesystem has to be
+/// mounted on `/`
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> FsResult<()> {
+    let path = absolute(path)?;
+    if MOUNTS.lock().is_empty() {
+        if path != "/" {
+            return Err(FsError::NotFound);
+        }
+    } else {
+        let (_, inode) = resolve(&path)?;
+        if inode.metadata()?.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+    }
+
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::Busy);
+    }
+    mounts.push(Arc::new(Mount { path, fs }));
+    Ok(())
+}
+
+/// Unmounts the filesystem mounted on `path`. Fails while files on it are
+/// open or other filesystems are mounted below it.
+pub fn unmount(path: &str) -> FsResult<()> {
+    let path = absolute(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == path)
+        .ok_or(FsError::InvalidArgument)?;
+
+    let nested = mounts
+        .iter()
+        .any(|mount| mount.path != path && path::strip_ancestor(&mount.path, &path).is_some());
+    if nested || Arc::strong_count(&mounts[index]) > 1 {
+        return Err(FsError::Busy);
+    }
+    let mount = mounts.remove(index);
+    // The filesystem may write to its device when it's dropped
+    drop(mounts);
+    drop(mount);
+    Ok(())
+}
+
+/// Writes every mounted filesystem and then every disk back, returns the
+/// first error
+pub fn sync() -> FsResult<()> {
+    let mut result = Ok(());
+    for mount in mounts() {
+        let synced = mount.fs.sync();
+        if result.is_ok() {
+            result = synced;
+        }
+    }
+    let synced = block::sync_all().map_err(FsError::from);
+    result.and(synced)
+}
+
+/// The mounted filesystems, in the order they were mounted
+pub fn mounts() -> Vec<Arc<Mount>> {
+    MOUNTS.lock().clone()
+}
+
+/// Looks up the inode at `path`
+pub fn lookup(path: &str) -> FsResult<Arc<dyn Inode>> {
+    let (_, inode) = resolve(&absolute(path)?)?;
+    Ok(inode)
+}
+
+/// Opens `path`, `flags` are the `O_*` constants
+pub fn open(path: &str, flags: u32) -> FsResult<Arc<OpenFile>> {
+    if flags & !KNOWN_FLAGS != 0 || flags & O_ACCMODE == O_ACCMODE {
+        return Err(FsError::InvalidArgument);
+    }
+
+    let path = absolute(path)?;
+    let (mount, inode) = match resolve(&path) {
+        Ok(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => {
+            return Err(FsError::AlreadyExists);
+        }
+        Err(FsError::NotFound) if flags & O_CREAT != 0 => create(&path, FileType::Regular)?,
+        result => result?,
+    };
+
+    let file_type = inode.metadata()?.file_type;
+    let writable = flags & O_ACCMODE != O_RDONLY;
+    if file_type == FileType::Directory && (writable || flags & O_TRUNC != 0) {
+        return Err(FsError::IsADirectory);
+    }
+    if file_type != FileType::Directory && flags & O_DIRECTORY != 0 {
+        return Err(FsError::NotADirectory);
+    }
+    if file_type == FileType::Regular && writable && flags & O_TRUNC != 0 {
+        inode.truncate(0)?;
+    }
+
+    Ok(Arc::new(OpenFile::new(path, mount, inode, flags)))
+}
+
+/// Creates the directory `path`, its parent has to exist
+pub fn create_dir(path: &str) -> FsResult<()> {
+    create(&absolute(path)?, FileType::Directory)?;
+    Ok(())
+}
+
+/// Creates the directory `path` and the missing ones above it
+pub fn create_dir_all(path: &str) -> FsResult<()> {
+    let path = absolute(path)?;
+    let mut current = String::new();
+    for name in path::components(&path) {
+        current.push('/');
+        current.push_str(name);
+        match create(&current, FileType::Directory) {
+            Ok(_) | Err(FsError::AlreadyExists) => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    if resolve(&path)?.1.metadata()?.file_type != FileType::Directory {
+        return Err(FsError::AlreadyExists);
+    }
+    Ok(())
+}
+
+/// Reads the whole file `path`
+pub fn read(path: &str) -> FsResult<Vec<u8>> {
+    let file = open(path, O_RDONLY)?;
+    let mut data = vec![0; file.metadata()?.size as usize];
+    let mut count = 0;
+    while count < data.len() {
+        let read = file.read(&mut data[count..])?;
+        if read == 0 {
+            break;
+        }
+        count += read;
+    }
+    data.truncate(count);
+    Ok(data)
+}
+
+/// Creates or truncates the file `path` and writes `data` to it
+pub fn write(path: &str, data: &[u8]) -> FsResult<()> {
+    let file = open(path, O_WRONLY | O_CREAT | O_TRUNC)?;
+    let mut count = 0;
+    while count < data.len() {
+        match file.write(&data[count..])? {
+            0 => return Err(FsError::NoSpace),
+            written => count += written,
+        }
+    }
+    Ok(())
+}
+
+/// Returns the metadata of the file at `path`
+pub fn metadata(path: &str) -> FsResult<Metadata> {
+    lookup(path)?.metadata()
+}
+
+/// Returns the metadata of the file at `path` without following a symbolic
+/// link at the end
+pub fn symlink_metadata(path: &str) -> FsResult<Metadata> {
+    resolve_links(&absolute(path)?, false)?.1.metadata()
+}
+
+/// Returns the target of the symbolic link `path`
+pub fn read_link(path: &str) -> FsResult<String> {
+    resolve_links(&absolute(path)?, false)?.1.read_link()
+}
+
+/// Lists the directory `path`
+pub fn read_dir(path: &str) -> FsResult<Vec<DirEntry>> {
+    let directory = open(path, O_RDONLY | O_DIRECTORY)?;
+    let mut entries = Vec::new();
+    while let Some(entry) = directory.read_dir()? {
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn is_mount_point(path: &str) -> bool {
+    MOUNTS.lock().iter().any(|mount| mount.path == path)
+}
+
+/// Returns `true` if a filesystem is mounted on `path` or below it
+fn has_mounts_below(path: &str) -> bool {
+    MOUNTS
+        .lock()
+        .iter()
+        .any(|mount| path::strip_ancestor(&mount.path, path).is_some())
+}
+
+/// Removes the entry at a normalized absolute path after `check` accepted
+/// the file type
+fn remove<F>(path: &str, check: F) -> FsResult<()>
+where
+    F: FnOnce(FileType) -> FsResult<()>,
+{
+    let (parent, name) = path::split_last(path).ok_or(FsError::Busy)?;
+    if is_mount_point(path) {
+        return Err(FsError::Busy);
+    }
+
+    let (_, directory) = resolve(parent)?;
+    check(directory.lookup(name)?.metadata()?.file_type)?;
+    directory.unlink(name)
+}
+
+/// Removes the file `path`
+pub fn remove_file(path: &str) -> FsResult<()> {
+    remove(&absolute(path)?, |file_type| {
+        if file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(())
+    })
+}
+
+/// Removes the empty directory `path`
+pub fn remove_dir(path: &str) -> FsResult<()> {
+    remove(&absolute(path)?, |file_type| {
+        if file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(())
+    })
+}
+
+/// Moves `from` to `to` on the same filesystem, replacing what `to` refers
+/// to unless it's a directory that isn't empty
+pub fn rename(from: &str, to: &str) -> FsResult<()> {
+    let (from, to) = (absolute(from)?, absolute(to)?);
+    let (from_parent, from_name) = path::split_last(&from).ok_or(FsError::Busy)?;
+    let (to_parent, to_name) = path::split_last(&to).ok_or(FsError::Busy)?;
+    if has_mounts_below(&from) || is_mount_point(&to) {
+        return Err(FsError::Busy);
+    }
+    // A directory can't be moved into itself
+    if from != to && path::strip_ancestor(&to, &from).is_some() {
+        return Err(FsError::InvalidArgument);
+    }
+
+    let (from_mount, from_directory) = resolve(from_parent)?;
+    let (to_mount, to_directory) = resolve(to_parent)?;
+    if !Arc::ptr_eq(&from_mount, &to_mount) {
+        return Err(FsError::CrossDevice);
+    }
+    from_directory.rename(from_name, &*to_directory, to_name)
+}
+
+/// Changes the size of the regular file `path`, new bytes are zero
+pub fn truncate(path: &str, size: u64) -> FsResult<()> {
+    let inode = lookup(path)?;
+    match inode.metadata()?.file_type {
+        FileType::Regular => inode.truncate(size),
+        FileType::Directory => Err(FsError::IsADirectory),
+        FileType::Symlink | FileType::CharDevice | FileType::BlockDevice => {
+            Err(FsError::InvalidArgument)
+        }
+    }
+}
+
+/// Changes the working directory of the calling process
+pub fn set_current_dir(path: &str) -> FsResult<()> {
+    let path = absolute(path)?;
+    let (_, inode) = resolve(&path)?;
+    if inode.metadata()?.file_type != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    process::set_current_dir(path).map_err(|_| FsError::InvalidArgument)
+}
@@ -0,0 +1,194 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use super::{
+    DirEntry, FileType, FsError, FsResult, Inode, Metadata, Mount, O_ACCMODE, O_APPEND, O_RDONLY,
+    O_WRONLY,
+};
+use crate::file::{File, FileResult};
+use crate::sync::Mutex;
+
+/// Where `seek` counts from
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// `seek` whence values of the syscall
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+impl SeekFrom {
+    pub fn from_whence(whence: u64, offset: i64) -> Option<SeekFrom> {
+        match whence {
+            SEEK_SET if offset >= 0 => Some(SeekFrom::Start(offset as u64)),
+            SEEK_CUR => Some(SeekFrom::Current(offset)),
+            SEEK_END => Some(SeekFrom::End(offset)),
+            _ => None,
+        }
+    }
+}
+
+/// A file or directory opened with `fs::open`. Descriptors duplicated by
+/// `fork` share the position.
+pub struct OpenFile {
+    path: String,
+    /// Keeps the filesystem mounted
+    _mount: Arc<Mount>,
+    inode: Arc<dyn Inode>,
+    flags: u32,
+    /// The byte offset, or the index of the next entry of a directory.
+    /// Held during the whole operation so that concurrent reads and
+    /// writes don't use the same offset.
+    position: Mutex<u64>,
+}
+
+impl OpenFile {
+    pub(super) fn new(path: String, mount: Arc<Mount>, inode: Arc<dyn Inode>, flags: u32) -> OpenFile {
+        OpenFile {
+            path,
+            _mount: mount,
+            inode,
+            flags,
+            position: Mutex::new(0),
+        }
+    }
+
+    /// The normalized absolute path the file was opened with
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+
+    fn file_type(&self) -> FsResult<FileType> {
+        Ok(self.inode.metadata()?.file_type)
+    }
+
+    /// Reads from the current position and moves it past what was read
+    pub fn read(&self, buffer: &mut [u8]) -> FsResult<usize> {
+        if !self.is_readable() {
+            return Err(FsError::WrongMode);
+        }
+        match self.file_type()? {
+            FileType::Directory => return Err(FsError::IsADirectory),
+            // A read that waits for input mustn't hold up writes
+            FileType::CharDevice => return self.inode.read_at(0, buffer),
+            _ => {}
+        }
+
+        let mut position = self.position.lock();
+        let count = self.inode.read_at(*position, buffer)?;
+        *position += count as u64;
+        Ok(count)
+    }
+
+    /// Writes at the current position, or at the end with `O_APPEND`, and
+    /// moves the position past what was written
+    pub fn write(&self, buffer: &[u8]) -> FsResult<usize> {
+        if !self.is_writable() {
+            return Err(FsError::WrongMode);
+        }
+        if self.file_type()? == FileType::CharDevice {
+            return self.inode.write_at(0, buffer);
+        }
+
+        let mut position = self.position.lock();
+        if self.flags & O_APPEND != 0 {
+            *position = self.inode.metadata()?.size;
+        }
+        let count = self.inode.write_at(*position, buffer)?;
+        *position += count as u64;
+        Ok(count)
+    }
+
+    /// Moves the position, it may go past the end of the file. Returns the
+    /// new position.
+    pub fn seek(&self, from: SeekFrom) -> FsResult<u64> {
+        let mut position = self.position.lock();
+        let (base, offset) = match from {
+            SeekFrom::Start(offset) => {
+                *position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (*position, offset),
+            SeekFrom::End(offset) => (self.inode.metadata()?.size, offset),
+        };
+
+        let new = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+        *position = new.ok_or(FsError::InvalidArgument)?;
+        Ok(*position)
+    }
+
+    pub fn metadata(&self) -> FsResult<Metadata> {
+        self.inode.metadata()
+    }
+
+    /// Changes the size of a regular file opened for writing, the position
+    /// stays where it is
+    pub fn set_len(&self, size: u64) -> FsResult<()> {
+        if !self.is_writable() {
+            return Err(FsError::WrongMode);
+        }
+        match self.file_type()? {
+            FileType::Regular => self.inode.truncate(size),
+            FileType::Directory => Err(FsError::IsADirectory),
+            FileType::Symlink | FileType::CharDevice | FileType::BlockDevice => {
+                Err(FsError::InvalidArgument)
+            }
+        }
+    }
+
+    /// Returns the next entry of a directory, `None` after the last one
+    pub fn read_dir(&self) -> FsResult<Option<DirEntry>> {
+        let mut position = self.position.lock();
+        let entry = self.inode.read_dir(*position as usize)?;
+        if entry.is_some() {
+            *position += 1;
+        }
+        Ok(entry)
+    }
+}
+
+impl File for OpenFile {
+    fn read(&self, buffer: &mut [u8]) -> FileResult<usize> {
+        Ok(OpenFile::read(self, buffer)?)
+    }
+
+    fn write(&self, buffer: &[u8]) -> FileResult<usize> {
+        Ok(OpenFile::write(self, buffer)?)
+    }
+
+    fn seek(&self, from: SeekFrom) -> FileResult<u64> {
+        Ok(OpenFile::seek(self, from)?)
+    }
+
+    fn metadata(&self) -> FileResult<Metadata> {
+        Ok(OpenFile::metadata(self)?)
+    }
+
+    fn set_len(&self, size: u64) -> FileResult<()> {
+        Ok(OpenFile::set_len(self, size)?)
+    }
+
+    fn read_dir(&self) -> FileResult<Option<DirEntry>> {
+        Ok(OpenFile::read_dir(self)?)
+    }
+}
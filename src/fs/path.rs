@@ -0,0 +1,87 @@
+//! Path strings.
+//!
+//! Paths are `/` separated, absolute ones start with a `/`. The VFS works on
+//! normalized absolute paths: no empty, `.` or `..` components and no
+//! trailing `/`, except for the root `/` itself.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{FsError, FsResult};
+
+/// Longest path accepted
+pub const MAX_PATH: usize = 4096;
+/// Longest file name accepted
+pub const MAX_NAME: usize = 255;
+
+/// Turns `path` into a normalized absolute path, a relative one starts at
+/// `cwd` which has to be normalized. `..` of the root is the root.
+pub fn normalize(cwd: &str, path: &str) -> FsResult<String> {
+    if path.is_empty() {
+        return Err(FsError::NotFound);
+    }
+    if path.len() > MAX_PATH {
+        return Err(FsError::NameTooLong);
+    }
+
+    let mut names: Vec<&str> = Vec::new();
+    if !path.starts_with('/') {
+        names.extend(components(cwd));
+    }
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => {
+                names.pop();
+            }
+            name if name.len() > MAX_NAME => return Err(FsError::NameTooLong),
+            name => names.push(name),
+        }
+    }
+
+    if names.is_empty() {
+        return Ok(String::from("/"));
+    }
+    let mut normalized = String::new();
+    for name in names {
+        normalized.push('/');
+        normalized.push_str(name);
+    }
+    Ok(normalized)
+}
+
+/// The names in a path, from the root down
+pub fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty())
+}
+
+/// Splits a normalized path into its parent directory and its last name,
+/// `None` for the root
+pub fn split_last(path: &str) -> Option<(&str, &str)> {
+    let index = path.rfind('/')?;
+    let name = &path[index + 1..];
+    if name.is_empty() {
+        return None;
+    }
+
+    let parent = if index == 0 { "/" } else { &path[..index] };
+    Some((parent, name))
+}
+
+/// Returns the rest of `path` if `ancestor` is `path` itself or one of the
+/// directories above it, both have to be normalized
+pub fn strip_ancestor<'a>(path: &'a str, ancestor: &str) -> Option<&'a str> {
+    if ancestor == "/" {
+        return Some(path);
+    }
+    if !path.starts_with(ancestor) {
+        return None;
+    }
+
+    let rest = &path[ancestor.len()..];
+    if rest.is_empty() || rest.starts_with('/') {
+        Some(rest)
+    } else {
+        None
+    }
+}
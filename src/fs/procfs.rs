@@ -0,0 +1,328 @@
+//! Kernel state as text files.
+//!
+//! Each file of the filesystem mounted on `/proc` has a function that
+//! generates its contents. They're generated when the file is looked up,
+//! so an open file keeps reading what was there when it was opened. Other
+//! parts of the kernel add their own files with `register`.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::Write;
+
+use lazy_static::lazy_static;
+
+use super::path;
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, Metadata, Timestamp};
+use crate::allocator;
+use crate::block;
+use crate::interrupts;
+use crate::lock_class;
+use crate::log;
+use crate::memory;
+use crate::process;
+use crate::ps2;
+use crate::sync::IrqSpinLock;
+use crate::task;
+use crate::time;
+
+const ROOT_INODE: u64 = 1;
+
+/// Returns the contents of a file
+pub type Generator = fn() -> String;
+
+struct Registry {
+    /// Inode number and generator by name
+    files: BTreeMap<String, (u64, Generator)>,
+    next_inode: u64,
+}
+
+lazy_static! {
+    static ref REGISTRY: IrqSpinLock<Registry> = IrqSpinLock::with_class(
+        Registry {
+            files: BTreeMap::new(),
+            next_inode: ROOT_INODE + 1,
+        },
+        lock_class!("PROC_FILES")
+    );
+}
+
+/// Adds the file `/proc/<name>` with the contents `generator` returns
+pub fn register(name: &str, generator: Generator) -> FsResult<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(FsError::InvalidArgument);
+    }
+    if name.len() > path::MAX_NAME {
+        return Err(FsError::NameTooLong);
+    }
+
+    let mut registry = REGISTRY.lock();
+    if registry.files.contains_key(name) {
+        return Err(FsError::AlreadyExists);
+    }
+    let inode = registry.next_inode;
+    registry.next_inode += 1;
+    registry.files.insert(String::from(name), (inode, generator));
+    Ok(())
+}
+
+/// Removes the file `/proc/<name>`
+pub fn unregister(name: &str) -> FsResult<()> {
+    REGISTRY
+        .lock()
+        .files
+        .remove(name)
+        .map(|_| ())
+        .ok_or(FsError::NotFound)
+}
+
+pub struct ProcFs;
+
+impl ProcFs {
+    pub fn new() -> Arc<ProcFs> {
+        Arc::new(ProcFs)
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(ProcRoot)
+    }
+}
+
+struct ProcRoot;
+
+impl Inode for ProcRoot {
+    fn metadata(&self) -> FsResult<Metadata> {
+        Ok(Metadata {
+            inode: ROOT_INODE,
+            size: 0,
+            file_type: FileType::Directory,
+            links: 2,
+            accessed: 0,
+            modified: 0,
+            changed: 0,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let (inode, generator) = *REGISTRY.lock().files.get(name).ok_or(FsError::NotFound)?;
+        // Generators take other locks
+        let contents = generator();
+        Ok(Arc::new(ProcFile {
+            inode,
+            contents,
+            generated: time::uptime_ms(),
+        }))
+    }
+
+    fn read_dir(&self, index: usize) -> FsResult<Option<DirEntry>> {
+        let entry = REGISTRY
+            .lock()
+            .files
+            .iter()
+            .nth(index)
+            .map(|(name, &(inode, _))| DirEntry {
+                inode,
+                file_type: FileType::Regular,
+                name: name.clone(),
+            });
+        Ok(entry)
+    }
+}
+
+/// A snapshot of a file's contents
+struct ProcFile {
+    inode: u64,
+    contents: String,
+    generated: Timestamp,
+}
+
+impl Inode for ProcFile {
+    fn metadata(&self) -> FsResult<Metadata> {
+        Ok(Metadata {
+            inode: self.inode,
+            size: self.contents.len() as u64,
+            file_type: FileType::Regular,
+            links: 1,
+            accessed: self.generated,
+            modified: self.generated,
+            changed: self.generated,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let contents = self.contents.as_bytes();
+        if offset >= contents.len() as u64 {
+            return Ok(0);
+        }
+        let rest = &contents[offset as usize..];
+        let length = rest.len().min(buffer.len());
+        buffer[..length].copy_from_slice(&rest[..length]);
+        Ok(length)
+    }
+}
+
+/// Seconds since boot, with milliseconds
+fn uptime() -> String {
+    let ms = time::uptime_ms();
+    format!("{}.{:03}\n", ms / 1000, ms % 1000)
+}
+
+/// Frames and heap in use, in KiB
+fn meminfo() -> String {
+    let frames = memory::frame_stats();
+    let heap_used = allocator::heap_used();
+    let lines = [
+        ("MemTotal:", frames.total * 4),
+        ("MemUsed:", frames.allocated * 4),
+        ("MemFree:", frames.free() * 4),
+        ("MemShared:", frames.shared * 4),
+        ("HeapTotal:", allocator::HEAP_SIZE / 1024),
+        ("HeapUsed:", heap_used / 1024),
+        ("HeapFree:", (allocator::HEAP_SIZE - heap_used) / 1024),
+    ];
+
+    let mut text = String::new();
+    for (name, kib) in lines.iter() {
+        writeln!(text, "{:<11}{:>10} kB", name, kib).unwrap();
+    }
+    text
+}
+
+/// The regions of physical memory
+fn memmap() -> String {
+    let mut text = String::new();
+    for region in memory::memory_map() {
+        writeln!(
+            text,
+            "{:#012x}-{:#012x} {:?}",
+            region.range.start_addr(),
+            region.range.end_addr(),
+            region.region_type
+        )
+        .unwrap();
+    }
+    text
+}
+
+/// How often each vector was raised
+fn interrupts() -> String {
+    let mut text = format!("{:>6} {:>10}\n", "VECTOR", "COUNT");
+    for (vector, count) in interrupts::counts() {
+        writeln!(
+            text,
+            "{:>6} {:>10} {}",
+            vector,
+            count,
+            interrupts::vector_name(vector)
+        )
+        .unwrap();
+    }
+    text
+}
+
+fn tasks() -> String {
+    let mut text = format!("scheduler: {}\n", task::scheduler_name());
+    writeln!(
+        text,
+        "{:>4} {:<16} {:<9} {:>4} {:>10} {:>8}",
+        "TID", "NAME", "STATE", "NICE", "CPU(ms)", "SWITCHES"
+    )
+    .unwrap();
+    for info in task::task_list() {
+        writeln!(text, "{}", info).unwrap();
+    }
+    text
+}
+
+fn processes() -> String {
+    let mut text = format!(
+        "{:>4} {:>4} {:<16} {:<8} {:>5}\n",
+        "PID", "PPID", "NAME", "STATE", "FILES"
+    );
+    for info in process::process_list() {
+        writeln!(text, "{}", info).unwrap();
+    }
+    text
+}
+
+/// The devices on the PS/2 ports
+fn ps2() -> String {
+    let mut text = String::new();
+    for (port, device) in ps2::devices().iter().enumerate() {
+        match device {
+            Some(device) => writeln!(text, "port {}: {:?}", port + 1, device).unwrap(),
+            None => writeln!(text, "port {}: none", port + 1).unwrap(),
+        }
+    }
+    text
+}
+
+fn kernel_log() -> String {
+    String::from_utf8_lossy(&log::contents()).into_owned()
+}
+
+/// Where filesystems are mounted
+fn mounts() -> String {
+    let mut text = String::new();
+    for mount in super::mounts() {
+        writeln!(text, "{} {}", mount.path(), mount.filesystem().name()).unwrap();
+    }
+    text
+}
+
+/// What each disk did and what its cache holds
+fn diskstats() -> String {
+    let mut text = format!(
+        "{:<6} {:>8} {:>10} {:>8} {:>10} {:>8} {:>10} {:>10} {:>6} {:>6}\n",
+        "DISK", "READS", "BLKREAD", "WRITES", "BLKWRITE", "MERGED", "HITS", "MISSES", "CACHED",
+        "DIRTY"
+    );
+    for disk in block::disks() {
+        let stats = disk.stats();
+        writeln!(
+            text,
+            "{:<6} {:>8} {:>10} {:>8} {:>10} {:>8} {:>10} {:>10} {:>6} {:>6}",
+            disk.name(),
+            stats.reads,
+            stats.blocks_read,
+            stats.writes,
+            stats.blocks_written,
+            stats.merged,
+            stats.hits,
+            stats.misses,
+            disk.cached_blocks(),
+            disk.dirty_blocks()
+        )
+        .unwrap();
+    }
+    text
+}
+
+/// Registers the kernel's files and mounts procfs on `/proc`
+pub fn init() {
+    let files: [(&str, Generator); 10] = [
+        ("uptime", uptime),
+        ("meminfo", meminfo),
+        ("memmap", memmap),
+        ("interrupts", interrupts),
+        ("tasks", tasks),
+        ("processes", processes),
+        ("ps2", ps2),
+        ("log", kernel_log),
+        ("mounts", mounts),
+        ("diskstats", diskstats),
+    ];
+    for &(name, generator) in files.iter() {
+        register(name, generator).expect("Failed to register a file");
+    }
+
+    super::create_dir_all("/proc").expect("Failed to create /proc");
+    super::mount("/proc", ProcFs::new()).expect("Failed to mount procfs");
+}
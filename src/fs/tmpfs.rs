@@ -0,0 +1,475 @@
+//! In-memory filesystem.
+//!
+//! All nodes of a tmpfs live in one table keyed by inode number behind a
+//! single lock, so that moving an entry between directories is atomic. File
+//! contents are kept in frames from the frame allocator instead of the heap,
+//! pages that were never written are holes and read as zeros. A node is
+//! freed once no directory entry and no `Inode` handle refers to it anymore,
+//! so removed files stay readable while they're open.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+
+use x86_64::structures::paging::PhysFrame;
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsResult, Inode, Metadata, Timestamp};
+use crate::lock_class;
+use crate::memory;
+use crate::sync::IrqSpinLock;
+use crate::time;
+
+/// Largest file a tmpfs holds
+pub const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+const ROOT_INODE: u64 = 1;
+
+struct Node {
+    file_type: FileType,
+    links: u32,
+    /// `TmpInode`s referring to the node
+    handles: usize,
+    size: u64,
+    /// Contents of a regular file, `None` for holes
+    pages: Vec<Option<PhysFrame>>,
+    /// Entries of a directory
+    entries: BTreeMap<String, u64>,
+    /// Directory the entry of a directory is in, the root is its own parent
+    parent: u64,
+    accessed: Timestamp,
+    modified: Timestamp,
+    changed: Timestamp,
+}
+
+impl Node {
+    fn new(file_type: FileType, parent: u64) -> Node {
+        let now = time::uptime_ms();
+        Node {
+            file_type,
+            // A directory is linked from its parent and from itself
+            links: if file_type == FileType::Directory { 2 } else { 1 },
+            handles: 0,
+            size: 0,
+            pages: Vec::new(),
+            entries: BTreeMap::new(),
+            parent,
+            accessed: now,
+            modified: now,
+            changed: now,
+        }
+    }
+
+    fn touch(&mut self) {
+        let now = time::uptime_ms();
+        self.modified = now;
+        self.changed = now;
+    }
+}
+
+struct Tree {
+    nodes: BTreeMap<u64, Node>,
+    next_inode: u64,
+    /// Frames holding file contents
+    page_count: usize,
+}
+
+impl Tree {
+    fn node(&self, inode: u64) -> &Node {
+        self.nodes.get(&inode).expect("tmpfs node disappeared")
+    }
+
+    fn node_mut(&mut self, inode: u64) -> &mut Node {
+        self.nodes.get_mut(&inode).expect("tmpfs node disappeared")
+    }
+
+    fn directory(&self, inode: u64) -> FsResult<&Node> {
+        let node = self.node(inode);
+        if node.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(node)
+    }
+
+    fn regular(&mut self, inode: u64) -> FsResult<&mut Node> {
+        let node = self.node_mut(inode);
+        if node.file_type == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(node)
+    }
+
+    /// Frees the node if nothing refers to it anymore
+    fn release(&mut self, inode: u64) {
+        let node = self.node(inode);
+        if node.links > 0 || node.handles > 0 {
+            return;
+        }
+
+        let node = self.nodes.remove(&inode).expect("tmpfs node disappeared");
+        for frame in node.pages.into_iter().flatten() {
+            memory::deallocate_frame(frame);
+            self.page_count -= 1;
+        }
+    }
+
+    /// Drops the directory entry `name` of `directory` that refers to
+    /// `inode`, a directory loses both of its links
+    fn drop_entry(&mut self, directory: u64, name: &str, inode: u64) {
+        let removed_directory = self.node(inode).file_type == FileType::Directory;
+        let parent = self.node_mut(directory);
+        parent.entries.remove(name);
+        parent.touch();
+        if removed_directory {
+            parent.links -= 1;
+        }
+
+        let node = self.node_mut(inode);
+        node.links = if removed_directory { 0 } else { node.links - 1 };
+        node.changed = time::uptime_ms();
+        self.release(inode);
+    }
+
+    /// Returns `true` if `directory` is `ancestor` or below it
+    fn is_below(&self, mut directory: u64, ancestor: u64) -> bool {
+        loop {
+            if directory == ancestor {
+                return true;
+            }
+            if directory == ROOT_INODE {
+                return false;
+            }
+            directory = self.node(directory).parent;
+        }
+    }
+
+    fn read(&mut self, inode: u64, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let node = self.regular(inode)?;
+        node.accessed = time::uptime_ms();
+        if offset >= node.size {
+            return Ok(0);
+        }
+
+        let count = buffer.len().min((node.size - offset) as usize);
+        let mut done = 0;
+        while done < count {
+            let position = offset + done as u64;
+            let in_page = (position % PAGE_SIZE) as usize;
+            let chunk = (count - done).min(PAGE_SIZE as usize - in_page);
+            let target = &mut buffer[done..done + chunk];
+            match node.pages.get((position / PAGE_SIZE) as usize) {
+                Some(Some(frame)) => unsafe {
+                    let page = page_ptr(*frame).add(in_page);
+                    ptr::copy_nonoverlapping(page, target.as_mut_ptr(), chunk);
+                },
+                _ => {
+                    for byte in target.iter_mut() {
+                        *byte = 0;
+                    }
+                }
+            }
+            done += chunk;
+        }
+        Ok(count)
+    }
+
+    /// Writes as much as there are frames for, fails only if nothing could
+    /// be written
+    fn write(&mut self, inode: u64, offset: u64, buffer: &[u8]) -> FsResult<usize> {
+        let end = offset
+            .checked_add(buffer.len() as u64)
+            .ok_or(FsError::InvalidArgument)?;
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+
+        let mut allocated = 0;
+        let node = self.regular(inode)?;
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let index = (position / PAGE_SIZE) as usize;
+            let in_page = (position % PAGE_SIZE) as usize;
+            let chunk = (buffer.len() - done).min(PAGE_SIZE as usize - in_page);
+
+            if index >= node.pages.len() {
+                node.pages.resize(index + 1, None);
+            }
+            let frame = match node.pages[index] {
+                Some(frame) => frame,
+                None => match memory::allocate_zeroed_frame() {
+                    Some(frame) => {
+                        node.pages[index] = Some(frame);
+                        allocated += 1;
+                        frame
+                    }
+                    None => break,
+                },
+            };
+
+            unsafe {
+                let page = page_ptr(frame).add(in_page);
+                ptr::copy_nonoverlapping(buffer[done..].as_ptr(), page, chunk);
+            }
+            done += chunk;
+        }
+
+        if done == 0 && !buffer.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        node.size = node.size.max(offset + done as u64);
+        node.touch();
+        self.page_count += allocated;
+        Ok(done)
+    }
+
+    fn truncate(&mut self, inode: u64, size: u64) -> FsResult<()> {
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+
+        let node = self.regular(inode)?;
+        let kept = ((size + PAGE_SIZE - 1) / PAGE_SIZE) as usize;
+        let mut freed = 0;
+        if kept < node.pages.len() {
+            for frame in node.pages.drain(kept..).flatten() {
+                memory::deallocate_frame(frame);
+                freed += 1;
+            }
+        }
+
+        // Growing again has to read zeros after the old end
+        let in_page = (size % PAGE_SIZE) as usize;
+        if size < node.size && in_page != 0 {
+            if let Some(Some(frame)) = node.pages.get(kept - 1) {
+                unsafe {
+                    let page = page_ptr(*frame).add(in_page);
+                    ptr::write_bytes(page, 0, PAGE_SIZE as usize - in_page);
+                }
+            }
+        }
+
+        node.size = size;
+        node.touch();
+        self.page_count -= freed;
+        Ok(())
+    }
+}
+
+fn page_ptr(frame: PhysFrame) -> *mut u8 {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+pub struct TmpFs {
+    tree: Arc<IrqSpinLock<Tree>>,
+}
+
+impl TmpFs {
+    /// Creates an empty filesystem, only the root directory exists
+    pub fn new() -> Arc<TmpFs> {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT_INODE, Node::new(FileType::Directory, ROOT_INODE));
+        let tree = Tree {
+            nodes,
+            next_inode: ROOT_INODE + 1,
+            page_count: 0,
+        };
+
+        Arc::new(TmpFs {
+            tree: Arc::new(IrqSpinLock::with_class(tree, lock_class!("TMPFS"))),
+        })
+    }
+
+    /// Number of files and directories, including removed ones that are
+    /// still in use
+    pub fn inode_count(&self) -> usize {
+        self.tree.lock().nodes.len()
+    }
+
+    /// Bytes of memory used for file contents
+    pub fn used_bytes(&self) -> u64 {
+        self.tree.lock().page_count as u64 * PAGE_SIZE
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        TmpInode::new(&self.tree, &mut self.tree.lock(), ROOT_INODE)
+    }
+}
+
+/// A handle to a node, it keeps the node alive
+struct TmpInode {
+    tree: Arc<IrqSpinLock<Tree>>,
+    inode: u64,
+}
+
+impl TmpInode {
+    fn new(tree: &Arc<IrqSpinLock<Tree>>, locked: &mut Tree, inode: u64) -> Arc<dyn Inode> {
+        locked.node_mut(inode).handles += 1;
+        Arc::new(TmpInode {
+            tree: tree.clone(),
+            inode,
+        })
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        let mut tree = self.tree.lock();
+        tree.node_mut(self.inode).handles -= 1;
+        tree.release(self.inode);
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> FsResult<Metadata> {
+        let tree = self.tree.lock();
+        let node = tree.node(self.inode);
+        let size = match node.file_type {
+            FileType::Directory => node.entries.len() as u64,
+            _ => node.size,
+        };
+
+        Ok(Metadata {
+            inode: self.inode,
+            size,
+            file_type: node.file_type,
+            links: node.links,
+            accessed: node.accessed,
+            modified: node.modified,
+            changed: node.changed,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        self.tree.lock().read(self.inode, offset, buffer)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> FsResult<usize> {
+        self.tree.lock().write(self.inode, offset, buffer)
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        self.tree.lock().truncate(self.inode, size)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let mut tree = self.tree.lock();
+        let inode = *tree
+            .directory(self.inode)?
+            .entries
+            .get(name)
+            .ok_or(FsError::NotFound)?;
+        Ok(TmpInode::new(&self.tree, &mut tree, inode))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> FsResult<Arc<dyn Inode>> {
+        if file_type != FileType::Regular && file_type != FileType::Directory {
+            return Err(FsError::NotSupported);
+        }
+        let mut tree = self.tree.lock();
+        if tree.directory(self.inode)?.entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let inode = tree.next_inode;
+        tree.next_inode += 1;
+        tree.nodes.insert(inode, Node::new(file_type, self.inode));
+
+        let parent = tree.node_mut(self.inode);
+        parent.entries.insert(String::from(name), inode);
+        parent.touch();
+        if file_type == FileType::Directory {
+            parent.links += 1;
+        }
+        Ok(TmpInode::new(&self.tree, &mut tree, inode))
+    }
+
+    fn read_dir(&self, index: usize) -> FsResult<Option<DirEntry>> {
+        let tree = self.tree.lock();
+        let entry = tree.directory(self.inode)?.entries.iter().nth(index);
+        Ok(entry.map(|(name, &inode)| DirEntry {
+            inode,
+            file_type: tree.node(inode).file_type,
+            name: name.clone(),
+        }))
+    }
+
+    fn unlink(&self, name: &str) -> FsResult<()> {
+        let mut tree = self.tree.lock();
+        let inode = *tree
+            .directory(self.inode)?
+            .entries
+            .get(name)
+            .ok_or(FsError::NotFound)?;
+        if !tree.node(inode).entries.is_empty() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        tree.drop_entry(self.inode, name, inode);
+        Ok(())
+    }
+
+    fn rename(&self, name: &str, directory: &dyn Inode, new_name: &str) -> FsResult<()> {
+        // Taken before the lock, it locks the tree itself
+        let target_directory = directory.metadata()?.inode;
+
+        let mut tree = self.tree.lock();
+        let inode = *tree
+            .directory(self.inode)?
+            .entries
+            .get(name)
+            .ok_or(FsError::NotFound)?;
+        let replaced = tree
+            .directory(target_directory)?
+            .entries
+            .get(new_name)
+            .cloned();
+        if replaced == Some(inode) {
+            return Ok(());
+        }
+
+        let is_directory = tree.node(inode).file_type == FileType::Directory;
+        if is_directory && tree.is_below(target_directory, inode) {
+            return Err(FsError::InvalidArgument);
+        }
+        if let Some(replaced) = replaced {
+            let replaced_node = tree.node(replaced);
+            match (is_directory, replaced_node.file_type == FileType::Directory) {
+                (true, false) => return Err(FsError::NotADirectory),
+                (false, true) => return Err(FsError::IsADirectory),
+                _ if !replaced_node.entries.is_empty() => {
+                    return Err(FsError::DirectoryNotEmpty);
+                }
+                _ => {}
+            }
+            tree.drop_entry(target_directory, new_name, replaced);
+        }
+
+        let source = tree.node_mut(self.inode);
+        source.entries.remove(name);
+        source.touch();
+        if is_directory {
+            source.links -= 1;
+        }
+
+        let target = tree.node_mut(target_directory);
+        target.entries.insert(String::from(new_name), inode);
+        target.touch();
+        if is_directory {
+            target.links += 1;
+        }
+
+        let node = tree.node_mut(inode);
+        node.parent = target_directory;
+        node.changed = time::uptime_ms();
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use core::cell::UnsafeCell;
+
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -5,8 +7,17 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+// Present, writable, data segment. `x86_64` has no constructor for a kernel data segment
+const KERNEL_DATA_SEGMENT: u64 = (1 << 41) | (1 << 44) | (1 << 47);
+
+/// The TSS is updated on every context switch with the kernel stack of the
+/// next thread, which the CPU switches to when user code is interrupted
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             const STACK_SIZE:usize = 4096;
@@ -18,20 +29,36 @@ lazy_static! {
             stack_end	// x86 stacks grow downwards
         };
 
-        tss
+        // Used for ring 3 -> ring 0 transitions until a thread sets its own
+        tss.privilege_stack_table[0] = {
+            const STACK_SIZE:usize = 4096 * 4;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe{&STACK});
+            stack_start + STACK_SIZE
+        };
+
+        Tss(UnsafeCell::new(tss))
     };
 }
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
+        // The order of the next four is fixed by SYSCALL/SYSRET, see `syscall.rs`
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let data_selector = gdt.add_entry(Descriptor::UserSegment(KERNEL_DATA_SEGMENT));
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
 
         (
             gdt,
             Selectors {
                 code_selector,
+                data_selector,
+                user_code_selector,
+                user_data_selector,
                 tss_selector,
             },
         )
@@ -40,17 +67,47 @@ lazy_static! {
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
 pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::segmentation::{load_ds, load_es, load_ss, set_cs};
     use x86_64::instructions::tables::load_tss;
 
     GDT.0.load();
     unsafe {
         // Set CS because we just changed the GDT
         set_cs(GDT.1.code_selector);
+        load_ss(GDT.1.data_selector);
+        load_ds(GDT.1.data_selector);
+        load_es(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+pub fn kernel_code_selector() -> SegmentSelector {
+    GDT.1.code_selector
+}
+
+pub fn kernel_data_selector() -> SegmentSelector {
+    GDT.1.data_selector
+}
+
+/// Selectors for ring 3, their RPL is already set to 3
+pub fn user_code_selector() -> SegmentSelector {
+    GDT.1.user_code_selector
+}
+
+pub fn user_data_selector() -> SegmentSelector {
+    GDT.1.user_data_selector
+}
+
+/// Sets the stack the CPU switches to when an interrupt arrives in ring 3
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        (*TSS.0.get()).privilege_stack_table[0] = stack_top;
+    }
+}
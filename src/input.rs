@@ -0,0 +1,88 @@
+//! Bytes interrupt handlers receive from devices, queued until a device
+//! file reads them.
+
+use alloc::collections::VecDeque;
+
+use lazy_static::lazy_static;
+
+use crate::lock_class;
+use crate::signal;
+use crate::sync::{IrqSpinLock, WaitQueue};
+
+// Input is dropped when nobody reads it
+const CAPACITY: usize = 256;
+
+lazy_static! {
+    /// Scan codes of the PS/2 keyboard
+    pub static ref KEYBOARD: InputQueue = InputQueue::new();
+    /// Packets of the PS/2 mouse, 4 bytes each
+    pub static ref MOUSE: InputQueue = InputQueue::new();
+    /// Bytes received on the first serial port
+    pub static ref SERIAL: InputQueue = InputQueue::new();
+}
+
+pub struct InputQueue {
+    bytes: IrqSpinLock<VecDeque<u8>>,
+    waiters: WaitQueue,
+}
+
+impl InputQueue {
+    pub fn new() -> InputQueue {
+        InputQueue {
+            bytes: IrqSpinLock::with_class(VecDeque::new(), lock_class!("INPUT_QUEUE")),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Queues `bytes`, they're dropped as a whole if they don't fit
+    pub fn push(&self, bytes: &[u8]) {
+        {
+            let mut queue = self.bytes.lock();
+            if queue.len() + bytes.len() > CAPACITY {
+                return;
+            }
+            queue.extend(bytes.iter());
+        }
+
+        self.waiters.wake_all();
+    }
+
+    /// Blocks until there's input and reads as much of it as fits into
+    /// `buffer`. Returns `None` if a signal for the calling process came
+    /// first.
+    pub fn read(&self, buffer: &mut [u8]) -> Option<usize> {
+        if buffer.is_empty() {
+            return Some(0);
+        }
+
+        self.waiters.wait_until(|| {
+            let ready = !self.bytes.lock().is_empty();
+            ready || signal::interrupted()
+        });
+
+        let mut queue = self.bytes.lock();
+        if queue.is_empty() {
+            return None;
+        }
+        let count = buffer.len().min(queue.len());
+        for (byte, queued) in buffer.iter_mut().zip(queue.drain(..count)) {
+            *byte = queued;
+        }
+        Some(count)
+    }
+
+    /// Number of bytes waiting to be read
+    pub fn len(&self) -> usize {
+        self.bytes.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for InputQueue {
+    fn default() -> InputQueue {
+        InputQueue::new()
+    }
+}
@@ -1,14 +1,24 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
 use pic8259_simple::ChainedPics;
-use spin;
 use x86_64::structures::idt::PageFaultErrorCode;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::PrivilegeLevel;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
 
 use lazy_static::lazy_static;
 
+use crate::apic;
 use crate::gdt;
 use crate::interrupts::InterruptIndex::Timer;
+use crate::lock_class;
 use crate::print;
 use crate::println;
+use crate::signal;
+use crate::sync::IrqSpinLock;
+use crate::syscall::SyscallFrame;
+use crate::trap;
+use crate::user;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -19,12 +29,39 @@ lazy_static! {
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
 
-            idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+            idt[InterruptIndex::Timer.as_usize()].set_handler_fn(trap::timer_handler());
             idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+            idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
 
             idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
-            idt.page_fault.set_handler_fn(page_fault_handler);
+            idt[InterruptIndex::PrimaryAta.as_usize()]
+                .set_handler_fn(primary_ata_interrupt_handler);
+            idt[InterruptIndex::SecondaryAta.as_usize()]
+                .set_handler_fn(secondary_ata_interrupt_handler);
+            idt.page_fault.set_handler_fn(trap::page_fault_handler());
+        }
+        let shared: [(u8, HandlerFunc); 7] = [
+            (3, irq3_handler),
+            (5, irq5_handler),
+            (6, irq6_handler),
+            (7, irq7_handler),
+            (9, irq9_handler),
+            (10, irq10_handler),
+            (11, irq11_handler),
+        ];
+        for &(irq, handler) in shared.iter() {
+            idt[usize::from(PIC_1_OFFSET + irq)].set_handler_fn(handler);
         }
+        for (index, &handler) in DYNAMIC_HANDLERS.iter().enumerate() {
+            idt[usize::from(irq_vector(LEGACY_IRQS + index as u8))].set_handler_fn(handler);
+        }
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+        idt.divide_error.set_handler_fn(trap::divide_error_handler());
+        idt.general_protection_fault.set_handler_fn(trap::general_protection_fault_handler());
+        idt.invalid_opcode.set_handler_fn(trap::invalid_opcode_handler());
+        idt[trap::INT80_VECTOR as usize]
+            .set_handler_fn(trap::int80_handler())
+            .set_privilege_level(PrivilegeLevel::Ring3);
 
         idt
     };
@@ -34,7 +71,79 @@ pub fn init_idt() {
     IDT.load();
 }
 
+static INTERRUPT_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Marks the code running until it's dropped as a hardware interrupt handler
+pub struct InterruptContext {
+    _private: (),
+}
+
+impl InterruptContext {
+    pub fn enter() -> InterruptContext {
+        INTERRUPT_DEPTH.fetch_add(1, Ordering::Relaxed);
+        InterruptContext { _private: () }
+    }
+}
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        INTERRUPT_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns `true` while a hardware interrupt handler is running
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH.load(Ordering::Relaxed) != 0
+}
+
+/// How often each vector was raised since boot
+static COUNTS: IrqSpinLock<[u64; 256]> =
+    IrqSpinLock::with_class([0; 256], lock_class!("INTERRUPT_COUNTS"));
+
+/// Counts an interrupt or exception raised on `vector`
+pub(crate) fn count(vector: u8) {
+    COUNTS.lock()[usize::from(vector)] += 1;
+}
+
+/// The vectors that were raised since boot and how often
+pub fn counts() -> Vec<(u8, u64)> {
+    let counts = *COUNTS.lock();
+    (0..=255u8)
+        .map(|vector| (vector, counts[usize::from(vector)]))
+        .filter(|&(_, count)| count != 0)
+        .collect()
+}
+
+/// What the kernel uses `vector` for, empty if it's unused
+pub fn vector_name(vector: u8) -> &'static str {
+    const KEYBOARD: u8 = InterruptIndex::Keyboard as u8;
+    const SERIAL: u8 = InterruptIndex::Serial as u8;
+    const MOUSE: u8 = InterruptIndex::Mouse as u8;
+    const PRIMARY_ATA: u8 = InterruptIndex::PrimaryAta as u8;
+    const SECONDARY_ATA: u8 = InterruptIndex::SecondaryAta as u8;
+
+    match vector {
+        0 => "divide error",
+        3 => "breakpoint",
+        6 => "invalid opcode",
+        8 => "double fault",
+        13 => "general protection fault",
+        14 => "page fault",
+        PIC_1_OFFSET => "timer",
+        KEYBOARD => "keyboard",
+        SERIAL => "serial",
+        MOUSE => "mouse",
+        PRIMARY_ATA => "primary ATA",
+        SECONDARY_ATA => "secondary ATA",
+        0x80 => "syscall",
+        apic::SPURIOUS_VECTOR => "spurious",
+        _ if is_dynamic_vector(vector) => "MSI",
+        _ => "",
+    }
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
+    count(3);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
@@ -43,16 +152,59 @@ extern "x86-interrupt" fn double_fault_handler(stack_frame: &mut InterruptStackF
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
+// The exceptions below come through the stubs in `trap`. Raised by user
+// code they become signals, in the kernel they're fatal.
+
+pub(crate) fn divide_error(frame: &SyscallFrame, from_user: bool) {
+    if from_user {
+        user::handle_exception("DIVIDE ERROR", signal::SIGFPE, frame, 0);
+        return;
+    }
+
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#x?}", frame);
+}
+
+pub(crate) fn invalid_opcode(frame: &SyscallFrame, from_user: bool) {
+    if from_user {
+        user::handle_exception("INVALID OPCODE", signal::SIGILL, frame, 0);
+        return;
+    }
+
+    panic!("EXCEPTION: INVALID OPCODE\n{:#x?}", frame);
+}
+
+pub(crate) fn general_protection_fault(frame: &SyscallFrame, error_code: u64, from_user: bool) {
+    if from_user {
+        user::handle_exception("GENERAL PROTECTION FAULT", signal::SIGSEGV, frame, error_code);
+        return;
+    }
+
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT, error code {:#x}\n{:#x?}",
+        error_code, frame
+    );
+}
+
+pub(crate) fn page_fault(frame: &SyscallFrame, error_code: u64, from_user: bool) {
     use crate::hlt_loop;
     use x86_64::registers::control::Cr2;
+
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    let write_to_present_page =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(write_to_present_page) && user::resolve_copy_on_write(Cr2::read()) {
+        return;
+    }
+
+    if from_user {
+        user::handle_exception("PAGE FAULT", signal::SIGSEGV, frame, error_code.bits());
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    println!("{:#x?}", frame);
     hlt_loop();
 }
 
@@ -61,15 +213,256 @@ pub const PIC_1_OFFSET: u8 = 32;
 // First empty interrupt number
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8; // First empty interrupt number
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: IrqSpinLock<ChainedPics> = IrqSpinLock::with_class(
+    unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) },
+    lock_class!("PICS"),
+);
+
+// Data ports of the PICs, writing them sets the interrupt masks
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xA1;
+// Line of the master PIC the slave PIC is connected to
+const CASCADE_IRQ: u8 = 2;
+
+/// Unmasks `irq` on the PICs, the firmware may have left the ones of some
+/// devices masked
+pub fn enable_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+
+    let _pics = PICS.lock();
+    let (port, line) = if irq < 8 {
+        (PIC_1_DATA, irq)
+    } else {
+        (PIC_2_DATA, irq - 8)
+    };
+    unsafe {
+        let mut data: Port<u8> = Port::new(port);
+        let mask = data.read();
+        data.write(mask & !(1 << line));
+        if irq >= 8 {
+            let mut master: Port<u8> = Port::new(PIC_1_DATA);
+            let mask = master.read();
+            master.write(mask & !(1 << CASCADE_IRQ));
+        }
+    }
+}
+
+/// Handlers a line can have, PCI devices may share one
+const HANDLERS_PER_IRQ: usize = 4;
+
+/// IRQs 0 to 15 are the lines of the PICs, the ones after them are handed
+/// out by `allocate_irq` to devices that interrupt with MSI
+pub const LEGACY_IRQS: u8 = 16;
+pub const DYNAMIC_IRQS: usize = 32;
+const IRQ_COUNT: usize = LEGACY_IRQS as usize + DYNAMIC_IRQS;
+/// Vector of the first IRQ `allocate_irq` hands out
+const FIRST_DYNAMIC_VECTOR: u8 = 0x50;
+
+struct IrqTable {
+    handlers: [[Option<fn()>; HANDLERS_PER_IRQ]; IRQ_COUNT],
+    /// Bit `n` is set while IRQ `LEGACY_IRQS + n` is allocated
+    allocated: u32,
+}
+
+/// Handlers of the lines that aren't wired to a fixed device and of the
+/// IRQs `allocate_irq` handed out
+static IRQ_HANDLERS: IrqSpinLock<IrqTable> = IrqSpinLock::with_class(
+    IrqTable {
+        handlers: [[None; HANDLERS_PER_IRQ]; IRQ_COUNT],
+        allocated: 0,
+    },
+    lock_class!("IRQ_HANDLERS"),
+);
+
+fn is_dynamic_vector(vector: u8) -> bool {
+    vector >= FIRST_DYNAMIC_VECTOR && usize::from(vector - FIRST_DYNAMIC_VECTOR) < DYNAMIC_IRQS
+}
+
+/// The vector `irq` is raised on, the one to put into an MSI message for
+/// the IRQs `allocate_irq` hands out
+pub fn irq_vector(irq: u8) -> u8 {
+    if irq < LEGACY_IRQS {
+        PIC_1_OFFSET + irq
+    } else {
+        FIRST_DYNAMIC_VECTOR + (irq - LEGACY_IRQS)
+    }
+}
+
+/// Takes an IRQ without a line for a device that interrupts with MSI, its
+/// handlers are registered with `register_irq_handler`. Returns `None` when
+/// they're all taken.
+pub fn allocate_irq() -> Option<u8> {
+    let mut table = IRQ_HANDLERS.lock();
+    let index = (0..DYNAMIC_IRQS).find(|index| table.allocated & 1 << index == 0)?;
+    table.allocated |= 1 << index;
+    Some(LEGACY_IRQS + index as u8)
+}
+
+/// Gives back an IRQ `allocate_irq` handed out and drops its handlers, the
+/// device mustn't interrupt on it anymore
+pub fn free_irq(irq: u8) {
+    if irq < LEGACY_IRQS || usize::from(irq) >= IRQ_COUNT {
+        return;
+    }
+    let mut table = IRQ_HANDLERS.lock();
+    table.allocated &= !(1 << (irq - LEGACY_IRQS));
+    table.handlers[usize::from(irq)] = [None; HANDLERS_PER_IRQ];
+}
+
+/// Calls `handler` on every interrupt of `irq` and unmasks the line.
+/// Returns `false` for the lines of the timer, the PS/2 devices, the serial
+/// port and the IDE channels, for IRQs `allocate_irq` didn't hand out and
+/// when the IRQ has too many handlers.
+pub fn register_irq_handler(irq: u8, handler: fn()) -> bool {
+    const SHARED: [u8; 7] = [3, 5, 6, 7, 9, 10, 11];
+
+    let legacy = irq < LEGACY_IRQS;
+    {
+        let mut table = IRQ_HANDLERS.lock();
+        let usable = if legacy {
+            SHARED.contains(&irq)
+        } else {
+            usize::from(irq) < IRQ_COUNT && table.allocated & 1 << (irq - LEGACY_IRQS) != 0
+        };
+        if !usable {
+            return false;
+        }
+        match table.handlers[usize::from(irq)].iter_mut().find(|slot| slot.is_none()) {
+            Some(free) => *free = Some(handler),
+            None => return false,
+        }
+    }
+
+    if legacy {
+        enable_irq(irq);
+    }
+    true
+}
+
+/// Runs the handlers registered for `irq`, each checks whether its device
+/// raised the interrupt. The lines of the PICs get their EOI there, MSI
+/// comes through the local APIC.
+fn irq_interrupt(irq: u8) {
+    let _context = InterruptContext::enter();
+    let vector = irq_vector(irq);
+    count(vector);
+    let handlers = IRQ_HANDLERS.lock().handlers[usize::from(irq)];
+    for handler in handlers.iter().filter_map(|&handler| handler) {
+        handler();
+    }
+    if irq < LEGACY_IRQS {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        }
+    } else {
+        apic::end_of_interrupt();
+    }
+}
+
+macro_rules! irq_handlers {
+    ($($name:ident => $irq:expr),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: &mut InterruptStackFrame) {
+                irq_interrupt($irq);
+            }
+        )*
+    };
+}
+
+irq_handlers!(
+    irq3_handler => 3,
+    irq5_handler => 5,
+    irq6_handler => 6,
+    irq7_handler => 7,
+    irq9_handler => 9,
+    irq10_handler => 10,
+    irq11_handler => 11
+);
+
+irq_handlers!(
+    irq16_handler => 16,
+    irq17_handler => 17,
+    irq18_handler => 18,
+    irq19_handler => 19,
+    irq20_handler => 20,
+    irq21_handler => 21,
+    irq22_handler => 22,
+    irq23_handler => 23,
+    irq24_handler => 24,
+    irq25_handler => 25,
+    irq26_handler => 26,
+    irq27_handler => 27,
+    irq28_handler => 28,
+    irq29_handler => 29,
+    irq30_handler => 30,
+    irq31_handler => 31,
+    irq32_handler => 32,
+    irq33_handler => 33,
+    irq34_handler => 34,
+    irq35_handler => 35,
+    irq36_handler => 36,
+    irq37_handler => 37,
+    irq38_handler => 38,
+    irq39_handler => 39,
+    irq40_handler => 40,
+    irq41_handler => 41,
+    irq42_handler => 42,
+    irq43_handler => 43,
+    irq44_handler => 44,
+    irq45_handler => 45,
+    irq46_handler => 46,
+    irq47_handler => 47
+);
+
+/// Entry points of the IRQs `allocate_irq` hands out, in order
+const DYNAMIC_HANDLERS: [HandlerFunc; DYNAMIC_IRQS] = [
+    irq16_handler,
+    irq17_handler,
+    irq18_handler,
+    irq19_handler,
+    irq20_handler,
+    irq21_handler,
+    irq22_handler,
+    irq23_handler,
+    irq24_handler,
+    irq25_handler,
+    irq26_handler,
+    irq27_handler,
+    irq28_handler,
+    irq29_handler,
+    irq30_handler,
+    irq31_handler,
+    irq32_handler,
+    irq33_handler,
+    irq34_handler,
+    irq35_handler,
+    irq36_handler,
+    irq37_handler,
+    irq38_handler,
+    irq39_handler,
+    irq40_handler,
+    irq41_handler,
+    irq42_handler,
+    irq43_handler,
+    irq44_handler,
+    irq45_handler,
+    irq46_handler,
+    irq47_handler,
+];
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    count(apic::SPURIOUS_VECTOR);
+}
 
 #[derive(Debug, Copy, Clone)]
 #[repr(u8)]
 enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard = PIC_1_OFFSET + 1,
+    Serial = PIC_1_OFFSET + 4,
     Mouse = PIC_1_OFFSET + 12,
+    PrimaryAta = PIC_1_OFFSET + 14,
+    SecondaryAta = PIC_1_OFFSET + 15,
 }
 
 impl InterruptIndex {
@@ -82,32 +475,61 @@ impl InterruptIndex {
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+pub(crate) fn timer_interrupt() {
     //    print!(".");
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(Timer.as_u8());
+    {
+        let _context = InterruptContext::enter();
+        crate::time::tick();
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(Timer.as_u8());
+        }
     }
+
+    // May switch to another thread, so it has to come after the EOI
+    crate::task::timer_tick();
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
-    use spin::Mutex;
+    use pc_keyboard::{layouts, DecodedKey, KeyCode, KeyState, Keyboard, ScancodeSet1};
     use x86_64::instructions::port::Port;
 
+    // The keyboard doesn't tell us about the modifiers it tracks
+    static CONTROL: AtomicBool = AtomicBool::new(false);
+
     lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
+        static ref KEYBOARD: IrqSpinLock<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+            IrqSpinLock::with_class(
+                Keyboard::new(layouts::Us104Key, ScancodeSet1),
+                lock_class!("KEYBOARD")
+            );
     }
 
+    let _context = InterruptContext::enter();
+    count(InterruptIndex::Keyboard.as_u8());
+
     let keyboard: &mut Keyboard<layouts::Us104Key, ScancodeSet1> = &mut KEYBOARD.lock();
     // Data port of PS/2 controller https://wiki.osdev.org/%228042%22_PS/2_Controller
     let mut keyboard_port = Port::new(0x60);
     let scan_code = unsafe { keyboard_port.read() };
+    crate::input::KEYBOARD.push(&[scan_code]);
 
     if let Ok(Some(key_event)) = keyboard.add_byte(scan_code) {
+        let code = key_event.code;
+        if code == KeyCode::ControlLeft || code == KeyCode::ControlRight {
+            CONTROL.store(key_event.state == KeyState::Down, Ordering::Relaxed);
+        }
+        let ctrl_c = code == KeyCode::C && CONTROL.load(Ordering::Relaxed);
+
         if let Some(key) = keyboard.process_keyevent(key_event) {
             match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
+                DecodedKey::Unicode(_) if ctrl_c => {
+                    print!("^C");
+                    crate::console::interrupt();
+                }
+                DecodedKey::Unicode(character) => {
+                    print!("{}", character);
+                    crate::console::push_input(character);
+                }
                 DecodedKey::RawKey(key) => print!("{:?}", key),
             }
         }
@@ -121,20 +543,27 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut Interrup
 
 extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
     use crate::mouse::Mouse;
-    use spin::Mutex;
     use x86_64::instructions::port::Port;
     let mut mouse_port = Port::new(0x60);
 
     lazy_static! {
-        static ref MOUSE: Mutex<Mouse> = Mutex::new(Mouse::new());
+        static ref MOUSE: IrqSpinLock<Mouse> =
+            IrqSpinLock::with_class(Mouse::new(), lock_class!("MOUSE"));
     }
 
+    let _context = InterruptContext::enter();
+    count(InterruptIndex::Mouse.as_u8());
+
     let mut packet = [0 as u8; 4];
     for i in 0..4 {
         let byte = unsafe { mouse_port.read() };
         packet[i] = byte;
     }
 
+    // Packets without the bit that's always set are garbage
+    if packet[0] & 0x8 != 0 {
+        crate::input::MOUSE.push(&packet);
+    }
     let mouse: &mut Mouse = &mut MOUSE.lock();
     mouse.add_standard_packet(packet);
     println!("{:?}", mouse.get_position());
@@ -143,3 +572,30 @@ extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: &mut InterruptSt
         pics.notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
     }
 }
+
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _context = InterruptContext::enter();
+    count(InterruptIndex::Serial.as_u8());
+    crate::serial::receive();
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Serial.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn primary_ata_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _context = InterruptContext::enter();
+    count(InterruptIndex::PrimaryAta.as_u8());
+    crate::block::ata::interrupt(0);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::PrimaryAta.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _context = InterruptContext::enter();
+    count(InterruptIndex::SecondaryAta.as_u8());
+    crate::block::ata::interrupt(1);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::SecondaryAta.as_u8());
+    }
+}
@@ -6,25 +6,55 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(global_asm)]
+
+extern crate alloc;
 
 use core::panic::PanicInfo;
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
 
+pub mod acpi;
+pub mod address_space;
+pub mod allocator;
+pub mod apic;
+pub mod block;
+pub mod channel;
+pub mod console;
+pub mod elf;
+pub mod file;
+pub mod fs;
 pub mod gdt;
+pub mod input;
 pub mod interrupts;
+pub mod log;
 pub mod memory;
 pub mod misc;
 pub mod mouse;
+pub mod pci;
+pub mod pipe;
+pub mod process;
+pub mod programs;
 pub mod ps2;
 pub mod serial;
+pub mod shm;
+pub mod signal;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod time;
+pub mod trap;
+pub mod user;
 pub mod vga_driver;
 
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
+    syscall::init();
     unsafe { interrupts::PICS.lock().initialize() };
     ps2::init();
+    serial::init();
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -50,6 +80,11 @@ pub fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
 }
 
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    panic!("allocation error: {:?}", layout)
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
@@ -71,9 +106,15 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo xtest`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     // like before
     init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
     test_main();
     hlt_loop();
 }
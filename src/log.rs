@@ -0,0 +1,58 @@
+//! The kernel log: the last bytes printed to the screen, kept so that they
+//! can be read back after they scrolled off.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::lock_class;
+use crate::sync::IrqSpinLock;
+
+/// Older output is dropped when the log is full
+const SIZE: usize = 16 * 1024;
+
+struct LogBuffer {
+    bytes: [u8; SIZE],
+    /// Index of the oldest byte
+    start: usize,
+    length: usize,
+}
+
+impl fmt::Write for LogBuffer {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        for &byte in text.as_bytes() {
+            self.bytes[(self.start + self.length) % SIZE] = byte;
+            if self.length == SIZE {
+                self.start = (self.start + 1) % SIZE;
+            } else {
+                self.length += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+static LOG: IrqSpinLock<LogBuffer> = IrqSpinLock::with_class(
+    LogBuffer {
+        bytes: [0; SIZE],
+        start: 0,
+        length: 0,
+    },
+    lock_class!("LOG"),
+);
+
+/// Appends formatted text to the log
+pub fn write(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    LOG.lock().write_fmt(args).unwrap();
+}
+
+/// What's in the log, oldest first
+pub fn contents() -> Vec<u8> {
+    let log = LOG.lock();
+    let end = (log.start + log.length).min(SIZE);
+    let mut contents = Vec::with_capacity(log.length);
+    contents.extend_from_slice(&log.bytes[log.start..end]);
+    contents.extend_from_slice(&log.bytes[..log.length - (end - log.start)]);
+    contents
+}
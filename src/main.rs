@@ -31,6 +31,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut frame_allocator =
         unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
+    ham_dos::allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("Heap initialization failed");
+    ham_dos::task::init();
+
     // The create example mapping function maps the physical address 0xb8000 to the given
     // virtual address
     const VGA_ADDRESS: u64 = 0xb8000;
@@ -46,6 +50,21 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
             .write(0x_0000_0000_0000_f021 | background);
     }
 
+    // Everything else allocates frames through the global frame allocator
+    memory::init_frame_allocator(frame_allocator);
+    ham_dos::address_space::init();
+    ham_dos::apic::init();
+    ham_dos::fs::init();
+    ham_dos::fs::initrd::init();
+    ham_dos::fs::devfs::init();
+    ham_dos::pci::init();
+    ham_dos::block::ata::init();
+    ham_dos::block::ahci::init();
+    ham_dos::block::floppy::init();
+    ham_dos::block::init();
+    ham_dos::block::partition::init();
+    ham_dos::fs::procfs::init();
+
     // The custom test frameworks feature generates a main function that
     // calls test_runner, but this function is ignored because we use
     // the #[no_main] attribute and provide our own entry point.
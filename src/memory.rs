@@ -1,8 +1,33 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
 use x86_64::structures::paging::{FrameAllocator, Mapper, Page, Size4KiB};
 use x86_64::structures::paging::{MappedPageTable, MapperAllSizes, PageTable, PhysFrame};
 use x86_64::{PhysAddr, VirtAddr};
 
+use lazy_static::lazy_static;
+
+use crate::address_space::{self, MapError};
+use crate::lock_class;
+use crate::sync::IrqSpinLock;
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+static FRAME_ALLOCATOR: IrqSpinLock<Option<BootInfoFrameAllocator>> =
+    IrqSpinLock::with_class(None, lock_class!("FRAME_ALLOCATOR"));
+
+/// Kernel page tables are changed by one `map_mmio` at a time
+static MMIO: IrqSpinLock<()> = IrqSpinLock::with_class((), lock_class!("MMIO"));
+
+lazy_static! {
+    /// Reference counts of frames that are mapped more than once, e.g. after
+    /// `fork`. Frames that aren't in here have a single owner.
+    static ref FRAME_REFS: IrqSpinLock<BTreeMap<PhysFrame, usize>> =
+        IrqSpinLock::with_class(BTreeMap::new(), lock_class!("FRAME_REFS"));
+}
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -10,6 +35,12 @@ use x86_64::{PhysAddr, VirtAddr};
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: u64) -> impl MapperAllSizes {
+    use x86_64::registers::model_specific::{Efer, EferFlags};
+
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset, Ordering::Relaxed);
+    // User pages are mapped with NO_EXECUTE
+    Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE);
+
     let level_4_table = active_level_4_page_table(physical_memory_offset);
     let phys_to_virtual = move |frame: PhysFrame| -> *mut PageTable {
         let phys = frame.start_address().as_u64();
@@ -57,10 +88,165 @@ pub fn map_to_writable_page(
     map_to_result.expect("Failed to make page mapping").flush();
 }
 
+/// Translates a physical address to the virtual address it's mapped at
+/// by the bootloader (the whole physical memory is mapped)
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(phys.as_u64() + PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Maps `size` bytes of device memory at `phys` uncached, at the address
+/// `phys_to_virt` gives for it. Pages the bootloader mapped along with the
+/// rest of the physical memory stay as they are, the firmware makes device
+/// memory below 4 GiB uncached anyway.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapError> {
+    use x86_64::structures::paging::mapper::MapToError;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let _lock = MMIO.lock();
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    let level_4_table = unsafe { page_table_at(address_space::kernel_level_4_frame()) };
+    let phys_to_virtual = move |frame: PhysFrame| -> *mut PageTable {
+        VirtAddr::new(frame.start_address().as_u64() + offset).as_mut_ptr()
+    };
+    let mut mapper = unsafe { MappedPageTable::new(level_4_table, phys_to_virtual) };
+
+    let flags = Flags::PRESENT
+        | Flags::WRITABLE
+        | Flags::WRITE_THROUGH
+        | Flags::NO_CACHE
+        | Flags::NO_EXECUTE;
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::containing_address(phys + size.max(1) - 1u64);
+    for frame in PhysFrame::range_inclusive(first, last) {
+        let page = Page::containing_address(phys_to_virt(frame.start_address()));
+        if mapper.translate_addr(page.start_address()).is_some() {
+            continue;
+        }
+        match unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::FrameAllocationFailed) => return Err(MapError::FrameAllocationFailed),
+            Err(_) => return Err(MapError::PageAlreadyMapped),
+        }
+    }
+    Ok(phys_to_virt(phys))
+}
+
+/// Returns the page table stored in the given frame
+pub unsafe fn page_table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Makes the frame allocator available to the rest of the kernel through
+/// `allocate_frame`/`deallocate_frame`, the heap has to be initialized
+pub fn init_frame_allocator(frame_allocator: BootInfoFrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .and_then(|allocator| allocator.allocate_frame())
+}
+
+/// Allocates a frame and fills it with zeros
+pub fn allocate_zeroed_frame() -> Option<PhysFrame> {
+    let frame = allocate_frame()?;
+    unsafe {
+        let page: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        page.write_bytes(0, 4096);
+    }
+    Some(frame)
+}
+
+/// Drops a reference to a frame, it's freed when it was the last one
+pub fn deallocate_frame(frame: PhysFrame) {
+    {
+        let mut refs = FRAME_REFS.lock();
+        if let Some(count) = refs.get_mut(&frame) {
+            *count -= 1;
+            if *count == 1 {
+                refs.remove(&frame);
+            }
+            return;
+        }
+    }
+
+    if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+        allocator.deallocate_frame(frame);
+    }
+}
+
+/// Adds a reference to a frame, `deallocate_frame` has to be called once
+/// more before it's freed
+pub fn share_frame(frame: PhysFrame) {
+    *FRAME_REFS.lock().entry(frame).or_insert(1) += 1;
+}
+
+/// Returns how many times a frame is mapped or otherwise in use
+pub fn frame_ref_count(frame: PhysFrame) -> usize {
+    FRAME_REFS.lock().get(&frame).cloned().unwrap_or(1)
+}
+
+/// How the frames of usable memory are used
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FrameStats {
+    /// Usable frames in the memory map
+    pub total: usize,
+    /// Frames handed out and not given back, the heap included
+    pub allocated: usize,
+    /// Frames mapped more than once
+    pub shared: usize,
+}
+
+impl FrameStats {
+    pub fn free(&self) -> usize {
+        self.total - self.allocated
+    }
+}
+
+/// Counts the frames in use, all zero before `init_frame_allocator`
+pub fn frame_stats() -> FrameStats {
+    let (total, allocated) = match FRAME_ALLOCATOR.lock().as_ref() {
+        Some(allocator) => {
+            let total = allocator.usable_frames().count();
+            (total, allocator.next.min(total) - allocator.free_frames.len())
+        }
+        None => (0, 0),
+    };
+
+    FrameStats {
+        total,
+        allocated,
+        shared: FRAME_REFS.lock().len(),
+    }
+}
+
+/// The regions of physical memory the bootloader found, empty before
+/// `init_frame_allocator`
+pub fn memory_map() -> Vec<MemoryRegion> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map_or(Vec::new(), |allocator| allocator.memory_map.iter().cloned().collect())
+}
+
+/// Frame allocator handle that can be passed to the `x86_64` mapping
+/// functions, it allocates from the global frame allocator
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate_frame()
+    }
+}
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    /// Frames that were handed out and given back
+    free_frames: Vec<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
@@ -73,9 +259,15 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_frames: Vec::new(),
         }
     }
 
+    /// Returns a frame to the allocator, the heap has to be initialized
+    pub fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free_frames.push(frame);
+    }
+
     /// Returns an iterator over the usable frames specified in the memory map.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         let regions = self.memory_map.iter();
@@ -93,6 +285,10 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_frames.pop() {
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
@@ -0,0 +1,207 @@
+//! What the configuration space header tells about a function.
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{Address, BAR0, COMMAND, COMMAND_IO_SPACE, COMMAND_MEMORY_SPACE};
+use super::{DEVICE_ID, HEADER_TYPE, INTERRUPT_LINE, INTERRUPT_PIN, REVISION, VENDOR_ID};
+use crate::memory;
+
+/// BARs of a general device, bridges only have the first 2
+pub const BAR_COUNT: usize = 6;
+
+const HEADER_GENERAL: u8 = 0x00;
+const HEADER_BRIDGE: u8 = 0x01;
+
+// Low bits of a BAR
+const BAR_IO: u32 = 0x1;
+const BAR_TYPE: u32 = 0x6;
+const BAR_TYPE_64BIT: u32 = 0x4;
+const BAR_PREFETCHABLE: u32 = 0x8;
+
+/// A range of I/O ports or memory a device decodes
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Bar {
+    Io {
+        port: u16,
+        size: u32,
+    },
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// Takes the next BAR for the upper half of the address
+        is_64bit: bool,
+    },
+}
+
+impl Bar {
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Io { size, .. } => u64::from(size),
+            Bar::Memory { size, .. } => size,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// Class, subclass and programming interface
+    pub class: (u8, u8, u8),
+    pub revision: u8,
+    /// Without the multi-function bit
+    pub header_type: u8,
+    /// `None` for unused BARs and for the upper halves of 64-bit ones
+    pub bars: [Option<Bar>; BAR_COUNT],
+    pub interrupt_line: u8,
+    /// INTA# to INTD# as 1 to 4, 0 if the device doesn't interrupt
+    pub interrupt_pin: u8,
+}
+
+impl Device {
+    /// Reads the header of the function at `address` and sizes its BARs
+    pub fn read(address: Address) -> Device {
+        let header_type = address.read_u8(HEADER_TYPE) & 0x7F;
+        let mut device = Device {
+            address,
+            vendor_id: address.read_u16(VENDOR_ID),
+            device_id: address.read_u16(DEVICE_ID),
+            class: address.class(),
+            revision: address.read_u8(REVISION),
+            header_type,
+            bars: [None; BAR_COUNT],
+            interrupt_line: 0,
+            interrupt_pin: 0,
+        };
+
+        let bar_count = match header_type {
+            HEADER_GENERAL => BAR_COUNT,
+            HEADER_BRIDGE => 2,
+            _ => 0,
+        };
+        if header_type == HEADER_GENERAL || header_type == HEADER_BRIDGE {
+            device.interrupt_line = address.read_u8(INTERRUPT_LINE);
+            device.interrupt_pin = address.read_u8(INTERRUPT_PIN);
+        }
+
+        // The device mustn't decode the sizing patterns as addresses
+        let command = address.read_u16(COMMAND);
+        address.write_u16(COMMAND, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+        let mut index = 0;
+        while index < bar_count {
+            let (bar, slots) = size_bar(address, index, bar_count);
+            device.bars[index] = bar;
+            index += slots;
+        }
+        address.write_u16(COMMAND, command);
+        device
+    }
+
+    /// Maps the memory of a memory BAR uncached, `None` if it isn't one,
+    /// has no address or can't be mapped
+    pub fn map_bar(&self, index: usize) -> Option<VirtAddr> {
+        match self.bars.get(index) {
+            Some(&Some(Bar::Memory { address, size, .. })) if address != 0 => {
+                memory::map_mmio(PhysAddr::new(address), size).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// What kind of device it is, from the class code
+    pub fn class_name(&self) -> &'static str {
+        let (class, subclass, interface) = self.class;
+        let names = [
+            ((0x01, 0x00), "SCSI controller"),
+            ((0x01, 0x01), "IDE controller"),
+            ((0x01, 0x02), "floppy controller"),
+            ((0x01, 0x05), "ATA controller"),
+            ((0x01, 0x08), "NVMe controller"),
+            ((0x02, 0x00), "Ethernet controller"),
+            ((0x03, 0x00), "VGA controller"),
+            ((0x04, 0x01), "audio device"),
+            ((0x04, 0x03), "HD audio device"),
+            ((0x06, 0x00), "host bridge"),
+            ((0x06, 0x01), "ISA bridge"),
+            ((0x06, 0x04), "PCI bridge"),
+            ((0x07, 0x00), "serial controller"),
+            ((0x08, 0x00), "interrupt controller"),
+            ((0x0C, 0x05), "SMBus controller"),
+        ];
+        if (class, subclass) == (0x01, 0x06) {
+            return if interface == 0x01 {
+                "AHCI controller"
+            } else {
+                "SATA controller"
+            };
+        }
+        if (class, subclass) == (0x0C, 0x03) {
+            return match interface {
+                0x00 => "UHCI controller",
+                0x10 => "OHCI controller",
+                0x20 => "EHCI controller",
+                0x30 => "xHCI controller",
+                _ => "USB controller",
+            };
+        }
+        if let Some(&(_, name)) = names.iter().find(|&&(code, _)| code == (class, subclass)) {
+            return name;
+        }
+
+        match class {
+            0x01 => "mass storage controller",
+            0x02 => "network controller",
+            0x03 => "display controller",
+            0x04 => "multimedia device",
+            0x05 => "memory controller",
+            0x06 => "bridge",
+            0x07 => "communication controller",
+            0x08 => "system peripheral",
+            0x0C => "serial bus controller",
+            _ => "device",
+        }
+    }
+}
+
+/// Sizes the BAR at `index` by writing all ones and reading back which bits
+/// stuck, returns it and how many BARs it takes
+fn size_bar(address: Address, index: usize, bar_count: usize) -> (Option<Bar>, usize) {
+    let offset = BAR0 + index as u16 * 4;
+    let original = address.read(offset);
+    address.write(offset, !0);
+    let mask = address.read(offset);
+    address.write(offset, original);
+    if mask == 0 {
+        return (None, 1);
+    }
+
+    if original & BAR_IO != 0 {
+        // The upper 16 bits may read as 0 on I/O BARs
+        let size = (!(mask & 0xFFFC)).wrapping_add(1) & 0xFFFF;
+        let port = (original & 0xFFFC) as u16;
+        return (Some(Bar::Io { port, size }), 1);
+    }
+
+    let is_64bit = original & BAR_TYPE == BAR_TYPE_64BIT && index + 1 < bar_count;
+    let mut base = u64::from(original & !0xF);
+    let mut mask = u64::from(mask & !0xF) | 0xFFFF_FFFF_0000_0000;
+    if is_64bit {
+        let high = offset + 4;
+        let original_high = address.read(high);
+        address.write(high, !0);
+        let mask_high = address.read(high);
+        address.write(high, original_high);
+        base |= u64::from(original_high) << 32;
+        mask = mask & 0xFFFF_FFFF | u64::from(mask_high) << 32;
+    }
+
+    let bar = Bar::Memory {
+        address: base,
+        size: (!mask).wrapping_add(1),
+        prefetchable: original & BAR_PREFETCHABLE != 0,
+        is_64bit,
+    };
+    (Some(bar), if is_64bit { 2 } else { 1 })
+}
@@ -0,0 +1,378 @@
+//! The PCI bus.
+//!
+//! The configuration space of a function is reached through the memory
+//! mapped ECAM area if the ACPI MCFG table lists one for its bus, and
+//! through the I/O ports 0xCF8 and 0xCFC otherwise, which only reach the
+//! first 256 bytes. The buses are scanned once, on first use; `init` lists
+//! what was found.
+//!
+//! Drivers register with the IDs of the devices they handle and are
+//! handed each matching device no other driver took. They may have it
+//! interrupt through `msi` rather than on its shared pin.
+
+pub mod device;
+pub mod msi;
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr;
+
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+use lazy_static::lazy_static;
+
+pub use device::{Bar, Device};
+
+use crate::acpi;
+use crate::lock_class;
+use crate::memory;
+use crate::println;
+use crate::sync::IrqSpinLock;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+// Registers of the configuration space header
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION: u16 = 0x08;
+pub const CLASS: u16 = 0x08;
+pub const HEADER_TYPE: u16 = 0x0E;
+pub const BAR0: u16 = 0x10;
+pub const CAPABILITIES: u16 = 0x34;
+pub const INTERRUPT_LINE: u16 = 0x3C;
+pub const INTERRUPT_PIN: u16 = 0x3D;
+
+// Bits of the command register
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+/// Set in the status register if there's a list of capabilities
+pub const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// Configuration space of a function through the ports
+const PORT_CONFIG_SIZE: u16 = 256;
+/// Configuration space of a function through ECAM
+const ECAM_CONFIG_SIZE: u16 = 4096;
+/// Bytes of an MCFG entry, after the table header and 8 reserved bytes
+const MCFG_ENTRY_SIZE: usize = 16;
+const MCFG_ENTRIES: usize = acpi::HEADER_SIZE + 8;
+
+/// The address and data ports are used in pairs
+static CONFIG: IrqSpinLock<()> = IrqSpinLock::with_class((), lock_class!("PCI_CONFIG"));
+
+/// Buses whose configuration space is memory mapped, from the MCFG
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EcamRegion {
+    /// Physical address of the first bus
+    pub base: u64,
+    pub first_bus: u8,
+    pub last_bus: u8,
+    /// Where `base` is mapped
+    virt: u64,
+}
+
+lazy_static! {
+    static ref ECAM: Vec<EcamRegion> = ecam_regions();
+    static ref DEVICES: Vec<Device> = scan();
+    /// Devices a driver took, with its name
+    static ref CLAIMED: IrqSpinLock<BTreeMap<Address, &'static str>> =
+        IrqSpinLock::with_class(BTreeMap::new(), lock_class!("PCI_CLAIMED"));
+}
+
+/// Maps the areas the MCFG lists for segment group 0, the only one there's
+/// an `Address` for
+fn ecam_regions() -> Vec<EcamRegion> {
+    let mcfg = match acpi::find_table(b"MCFG") {
+        Some(mcfg) => mcfg,
+        None => return Vec::new(),
+    };
+
+    let mut regions = Vec::new();
+    for entry in mcfg[MCFG_ENTRIES..].chunks_exact(MCFG_ENTRY_SIZE) {
+        let base = acpi::read_u64(entry, 0);
+        let segment = u16::from_le_bytes([entry[8], entry[9]]);
+        let (first_bus, last_bus) = (entry[10], entry[11]);
+        if segment != 0 || last_bus < first_bus {
+            continue;
+        }
+
+        // 1 MiB per bus, from the start of the area which is at bus 0
+        let start = base + (u64::from(first_bus) << 20);
+        let size = u64::from(last_bus - first_bus + 1) << 20;
+        match memory::map_mmio(PhysAddr::new(start), size) {
+            Ok(virt) => regions.push(EcamRegion {
+                base,
+                first_bus,
+                last_bus,
+                virt: virt.as_u64() - (start - base),
+            }),
+            Err(error) => println!("pci: can't map ECAM at {:#x}: {:?}", start, error),
+        }
+    }
+    regions
+}
+
+/// A function of a device on a bus
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Address {
+    pub fn new(bus: u8, device: u8, function: u8) -> Address {
+        Address {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    /// Reads the aligned dword at `offset`, all ones past the end of the
+    /// configuration space
+    pub fn read(self, offset: u16) -> u32 {
+        if let Some(config) = self.ecam(offset) {
+            return unsafe { ptr::read_volatile(config) };
+        }
+        if offset >= PORT_CONFIG_SIZE {
+            return !0;
+        }
+
+        let _config = CONFIG.lock();
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::new(CONFIG_DATA).read()
+        }
+    }
+
+    /// Writes the aligned dword at `offset`, nothing past the end of the
+    /// configuration space
+    pub fn write(self, offset: u16, value: u32) {
+        if let Some(config) = self.ecam(offset) {
+            unsafe { ptr::write_volatile(config, value) };
+            return;
+        }
+        if offset >= PORT_CONFIG_SIZE {
+            return;
+        }
+
+        let _config = CONFIG.lock();
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    pub fn read_u16(self, offset: u16) -> u16 {
+        (self.read(offset & !3) >> (u32::from(offset & 2) * 8)) as u16
+    }
+
+    /// Writes a word, the other half of its dword is written back as it is
+    pub fn write_u16(self, offset: u16, value: u16) {
+        let shift = u32::from(offset & 2) * 8;
+        let dword = self.read(offset & !3) & !(0xFFFF << shift);
+        self.write(offset & !3, dword | u32::from(value) << shift);
+    }
+
+    pub fn read_u8(self, offset: u16) -> u8 {
+        (self.read(offset & !3) >> (u32::from(offset & 3) * 8)) as u8
+    }
+
+    /// Class, subclass and programming interface
+    pub fn class(self) -> (u8, u8, u8) {
+        let class = self.read(CLASS);
+        ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8)
+    }
+
+    /// Sets bits of the command register
+    pub fn enable(self, bits: u16) {
+        self.write_u16(COMMAND, self.read_u16(COMMAND) | bits);
+    }
+
+    /// The line of the PIC the device interrupts on
+    pub fn interrupt_line(self) -> u8 {
+        self.read_u8(INTERRUPT_LINE)
+    }
+
+    /// Offset of the first capability with `id`
+    pub fn find_capability(self, id: u8) -> Option<u16> {
+        if self.read_u16(STATUS) & STATUS_CAPABILITIES == 0 {
+            return None;
+        }
+        let mut offset = u16::from(self.read_u8(CAPABILITIES) & 0xFC);
+        // The list can't be longer than what fits into the header's space
+        for _ in 0..48 {
+            if offset == 0 {
+                break;
+            }
+            if self.read_u8(offset) == id {
+                return Some(offset);
+            }
+            offset = u16::from(self.read_u8(offset + 1) & 0xFC);
+        }
+        None
+    }
+
+    /// The register at `offset` in the ECAM area of the bus, if there's one
+    fn ecam(self, offset: u16) -> Option<*mut u32> {
+        if offset >= ECAM_CONFIG_SIZE {
+            return None;
+        }
+        let region = ECAM
+            .iter()
+            .find(|region| region.first_bus <= self.bus && self.bus <= region.last_bus)?;
+        let address = region.virt
+            + (u64::from(self.bus) << 20
+                | u64::from(self.device) << 15
+                | u64::from(self.function) << 12
+                | u64::from(offset & 0xFFC));
+        Some(address as *mut u32)
+    }
+
+    fn config_address(self, offset: u16) -> u32 {
+        1 << 31
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xFC)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Tries every address, returns the functions that answer in bus order
+fn scan() -> Vec<Device> {
+    let mut found = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            for function in 0..8 {
+                let address = Address::new(bus, device, function);
+                if address.read_u16(VENDOR_ID) == 0xFFFF {
+                    // Functions of a device that isn't there
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                found.push(Device::read(address));
+                // Only multi-function devices have more than function 0
+                if function == 0 && address.read_u8(HEADER_TYPE) & 0x80 == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    found
+}
+
+/// The functions on all buses
+pub fn devices() -> Vec<Device> {
+    DEVICES.clone()
+}
+
+/// The functions of class `class` in bus order
+pub fn find_class(class: (u8, u8, u8)) -> Vec<Address> {
+    DEVICES
+        .iter()
+        .filter(|device| device.class == class)
+        .map(|device| device.address)
+        .collect()
+}
+
+/// The ECAM areas in use, empty if configuration space goes through the
+/// ports
+pub fn ecam_regions_in_use() -> Vec<EcamRegion> {
+    ECAM.clone()
+}
+
+/// What a driver handles
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DeviceId {
+    /// Vendor and device ID
+    Id(u16, u16),
+    /// Class and subclass, with any programming interface
+    Class(u8, u8),
+    /// Class, subclass and programming interface
+    Interface(u8, u8, u8),
+}
+
+impl DeviceId {
+    pub fn matches(self, device: &Device) -> bool {
+        let (class, subclass, interface) = device.class;
+        match self {
+            DeviceId::Id(vendor, id) => device.vendor_id == vendor && device.device_id == id,
+            DeviceId::Class(c, s) => (class, subclass) == (c, s),
+            DeviceId::Interface(c, s, i) => device.class == (c, s, i),
+        }
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub ids: &'static [DeviceId],
+    /// Sets up a device, `false` if the driver can't handle it after all
+    pub probe: fn(&Device) -> bool,
+}
+
+/// Hands the driver every device it matches that no other driver took,
+/// returns how many it took
+pub fn register_driver(driver: &'static Driver) -> usize {
+    let mut taken = 0;
+    let matching = DEVICES
+        .iter()
+        .filter(|device| driver.ids.iter().any(|id| id.matches(device)));
+    for device in matching {
+        // Claimed before probing, which may sleep, so that no other
+        // driver gets the device in the meantime
+        {
+            let mut claimed = CLAIMED.lock();
+            if claimed.contains_key(&device.address) {
+                continue;
+            }
+            claimed.insert(device.address, driver.name);
+        }
+
+        if (driver.probe)(device) {
+            taken += 1;
+        } else {
+            CLAIMED.lock().remove(&device.address);
+        }
+    }
+    taken
+}
+
+/// Name of the driver that took the device at `address`
+pub fn driver_of(address: Address) -> Option<&'static str> {
+    CLAIMED.lock().get(&address).cloned()
+}
+
+/// Scans the buses and lists the devices
+pub fn init() {
+    for region in ECAM.iter() {
+        println!(
+            "pci: ECAM at {:#x} for buses {:02x} to {:02x}",
+            region.base, region.first_bus, region.last_bus
+        );
+    }
+    for device in DEVICES.iter() {
+        println!(
+            "pci: {} {:04x}:{:04x} {}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            device.class_name()
+        );
+    }
+}
@@ -0,0 +1,285 @@
+//! Message signaled interrupts.
+//!
+//! A device with an MSI or MSI-X capability interrupts by writing a message
+//! to the local APIC instead of asserting a pin it may share. Each message
+//! gets an IRQ of its own from `interrupts::allocate_irq`, whose handlers
+//! registered with `interrupts::register_irq_handler` are called when it
+//! arrives. MSI gives a function a single IRQ here; MSI-X gives it one per
+//! entry of a table behind one of its BARs. The INTx pin is turned off
+//! while either is on.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ptr;
+
+use lazy_static::lazy_static;
+
+use super::{Address, Device, COMMAND, COMMAND_INTERRUPT_DISABLE, COMMAND_MEMORY_SPACE};
+use crate::apic;
+use crate::interrupts;
+use crate::lock_class;
+use crate::sync::IrqSpinLock;
+
+const CAPABILITY_MSI: u8 = 0x05;
+const CAPABILITY_MSIX: u8 = 0x11;
+
+// Registers of the MSI capability, at offsets from it
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08;
+/// Data of a function with 32-bit addresses, 64-bit ones have it 4 later
+const MSI_DATA: u16 = 0x08;
+const MSI_ENABLE: u16 = 1 << 0;
+/// How many of the vectors the function asks for it may use, 1 << n
+const MSI_MULTIPLE_ENABLE: u16 = 0x7 << 4;
+const MSI_64BIT: u16 = 1 << 7;
+
+// Registers of the MSI-X capability, at offsets from it
+const MSIX_CONTROL: u16 = 0x02;
+/// BAR of the table in the low 3 bits, its offset into the BAR above them
+const MSIX_TABLE: u16 = 0x04;
+const MSIX_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+
+// An entry of the MSI-X table
+const MSIX_ENTRY_SIZE: usize = 16;
+const ENTRY_ADDRESS: usize = 0x0;
+const ENTRY_ADDRESS_HIGH: usize = 0x4;
+const ENTRY_DATA: usize = 0x8;
+const ENTRY_CONTROL: usize = 0xC;
+const ENTRY_MASKED: u32 = 1 << 0;
+
+/// Messages to this address go to the local APIC whose ID is in bits 12 to
+/// 19, fixed delivery in physical destination mode
+const MESSAGE_ADDRESS: u32 = 0xFEE0_0000;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MsiError {
+    /// The function has no MSI or MSI-X capability
+    NotSupported,
+    /// The local APIC isn't on, there's nowhere to send messages to
+    NoApic,
+    /// All IRQs are taken
+    NoIrqs,
+    /// The BAR of the MSI-X table isn't a memory BAR or can't be mapped
+    BadTable,
+    /// The function already has MSI or MSI-X on
+    AlreadyEnabled,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mode {
+    Msi,
+    Msix,
+}
+
+struct Enabled {
+    mode: Mode,
+    /// Empty while the function is being set up
+    irqs: Vec<u8>,
+}
+
+lazy_static! {
+    /// Functions with MSI or MSI-X on, or being set up for it
+    static ref ENABLED: IrqSpinLock<BTreeMap<Address, Enabled>> =
+        IrqSpinLock::with_class(BTreeMap::new(), lock_class!("MSI_ENABLED"));
+}
+
+/// Address and data of the message that raises `irq` on this CPU
+fn message(irq: u8) -> (u32, u32) {
+    let vector = interrupts::irq_vector(irq);
+    (MESSAGE_ADDRESS | u32::from(apic::id()) << 12, u32::from(vector))
+}
+
+/// Allocates an IRQ for each handler, gives them all back if one can't be
+/// had
+fn allocate_irqs(handlers: &[fn()]) -> Result<Vec<u8>, MsiError> {
+    let mut irqs = Vec::new();
+    for &handler in handlers {
+        match interrupts::allocate_irq() {
+            Some(irq) if interrupts::register_irq_handler(irq, handler) => irqs.push(irq),
+            allocated => {
+                allocated.into_iter().chain(irqs).for_each(interrupts::free_irq);
+                return Err(MsiError::NoIrqs);
+            }
+        }
+    }
+    Ok(irqs)
+}
+
+/// Claims the function for `mode` so that nothing else sets it up at the
+/// same time, `finish` or `unreserve` end the claim
+fn reserve(address: Address, mode: Mode) -> Result<(), MsiError> {
+    if !apic::is_enabled() {
+        return Err(MsiError::NoApic);
+    }
+    let mut enabled = ENABLED.lock();
+    if enabled.contains_key(&address) {
+        return Err(MsiError::AlreadyEnabled);
+    }
+    enabled.insert(address, Enabled { mode, irqs: Vec::new() });
+    Ok(())
+}
+
+fn unreserve(address: Address) {
+    ENABLED.lock().remove(&address);
+}
+
+fn finish(address: Address, irqs: &[u8]) {
+    if let Some(enabled) = ENABLED.lock().get_mut(&address) {
+        enabled.irqs = irqs.to_vec();
+    }
+}
+
+/// Has the function interrupt with an MSI message, `handler` is called for
+/// each. Returns the IRQ.
+pub fn enable_msi(address: Address, handler: fn()) -> Result<u8, MsiError> {
+    let capability = address
+        .find_capability(CAPABILITY_MSI)
+        .ok_or(MsiError::NotSupported)?;
+    reserve(address, Mode::Msi)?;
+    let irq = match allocate_irqs(&[handler]) {
+        Ok(irqs) => irqs[0],
+        Err(error) => {
+            unreserve(address);
+            return Err(error);
+        }
+    };
+
+    let control = address.read_u16(capability + MSI_CONTROL);
+    let (message_address, data) = message(irq);
+    address.write(capability + MSI_ADDRESS, message_address);
+    let data_offset = if control & MSI_64BIT != 0 {
+        address.write(capability + MSI_ADDRESS_HIGH, 0);
+        MSI_DATA + 4
+    } else {
+        MSI_DATA
+    };
+    address.write_u16(capability + data_offset, data as u16);
+
+    address.enable(COMMAND_INTERRUPT_DISABLE);
+    let control = control & !MSI_MULTIPLE_ENABLE | MSI_ENABLE;
+    address.write_u16(capability + MSI_CONTROL, control);
+    finish(address, &[irq]);
+    Ok(irq)
+}
+
+/// The MSI-X table of the function, mapped, and its number of entries
+fn msix_table(device: &Device, capability: u16) -> Result<(*mut u8, usize), MsiError> {
+    let address = device.address;
+    let entries = usize::from(address.read_u16(capability + MSIX_CONTROL) & MSIX_TABLE_SIZE) + 1;
+    let table = address.read(capability + MSIX_TABLE);
+    let bar = (table & 0x7) as usize;
+    let offset = (table & !0x7) as usize;
+
+    let base = device.map_bar(bar).ok_or(MsiError::BadTable)?;
+    let size = device.bars[bar].map_or(0, |bar| bar.size());
+    if (offset + entries * MSIX_ENTRY_SIZE) as u64 > size {
+        return Err(MsiError::BadTable);
+    }
+    Ok(((base.as_u64() as usize + offset) as *mut u8, entries))
+}
+
+fn write_entry(table: *mut u8, entry: usize, register: usize, value: u32) {
+    let offset = entry * MSIX_ENTRY_SIZE + register;
+    unsafe { ptr::write_volatile(table.add(offset) as *mut u32, value) }
+}
+
+/// Has the function interrupt with MSI-X messages, one for each handler
+/// from the first entry of its table on. The rest of the entries stay
+/// masked. Returns the IRQs in the order of the handlers.
+pub fn enable_msix(device: &Device, handlers: &[fn()]) -> Result<Vec<u8>, MsiError> {
+    let address = device.address;
+    let capability = address
+        .find_capability(CAPABILITY_MSIX)
+        .ok_or(MsiError::NotSupported)?;
+    reserve(address, Mode::Msix)?;
+    let set_up = msix_table(device, capability).and_then(|(table, entries)| {
+        if handlers.is_empty() || handlers.len() > entries {
+            return Err(MsiError::NotSupported);
+        }
+        Ok((table, entries, allocate_irqs(handlers)?))
+    });
+    let (table, entries, irqs) = match set_up {
+        Ok(set_up) => set_up,
+        Err(error) => {
+            unreserve(address);
+            return Err(error);
+        }
+    };
+
+    // The function mask keeps the entries quiet until they're all set up
+    address.enable(COMMAND_MEMORY_SPACE);
+    let control = address.read_u16(capability + MSIX_CONTROL);
+    address.write_u16(capability + MSIX_CONTROL, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+    for entry in 0..entries {
+        write_entry(table, entry, ENTRY_CONTROL, ENTRY_MASKED);
+    }
+    for (entry, &irq) in irqs.iter().enumerate() {
+        let (message_address, data) = message(irq);
+        write_entry(table, entry, ENTRY_ADDRESS, message_address);
+        write_entry(table, entry, ENTRY_ADDRESS_HIGH, 0);
+        write_entry(table, entry, ENTRY_DATA, data);
+        write_entry(table, entry, ENTRY_CONTROL, 0);
+    }
+
+    address.enable(COMMAND_INTERRUPT_DISABLE);
+    address.write_u16(capability + MSIX_CONTROL, control & !MSIX_FUNCTION_MASK | MSIX_ENABLE);
+    finish(address, &irqs);
+    Ok(irqs)
+}
+
+/// Sets the function up with MSI-X if it has it and with MSI otherwise,
+/// with a single IRQ. Returns the IRQ.
+pub fn enable(device: &Device, handler: fn()) -> Result<u8, MsiError> {
+    match enable_msix(device, &[handler]) {
+        Ok(irqs) => Ok(irqs[0]),
+        Err(MsiError::NotSupported) | Err(MsiError::BadTable) => {
+            enable_msi(device.address, handler)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Turns MSI or MSI-X off and gives the IRQs back, the function interrupts
+/// on its pin again
+pub fn disable(address: Address) {
+    let enabled = {
+        let mut enabled = ENABLED.lock();
+        // Not done being set up, whoever does it still owns the entry
+        if enabled.get(&address).map_or(true, |enabled| enabled.irqs.is_empty()) {
+            return;
+        }
+        enabled.remove(&address).unwrap()
+    };
+
+    let (capability, control_offset, enable) = match enabled.mode {
+        Mode::Msi => (CAPABILITY_MSI, MSI_CONTROL, MSI_ENABLE),
+        Mode::Msix => (CAPABILITY_MSIX, MSIX_CONTROL, MSIX_ENABLE),
+    };
+    if let Some(capability) = address.find_capability(capability) {
+        let control = address.read_u16(capability + control_offset);
+        address.write_u16(capability + control_offset, control & !enable);
+    }
+    let command = address.read_u16(COMMAND);
+    address.write_u16(COMMAND, command & !COMMAND_INTERRUPT_DISABLE);
+    enabled.irqs.into_iter().for_each(interrupts::free_irq);
+}
+
+/// How the function interrupts if MSI or MSI-X is on, and on which IRQs
+pub fn mode(address: Address) -> Option<(Mode, Vec<u8>)> {
+    ENABLED
+        .lock()
+        .get(&address)
+        .filter(|enabled| !enabled.irqs.is_empty())
+        .map(|enabled| (enabled.mode, enabled.irqs.clone()))
+}
+
+/// Whether the function has an MSI and an MSI-X capability
+pub fn capabilities(address: Address) -> (bool, bool) {
+    (
+        address.find_capability(CAPABILITY_MSI).is_some(),
+        address.find_capability(CAPABILITY_MSIX).is_some(),
+    )
+}
@@ -0,0 +1,159 @@
+//! Anonymous pipes.
+//!
+//! A pipe is a bounded byte buffer with a read end and a write end, both
+//! are files. Reads block while it's empty and return 0 (end of file) once
+//! the write end is closed, writes block while it's full and fail with
+//! `BrokenPipe` and `SIGPIPE` once the read end is closed. An end is closed
+//! when the last descriptor referring to it is.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+use crate::file::{File, FileResult};
+use crate::lock_class;
+use crate::signal;
+use crate::sync::{IrqSpinLock, WaitQueue};
+use crate::syscall::SyscallError;
+
+/// Bytes a pipe holds before writers block
+pub const PIPE_CAPACITY: usize = 4096;
+
+struct PipeBuffer {
+    bytes: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+struct Pipe {
+    buffer: IrqSpinLock<PipeBuffer>,
+    /// Woken up when there's something to read or the writer closed
+    readers: WaitQueue,
+    /// Woken up when there's room or the reader closed
+    writers: WaitQueue,
+}
+
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+/// Creates a pipe, returns its read and write end
+pub fn pipe() -> (Arc<PipeReader>, Arc<PipeWriter>) {
+    let pipe = Arc::new(Pipe {
+        buffer: IrqSpinLock::with_class(
+            PipeBuffer {
+                bytes: VecDeque::with_capacity(PIPE_CAPACITY),
+                reader_open: true,
+                writer_open: true,
+            },
+            lock_class!("PIPE"),
+        ),
+        readers: WaitQueue::new(),
+        writers: WaitQueue::new(),
+    });
+
+    (
+        Arc::new(PipeReader { pipe: pipe.clone() }),
+        Arc::new(PipeWriter { pipe }),
+    )
+}
+
+impl File for PipeReader {
+    /// Blocks until there's data, returns 0 at the end of file
+    fn read(&self, buffer: &mut [u8]) -> FileResult<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        let pipe = &self.pipe;
+        pipe.readers.wait_until(|| {
+            let ready = {
+                let state = pipe.buffer.lock();
+                !state.bytes.is_empty() || !state.writer_open
+            };
+            ready || signal::interrupted()
+        });
+
+        let count = {
+            let mut state = pipe.buffer.lock();
+            if state.bytes.is_empty() && state.writer_open {
+                return Err(SyscallError::Interrupted);
+            }
+
+            let mut count = 0;
+            while count < buffer.len() {
+                match state.bytes.pop_front() {
+                    Some(byte) => buffer[count] = byte,
+                    None => break,
+                }
+                count += 1;
+            }
+            count
+        };
+
+        pipe.writers.wake_all();
+        Ok(count)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.pipe.buffer.lock().reader_open = false;
+        self.pipe.writers.wake_all();
+    }
+}
+
+impl File for PipeWriter {
+    /// Blocks until everything is written, unless a signal comes first
+    fn write(&self, buffer: &[u8]) -> FileResult<usize> {
+        let pipe = &self.pipe;
+        let mut written = 0;
+        while written < buffer.len() {
+            pipe.writers.wait_until(|| {
+                let ready = {
+                    let state = pipe.buffer.lock();
+                    state.bytes.len() < PIPE_CAPACITY || !state.reader_open
+                };
+                ready || signal::interrupted()
+            });
+
+            let reader_open = {
+                let mut state = pipe.buffer.lock();
+                if state.reader_open && state.bytes.len() == PIPE_CAPACITY {
+                    // Interrupted, report what got through
+                    if written == 0 {
+                        return Err(SyscallError::Interrupted);
+                    }
+                    return Ok(written);
+                }
+
+                while state.reader_open
+                    && written < buffer.len()
+                    && state.bytes.len() < PIPE_CAPACITY
+                {
+                    state.bytes.push_back(buffer[written]);
+                    written += 1;
+                }
+                state.reader_open
+            };
+
+            if !reader_open {
+                signal::raise(signal::SIGPIPE);
+                return Err(SyscallError::BrokenPipe);
+            }
+            pipe.readers.wake_all();
+        }
+
+        Ok(written)
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.buffer.lock().writer_open = false;
+        self.pipe.readers.wake_all();
+    }
+}
@@ -0,0 +1,417 @@
+//! User processes.
+//!
+//! A process is a running program: an address space, a table of open files,
+//! a working directory and the thread executing it. Processes form a tree,
+//! when one exits it becomes a zombie until its parent collects the exit
+//! code with `wait`. Processes started from kernel threads have the kernel
+//! as their parent, any kernel thread may wait for them. Orphans are reaped
+//! as soon as they exit.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use x86_64::VirtAddr;
+
+use crate::address_space::AddressSpace;
+use crate::elf::{self, ElfError};
+use crate::file::{File, FileResult, FileTable};
+use crate::lock_class;
+use crate::println;
+use crate::signal::{self, Signal, SignalState};
+use crate::sync::{IrqSpinLock, WaitQueue};
+use crate::syscall::{SyscallError, SyscallFrame};
+use crate::task::{self, ThreadId};
+use crate::user;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Pid {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn from_u64(pid: u64) -> Pid {
+        Pid(pid)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProcessState {
+    Running,
+    /// Exited with the given code, waiting for the parent to collect it
+    Zombie(i32),
+}
+
+impl fmt::Display for ProcessState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ProcessState::Running => "running",
+            ProcessState::Zombie(_) => "zombie",
+        };
+        f.pad(name)
+    }
+}
+
+struct Process {
+    name: String,
+    /// `None` if the process belongs to the kernel
+    parent: Option<Pid>,
+    /// The parent exited, nobody is going to wait for the process
+    orphaned: bool,
+    state: ProcessState,
+    /// Dropped when the process exits
+    address_space: Option<Arc<AddressSpace>>,
+    files: FileTable,
+    /// Normalized absolute path
+    cwd: String,
+    thread: Option<ThreadId>,
+    signals: SignalState,
+}
+
+lazy_static! {
+    static ref PROCESSES: IrqSpinLock<BTreeMap<Pid, Process>> =
+        IrqSpinLock::with_class(BTreeMap::new(), lock_class!("PROCESSES"));
+    /// Woken up whenever a process exits, for `wait`
+    static ref CHILD_EXITED: WaitQueue = WaitQueue::new();
+}
+
+/// Returns the process of the running thread, `None` for kernel threads
+pub fn current_pid() -> Option<Pid> {
+    task::current_process()
+}
+
+/// Starts a process in the given address space with the registers in
+/// `context`, its parent is the calling process. It starts in the working
+/// directory of its parent.
+pub fn start(
+    name: &str,
+    space: Arc<AddressSpace>,
+    files: FileTable,
+    signals: SignalState,
+    context: SyscallFrame,
+) -> Pid {
+    let pid = Pid::new();
+    let cwd = current_dir();
+    PROCESSES.lock().insert(
+        pid,
+        Process {
+            name: String::from(name),
+            parent: current_pid(),
+            orphaned: false,
+            state: ProcessState::Running,
+            address_space: Some(space.clone()),
+            files,
+            cwd,
+            thread: None,
+            signals,
+        },
+    );
+
+    // Blocked until its id is recorded, a signal sent to the process before
+    // that would wake nothing
+    let thread = task::spawn_blocked(name, move || {
+        task::set_process(pid);
+        task::set_address_space(space);
+        user::return_to_user(&context);
+    });
+
+    if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+        process.thread = Some(thread);
+    }
+    task::wake_up(thread);
+    pid
+}
+
+/// Runs an ELF executable as a child of the calling process, with the
+/// console as stdin, stdout and stderr
+pub fn spawn(name: &str, data: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, ElfError> {
+    let (space, entry, stack_pointer) = elf::load_executable(data, argv, envp)?;
+    Ok(start(
+        name,
+        Arc::new(space),
+        FileTable::with_console(),
+        SignalState::new(),
+        user::initial_context(entry, stack_pointer),
+    ))
+}
+
+/// Creates a copy of the calling process that continues from the same
+/// syscall, it returns 0 in the child. Memory is copied on write, the open
+/// files are shared, signal handlers are inherited.
+pub fn fork(frame: &SyscallFrame) -> Result<Pid, SyscallError> {
+    let pid = current_pid().ok_or(SyscallError::InvalidArgument)?;
+    let (name, files, signals) = {
+        let processes = PROCESSES.lock();
+        let process = processes.get(&pid).ok_or(SyscallError::InvalidArgument)?;
+        (
+            process.name.clone(),
+            process.files.clone(),
+            process.signals.fork(),
+        )
+    };
+
+    let space = task::current_address_space().ok_or(SyscallError::InvalidArgument)?;
+    let child_space = space.fork().map_err(|_| SyscallError::OutOfMemory)?;
+
+    let mut context = frame.clone();
+    context.rax = 0;
+    Ok(start(&name, Arc::new(child_space), files, signals, context))
+}
+
+/// Replaces the program of the calling process, `frame` is changed to
+/// start it when the syscall returns. The open files stay open, signal
+/// handlers are reset.
+pub fn exec(
+    name: &str,
+    data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+    frame: &mut SyscallFrame,
+) -> Result<(), ElfError> {
+    let pid = current_pid().expect("Only processes can exec");
+    let (space, entry, stack_pointer) = elf::load_executable(data, argv, envp)?;
+    let space = Arc::new(space);
+
+    if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+        process.name = String::from(name);
+        process.address_space = Some(space.clone());
+        process.signals.exec();
+    }
+    // Frees the old address space, we're not going back there
+    task::set_address_space(space);
+
+    *frame = user::initial_context(entry, stack_pointer);
+    Ok(())
+}
+
+/// Terminates the calling process, its files are closed, its children are
+/// orphaned and its parent gets `SIGCHLD`
+pub fn exit(code: i32) -> ! {
+    let pid = current_pid().expect("Only processes can exit");
+    let (files, space) = {
+        let mut processes = PROCESSES.lock();
+
+        let orphans: Vec<Pid> = processes
+            .iter()
+            .filter(|(_, process)| process.parent == Some(pid))
+            .map(|(&child, _)| child)
+            .collect();
+        for child in orphans {
+            let zombie = match processes.get_mut(&child) {
+                Some(process) => {
+                    process.parent = None;
+                    process.orphaned = true;
+                    process.state != ProcessState::Running
+                }
+                None => false,
+            };
+            if zombie {
+                processes.remove(&child);
+            }
+        }
+
+        let process = processes.get_mut(&pid).expect("Current process is missing");
+        process.state = ProcessState::Zombie(code);
+        let files = mem::replace(&mut process.files, FileTable::new());
+        let space = process.address_space.take();
+        let parent = process.parent;
+        if process.orphaned {
+            processes.remove(&pid);
+        }
+
+        if let Some(parent) = parent.and_then(|parent| processes.get_mut(&parent)) {
+            parent.signals.raise(signal::SIGCHLD);
+        }
+        (files, space)
+    };
+
+    // Closing files may wake up other threads, so it's done without the lock
+    drop(files);
+    drop(space);
+    CHILD_EXITED.wake_all();
+    task::exit();
+}
+
+/// Reaps a zombie child of `parent`, `None` if the caller has to wait
+fn try_reap(parent: Option<Pid>, pid: Option<Pid>) -> Option<Result<(Pid, i32), SyscallError>> {
+    let mut processes = PROCESSES.lock();
+    let mut found = false;
+    let mut zombie = None;
+    for (&child, process) in processes.iter() {
+        if process.parent != parent
+            || process.orphaned
+            || pid.map_or(false, |pid| pid != child)
+        {
+            continue;
+        }
+
+        found = true;
+        if let ProcessState::Zombie(code) = process.state {
+            zombie = Some((child, code));
+            break;
+        }
+    }
+
+    if !found {
+        return Some(Err(SyscallError::NoChildren));
+    }
+
+    let (child, code) = zombie?;
+    processes.remove(&child);
+    Some(Ok((child, code)))
+}
+
+/// Blocks until a child of the calling process (or any process the kernel
+/// started, when called from a kernel thread) exits and reaps it. Waits for
+/// any child if `pid` is `None`. Returns the child and its exit code, a
+/// process killed by a signal exits with `128 + signal`.
+pub fn wait(pid: Option<Pid>) -> Result<(Pid, i32), SyscallError> {
+    let parent = current_pid();
+    let mut result = None;
+    CHILD_EXITED.wait_until(|| {
+        result = try_reap(parent, pid);
+        result.is_some() || signal::interrupted()
+    });
+    result.unwrap_or(Err(SyscallError::Interrupted))
+}
+
+pub fn parent_pid() -> Option<Pid> {
+    let pid = current_pid()?;
+    PROCESSES.lock().get(&pid)?.parent
+}
+
+/// Runs `f` with the file table of the calling process
+pub fn with_files<F, R>(f: F) -> FileResult<R>
+where
+    F: FnOnce(&mut FileTable) -> FileResult<R>,
+{
+    let pid = current_pid().ok_or(SyscallError::BadFileDescriptor)?;
+    let mut processes = PROCESSES.lock();
+    let process = processes
+        .get_mut(&pid)
+        .ok_or(SyscallError::BadFileDescriptor)?;
+    f(&mut process.files)
+}
+
+/// Returns an open file of the calling process
+pub fn file(fd: usize) -> FileResult<Arc<dyn File>> {
+    with_files(|files| files.get(fd))
+}
+
+/// Working directory of the calling process, kernel threads work in `/`
+pub fn current_dir() -> String {
+    current_pid()
+        .and_then(|pid| PROCESSES.lock().get(&pid).map(|process| process.cwd.clone()))
+        .unwrap_or_else(|| String::from("/"))
+}
+
+/// Changes the working directory of the calling process, `path` has to be
+/// normalized. Kernel threads can't change theirs.
+pub fn set_current_dir(path: String) -> Result<(), SyscallError> {
+    let pid = current_pid().ok_or(SyscallError::InvalidArgument)?;
+    let mut processes = PROCESSES.lock();
+    let process = processes
+        .get_mut(&pid)
+        .ok_or(SyscallError::InvalidArgument)?;
+    process.cwd = path;
+    Ok(())
+}
+
+/// Makes `signal` pending for `pid`. Its thread is woken up if it's blocked,
+/// so that a syscall waiting for something can return early.
+pub fn send_signal(pid: Pid, signal: Signal) -> Result<(), SyscallError> {
+    let thread = {
+        let mut processes = PROCESSES.lock();
+        let process = processes
+            .get_mut(&pid)
+            .ok_or(SyscallError::NoSuchProcess)?;
+        // Signal 0 only checks that the process exists, zombies ignore signals
+        if signal == 0 || process.state != ProcessState::Running {
+            return Ok(());
+        }
+
+        process.signals.raise(signal);
+        process.thread
+    };
+
+    if let Some(thread) = thread {
+        task::wake_up(thread);
+    }
+    Ok(())
+}
+
+/// Runs `f` with the signal state of the calling process, `None` for
+/// threads outside of a process
+pub fn with_signals<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut SignalState) -> R,
+{
+    let pid = current_pid()?;
+    let mut processes = PROCESSES.lock();
+    processes.get_mut(&pid).map(|process| f(&mut process.signals))
+}
+
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub state: ProcessState,
+    pub thread: Option<ThreadId>,
+    pub open_files: usize,
+}
+
+impl fmt::Display for ProcessInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The kernel is shown as parent 0
+        let parent = self.parent.map_or(0, Pid::as_u64);
+        write!(
+            f,
+            "{:>4} {:>4} {:<16} {:<8} {:>5}",
+            self.pid, parent, self.name, self.state, self.open_files
+        )
+    }
+}
+
+pub fn process_list() -> Vec<ProcessInfo> {
+    PROCESSES
+        .lock()
+        .iter()
+        .map(|(&pid, process)| ProcessInfo {
+            pid,
+            parent: process.parent,
+            name: process.name.clone(),
+            state: process.state,
+            thread: process.thread,
+            open_files: process.files.len(),
+        })
+        .collect()
+}
+
+/// What a shell's `ps` prints
+pub fn print_process_list() {
+    println!(
+        "{:>4} {:>4} {:<16} {:<8} {:>5}",
+        "PID", "PPID", "NAME", "STATE", "FILES"
+    );
+    for process in process_list() {
+        println!("{}", process);
+    }
+}
@@ -0,0 +1,15 @@
+# Divides by zero without a handler for SIGFPE, which terminates it
+
+.intel_syntax noprefix
+.global _start
+
+.text
+_start:
+    mov eax, 1
+    xor edx, edx
+    xor ecx, ecx
+    div rcx
+
+    xor edi, edi                    # exit(0), never reached
+    mov eax, 2
+    syscall
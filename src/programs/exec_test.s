@@ -0,0 +1,61 @@
+# Forks and replaces the child with `hello`, which exits with 40 + argc.
+# With an argument, it's the path of the program to run instead.
+# Exits with 0 if the child's exit code was right.
+
+.intel_syntax noprefix
+.global _start
+
+.text
+_start:
+    lea r12, [rip + path]
+    cmp qword ptr [rsp], 2          # argc
+    jb start_child
+    mov r12, [rsp + 16]             # argv[1]
+
+start_child:
+    mov eax, 9                      # fork()
+    syscall
+    test rax, rax
+    js fail
+    jz child
+
+    mov rdi, rax                    # wait(child, &status)
+    lea rsi, [rip + status]
+    mov eax, 6
+    syscall
+    cmp dword ptr [rip + status], 42
+    jne fail
+
+    xor edi, edi                    # exit(0)
+    mov eax, 2
+    syscall
+
+child:
+    mov rdi, r12                    # exec(path, argv, envp)
+    lea rsi, [rip + argv]
+    lea rdx, [rip + envp]
+    mov eax, 10
+    syscall
+
+fail:
+    mov edi, 1                      # exit(1)
+    mov eax, 2
+    syscall
+
+.data
+path:
+    .asciz "hello"
+first:
+    .asciz "first"
+environment:
+    .asciz "HOME=/"
+
+.align 8
+argv:
+    .quad path, first, 0
+envp:
+    .quad environment, 0
+
+.bss
+status:
+    .skip 8
@@ -0,0 +1,64 @@
+# Forks, the child changes its data, BSS and stack and exits with 7. The
+# parent waits for it and checks that its own memory is unchanged.
+# Exits with 0 on success.
+
+.intel_syntax noprefix
+.global _start
+
+.text
+_start:
+    mov qword ptr [rip + value], 1
+    push 5
+
+    mov eax, 9                      # fork()
+    syscall
+    test rax, rax
+    js fail
+    jz child
+
+    mov r12, rax
+    mov rdi, r12                    # wait(child, &status)
+    lea rsi, [rip + status]
+    mov eax, 6
+    syscall
+    cmp rax, r12
+    jne fail
+    cmp dword ptr [rip + status], 7
+    jne fail
+
+    cmp qword ptr [rip + value], 1
+    jne fail
+    cmp qword ptr [rip + buffer + 4096 * 3], 0
+    jne fail
+    pop rax
+    cmp rax, 5
+    jne fail
+
+    xor edi, edi                    # exit(0)
+    mov eax, 2
+    syscall
+
+child:
+    mov qword ptr [rip + value], 2
+    mov qword ptr [rip + buffer + 4096 * 3], 99
+    mov qword ptr [rsp], 6
+    cmp qword ptr [rip + value], 2
+    jne fail
+    mov edi, 7                      # exit(7)
+    mov eax, 2
+    syscall
+
+fail:
+    mov edi, 1                      # exit(1)
+    mov eax, 2
+    syscall
+
+.data
+value:
+    .quad 0
+
+.bss
+status:
+    .skip 8
+buffer:
+    .skip 4096 * 4
@@ -0,0 +1,140 @@
+# Creates a directory and a file in it, writes to the file, reads it back
+# and checks its size. Then renames, truncates and removes it again.
+# Exits with 0 on success.
+
+.intel_syntax noprefix
+.global _start
+
+.text
+_start:
+    lea rdi, [rip + directory]      # mkdir("/fs_test")
+    mov eax, 26
+    syscall
+    test rax, rax
+    jnz fail
+    lea rdi, [rip + directory]      # chdir("/fs_test")
+    mov eax, 27
+    syscall
+    test rax, rax
+    jnz fail
+
+    lea rdi, [rip + file]           # open("file", O_CREAT | O_RDWR)
+    mov esi, 0x42
+    mov eax, 21
+    syscall
+    test rax, rax
+    js fail
+    mov r12, rax
+
+    mov rdi, r12                    # write(fd, message, 5)
+    lea rsi, [rip + message]
+    mov edx, 5
+    mov eax, 1
+    syscall
+    cmp rax, 5
+    jne fail
+    mov rdi, r12                    # seek(fd, 0, SEEK_SET)
+    xor esi, esi
+    xor edx, edx
+    mov eax, 22
+    syscall
+    test rax, rax
+    jnz fail
+    mov rdi, r12                    # read(fd, buffer, 16)
+    lea rsi, [rip + buffer]
+    mov edx, 16
+    xor eax, eax
+    syscall
+    cmp rax, 5
+    jne fail
+    mov eax, [rip + buffer]
+    cmp eax, [rip + message]
+    jne fail
+
+    mov rdi, r12                    # fstat(fd, metadata)
+    lea rsi, [rip + metadata]
+    mov eax, 24
+    syscall
+    test rax, rax
+    jnz fail
+    cmp qword ptr [rip + metadata + 8], 5
+    jne fail
+
+    lea rdi, [rip + file]           # rename("file", "/fs_test/renamed")
+    lea rsi, [rip + renamed]
+    mov eax, 31
+    syscall
+    test rax, rax
+    jnz fail
+    lea rdi, [rip + file]           # stat("file"), it's gone
+    lea rsi, [rip + metadata]
+    mov eax, 23
+    syscall
+    cmp rax, -2                     # ENOENT
+    jne fail
+
+    mov rdi, r12                    # ftruncate(fd, 2)
+    mov esi, 2
+    mov eax, 32
+    syscall
+    test rax, rax
+    jnz fail
+    lea rdi, [rip + renamed]        # stat("/fs_test/renamed")
+    lea rsi, [rip + metadata]
+    mov eax, 23
+    syscall
+    test rax, rax
+    jnz fail
+    cmp qword ptr [rip + metadata + 8], 2
+    jne fail
+
+    mov rdi, r12                    # close(fd)
+    mov eax, 8
+    syscall
+    lea rdi, [rip + directory]      # rmdir("/fs_test"), not empty
+    mov eax, 30
+    syscall
+    cmp rax, -39                    # ENOTEMPTY
+    jne fail
+    lea rdi, [rip + renamed]        # unlink("/fs_test/renamed")
+    mov eax, 29
+    syscall
+    test rax, rax
+    jnz fail
+    lea rdi, [rip + parent]         # chdir("..")
+    mov eax, 27
+    syscall
+    test rax, rax
+    jnz fail
+    lea rdi, [rip + directory]      # rmdir("/fs_test")
+    mov eax, 30
+    syscall
+    test rax, rax
+    jnz fail
+
+    xor edi, edi                    # exit(0)
+    mov eax, 2
+    syscall
+
+fail:
+    mov edi, 1                      # exit(1)
+    mov eax, 2
+    syscall
+
+.data
+directory:
+    .asciz "/fs_test"
+file:
+    .asciz "file"
+renamed:
+    .asciz "/fs_test/renamed"
+parent:
+    .asciz ".."
+message:
+    .ascii "hello"
+
+.bss
+buffer:
+    .skip 16
+metadata:
+    .skip 48
@@ -0,0 +1,48 @@
+# A static test program for the ELF loader, see `mod.rs` for how to build it.
+# Prints a greeting and exits with 40 + argc if its BSS was zeroed and it
+# got an environment, 1 otherwise.
+
+.intel_syntax noprefix
+.global _start
+
+.text
+_start:
+    mov r12, [rsp]                  # argc
+    lea r13, [rsp + 8]              # argv
+    lea r14, [r13 + r12 * 8 + 8]    # envp
+
+    mov eax, 1                      # write(1, message, length)
+    mov edi, 1
+    lea rsi, [rip + message]
+    mov edx, message_end - message
+    syscall
+
+    lea rbx, [rip + buffer]
+    mov ecx, 512
+check_bss:
+    cmp qword ptr [rbx], 0
+    jne fail
+    add rbx, 8
+    dec ecx
+    jnz check_bss
+
+    cmp qword ptr [r14], 0
+    je fail
+
+    lea rdi, [r12 + 40]
+    mov eax, 2                      # exit(40 + argc)
+    syscall
+
+fail:
+    mov edi, 1
+    mov eax, 2                      # exit(1)
+    syscall
+
+.data
+message:
+    .ascii "Hello from an ELF program\n"
+message_end:
+
+.bss
+buffer:
+    .skip 4096
@@ -0,0 +1,48 @@
+//! User programs embedded in the kernel image.
+//!
+//! They're checked in prebuilt so the kernel builds without a host
+//! toolchain, rebuild one with
+//!
+//! ```text
+//! as hello.s -o hello.o
+//! ld -static -nostdlib -z max-page-size=4096 -z noexecstack \
+//!     -Ttext-segment=0x200000400000 -o hello.elf hello.o
+//! strip hello.elf
+//! ```
+//!
+//! The link address has to be inside user space, see `address_space`.
+
+/// Prints a greeting and exits with `40 + argc`
+pub static HELLO: &[u8] = include_bytes!("hello.elf");
+/// Checks that a forked child doesn't change the parent's memory
+pub static FORK_TEST: &[u8] = include_bytes!("fork_test.elf");
+/// Forks and execs `hello` in the child
+pub static EXEC_TEST: &[u8] = include_bytes!("exec_test.elf");
+/// Checks signal handlers, masks and `sigreturn`
+pub static SIGNAL_TEST: &[u8] = include_bytes!("signal_test.elf");
+/// Runs until a signal ends it, blocked in `read` if it has arguments
+pub static SPIN: &[u8] = include_bytes!("spin.elf");
+/// Divides by zero
+pub static DIVIDE_ERROR: &[u8] = include_bytes!("divide_error.elf");
+/// Talks to a forked child through a pipe and to itself through a channel
+pub static PIPE_TEST: &[u8] = include_bytes!("pipe_test.elf");
+/// Shares memory with a forked child
+pub static SHM_TEST: &[u8] = include_bytes!("shm_test.elf");
+/// Creates, renames and removes files and directories
+pub static FS_TEST: &[u8] = include_bytes!("fs_test.elf");
+
+/// Looks up a program `exec` can run by name
+pub fn find(name: &str) -> Option<&'static [u8]> {
+    match name {
+        "hello" => Some(HELLO),
+        "fork_test" => Some(FORK_TEST),
+        "exec_test" => Some(EXEC_TEST),
+        "signal_test" => Some(SIGNAL_TEST),
+        "spin" => Some(SPIN),
+        "divide_error" => Some(DIVIDE_ERROR),
+        "pipe_test" => Some(PIPE_TEST),
+        "shm_test" => Some(SHM_TEST),
+        "fs_test" => Some(FS_TEST),
+        _ => None,
+    }
+}
@@ -0,0 +1,146 @@
+# A forked child writes to its parent through a pipe, the parent reads
+# until the end of file it gets once the child is gone. Then it sends two
+# messages through a channel and receives them through a second handle.
+# Exits with 0 on success.
+
+.intel_syntax noprefix
+.global _start
+
+.text
+_start:
+    lea rdi, [rip + fds]            # pipe(fds)
+    mov eax, 15
+    syscall
+    test rax, rax
+    jnz fail
+
+    mov eax, 9                      # fork()
+    syscall
+    test rax, rax
+    js fail
+    jz child
+    mov r12, rax
+
+    mov edi, [rip + fds + 4]        # close(write end)
+    mov eax, 8
+    syscall
+
+    xor r13, r13                    # bytes read so far
+read_pipe:
+    mov edi, [rip + fds]            # read(read end, buffer + r13, 16 - r13)
+    lea rsi, [rip + buffer]
+    add rsi, r13
+    mov edx, 16
+    sub rdx, r13
+    xor eax, eax
+    syscall
+    test rax, rax
+    js fail
+    jz end_of_file
+    add r13, rax
+    cmp r13, 16
+    jb read_pipe
+    jmp fail
+
+end_of_file:
+    cmp r13, 5
+    jne fail
+    cmp dword ptr [rip + buffer], 0x676e6970    # "ping"
+    jne fail
+
+    mov rdi, r12                    # wait(child, null)
+    xor esi, esi
+    mov eax, 6
+    syscall
+    cmp rax, r12
+    jne fail
+
+    mov edi, 2                      # channel(2, &channel_id)
+    lea rsi, [rip + channel_id]
+    mov eax, 16
+    syscall
+    test rax, rax
+    js fail
+    mov r14, rax
+
+    mov rdi, r14                    # write(channel, message, 3)
+    lea rsi, [rip + message]
+    mov edx, 3
+    mov eax, 1
+    syscall
+    cmp rax, 3
+    jne fail
+    mov rdi, r14                    # write(channel, message, 2)
+    lea rsi, [rip + message]
+    mov edx, 2
+    mov eax, 1
+    syscall
+    cmp rax, 2
+    jne fail
+
+    mov rdi, [rip + channel_id]     # channel_open(channel_id)
+    mov eax, 17
+    syscall
+    test rax, rax
+    js fail
+    mov r15, rax
+
+    mov rdi, r15                    # read(channel, buffer, 2), too small
+    lea rsi, [rip + buffer]
+    mov edx, 2
+    xor eax, eax
+    syscall
+    cmp rax, -90                    # EMSGSIZE
+    jne fail
+    mov rdi, r15                    # read(channel, buffer, 16)
+    lea rsi, [rip + buffer]
+    mov edx, 16
+    xor eax, eax
+    syscall
+    cmp rax, 3
+    jne fail
+    mov rdi, r15                    # read(channel, buffer, 16)
+    lea rsi, [rip + buffer]
+    mov edx, 16
+    xor eax, eax
+    syscall
+    cmp rax, 2
+    jne fail
+
+    xor edi, edi                    # exit(0)
+    mov eax, 2
+    syscall
+
+child:
+    mov edi, [rip + fds]            # close(read end)
+    mov eax, 8
+    syscall
+    mov edi, [rip + fds + 4]        # write(write end, ping, 5)
+    lea rsi, [rip + ping]
+    mov edx, 5
+    mov eax, 1
+    syscall
+    cmp rax, 5
+    jne fail
+    xor edi, edi                    # exit(0)
+    mov eax, 2
+    syscall
+
+fail:
+    mov edi, 1                      # exit(1)
+    mov eax, 2
+    syscall
+
+.data
+ping:
+    .ascii "ping\n"
+message:
+    .ascii "abc"
+
+.bss
+fds:
+    .skip 8
+channel_id:
+    .skip 8
+buffer:
+    .skip 16
@@ -0,0 +1,71 @@
+# Maps a shared memory object, a forked child stores 42 in it and the
+# parent checks that it sees the value once the child is gone. Then it
+# unmaps and unlinks the object, unlinking it twice has to fail.
+# Exits with 0 on success.
+
+.intel_syntax noprefix
+.global _start
+
+.text
+_start:
+    lea rdi, [rip + name]           # shm_map(name, 4096, PROT_READ | PROT_WRITE)
+    mov esi, 4096
+    mov edx, 3
+    mov eax, 18
+    syscall
+    test rax, rax
+    js fail
+    mov r12, rax
+
+    mov eax, 9                      # fork()
+    syscall
+    test rax, rax
+    js fail
+    jz child
+    mov r13, rax
+
+    mov rdi, r13                    # wait(child, null)
+    xor esi, esi
+    mov eax, 6
+    syscall
+    cmp rax, r13
+    jne fail
+    cmp qword ptr [r12], 42
+    jne fail
+
+    mov rdi, r12                    # shm_unmap(address, 4096)
+    mov esi, 4096
+    mov eax, 19
+    syscall
+    test rax, rax
+    jnz fail
+
+    lea rdi, [rip + name]           # shm_unlink(name)
+    mov eax, 20
+    syscall
+    test rax, rax
+    jnz fail
+    lea rdi, [rip + name]           # shm_unlink(name), already gone
+    mov eax, 20
+    syscall
+    cmp rax, -2                     # ENOENT
+    jne fail
+
+    xor edi, edi                    # exit(0)
+    mov eax, 2
+    syscall
+
+child:
+    mov qword ptr [r12], 42
+    xor edi, edi                    # exit(0)
+    mov eax, 2
+    syscall
+
+fail:
+    mov edi, 1                      # exit(1)
+    mov eax, 2
+    syscall
+
+.data
+name:
+    .asciz "counter"
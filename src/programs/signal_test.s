@@ -0,0 +1,116 @@
+# Installs signal handlers and checks that they run: for a signal sent with
+# kill, for a blocked one once it's unblocked, and for the SIGSEGV and
+# SIGFPE the CPU raises, where the handler skips the faulting instruction.
+# Exits with 0 on success.
+
+.intel_syntax noprefix
+.global _start
+
+.text
+_start:
+    mov edi, 10                     # sigaction(SIGUSR1, count_handler, restorer)
+    lea rsi, [rip + count_handler]
+    lea rdx, [rip + restorer]
+    mov eax, 12
+    syscall
+    test rax, rax                   # the previous handler was SIG_DFL
+    jnz fail
+
+    mov eax, 4                      # getpid()
+    syscall
+    mov r13, rax
+
+    mov r12, 0x1234                 # the handler clobbers it
+    mov rdi, r13                    # kill(pid, SIGUSR1)
+    mov esi, 10
+    mov eax, 11
+    syscall
+    test rax, rax
+    jnz fail
+    cmp qword ptr [rip + count], 1
+    jne fail
+    cmp r12, 0x1234
+    jne fail
+
+    # Blocked signals wait until they're unblocked
+    xor edi, edi                    # sigprocmask(SIG_BLOCK, 1 << SIGUSR1)
+    mov esi, 1 << 10
+    mov eax, 13
+    syscall
+    test rax, rax                   # nothing was blocked before
+    jnz fail
+    mov rdi, r13                    # kill(pid, SIGUSR1)
+    mov esi, 10
+    mov eax, 11
+    syscall
+    cmp qword ptr [rip + count], 1
+    jne fail
+    mov edi, 1                      # sigprocmask(SIG_UNBLOCK, 1 << SIGUSR1)
+    mov esi, 1 << 10
+    mov eax, 13
+    syscall
+    cmp rax, 1 << 10                # the previous mask
+    jne fail
+    cmp qword ptr [rip + count], 2
+    jne fail
+
+    # Faults continue at the address in r15
+    mov edi, 11                     # sigaction(SIGSEGV, skip_handler, restorer)
+    lea rsi, [rip + skip_handler]
+    lea rdx, [rip + restorer]
+    mov eax, 12
+    syscall
+    mov edi, 8                      # sigaction(SIGFPE, skip_handler, restorer)
+    lea rsi, [rip + skip_handler]
+    lea rdx, [rip + restorer]
+    mov eax, 12
+    syscall
+
+    lea r15, [rip + after_page_fault]
+    mov rax, qword ptr [0]
+after_page_fault:
+    cmp qword ptr [rip + last_signal], 11
+    jne fail
+
+    lea r15, [rip + after_divide_error]
+    mov eax, 1
+    xor edx, edx
+    xor ecx, ecx
+    div rcx
+after_divide_error:
+    cmp qword ptr [rip + last_signal], 8
+    jne fail
+
+    xor edi, edi                    # exit(0)
+    mov eax, 2
+    syscall
+
+fail:
+    mov edi, 1                      # exit(1)
+    mov eax, 2
+    syscall
+
+# Handlers get the signal number in rdi and return to the restorer
+count_handler:
+    add qword ptr [rip + count], 1
+    cmp rdi, 10
+    jne fail
+    xor r12, r12                    # sigreturn restores it
+    ret
+
+# Above the return address are the signal number, the old mask and the
+# saved registers, rip is at offset 128 of those
+skip_handler:
+    mov [rip + last_signal], rdi
+    mov [rsp + 24 + 128], r15
+    ret
+
+restorer:
+    mov eax, 14                     # sigreturn()
+    syscall
+
+.bss
+count:
+    .skip 8
+last_signal:
+    .skip 8
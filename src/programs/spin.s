@@ -0,0 +1,25 @@
+# Runs until a signal ends it: spins in user mode when started without
+# arguments, blocks reading the console when there are some.
+
+.intel_syntax noprefix
+.global _start
+
+.text
+_start:
+    cmp qword ptr [rsp], 1          # argc
+    jne read
+
+spin:
+    jmp spin
+
+read:
+    xor edi, edi                    # read(0, buffer, 1)
+    lea rsi, [rip + buffer]
+    mov edx, 1
+    xor eax, eax
+    syscall
+    jmp read
+
+.bss
+buffer:
+    .skip 8
@@ -1,9 +1,10 @@
-use spin::Mutex;
 use x86_64::instructions::port::Port;
 
 use lazy_static::lazy_static;
 
+use crate::lock_class;
 use crate::println;
+use crate::sync::IrqSpinLock;
 
 const CMD_READ_CONFIG_BYTE: u8 = 0x20;
 const CMD_WRITE_CONFIG_BYTE: u8 = 0x60;
@@ -30,7 +31,17 @@ const REPLY_DEVICE_ACK: u8 = 0xFA;
 const REPLY_DEVICE_RESEND: u8 = 0xFE;
 
 lazy_static! {
-    static ref PS2: Mutex<Ps2Controller> = Mutex::new(Ps2Controller::new());
+    static ref PS2: IrqSpinLock<Ps2Controller> =
+        IrqSpinLock::with_class(Ps2Controller::new(), lock_class!("PS2"));
+}
+
+/// What `init` found on the two ports
+static DEVICES: IrqSpinLock<[Option<DeviceType>; 2]> =
+    IrqSpinLock::with_class([None; 2], lock_class!("PS2_DEVICES"));
+
+/// The types of the devices on the first and on the second port
+pub fn devices() -> [Option<DeviceType>; 2] {
+    *DEVICES.lock()
 }
 
 pub fn init() {
@@ -44,15 +55,13 @@ pub fn init() {
     controller.perform_interface_tests();
     controller.enable_devices_and_translation();
 
-    println!(
-        "Found PS/2 device [0]: {:#?}",
-        controller.identify_port_device(Ps2Port::One)
-    );
+    let first = controller.identify_port_device(Ps2Port::One);
+    DEVICES.lock()[0] = Some(first);
+    println!("Found PS/2 device [0]: {:#?}", first);
     if controller.is_dual_channel {
-        println!(
-            "Found PS/2 device [1]: {:#?}",
-            controller.identify_port_device(Ps2Port::Two)
-        );
+        let second = controller.identify_port_device(Ps2Port::Two);
+        DEVICES.lock()[1] = Some(second);
+        println!("Found PS/2 device [1]: {:#?}", second);
         //        controller.write_to_port_two_device()
         controller.write_to_port_two_device(DEVICE_MOUSE_RESET);
 
@@ -83,9 +92,9 @@ enum AckType {
     Ok,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[repr(u8)]
-enum DeviceType {
+pub enum DeviceType {
     Ps2Mouse = 0x00,
     MouseScrollWheel = 0x03,
     FiveButtonMouse = 0x04,
@@ -1,27 +1,69 @@
-use spin::Mutex;
+use alloc::vec::Vec;
+
 use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
 
 use lazy_static::lazy_static;
 
+use crate::input;
+use crate::interrupts;
+use crate::lock_class;
+use crate::sync::IrqSpinLock;
+
+const COM1: u16 = 0x3F8;
+const COM1_IRQ: u8 = 4;
+// Registers at offsets from the base port
+const INTERRUPT_ENABLE: u16 = 1;
+const LINE_STATUS: u16 = 5;
+const INTERRUPT_DATA_AVAILABLE: u8 = 1;
+const LINE_DATA_READY: u8 = 1;
+
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    pub static ref SERIAL1: IrqSpinLock<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSpinLock::with_class(serial_port, lock_class!("SERIAL1"))
     };
 }
 
+/// Enables the interrupt for received bytes, they go to `input::SERIAL`
+pub fn init() {
+    let _port = SERIAL1.lock();
+    unsafe { Port::new(COM1 + INTERRUPT_ENABLE).write(INTERRUPT_DATA_AVAILABLE) };
+    interrupts::enable_irq(COM1_IRQ);
+}
+
+/// Called by the interrupt handler, moves the received bytes to the input
+/// queue
+pub fn receive() {
+    let mut received = Vec::new();
+    {
+        let _port = SERIAL1.lock();
+        let mut line_status: Port<u8> = Port::new(COM1 + LINE_STATUS);
+        let mut data: Port<u8> = Port::new(COM1);
+        while unsafe { line_status.read() } & LINE_DATA_READY != 0 {
+            received.push(unsafe { data.read() });
+        }
+    }
+    input::SERIAL.push(&received);
+}
+
+/// Sends `bytes` as they are
+pub fn write(bytes: &[u8]) {
+    let mut port = SERIAL1.lock();
+    for &byte in bytes {
+        port.send(byte);
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
-
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
 }
 
 /// Prints to the host through the serial interface.
@@ -0,0 +1,195 @@
+//! Named shared memory.
+//!
+//! A shared memory object is a set of zeroed frames with a name. Any number
+//! of address spaces can map it at once and see each other's writes. The
+//! object holds one reference to each of its frames and every mapped page
+//! another one, so the memory is freed once the name is unlinked and the
+//! last mapping is gone, either unmapped explicitly or dropped together with
+//! the address space when its process exits. `fork` keeps the pages shared.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use lazy_static::lazy_static;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use crate::address_space::{AddressSpace, MapError, SHARED};
+use crate::lock_class;
+use crate::memory;
+use crate::sync::IrqSpinLock;
+use crate::syscall::SyscallError;
+
+/// Largest object that can be created
+pub const MAX_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ShmError {
+    NotFound,
+    /// Zero, larger than `MAX_SIZE` or larger than the existing object
+    InvalidSize,
+    /// The range to unmap isn't all shared memory
+    NotMapped,
+    Map(MapError),
+}
+
+impl From<MapError> for ShmError {
+    fn from(error: MapError) -> ShmError {
+        ShmError::Map(error)
+    }
+}
+
+impl From<ShmError> for SyscallError {
+    fn from(error: ShmError) -> SyscallError {
+        match error {
+            ShmError::NotFound => SyscallError::NoSuchFile,
+            ShmError::Map(MapError::FrameAllocationFailed) => SyscallError::OutOfMemory,
+            _ => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+pub struct SharedMemory {
+    name: String,
+    frames: Vec<PhysFrame>,
+}
+
+lazy_static! {
+    /// Objects that weren't unlinked yet
+    static ref OBJECTS: IrqSpinLock<BTreeMap<String, Arc<SharedMemory>>> =
+        IrqSpinLock::with_class(BTreeMap::new(), lock_class!("SHARED_MEMORY"));
+}
+
+impl SharedMemory {
+    fn new(name: &str, size: usize) -> Result<SharedMemory, ShmError> {
+        if size == 0 || size > MAX_SIZE {
+            return Err(ShmError::InvalidSize);
+        }
+
+        let mut object = SharedMemory {
+            name: String::from(name),
+            frames: Vec::new(),
+        };
+        for _ in 0..(size + 4095) / 4096 {
+            // Dropping the object frees what was allocated so far
+            let frame = memory::allocate_zeroed_frame()
+                .ok_or(ShmError::Map(MapError::FrameAllocationFailed))?;
+            object.frames.push(frame);
+        }
+        Ok(object)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Size in bytes, always whole pages
+    pub fn size(&self) -> usize {
+        self.frames.len() * 4096
+    }
+
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.frames
+    }
+
+    /// Maps the whole object into `space` at `start`, which has to be page
+    /// aligned. Nothing stays mapped if it fails.
+    pub fn map(&self, space: &AddressSpace, start: VirtAddr, writable: bool) -> Result<(), ShmError> {
+        let mut flags = PageTableFlags::NO_EXECUTE | SHARED;
+        if writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+
+        let first: Page = Page::containing_address(start);
+        for (index, &frame) in self.frames.iter().enumerate() {
+            memory::share_frame(frame);
+            if let Err(error) = space.map_page(first + index as u64, frame, flags) {
+                memory::deallocate_frame(frame);
+                unmap_pages(space, first, index);
+                return Err(error.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        for &frame in self.frames.iter() {
+            memory::deallocate_frame(frame);
+        }
+    }
+}
+
+/// Looks up an object by name
+pub fn open(name: &str) -> Option<Arc<SharedMemory>> {
+    OBJECTS.lock().get(name).cloned()
+}
+
+/// Opens the object called `name`, it's created with `size` bytes (rounded
+/// up to whole pages) if it doesn't exist. An existing object has to be at
+/// least `size` bytes large.
+pub fn open_or_create(name: &str, size: usize) -> Result<Arc<SharedMemory>, ShmError> {
+    let object = match open(name) {
+        Some(object) => object,
+        None => {
+            // Allocated without the lock, another thread may win the race
+            let created = Arc::new(SharedMemory::new(name, size)?);
+            let existing = {
+                let mut objects = OBJECTS.lock();
+                match objects.get(name) {
+                    Some(existing) => Some(existing.clone()),
+                    None => {
+                        objects.insert(String::from(name), created.clone());
+                        None
+                    }
+                }
+            };
+            existing.unwrap_or(created)
+        }
+    };
+
+    if size > object.size() {
+        return Err(ShmError::InvalidSize);
+    }
+    Ok(object)
+}
+
+/// Removes the name, the memory stays until the last mapping is gone
+pub fn unlink(name: &str) -> Result<(), ShmError> {
+    let object = OBJECTS.lock().remove(name).ok_or(ShmError::NotFound)?;
+    // Frees the frames outside of the lock if nobody else holds the object
+    drop(object);
+    Ok(())
+}
+
+fn unmap_pages(space: &AddressSpace, first: Page, count: usize) {
+    for index in 0..count {
+        if let Some(frame) = space.unmap_page(first + index as u64) {
+            memory::deallocate_frame(frame);
+        }
+    }
+}
+
+/// Unmaps `size` bytes of shared memory at `start`, fails without
+/// changing anything if a page in the range isn't shared memory
+pub fn unmap(space: &AddressSpace, start: VirtAddr, size: usize) -> Result<(), ShmError> {
+    if size == 0 || start.as_u64() % 4096 != 0 {
+        return Err(ShmError::InvalidSize);
+    }
+
+    let first: Page = Page::containing_address(start);
+    let count = (size + 4095) / 4096;
+    for index in 0..count {
+        let page = first + index as u64;
+        match space.translate(page.start_address()) {
+            Some((_, flags)) if flags.contains(SHARED) => {}
+            _ => return Err(ShmError::NotMapped),
+        }
+    }
+
+    unmap_pages(space, first, count);
+    Ok(())
+}
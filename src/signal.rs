@@ -0,0 +1,357 @@
+//! POSIX-like signals.
+//!
+//! Every process has a set of pending signals, a mask of blocked ones and
+//! an action for each signal. Pending signals that aren't blocked are
+//! delivered right before the process returns to ring 3, after a syscall,
+//! an exception or an interrupt. The default action either ignores the
+//! signal or terminates the process with exit code `128 + signal`.
+//!
+//! A handler is called on the user stack with the signal number in `rdi`,
+//! on top of a `SignalFrame` holding the interrupted registers. It returns
+//! to the restorer the program registered with it, which has to call
+//! `sigreturn` to continue where the program was interrupted.
+
+use core::{mem, ptr, slice};
+
+use crate::address_space::{USER_SPACE_END, USER_SPACE_START};
+use crate::println;
+use crate::process::{self, Pid};
+use crate::syscall::{self, SyscallError, SyscallFrame};
+use crate::user;
+
+pub type Signal = u32;
+
+pub const SIGHUP: Signal = 1;
+pub const SIGINT: Signal = 2;
+pub const SIGQUIT: Signal = 3;
+pub const SIGILL: Signal = 4;
+pub const SIGTRAP: Signal = 5;
+pub const SIGABRT: Signal = 6;
+pub const SIGBUS: Signal = 7;
+pub const SIGFPE: Signal = 8;
+pub const SIGKILL: Signal = 9;
+pub const SIGUSR1: Signal = 10;
+pub const SIGSEGV: Signal = 11;
+pub const SIGUSR2: Signal = 12;
+pub const SIGPIPE: Signal = 13;
+pub const SIGALRM: Signal = 14;
+pub const SIGTERM: Signal = 15;
+pub const SIGCHLD: Signal = 17;
+
+/// Signals are numbered from 1 to `NSIG - 1`
+pub const NSIG: Signal = 32;
+
+/// `sigaction` handlers that stand for the default action and for ignoring
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// How `sigprocmask` changes the mask
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+// Signals that can't be caught, ignored or blocked
+const UNCATCHABLE: u32 = 1 << SIGKILL;
+
+// Cleared for handlers, so that they don't run single stepped or with
+// the direction flag set
+const HANDLER_CLEARED_RFLAGS: u64 = 0x500;
+
+// Left alone below the interrupted stack pointer, code may use it
+// without moving rsp
+const RED_ZONE: u64 = 128;
+
+pub fn is_valid(signal: u64) -> bool {
+    signal > 0 && signal < u64::from(NSIG)
+}
+
+fn bit(signal: Signal) -> u32 {
+    1 << signal
+}
+
+pub fn name(signal: Signal) -> &'static str {
+    match signal {
+        SIGHUP => "SIGHUP",
+        SIGINT => "SIGINT",
+        SIGQUIT => "SIGQUIT",
+        SIGILL => "SIGILL",
+        SIGTRAP => "SIGTRAP",
+        SIGABRT => "SIGABRT",
+        SIGBUS => "SIGBUS",
+        SIGFPE => "SIGFPE",
+        SIGKILL => "SIGKILL",
+        SIGUSR1 => "SIGUSR1",
+        SIGSEGV => "SIGSEGV",
+        SIGUSR2 => "SIGUSR2",
+        SIGPIPE => "SIGPIPE",
+        SIGALRM => "SIGALRM",
+        SIGTERM => "SIGTERM",
+        SIGCHLD => "SIGCHLD",
+        _ => "unknown signal",
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+}
+
+pub fn default_action(signal: Signal) -> DefaultAction {
+    match signal {
+        SIGCHLD => DefaultAction::Ignore,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SignalAction {
+    Default,
+    Ignore,
+    /// Calls `handler(signal)` in ring 3, it returns to `restorer`
+    Handler { handler: u64, restorer: u64 },
+}
+
+/// The signal bookkeeping of a process, bit `n` of the masks stands for
+/// signal `n`
+#[derive(Debug, Clone)]
+pub struct SignalState {
+    pending: u32,
+    blocked: u32,
+    actions: [SignalAction; NSIG as usize],
+}
+
+impl SignalState {
+    pub fn new() -> SignalState {
+        SignalState {
+            pending: 0,
+            blocked: 0,
+            actions: [SignalAction::Default; NSIG as usize],
+        }
+    }
+
+    /// The state of a forked child: the same actions and mask, nothing pending
+    pub fn fork(&self) -> SignalState {
+        SignalState {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Handlers don't survive `exec`, ignored signals stay ignored
+    pub fn exec(&mut self) {
+        for action in self.actions.iter_mut() {
+            if let SignalAction::Handler { .. } = action {
+                *action = SignalAction::Default;
+            }
+        }
+    }
+
+    pub fn raise(&mut self, signal: Signal) {
+        self.pending |= bit(signal);
+    }
+
+    pub fn pending(&self) -> u32 {
+        self.pending
+    }
+
+    pub fn blocked(&self) -> u32 {
+        self.blocked
+    }
+
+    pub fn set_blocked(&mut self, mask: u32) {
+        self.blocked = mask & !UNCATCHABLE & !1;
+    }
+
+    pub fn action(&self, signal: Signal) -> SignalAction {
+        self.actions[signal as usize]
+    }
+
+    /// Returns the previous action
+    pub fn set_action(
+        &mut self,
+        signal: Signal,
+        action: SignalAction,
+    ) -> Result<SignalAction, SyscallError> {
+        if bit(signal) & UNCATCHABLE != 0 {
+            return Err(SyscallError::InvalidArgument);
+        }
+
+        let previous = mem::replace(&mut self.actions[signal as usize], action);
+        // POSIX discards pending signals that are going to be ignored
+        if self.is_ignored(signal) {
+            self.pending &= !bit(signal);
+        }
+        Ok(previous)
+    }
+
+    /// Makes `signal` pending even if it's blocked or ignored, for the ones
+    /// the CPU raises: returning to the faulting instruction would only
+    /// raise them again
+    pub fn force(&mut self, signal: Signal) {
+        self.blocked &= !bit(signal);
+        if self.actions[signal as usize] == SignalAction::Ignore {
+            self.actions[signal as usize] = SignalAction::Default;
+        }
+        self.raise(signal);
+    }
+
+    fn is_ignored(&self, signal: Signal) -> bool {
+        match self.actions[signal as usize] {
+            SignalAction::Ignore => true,
+            SignalAction::Default => default_action(signal) == DefaultAction::Ignore,
+            SignalAction::Handler { .. } => false,
+        }
+    }
+
+    /// Returns `true` if a signal is waiting to be delivered
+    pub fn has_deliverable(&self) -> bool {
+        (1..NSIG).any(|signal| {
+            self.pending & !self.blocked & bit(signal) != 0 && !self.is_ignored(signal)
+        })
+    }
+
+    /// Removes the lowest pending signal that isn't blocked
+    pub fn take(&mut self) -> Option<(Signal, SignalAction)> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+
+        let signal = deliverable.trailing_zeros();
+        self.pending &= !bit(signal);
+        Some((signal, self.actions[signal as usize]))
+    }
+}
+
+impl Default for SignalState {
+    fn default() -> Self {
+        SignalState::new()
+    }
+}
+
+/// What a handler finds on its stack, `restorer` is its return address
+#[derive(Debug, Clone)]
+#[repr(C)]
+struct SignalFrame {
+    restorer: u64,
+    signal: u64,
+    /// The mask from before the handler was called
+    blocked: u64,
+    context: SyscallFrame,
+}
+
+/// Makes `signal` pending for the calling process
+pub fn raise(signal: Signal) {
+    process::with_signals(|signals| signals.raise(signal));
+}
+
+/// Makes `signal` pending for the calling process even if it's blocked or
+/// ignored, see `SignalState::force`
+pub fn force(signal: Signal) {
+    process::with_signals(|signals| signals.force(signal));
+}
+
+/// Returns `true` if the calling process has a signal to handle, blocking
+/// syscalls check it to return early
+pub fn interrupted() -> bool {
+    process::with_signals(|signals| signals.has_deliverable()).unwrap_or(false)
+}
+
+/// Delivers the pending signals of the calling process, `frame` holds the
+/// registers it's about to return to ring 3 with. A handler gets called by
+/// changing `frame`, the default action may terminate the process.
+pub fn deliver_pending(frame: &mut SyscallFrame) {
+    let pid = match process::current_pid() {
+        Some(pid) => pid,
+        None => return,
+    };
+
+    loop {
+        let next = process::with_signals(|signals| {
+            let blocked = signals.blocked();
+            signals.take().map(|(signal, action)| (signal, action, blocked))
+        });
+        let (signal, action, blocked) = match next {
+            Some(Some(next)) => next,
+            _ => return,
+        };
+
+        match action {
+            SignalAction::Ignore => {}
+            SignalAction::Default => {
+                if default_action(signal) == DefaultAction::Terminate {
+                    terminate(pid, signal);
+                }
+            }
+            SignalAction::Handler { handler, restorer } => {
+                if push_frame(frame, signal, handler, restorer, blocked).is_err() {
+                    // There's no stack to run a handler on
+                    terminate(pid, SIGSEGV);
+                }
+                // Blocked until the handler returns, the others wait for then
+                process::with_signals(|signals| signals.set_blocked(blocked | bit(signal)));
+                return;
+            }
+        }
+    }
+}
+
+fn terminate(pid: Pid, signal: Signal) -> ! {
+    println!("Process {} killed by {}", pid, name(signal));
+    process::exit(128 + signal as i32);
+}
+
+/// Saves `frame` on the user stack and changes it to call `handler`
+fn push_frame(
+    frame: &mut SyscallFrame,
+    signal: Signal,
+    handler: u64,
+    restorer: u64,
+    blocked: u32,
+) -> Result<(), SyscallError> {
+    let size = mem::size_of::<SignalFrame>();
+    // rsp + 8 has to be 16 byte aligned at the start of a function
+    let address = frame
+        .rsp
+        .checked_sub(RED_ZONE + size as u64 + 8)
+        .ok_or(SyscallError::BadAddress)?;
+    let address = (address & !0xF) - 8;
+
+    let signal_frame = SignalFrame {
+        restorer,
+        signal: u64::from(signal),
+        blocked: u64::from(blocked),
+        context: frame.clone(),
+    };
+    let bytes =
+        unsafe { slice::from_raw_parts(&signal_frame as *const SignalFrame as *const u8, size) };
+    syscall::user_buffer_mut(address, size)?.copy_from_slice(bytes);
+
+    frame.rip = handler;
+    frame.rsp = address;
+    frame.rdi = u64::from(signal);
+    frame.rflags &= !HANDLER_CLEARED_RFLAGS;
+    Ok(())
+}
+
+/// Restores the registers and the mask saved when a handler was called,
+/// `frame.rsp` is the stack pointer the handler returned with
+pub fn sigreturn(frame: &mut SyscallFrame) -> Result<(), SyscallError> {
+    let size = mem::size_of::<SignalFrame>();
+    let address = frame.rsp.checked_sub(8).ok_or(SyscallError::BadAddress)?;
+    let bytes = syscall::user_buffer(address, size)?;
+    let saved = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const SignalFrame) };
+
+    // A kernel or non-canonical address would fault in `iretq`, the other
+    // registers can be left to the CPU to complain about in ring 3
+    let in_user_space = |addr| addr >= USER_SPACE_START && addr < USER_SPACE_END;
+    if !in_user_space(saved.context.rip) || !in_user_space(saved.context.rsp) {
+        return Err(SyscallError::BadAddress);
+    }
+
+    process::with_signals(|signals| signals.set_blocked(saved.blocked as u32));
+    *frame = saved.context;
+    frame.rflags = user::sanitize_rflags(frame.rflags);
+    Ok(())
+}
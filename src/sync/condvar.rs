@@ -0,0 +1,57 @@
+use x86_64::instructions::interrupts;
+
+use super::{MutexGuard, WaitQueue};
+use crate::task;
+
+/// A condition variable used together with the sleeping `Mutex`
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub fn new() -> Condvar {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Releases the mutex and sleeps until notified, the mutex is locked
+    /// again before returning. Spurious wake ups are possible, use
+    /// `wait_while` or check the condition in a loop.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+
+        // Queueing, unlocking and blocking happen with interrupts disabled
+        // so a notification can't slip in between them
+        interrupts::without_interrupts(|| {
+            self.waiters.enqueue_current();
+            drop(guard);
+            task::block_current();
+        });
+
+        mutex.lock()
+    }
+
+    /// Sleeps as long as `condition` returns `true`
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
@@ -0,0 +1,369 @@
+//! Lock dependency validator for the spin locks (debug builds only).
+//!
+//! Every named lock belongs to a `LockClass`, usually one per `lock_class!`
+//! call site. When a lock is acquired while others are held, the validator
+//! records "held -> acquired" edges between their classes. A deadlock is
+//! possible as soon as the graph gets a cycle, which is reported over serial
+//! the first time the offending order shows up, even if it didn't deadlock
+//! this time. It also checks that locks taken in interrupt handlers are never
+//! taken with interrupts enabled.
+//!
+//! The state is kept in fixed size tables so that validating doesn't
+//! allocate, and reports are written to the serial port directly since
+//! `SERIAL1` is a validated lock itself.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use uart_16550::SerialPort;
+use x86_64::instructions::interrupts;
+
+const MAX_CLASSES: usize = 64;
+const MAX_HELD: usize = 16;
+const SERIAL_PORT_ADDRESS: u16 = 0x3F8;
+const UNREGISTERED: usize = 0;
+
+/// Creates a new lock class, each call site is its own class
+#[macro_export]
+macro_rules! lock_class {
+    ($name:expr) => {{
+        static CLASS: $crate::sync::lockdep::LockClass =
+            $crate::sync::lockdep::LockClass::new($name);
+        &CLASS
+    }};
+}
+
+pub struct LockClass {
+    name: &'static str,
+    /// Index in the validator's tables plus one, zero until first used
+    id: AtomicUsize,
+}
+
+impl LockClass {
+    pub const fn new(name: &'static str) -> LockClass {
+        LockClass {
+            name,
+            id: AtomicUsize::new(UNREGISTERED),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Classes of the locks a thread holds, in acquisition order. Swapped in and
+/// out by the scheduler so that every thread has its own.
+#[derive(Debug, Copy, Clone)]
+pub struct HeldLocks {
+    classes: [u8; MAX_HELD],
+    len: usize,
+}
+
+impl HeldLocks {
+    pub const fn new() -> HeldLocks {
+        HeldLocks {
+            classes: [0; MAX_HELD],
+            len: 0,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.classes[..self.len].iter().map(|&class| class as usize)
+    }
+
+    fn contains(&self, class: usize) -> bool {
+        self.iter().any(|held| held == class)
+    }
+
+    /// Deeper nesting than `MAX_HELD` isn't tracked
+    fn push(&mut self, class: usize) {
+        if self.len < MAX_HELD {
+            self.classes[self.len] = class as u8;
+            self.len += 1;
+        }
+    }
+
+    /// Removes the most recent acquisition of `class`, locks don't have to
+    /// be released in reverse order
+    fn remove(&mut self, class: usize) {
+        if let Some(index) = self.iter().rposition(|held| held == class) {
+            for i in index..self.len - 1 {
+                self.classes[i] = self.classes[i + 1];
+            }
+            self.len -= 1;
+        }
+    }
+}
+
+struct Validator {
+    names: [&'static str; MAX_CLASSES],
+    registered: usize,
+    /// Bit `b` of `after[a]` is set once class `b` was acquired while `a` was held
+    after: [u64; MAX_CLASSES],
+    /// Same layout as `after`, inversions that were already reported
+    reported: [u64; MAX_CLASSES],
+    used_in_irq: u64,
+    used_with_irqs_enabled: u64,
+    irq_reported: u64,
+    held: HeldLocks,
+    disabled: bool,
+}
+
+static VALIDATOR: spin::Mutex<Validator> = spin::Mutex::new(Validator {
+    names: [""; MAX_CLASSES],
+    registered: 0,
+    after: [0; MAX_CLASSES],
+    reported: [0; MAX_CLASSES],
+    used_in_irq: 0,
+    used_with_irqs_enabled: 0,
+    irq_reported: 0,
+    held: HeldLocks::new(),
+    disabled: false,
+});
+
+static REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+fn bit(class: usize) -> u64 {
+    1 << class
+}
+
+impl Validator {
+    fn class_id(&mut self, class: &'static LockClass) -> Option<usize> {
+        let id = class.id.load(Ordering::Relaxed);
+        if id != UNREGISTERED {
+            return Some(id - 1);
+        }
+
+        if self.registered == MAX_CLASSES {
+            if !self.disabled {
+                self.disabled = true;
+                report_line(format_args!(
+                    "lockdep: out of lock classes, validation turned off"
+                ));
+            }
+            return None;
+        }
+
+        let id = self.registered;
+        self.registered += 1;
+        self.names[id] = class.name;
+        class.id.store(id + 1, Ordering::Relaxed);
+        Some(id)
+    }
+
+    fn check_irq_usage(&mut self, class: usize, irq_safe: bool) {
+        let in_irq = crate::interrupts::in_interrupt();
+        if self.irq_reported & bit(class) != 0 {
+            return;
+        }
+
+        if in_irq && !irq_safe {
+            self.irq_reported |= bit(class);
+            self.report_header("non IRQ-safe lock acquired in interrupt context");
+            report_line(format_args!("  acquiring {}", self.names[class]));
+            self.report_held();
+            return;
+        }
+
+        if in_irq {
+            self.used_in_irq |= bit(class);
+        } else if !irq_safe && interrupts::are_enabled() {
+            self.used_with_irqs_enabled |= bit(class);
+        }
+
+        if self.used_in_irq & self.used_with_irqs_enabled & bit(class) != 0 {
+            self.irq_reported |= bit(class);
+            self.report_header("inconsistent IRQ state");
+            report_line(format_args!(
+                "  {} is taken in interrupt context and with interrupts enabled",
+                self.names[class]
+            ));
+            self.report_held();
+        }
+    }
+
+    fn check_order(&mut self, class: usize) {
+        if self.held.contains(class) {
+            if self.reported[class] & bit(class) == 0 {
+                self.reported[class] |= bit(class);
+                self.report_header("recursive locking");
+                report_line(format_args!(
+                    "  acquiring {} which is already held",
+                    self.names[class]
+                ));
+                self.report_held();
+            }
+            return;
+        }
+
+        let held = self.held;
+        for holding in held.iter() {
+            if self.after[holding] & bit(class) != 0 {
+                continue; // Known and valid order
+            }
+
+            let mut chain = [0u8; MAX_CLASSES];
+            match self.find_path(class, holding, &mut chain) {
+                Some(len) => {
+                    // `class` is already taken before `holding` somewhere else
+                    if self.reported[holding] & bit(class) == 0 {
+                        self.reported[holding] |= bit(class);
+                        self.report_inversion(class, holding, &chain[..len]);
+                    }
+                }
+                None => self.after[holding] |= bit(class),
+            }
+        }
+    }
+
+    /// Breadth first search for a path `from -> ... -> to` in the dependency
+    /// graph, the path is written to `chain`, returns its length
+    fn find_path(&self, from: usize, to: usize, chain: &mut [u8; MAX_CLASSES]) -> Option<usize> {
+        let mut parent = [u8::max_value(); MAX_CLASSES];
+        let mut queue = [0u8; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        let mut visited = bit(from);
+        queue[0] = from as u8;
+
+        while head < tail {
+            let node = queue[head] as usize;
+            head += 1;
+            if node == to {
+                let mut len = 0;
+                let mut current = to;
+                loop {
+                    chain[len] = current as u8;
+                    len += 1;
+                    if current == from {
+                        break;
+                    }
+                    current = parent[current] as usize;
+                }
+                chain[..len].reverse();
+                return Some(len);
+            }
+
+            let mut next = self.after[node] & !visited;
+            while next != 0 {
+                let neighbour = next.trailing_zeros() as usize;
+                next &= next - 1;
+                visited |= bit(neighbour);
+                parent[neighbour] = node as u8;
+                queue[tail] = neighbour as u8;
+                tail += 1;
+            }
+        }
+
+        None
+    }
+
+    fn report_inversion(&self, acquiring: usize, holding: usize, chain: &[u8]) {
+        self.report_header("possible circular locking dependency");
+        report_line(format_args!(
+            "  acquiring {} while holding {}",
+            self.names[acquiring], self.names[holding]
+        ));
+        report_line(format_args!("  but this order was seen before:"));
+        let mut port = serial_port();
+        let _ = write!(port, "    ");
+        for (index, &class) in chain.iter().enumerate() {
+            let separator = if index == 0 { "" } else { " -> " };
+            let _ = write!(port, "{}{}", separator, self.names[class as usize]);
+        }
+        let _ = writeln!(port);
+        self.report_held();
+    }
+
+    fn report_header(&self, what: &str) {
+        REPORTS.fetch_add(1, Ordering::Relaxed);
+        report_line(format_args!("lockdep: {}", what));
+        if crate::interrupts::in_interrupt() {
+            report_line(format_args!("  (in interrupt context)"));
+        }
+    }
+
+    fn report_held(&self) {
+        let mut port = serial_port();
+        let _ = write!(port, "  held locks:");
+        for class in self.held.iter() {
+            let _ = write!(port, " {}", self.names[class]);
+        }
+        let _ = writeln!(port);
+    }
+}
+
+fn serial_port() -> SerialPort {
+    // Already initialized by `SERIAL1`, which may be the lock being reported
+    unsafe { SerialPort::new(SERIAL_PORT_ADDRESS) }
+}
+
+fn report_line(args: core::fmt::Arguments) {
+    let mut port = serial_port();
+    let _ = port.write_fmt(args);
+    let _ = writeln!(port);
+}
+
+/// Validates and records an acquisition, called right before spinning on the
+/// lock so that a deadlock is reported before it happens
+pub fn acquire(class: &'static LockClass, irq_safe: bool) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut validator = VALIDATOR.lock();
+        let class = match validator.class_id(class) {
+            Some(class) => class,
+            None => return,
+        };
+
+        validator.check_irq_usage(class, irq_safe);
+        validator.check_order(class);
+        validator.held.push(class);
+    });
+}
+
+/// Records a successful `try_lock`, it can't deadlock so the order isn't checked
+pub fn acquired_without_waiting(class: &'static LockClass) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut validator = VALIDATOR.lock();
+        if let Some(class) = validator.class_id(class) {
+            validator.held.push(class);
+        }
+    });
+}
+
+pub fn release(class: &'static LockClass) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    let id = class.id.load(Ordering::Relaxed);
+    if id == UNREGISTERED {
+        return;
+    }
+
+    interrupts::without_interrupts(|| VALIDATOR.lock().held.remove(id - 1));
+}
+
+/// Called by the scheduler with interrupts disabled right before switching
+/// threads, stores the held locks of the old thread and loads the new one's
+pub fn switch_held_locks(old: &mut HeldLocks, new: &HeldLocks) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
+    let mut validator = VALIDATOR.lock();
+    *old = validator.held;
+    validator.held = *new;
+}
+
+/// Number of problems reported so far
+pub fn reports() -> usize {
+    REPORTS.load(Ordering::Relaxed)
+}
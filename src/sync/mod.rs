@@ -0,0 +1,21 @@
+//! Synchronization primitives.
+//!
+//! `IrqSpinLock` is for data shared with interrupt handlers, everything else
+//! puts the waiting thread to sleep through a `WaitQueue` and must only be
+//! used from thread context. Spin locks created with a `lock_class!` are
+//! checked by the lock validator in debug builds.
+
+mod condvar;
+pub mod lockdep;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod spinlock;
+mod wait_queue;
+
+pub use self::condvar::Condvar;
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::semaphore::Semaphore;
+pub use self::spinlock::{IrqSpinLock, IrqSpinLockGuard, SpinLock, SpinLockGuard};
+pub use self::wait_queue::WaitQueue;
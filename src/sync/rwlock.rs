@@ -0,0 +1,141 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use super::{IrqSpinLock, WaitQueue};
+use crate::lock_class;
+
+struct RwState {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+}
+
+/// A sleeping reader-writer lock. Waiting writers block new readers so
+/// that a steady stream of readers can't starve them.
+pub struct RwLock<T: ?Sized> {
+    state: IrqSpinLock<RwState>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub fn new(data: T) -> RwLock<T> {
+        RwLock {
+            state: IrqSpinLock::with_class(
+                RwState {
+                    readers: 0,
+                    writer: false,
+                    waiting_writers: 0,
+                },
+                lock_class!("RwLock.state"),
+            ),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        self.readers.wait_until(|| {
+            let mut state = self.state.lock();
+            if state.writer || state.waiting_writers > 0 {
+                return false;
+            }
+
+            state.readers += 1;
+            true
+        });
+
+        RwLockReadGuard { lock: self }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.state.lock().waiting_writers += 1;
+        self.writers.wait_until(|| {
+            let mut state = self.state.lock();
+            if state.writer || state.readers > 0 {
+                return false;
+            }
+
+            state.waiting_writers -= 1;
+            state.writer = true;
+            true
+        });
+
+        RwLockWriteGuard { lock: self }
+    }
+
+    fn read_unlock(&self) {
+        let last_reader = {
+            let mut state = self.state.lock();
+            state.readers -= 1;
+            state.readers == 0
+        };
+
+        if last_reader {
+            self.writers.wake_one();
+        }
+    }
+
+    fn write_unlock(&self) {
+        let writers_waiting = {
+            let mut state = self.state.lock();
+            state.writer = false;
+            state.waiting_writers > 0
+        };
+
+        if writers_waiting {
+            self.writers.wake_one();
+        } else {
+            self.readers.wake_all();
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
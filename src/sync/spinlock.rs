@@ -0,0 +1,180 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use x86_64::instructions::interrupts;
+
+use super::lockdep::{self, LockClass};
+
+/// A spin lock that disables interrupts while it's held.
+///
+/// An interrupt handler taking a `spin::Mutex` that the interrupted code
+/// holds spins forever, with interrupts disabled the handler can't run
+/// until the lock is released.
+pub struct IrqSpinLock<T: ?Sized> {
+    class: Option<&'static LockClass>,
+    inner: spin::Mutex<T>,
+}
+
+pub struct IrqSpinLockGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    class: Option<&'static LockClass>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    /// Creates a lock that isn't checked by the lock validator
+    pub const fn new(data: T) -> IrqSpinLock<T> {
+        IrqSpinLock {
+            class: None,
+            inner: spin::Mutex::new(data),
+        }
+    }
+
+    /// Creates a lock checked by the lock validator, see `lock_class!`
+    pub const fn with_class(data: T, class: &'static LockClass) -> IrqSpinLock<T> {
+        IrqSpinLock {
+            class: Some(class),
+            inner: spin::Mutex::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSpinLock<T> {
+    pub fn lock(&self) -> IrqSpinLockGuard<T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        if let Some(class) = self.class {
+            lockdep::acquire(class, true);
+        }
+
+        IrqSpinLockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            class: self.class,
+            interrupts_were_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        match self.inner.try_lock() {
+            Some(guard) => {
+                if let Some(class) = self.class {
+                    lockdep::acquired_without_waiting(class);
+                }
+
+                Some(IrqSpinLockGuard {
+                    guard: ManuallyDrop::new(guard),
+                    class: self.class,
+                    interrupts_were_enabled,
+                })
+            }
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Releases the lock without a guard, e.g. when the holder is known to
+    /// never return (panic handler)
+    pub unsafe fn force_unlock(&self) {
+        if let Some(class) = self.class {
+            lockdep::release(class);
+        }
+        self.inner.force_unlock();
+    }
+}
+
+impl<'a, T: ?Sized> Deref for IrqSpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for IrqSpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for IrqSpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // The lock has to be released before interrupts are enabled again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if let Some(class) = self.class {
+            lockdep::release(class);
+        }
+
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// A plain spin lock checked by the lock validator, for data that interrupt
+/// handlers never touch (use `IrqSpinLock` otherwise).
+pub struct SpinLock<T: ?Sized> {
+    class: &'static LockClass,
+    inner: spin::Mutex<T>,
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    class: &'static LockClass,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T, class: &'static LockClass) -> SpinLock<T> {
+        SpinLock {
+            class,
+            inner: spin::Mutex::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinLock<T> {
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        lockdep::acquire(self.class, false);
+        SpinLockGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            class: self.class,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let guard = self.inner.try_lock()?;
+        lockdep::acquired_without_waiting(self.class);
+        Some(SpinLockGuard {
+            guard: ManuallyDrop::new(guard),
+            class: self.class,
+        })
+    }
+}
+
+impl<'a, T: ?Sized> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        lockdep::release(self.class);
+    }
+}
@@ -0,0 +1,132 @@
+use alloc::collections::VecDeque;
+
+use x86_64::instructions::interrupts;
+
+use super::IrqSpinLock;
+use crate::lock_class;
+use crate::task::{self, ThreadId};
+use crate::time;
+
+/// Threads sleeping until some condition becomes true, the building
+/// block of all the blocking primitives.
+pub struct WaitQueue {
+    waiters: IrqSpinLock<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: IrqSpinLock::with_class(VecDeque::new(), lock_class!("WaitQueue")),
+        }
+    }
+
+    /// Blocks the running thread until `condition` returns `true`.
+    ///
+    /// `condition` is checked with interrupts disabled and the thread is
+    /// queued before they're enabled again, so a wake up between the check
+    /// and blocking can't get lost.
+    pub fn wait_until<F>(&self, mut condition: F)
+    where
+        F: FnMut() -> bool,
+    {
+        loop {
+            let satisfied = interrupts::without_interrupts(|| {
+                if condition() {
+                    return true;
+                }
+
+                self.enqueue_current();
+                task::block_current();
+                false
+            });
+
+            if satisfied {
+                return;
+            }
+        }
+    }
+
+    /// Like `wait_until`, but gives up at tick `deadline`. Returns whether
+    /// `condition` became `true`.
+    pub fn wait_until_deadline<F>(&self, mut condition: F, deadline: u64) -> bool
+    where
+        F: FnMut() -> bool,
+    {
+        let mut queued = false;
+        loop {
+            let done = interrupts::without_interrupts(|| {
+                let done = if condition() {
+                    Some(true)
+                } else if time::ticks() >= deadline {
+                    Some(false)
+                } else {
+                    None
+                };
+                if done.is_some() {
+                    // The deadline may have woken it up instead of a waker,
+                    // which would otherwise find it still queued later on
+                    if queued {
+                        self.dequeue_current();
+                    }
+                    return done;
+                }
+
+                self.enqueue_current();
+                queued = true;
+                task::block_current_until(deadline);
+                None
+            });
+
+            if let Some(satisfied) = done {
+                return satisfied;
+            }
+        }
+    }
+
+    /// Queues the running thread, the caller blocks it afterwards with
+    /// interrupts still disabled
+    pub(crate) fn enqueue_current(&self) {
+        if let Some(id) = task::current_id() {
+            self.waiters.lock().push_back(id);
+        }
+    }
+
+    fn dequeue_current(&self) {
+        if let Some(id) = task::current_id() {
+            self.waiters.lock().retain(|&waiter| waiter != id);
+        }
+    }
+
+    /// Wakes the thread that waited the longest, returns `false` if nobody
+    /// was waiting
+    pub fn wake_one(&self) -> bool {
+        loop {
+            let id = match self.waiters.lock().pop_front() {
+                Some(id) => id,
+                None => return false,
+            };
+
+            // Skip threads that were already woken up some other way
+            if task::wake_up(id) {
+                return true;
+            }
+        }
+    }
+
+    /// Wakes every waiting thread, returns how many were woken up
+    pub fn wake_all(&self) -> usize {
+        let mut woken = 0;
+        while self.wake_one() {
+            woken += 1;
+        }
+        woken
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
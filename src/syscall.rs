@@ -0,0 +1,879 @@
+//! System calls.
+//!
+//! User code enters the kernel with `syscall`, or the slower legacy
+//! `int 0x80`, passing the number in `rax` and up to six arguments in `rdi`,
+//! `rsi`, `rdx`, `r10`, `r8` and `r9`. The result is returned in `rax`,
+//! failures as a negative `SyscallError`. Both entries clobber `rcx` and
+//! `r11` and preserve every other register. Pending signals are delivered
+//! on the way back, see `signal`.
+//!
+//! `SYSCALL` loads CS and SS from the STAR MSR as `base` and `base + 8` and
+//! `SYSRET` as `base + 16` and `base + 8`, that's why the GDT holds the
+//! kernel code, kernel data, user data and user code segments in this order.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::{mem, slice};
+
+use x86_64::registers::model_specific::{Efer, EferFlags, Msr};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::address_space::{MapError, COPY_ON_WRITE, USER_SPACE_END, USER_SPACE_START};
+use crate::channel::{self, Channel, ChannelId};
+use crate::elf::ElfError;
+use crate::file::{Console, File};
+use crate::fs::{self, Metadata, SeekFrom};
+use crate::gdt;
+use crate::pipe;
+use crate::process::{self, Pid};
+use crate::programs;
+use crate::shm;
+use crate::signal::{self, Signal, SignalAction};
+use crate::task;
+use crate::user;
+
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_EXIT: u64 = 2;
+pub const SYS_SLEEP: u64 = 3;
+pub const SYS_GETPID: u64 = 4;
+pub const SYS_MMAP: u64 = 5;
+pub const SYS_WAIT: u64 = 6;
+pub const SYS_GETPPID: u64 = 7;
+pub const SYS_CLOSE: u64 = 8;
+pub const SYS_FORK: u64 = 9;
+pub const SYS_EXEC: u64 = 10;
+pub const SYS_KILL: u64 = 11;
+pub const SYS_SIGACTION: u64 = 12;
+pub const SYS_SIGPROCMASK: u64 = 13;
+pub const SYS_SIGRETURN: u64 = 14;
+pub const SYS_PIPE: u64 = 15;
+pub const SYS_CHANNEL: u64 = 16;
+pub const SYS_CHANNEL_OPEN: u64 = 17;
+pub const SYS_SHM_MAP: u64 = 18;
+pub const SYS_SHM_UNMAP: u64 = 19;
+pub const SYS_SHM_UNLINK: u64 = 20;
+pub const SYS_OPEN: u64 = 21;
+pub const SYS_SEEK: u64 = 22;
+pub const SYS_STAT: u64 = 23;
+pub const SYS_FSTAT: u64 = 24;
+pub const SYS_READDIR: u64 = 25;
+pub const SYS_MKDIR: u64 = 26;
+pub const SYS_CHDIR: u64 = 27;
+pub const SYS_GETCWD: u64 = 28;
+pub const SYS_UNLINK: u64 = 29;
+pub const SYS_RMDIR: u64 = 30;
+pub const SYS_RENAME: u64 = 31;
+pub const SYS_FTRUNCATE: u64 = 32;
+pub const SYS_SYNC: u64 = 33;
+
+/// `mmap` protection flags
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+const MSR_STAR: u32 = 0xC000_0081;
+const MSR_LSTAR: u32 = 0xC000_0082;
+const MSR_FMASK: u32 = 0xC000_0084;
+// IF, TF, DF and AC are cleared on entry
+const SYSCALL_RFLAGS_MASK: u64 = 0x4_0700;
+
+/// Negated and returned in `rax`, the values match Linux
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(i64)]
+pub enum SyscallError {
+    NoSuchFile = 2,
+    NoSuchProcess = 3,
+    Interrupted = 4,
+    IoError = 5,
+    ArgumentListTooLong = 7,
+    ExecFormat = 8,
+    BadFileDescriptor = 9,
+    NoChildren = 10,
+    WouldBlock = 11,
+    OutOfMemory = 12,
+    BadAddress = 14,
+    Busy = 16,
+    FileExists = 17,
+    CrossDevice = 18,
+    NotADirectory = 20,
+    IsADirectory = 21,
+    InvalidArgument = 22,
+    TooManyFiles = 24,
+    NoSpace = 28,
+    IllegalSeek = 29,
+    ReadOnlyFileSystem = 30,
+    BrokenPipe = 32,
+    NameTooLong = 36,
+    NotImplemented = 38,
+    DirectoryNotEmpty = 39,
+    SymlinkLoop = 40,
+    MessageTooLong = 90,
+    NotSupported = 95,
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+impl From<ElfError> for SyscallError {
+    fn from(error: ElfError) -> SyscallError {
+        match error {
+            ElfError::ArgumentsTooLong => SyscallError::ArgumentListTooLong,
+            ElfError::Map(MapError::FrameAllocationFailed) => SyscallError::OutOfMemory,
+            _ => SyscallError::ExecFormat,
+        }
+    }
+}
+
+/// User registers saved by the entry stubs, the handlers may change them
+/// to alter what user code sees after the syscall returns. The interrupt
+/// stubs in `trap` save the same frame.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    fn arg(&self, index: usize) -> u64 {
+        match index {
+            0 => self.rdi,
+            1 => self.rsi,
+            2 => self.rdx,
+            3 => self.r10,
+            4 => self.r8,
+            5 => self.r9,
+            _ => panic!("Syscalls take at most 6 arguments"),
+        }
+    }
+}
+
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global ham_dos_syscall_entry
+
+// The user stack pointer is only kept here until the kernel stack is loaded,
+// interrupts are off until then
+.section .bss
+.align 8
+ham_dos_syscall_user_rsp:
+    .quad 0
+.section .text
+
+// rcx holds the user rip and r11 the user rflags, they're saved twice so
+// the frame looks the same as the one the interrupt stubs build
+ham_dos_syscall_entry:
+    mov [rip + ham_dos_syscall_user_rsp], rsp
+    mov rsp, [rip + HAM_DOS_SYSCALL_STACK]
+    push qword ptr [rip + ham_dos_syscall_user_rsp]
+    push rcx
+    push r11
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push r8
+    push r9
+    push r10
+    push r11
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    call ham_dos_syscall_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop r11
+    pop rcx
+    pop rsp
+    sysretq
+.att_syntax
+"#
+);
+
+extern "C" {
+    fn ham_dos_syscall_entry();
+}
+
+/// Top of the running thread's kernel stack, `syscall` doesn't switch
+/// stacks by itself
+#[no_mangle]
+static mut HAM_DOS_SYSCALL_STACK: u64 = 0;
+
+pub fn init() {
+    // SYSRET loads the user data segment from base + 8
+    let user_base = u64::from(gdt::user_data_selector().0) - 8;
+    let star = (user_base << 48) | (u64::from(gdt::kernel_code_selector().0) << 32);
+
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+        Msr::new(MSR_STAR).write(star);
+        Msr::new(MSR_LSTAR).write(ham_dos_syscall_entry as usize as u64);
+        Msr::new(MSR_FMASK).write(SYSCALL_RFLAGS_MASK);
+    }
+}
+
+/// Sets the stack `syscall` switches to, has to match the TSS
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        HAM_DOS_SYSCALL_STACK = stack_top.as_u64();
+    }
+}
+
+type SyscallHandler = fn(&mut SyscallFrame) -> SyscallResult;
+
+/// Indexed by the syscall number
+static SYSCALL_TABLE: [SyscallHandler; 34] = [
+    sys_read,
+    sys_write,
+    sys_exit,
+    sys_sleep,
+    sys_getpid,
+    sys_mmap,
+    sys_wait,
+    sys_getppid,
+    sys_close,
+    sys_fork,
+    sys_exec,
+    sys_kill,
+    sys_sigaction,
+    sys_sigprocmask,
+    sys_sigreturn,
+    sys_pipe,
+    sys_channel,
+    sys_channel_open,
+    sys_shm_map,
+    sys_shm_unmap,
+    sys_shm_unlink,
+    sys_open,
+    sys_seek,
+    sys_stat,
+    sys_fstat,
+    sys_readdir,
+    sys_mkdir,
+    sys_chdir,
+    sys_getcwd,
+    sys_unlink,
+    sys_rmdir,
+    sys_rename,
+    sys_ftruncate,
+    sys_sync,
+];
+
+/// Runs the syscall `frame` asks for and delivers pending signals, both
+/// entries end up here. Called and returns with interrupts disabled.
+pub fn handle(frame: &mut SyscallFrame) {
+    x86_64::instructions::interrupts::enable();
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => Err(SyscallError::NotImplemented),
+    };
+
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(error) => (-(error as i64)) as u64,
+    };
+    signal::deliver_pending(frame);
+    frame.rflags = user::sanitize_rflags(frame.rflags);
+    // The stubs restore the user registers with interrupts off
+    x86_64::instructions::interrupts::disable();
+}
+
+#[no_mangle]
+extern "C" fn ham_dos_syscall_dispatch(frame: &mut SyscallFrame) {
+    let number = frame.rax;
+    handle(frame);
+
+    // `sysret` overwrites rcx and r11, the context `sigreturn` restored
+    // needs all of its registers back
+    if number == SYS_SIGRETURN {
+        user::return_to_user(frame);
+    }
+}
+
+/// Checks that user code may access `size` bytes at `addr`
+fn check_user_range(addr: u64, size: usize, writable: bool) -> Result<(), SyscallError> {
+    let end = addr
+        .checked_add(size as u64)
+        .ok_or(SyscallError::BadAddress)?;
+    if addr < USER_SPACE_START || end > USER_SPACE_END {
+        return Err(SyscallError::BadAddress);
+    }
+    if size == 0 {
+        return Ok(());
+    }
+
+    let space = task::current_address_space().ok_or(SyscallError::BadAddress)?;
+    let first = Page::containing_address(VirtAddr::new(addr));
+    let last = Page::containing_address(VirtAddr::new(end - 1));
+    for page in Page::range_inclusive(first, last) {
+        let (_, flags) = space
+            .translate(page.start_address())
+            .ok_or(SyscallError::BadAddress)?;
+        if writable && !flags.contains(PageTableFlags::WRITABLE) {
+            // Copy the page now instead of faulting while the kernel writes to it
+            if !flags.contains(COPY_ON_WRITE) || space.resolve_copy_on_write(page).is_err() {
+                return Err(SyscallError::BadAddress);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns a user buffer after checking that it's mapped
+pub fn user_buffer<'a>(addr: u64, size: usize) -> Result<&'a [u8], SyscallError> {
+    check_user_range(addr, size, false)?;
+    if size == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { slice::from_raw_parts(addr as *const u8, size) })
+}
+
+/// Returns a user buffer after checking that it's mapped writable
+pub fn user_buffer_mut<'a>(addr: u64, size: usize) -> Result<&'a mut [u8], SyscallError> {
+    check_user_range(addr, size, true)?;
+    if size == 0 {
+        return Ok(&mut []);
+    }
+    Ok(unsafe { slice::from_raw_parts_mut(addr as *mut u8, size) })
+}
+
+/// Looks up a file descriptor of the calling process. User threads that
+/// don't belong to a process only have the console.
+fn current_file(fd: u64) -> Result<Arc<dyn File>, SyscallError> {
+    match process::current_pid() {
+        Some(_) => process::file(fd as usize),
+        None if fd <= 2 => Ok(Arc::new(Console)),
+        None => Err(SyscallError::BadFileDescriptor),
+    }
+}
+
+// Longest string accepted from user code
+const MAX_USER_STRING: usize = 4096;
+// Most strings accepted in an array like `argv`
+const MAX_USER_STRINGS: usize = 256;
+
+/// Copies a NUL terminated UTF-8 string from user memory
+pub fn user_string(addr: u64) -> Result<String, SyscallError> {
+    let mut bytes = Vec::new();
+    let mut current = addr;
+    loop {
+        // Checked a page at a time, the string may end before the next one
+        let page_end = (current & !4095) + 4096;
+        let chunk = user_buffer(current, (page_end - current) as usize)?;
+        if let Some(end) = chunk.iter().position(|&byte| byte == 0) {
+            bytes.extend_from_slice(&chunk[..end]);
+            break;
+        }
+
+        bytes.extend_from_slice(chunk);
+        if bytes.len() > MAX_USER_STRING {
+            return Err(SyscallError::ArgumentListTooLong);
+        }
+        current = page_end;
+    }
+
+    String::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)
+}
+
+/// Copies a null terminated array of string pointers like `argv`, a null
+/// `addr` is an empty array
+pub fn user_string_array(addr: u64) -> Result<Vec<String>, SyscallError> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings);
+    }
+
+    loop {
+        let mut word = [0; 8];
+        word.copy_from_slice(user_buffer(addr + strings.len() as u64 * 8, 8)?);
+        let pointer = u64::from_le_bytes(word);
+        if pointer == 0 {
+            return Ok(strings);
+        }
+        if strings.len() == MAX_USER_STRINGS {
+            return Err(SyscallError::ArgumentListTooLong);
+        }
+        strings.push(user_string(pointer)?);
+    }
+}
+
+/// read(fd, buffer, size) -> bytes read
+fn sys_read(frame: &mut SyscallFrame) -> SyscallResult {
+    let file = current_file(frame.arg(0))?;
+    let buffer = user_buffer_mut(frame.arg(1), frame.arg(2) as usize)?;
+    file.read(buffer).map(|count| count as u64)
+}
+
+/// write(fd, buffer, size) -> bytes written
+fn sys_write(frame: &mut SyscallFrame) -> SyscallResult {
+    let file = current_file(frame.arg(0))?;
+    let buffer = user_buffer(frame.arg(1), frame.arg(2) as usize)?;
+    file.write(buffer).map(|count| count as u64)
+}
+
+/// exit(code) -> never returns
+fn sys_exit(frame: &mut SyscallFrame) -> SyscallResult {
+    user::exit(frame.arg(0) as i32);
+}
+
+/// sleep(milliseconds)
+fn sys_sleep(frame: &mut SyscallFrame) -> SyscallResult {
+    task::sleep_ms(frame.arg(0));
+    Ok(0)
+}
+
+/// getpid() -> id of the calling process, or of the thread outside of a process
+fn sys_getpid(_frame: &mut SyscallFrame) -> SyscallResult {
+    if let Some(pid) = process::current_pid() {
+        return Ok(pid.as_u64());
+    }
+
+    task::current_id()
+        .map(|id| id.as_u64())
+        .ok_or(SyscallError::InvalidArgument)
+}
+
+/// getppid() -> id of the parent process, 0 for the kernel
+fn sys_getppid(_frame: &mut SyscallFrame) -> SyscallResult {
+    Ok(process::parent_pid().map_or(0, Pid::as_u64))
+}
+
+/// wait(pid, status) -> pid of the reaped child, waits for any child if
+/// `pid` is -1. Stores the exit code at `status` unless it's null.
+fn sys_wait(frame: &mut SyscallFrame) -> SyscallResult {
+    let pid = match frame.arg(0) as i64 {
+        -1 => None,
+        pid if pid > 0 => Some(Pid::from_u64(pid as u64)),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    let status = frame.arg(1);
+    if status != 0 {
+        check_user_range(status, 4, true)?;
+    }
+
+    let (child, code) = process::wait(pid)?;
+    if status != 0 {
+        user_buffer_mut(status, 4)?.copy_from_slice(&code.to_le_bytes());
+    }
+    Ok(child.as_u64())
+}
+
+/// fork() -> pid of the child in the parent, 0 in the child
+fn sys_fork(frame: &mut SyscallFrame) -> SyscallResult {
+    process::fork(frame).map(Pid::as_u64)
+}
+
+/// exec(path, argv, envp) -> only returns on failure, `path` names a
+/// built-in program or a file, `argv` and `envp` are null terminated arrays
+/// of strings
+fn sys_exec(frame: &mut SyscallFrame) -> SyscallResult {
+    if process::current_pid().is_none() {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let path = user_string(frame.arg(0))?;
+    let argv = user_string_array(frame.arg(1))?;
+    let envp = user_string_array(frame.arg(2))?;
+    // Built-in programs come first, their names don't contain a `/`
+    let file;
+    let data = match programs::find(&path) {
+        Some(data) => data,
+        None => {
+            file = fs::read(&path)?;
+            &file[..]
+        }
+    };
+
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let envp: Vec<&str> = envp.iter().map(String::as_str).collect();
+    process::exec(&path, data, &argv, &envp, frame)?;
+    Ok(0)
+}
+
+/// close(fd)
+fn sys_close(frame: &mut SyscallFrame) -> SyscallResult {
+    let fd = frame.arg(0) as usize;
+    process::with_files(|files| files.close(fd))?;
+    Ok(0)
+}
+
+/// mmap(address, size, protection) -> address of new zeroed memory,
+/// the kernel picks the address if it's 0
+fn sys_mmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let (addr, size, protection) = (frame.arg(0), frame.arg(1) as usize, frame.arg(2));
+    if size == 0 || addr % 4096 != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    // Pages are mapped whole, the rounded size has to fit as well
+    let rounded = (size as u64)
+        .checked_add(4095)
+        .ok_or(SyscallError::InvalidArgument)?
+        & !4095;
+
+    let space = task::current_address_space().ok_or(SyscallError::InvalidArgument)?;
+    let start = if addr == 0 {
+        space
+            .reserve_region(size)
+            .map_err(|_| SyscallError::OutOfMemory)?
+    } else {
+        check_user_range(addr, 0, false)?;
+        if rounded > USER_SPACE_END - addr {
+            return Err(SyscallError::InvalidArgument);
+        }
+        VirtAddr::new(addr)
+    };
+
+    let mut flags = PageTableFlags::empty();
+    if protection & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if protection & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    if let Err(error) = user::map_region(&space, start, size, flags) {
+        if addr == 0 {
+            space.release_region(start, size);
+        }
+        return Err(match error {
+            MapError::FrameAllocationFailed => SyscallError::OutOfMemory,
+            _ => SyscallError::InvalidArgument,
+        });
+    }
+    Ok(start.as_u64())
+}
+
+/// kill(pid, signal), signal 0 only checks that the process exists
+fn sys_kill(frame: &mut SyscallFrame) -> SyscallResult {
+    let pid = match frame.arg(0) as i64 {
+        pid if pid > 0 => Pid::from_u64(pid as u64),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    let signal = frame.arg(1);
+    if signal != 0 && !signal::is_valid(signal) {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    process::send_signal(pid, signal as Signal)?;
+    Ok(0)
+}
+
+/// sigaction(signal, handler, restorer) -> previous handler. `handler` is
+/// `SIG_DFL`, `SIG_IGN` or a function taking the signal number, it returns
+/// to `restorer` which has to call `sigreturn` without touching the stack.
+fn sys_sigaction(frame: &mut SyscallFrame) -> SyscallResult {
+    let signal = frame.arg(0);
+    if !signal::is_valid(signal) {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let action = match (frame.arg(1), frame.arg(2)) {
+        (signal::SIG_DFL, _) => SignalAction::Default,
+        (signal::SIG_IGN, _) => SignalAction::Ignore,
+        (handler, restorer) => {
+            check_user_range(handler, 1, false)?;
+            check_user_range(restorer, 1, false)?;
+            SignalAction::Handler { handler, restorer }
+        }
+    };
+
+    let previous = process::with_signals(|signals| signals.set_action(signal as Signal, action))
+        .ok_or(SyscallError::InvalidArgument)??;
+    Ok(match previous {
+        SignalAction::Default => signal::SIG_DFL,
+        SignalAction::Ignore => signal::SIG_IGN,
+        SignalAction::Handler { handler, .. } => handler,
+    })
+}
+
+/// sigprocmask(how, mask) -> previous mask, `how` is `SIG_BLOCK`,
+/// `SIG_UNBLOCK` or `SIG_SETMASK`. Bit `n` of a mask stands for signal `n`.
+fn sys_sigprocmask(frame: &mut SyscallFrame) -> SyscallResult {
+    let (how, mask) = (frame.arg(0), frame.arg(1) as u32);
+    process::with_signals(|signals| {
+        let previous = signals.blocked();
+        let blocked = match how {
+            signal::SIG_BLOCK => previous | mask,
+            signal::SIG_UNBLOCK => previous & !mask,
+            signal::SIG_SETMASK => mask,
+            _ => return Err(SyscallError::InvalidArgument),
+        };
+        signals.set_blocked(blocked);
+        Ok(u64::from(previous))
+    })
+    .ok_or(SyscallError::InvalidArgument)?
+}
+
+/// sigreturn() -> never returns to the caller, resumes the code a signal
+/// handler interrupted
+fn sys_sigreturn(frame: &mut SyscallFrame) -> SyscallResult {
+    if process::current_pid().is_none() {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    if let Err(error) = signal::sigreturn(frame) {
+        // There's nothing sane to go back to
+        signal::force(signal::SIGSEGV);
+        return Err(error);
+    }
+    // `rax` is part of the restored registers
+    Ok(frame.rax)
+}
+
+/// pipe(fds) -> 0, stores the descriptors of the read and the write end
+/// at `fds` as two 32 bit integers
+fn sys_pipe(frame: &mut SyscallFrame) -> SyscallResult {
+    let fds = frame.arg(0);
+    check_user_range(fds, 8, true)?;
+
+    let (reader, writer) = pipe::pipe();
+    let (read_fd, write_fd) = process::with_files(|files| {
+        let read_fd = files.insert(reader)?;
+        match files.insert(writer) {
+            Ok(write_fd) => Ok((read_fd, write_fd)),
+            Err(error) => {
+                files.close(read_fd)?;
+                Err(error)
+            }
+        }
+    })?;
+
+    let buffer = user_buffer_mut(fds, 8)?;
+    buffer[..4].copy_from_slice(&(read_fd as u32).to_le_bytes());
+    buffer[4..].copy_from_slice(&(write_fd as u32).to_le_bytes());
+    Ok(0)
+}
+
+/// channel(capacity, id) -> descriptor of a new channel for up to
+/// `capacity` messages, its handle is stored at `id` unless it's null
+fn sys_channel(frame: &mut SyscallFrame) -> SyscallResult {
+    let (capacity, id) = (frame.arg(0) as usize, frame.arg(1));
+    if capacity == 0 || capacity > channel::MAX_CAPACITY {
+        return Err(SyscallError::InvalidArgument);
+    }
+    if id != 0 {
+        check_user_range(id, 8, true)?;
+    }
+
+    let channel = Channel::new(capacity);
+    let handle = channel.id();
+    let fd = process::with_files(|files| files.insert(channel))?;
+    if id != 0 {
+        user_buffer_mut(id, 8)?.copy_from_slice(&handle.as_u64().to_le_bytes());
+    }
+    Ok(fd as u64)
+}
+
+/// channel_open(id) -> descriptor of the channel with the handle `id`
+fn sys_channel_open(frame: &mut SyscallFrame) -> SyscallResult {
+    let channel = Channel::open(ChannelId::from_u64(frame.arg(0)))
+        .ok_or(SyscallError::NoSuchFile)?;
+    let fd = process::with_files(|files| files.insert(channel))?;
+    Ok(fd as u64)
+}
+
+/// shm_map(name, size, protection) -> address the shared memory object
+/// `name` got mapped at, it's created with `size` zeroed bytes if needed
+fn sys_shm_map(frame: &mut SyscallFrame) -> SyscallResult {
+    let name = user_string(frame.arg(0))?;
+    let (size, protection) = (frame.arg(1) as usize, frame.arg(2));
+    if name.is_empty() {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let space = task::current_address_space().ok_or(SyscallError::InvalidArgument)?;
+    let object = shm::open_or_create(&name, size)?;
+    let start = space
+        .reserve_region(object.size())
+        .map_err(|_| SyscallError::OutOfMemory)?;
+    if let Err(error) = object.map(&space, start, protection & PROT_WRITE != 0) {
+        space.release_region(start, object.size());
+        return Err(error.into());
+    }
+    Ok(start.as_u64())
+}
+
+/// shm_unmap(address, size), the range has to be shared memory
+fn sys_shm_unmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let (addr, size) = (frame.arg(0), frame.arg(1) as usize);
+    check_user_range(addr, size, false)?;
+    let space = task::current_address_space().ok_or(SyscallError::InvalidArgument)?;
+    shm::unmap(&space, VirtAddr::new(addr), size)?;
+    Ok(0)
+}
+
+/// shm_unlink(name), the memory lives on in the address spaces mapping it
+fn sys_shm_unlink(frame: &mut SyscallFrame) -> SyscallResult {
+    let name = user_string(frame.arg(0))?;
+    shm::unlink(&name)?;
+    Ok(0)
+}
+
+/// open(path, flags) -> descriptor, `flags` are the `fs::O_*` constants
+fn sys_open(frame: &mut SyscallFrame) -> SyscallResult {
+    let path = user_string(frame.arg(0))?;
+    let file = fs::open(&path, frame.arg(1) as u32)?;
+    let fd = process::with_files(|files| files.insert(file))?;
+    Ok(fd as u64)
+}
+
+/// seek(fd, offset, whence) -> new position, `whence` is one of the
+/// `fs::SEEK_*` constants
+fn sys_seek(frame: &mut SyscallFrame) -> SyscallResult {
+    let file = current_file(frame.arg(0))?;
+    let from = SeekFrom::from_whence(frame.arg(2), frame.arg(1) as i64)
+        .ok_or(SyscallError::InvalidArgument)?;
+    file.seek(from)
+}
+
+fn copy_metadata(metadata: &Metadata, addr: u64) -> SyscallResult {
+    let size = mem::size_of::<Metadata>();
+    let bytes = unsafe { slice::from_raw_parts(metadata as *const Metadata as *const u8, size) };
+    user_buffer_mut(addr, size)?.copy_from_slice(bytes);
+    Ok(0)
+}
+
+/// stat(path, metadata) -> 0, stores an `fs::Metadata` at `metadata`
+fn sys_stat(frame: &mut SyscallFrame) -> SyscallResult {
+    let path = user_string(frame.arg(0))?;
+    copy_metadata(&fs::metadata(&path)?, frame.arg(1))
+}
+
+/// fstat(fd, metadata) -> 0, like `stat` for an open file
+fn sys_fstat(frame: &mut SyscallFrame) -> SyscallResult {
+    let file = current_file(frame.arg(0))?;
+    copy_metadata(&file.metadata()?, frame.arg(1))
+}
+
+// Inode number, file type and name length in front of the name
+const DIR_ENTRY_HEADER_SIZE: usize = 16;
+
+/// readdir(fd, buffer, size) -> size of the next entry of the directory,
+/// 0 after the last one. An entry is the inode number (8 bytes), the file
+/// type and the length of the name (4 bytes each) and the NUL terminated
+/// name, `buffer` has to hold one with a name of `MAX_NAME` bytes.
+fn sys_readdir(frame: &mut SyscallFrame) -> SyscallResult {
+    let file = current_file(frame.arg(0))?;
+    let (addr, size) = (frame.arg(1), frame.arg(2) as usize);
+    // Checked first, the entry would be lost otherwise
+    check_user_range(addr, size, true)?;
+    if size < DIR_ENTRY_HEADER_SIZE + fs::path::MAX_NAME + 1 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let entry = match file.read_dir()? {
+        Some(entry) => entry,
+        None => return Ok(0),
+    };
+    let name = entry.name.as_bytes();
+    let length = DIR_ENTRY_HEADER_SIZE + name.len() + 1;
+    let buffer = user_buffer_mut(addr, length)?;
+    buffer[..8].copy_from_slice(&entry.inode.to_le_bytes());
+    buffer[8..12].copy_from_slice(&(entry.file_type as u32).to_le_bytes());
+    buffer[12..16].copy_from_slice(&(name.len() as u32).to_le_bytes());
+    buffer[16..length - 1].copy_from_slice(name);
+    buffer[length - 1] = 0;
+    Ok(length as u64)
+}
+
+/// mkdir(path)
+fn sys_mkdir(frame: &mut SyscallFrame) -> SyscallResult {
+    let path = user_string(frame.arg(0))?;
+    fs::create_dir(&path)?;
+    Ok(0)
+}
+
+/// chdir(path)
+fn sys_chdir(frame: &mut SyscallFrame) -> SyscallResult {
+    let path = user_string(frame.arg(0))?;
+    fs::set_current_dir(&path)?;
+    Ok(0)
+}
+
+/// getcwd(buffer, size) -> length of the working directory, which is
+/// stored NUL terminated in `buffer`
+fn sys_getcwd(frame: &mut SyscallFrame) -> SyscallResult {
+    let cwd = process::current_dir();
+    let (addr, size) = (frame.arg(0), frame.arg(1) as usize);
+    if cwd.len() + 1 > size {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let buffer = user_buffer_mut(addr, cwd.len() + 1)?;
+    buffer[..cwd.len()].copy_from_slice(cwd.as_bytes());
+    buffer[cwd.len()] = 0;
+    Ok(cwd.len() as u64)
+}
+
+/// unlink(path), removes a file
+fn sys_unlink(frame: &mut SyscallFrame) -> SyscallResult {
+    let path = user_string(frame.arg(0))?;
+    fs::remove_file(&path)?;
+    Ok(0)
+}
+
+/// rmdir(path), removes an empty directory
+fn sys_rmdir(frame: &mut SyscallFrame) -> SyscallResult {
+    let path = user_string(frame.arg(0))?;
+    fs::remove_dir(&path)?;
+    Ok(0)
+}
+
+/// rename(from, to)
+fn sys_rename(frame: &mut SyscallFrame) -> SyscallResult {
+    let from = user_string(frame.arg(0))?;
+    let to = user_string(frame.arg(1))?;
+    fs::rename(&from, &to)?;
+    Ok(0)
+}
+
+/// ftruncate(fd, size), changes the size of a regular file
+fn sys_ftruncate(frame: &mut SyscallFrame) -> SyscallResult {
+    let file = current_file(frame.arg(0))?;
+    file.set_len(frame.arg(1))?;
+    Ok(0)
+}
+
+/// sync(), writes cached filesystem data and disk blocks to the disks
+fn sys_sync(_frame: &mut SyscallFrame) -> SyscallResult {
+    fs::sync()?;
+    Ok(0)
+}
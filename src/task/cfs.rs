@@ -0,0 +1,156 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+
+use super::scheduler::{nice_to_weight, SchedEntity, Scheduler, NICE_0_WEIGHT};
+use super::ThreadId;
+#[cfg(test)]
+use crate::serial_println;
+
+/// Virtual run time a nice 0 thread accumulates per tick
+const TICK_VRUNTIME: u64 = 1_000_000;
+/// Period in which every runnable thread should get to run once
+const SCHED_LATENCY_TICKS: u64 = 6;
+/// Smallest time slice handed out no matter how many threads are runnable
+const MIN_GRANULARITY_TICKS: u64 = 1;
+/// How far ahead of a woken thread the running one may be before it's
+/// preempted, avoids switching on every wake up
+const WAKEUP_GRANULARITY: u64 = TICK_VRUNTIME;
+
+/// A CFS-like fair scheduler.
+///
+/// Every thread accumulates virtual run time at a rate inversely proportional
+/// to its weight (derived from the nice value), the thread with the smallest
+/// virtual run time runs next. https://docs.kernel.org/scheduler/sched-design-CFS.html
+pub struct FairScheduler {
+    /// Runnable threads ordered by virtual run time
+    timeline: BTreeSet<(u64, ThreadId)>,
+    /// vruntime and weight of each queued thread, to find it in `timeline`
+    queued: BTreeMap<ThreadId, (u64, u64)>,
+    total_weight: u64,
+    /// Monotonic lower bound of the vruntime of all runnable threads
+    min_vruntime: u64,
+}
+
+impl FairScheduler {
+    pub fn new() -> FairScheduler {
+        FairScheduler {
+            timeline: BTreeSet::new(),
+            queued: BTreeMap::new(),
+            total_weight: 0,
+            min_vruntime: 0,
+        }
+    }
+
+    fn leftmost_vruntime(&self) -> Option<u64> {
+        self.timeline.iter().next().map(|&(vruntime, _)| vruntime)
+    }
+
+    fn update_min_vruntime(&mut self, current_vruntime: u64) {
+        let candidate = match self.leftmost_vruntime() {
+            Some(leftmost) => core::cmp::min(leftmost, current_vruntime),
+            None => current_vruntime,
+        };
+        self.min_vruntime = core::cmp::max(self.min_vruntime, candidate);
+    }
+
+    /// Length of the time slice of a thread with the given weight
+    fn slice(&self, weight: u64) -> u64 {
+        let slice = SCHED_LATENCY_TICKS * weight / (self.total_weight + weight);
+        core::cmp::max(slice, MIN_GRANULARITY_TICKS)
+    }
+}
+
+impl Scheduler for FairScheduler {
+    fn name(&self) -> &'static str {
+        "fair"
+    }
+
+    fn enqueue(&mut self, id: ThreadId, entity: &mut SchedEntity) {
+        // New and long sleeping threads start slightly before everyone else
+        // but can't use the time they slept to monopolize the CPU
+        let sleeper_credit = SCHED_LATENCY_TICKS * TICK_VRUNTIME / 2;
+        let floor = self.min_vruntime.saturating_sub(sleeper_credit);
+        entity.vruntime = core::cmp::max(entity.vruntime, floor);
+
+        let weight = nice_to_weight(entity.nice);
+        self.timeline.insert((entity.vruntime, id));
+        self.queued.insert(id, (entity.vruntime, weight));
+        self.total_weight += weight;
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let &(vruntime, id) = self.timeline.iter().next()?;
+        self.timeline.remove(&(vruntime, id));
+        if let Some((_, weight)) = self.queued.remove(&id) {
+            self.total_weight -= weight;
+        }
+
+        Some(id)
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        if let Some((vruntime, weight)) = self.queued.remove(&id) {
+            self.timeline.remove(&(vruntime, id));
+            self.total_weight -= weight;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    fn tick(&mut self, current: &mut SchedEntity) -> bool {
+        let weight = nice_to_weight(current.nice);
+        current.vruntime += TICK_VRUNTIME * NICE_0_WEIGHT / weight;
+        self.update_min_vruntime(current.vruntime);
+
+        let slice_used = current.ran_ticks >= self.slice(weight);
+        let behind = match self.leftmost_vruntime() {
+            Some(leftmost) => leftmost < current.vruntime,
+            None => false,
+        };
+
+        (slice_used && behind) || self.check_preempt(current)
+    }
+
+    fn check_preempt(&self, current: &SchedEntity) -> bool {
+        match self.leftmost_vruntime() {
+            Some(leftmost) => leftmost + WAKEUP_GRANULARITY < current.vruntime,
+            None => false,
+        }
+    }
+}
+
+#[test_case]
+fn test_fair_scheduler_weights() {
+    let mut scheduler = FairScheduler::new();
+    let mut favoured = SchedEntity::new(-5);
+    let mut normal = SchedEntity::new(0);
+    let mut runs = [0u64; 2];
+
+    scheduler.enqueue(ThreadId(1), &mut favoured);
+    scheduler.enqueue(ThreadId(2), &mut normal);
+
+    for _ in 0..1000 {
+        let id = scheduler.pick_next().unwrap();
+        let (entity, index) = if id == ThreadId(1) {
+            (&mut favoured, 0)
+        } else {
+            (&mut normal, 1)
+        };
+
+        entity.ran_ticks = 0;
+        loop {
+            entity.ran_ticks += 1;
+            runs[index] += 1;
+            if scheduler.tick(entity) {
+                break;
+            }
+        }
+        scheduler.enqueue(id, entity);
+    }
+
+    // Nice -5 weighs about 3 times as much as nice 0
+    let ratio = runs[0] * 10 / runs[1];
+    assert!(ratio >= 25 && ratio <= 35, "unexpected ratio {}", ratio);
+    serial_println!("Fair scheduler weights...[ok]");
+}
@@ -0,0 +1,167 @@
+use alloc::collections::VecDeque;
+
+use super::scheduler::{Nice, SchedEntity, Scheduler, NICE_MAX};
+use super::ThreadId;
+use crate::time;
+#[cfg(test)]
+use crate::serial_println;
+
+const LEVELS: usize = 4;
+/// Time allotment of the top level, doubles on every level below it
+const BASE_QUANTUM_TICKS: u64 = 2;
+/// Every thread goes back to its starting level this often so that
+/// CPU bound threads don't starve
+const BOOST_INTERVAL_TICKS: u64 = 100;
+
+/// Multi-level feedback queue.
+///
+/// Threads start at a level picked by their nice value and are demoted once
+/// they use up the time allotment of their level, threads that block a lot
+/// (e.g. waiting for keyboard input) stay on the top levels and preempt the
+/// CPU hogs as soon as they're woken up.
+/// http://pages.cs.wisc.edu/~remzi/OSTEP/cpu-sched-mlfq.pdf
+pub struct MlfqScheduler {
+    queues: [VecDeque<(ThreadId, Nice)>; LEVELS],
+    epoch: u64,
+    last_boost: u64,
+}
+
+impl MlfqScheduler {
+    pub fn new() -> MlfqScheduler {
+        MlfqScheduler {
+            queues: [
+                VecDeque::new(),
+                VecDeque::new(),
+                VecDeque::new(),
+                VecDeque::new(),
+            ],
+            epoch: 0,
+            last_boost: time::ticks(),
+        }
+    }
+
+    /// Level a thread starts at and returns to on every boost, nice values
+    /// <= 0 start at the top, positive ones are spread over the rest
+    fn base_level(nice: Nice) -> usize {
+        if nice <= 0 {
+            0
+        } else {
+            let spread = (nice as usize * (LEVELS - 1) + NICE_MAX as usize - 1) / NICE_MAX as usize;
+            core::cmp::min(spread, LEVELS - 1)
+        }
+    }
+
+    fn quantum(level: usize) -> u64 {
+        BASE_QUANTUM_TICKS << level
+    }
+
+    /// Resets the level of threads that weren't seen since the last boost
+    fn refresh(&self, entity: &mut SchedEntity) {
+        if entity.epoch != self.epoch {
+            entity.epoch = self.epoch;
+            entity.level = MlfqScheduler::base_level(entity.nice);
+            entity.level_ticks = 0;
+        }
+    }
+
+    fn boost_if_due(&mut self) {
+        let now = time::ticks();
+        if now - self.last_boost < BOOST_INTERVAL_TICKS {
+            return;
+        }
+
+        self.last_boost = now;
+        self.epoch += 1;
+        for level in 1..LEVELS {
+            while let Some((id, nice)) = self.queues[level].pop_front() {
+                let base = MlfqScheduler::base_level(nice);
+                self.queues[base].push_back((id, nice));
+            }
+        }
+    }
+
+    fn highest_ready_level(&self) -> Option<usize> {
+        self.queues.iter().position(|queue| !queue.is_empty())
+    }
+}
+
+impl Scheduler for MlfqScheduler {
+    fn name(&self) -> &'static str {
+        "mlfq"
+    }
+
+    fn enqueue(&mut self, id: ThreadId, entity: &mut SchedEntity) {
+        self.refresh(entity);
+        self.queues[entity.level].push_back((id, entity.nice));
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.boost_if_due();
+        let level = self.highest_ready_level()?;
+        self.queues[level].pop_front().map(|(id, _)| id)
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        for queue in self.queues.iter_mut() {
+            queue.retain(|&(queued, _)| queued != id);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    fn tick(&mut self, current: &mut SchedEntity) -> bool {
+        self.refresh(current);
+        self.boost_if_due();
+        current.level_ticks += 1;
+
+        // Used up the allotment of this level, the allotment isn't reset by
+        // yielding so a thread can't game the scheduler by yielding early
+        if current.level_ticks >= MlfqScheduler::quantum(current.level) {
+            current.level = core::cmp::min(current.level + 1, LEVELS - 1);
+            current.level_ticks = 0;
+            return true;
+        }
+
+        self.check_preempt(current)
+    }
+
+    fn check_preempt(&self, current: &SchedEntity) -> bool {
+        match self.highest_ready_level() {
+            Some(level) => level < current.level,
+            None => false,
+        }
+    }
+}
+
+#[test_case]
+fn test_mlfq_demotes_cpu_bound_threads() {
+    let mut scheduler = MlfqScheduler::new();
+    let mut entity = SchedEntity::new(0);
+    scheduler.enqueue(ThreadId(1), &mut entity);
+    assert_eq!(scheduler.pick_next(), Some(ThreadId(1)));
+    assert_eq!(entity.level, 0);
+
+    let mut preempted = false;
+    for _ in 0..MlfqScheduler::quantum(0) {
+        preempted = scheduler.tick(&mut entity);
+    }
+    assert!(preempted);
+    assert_eq!(entity.level, 1);
+
+    // A thread that's still on the top level preempts the demoted one
+    let mut interactive = SchedEntity::new(0);
+    scheduler.enqueue(ThreadId(2), &mut interactive);
+    assert!(scheduler.check_preempt(&entity));
+    serial_println!("MLFQ demotion...[ok]");
+}
+
+#[test_case]
+fn test_mlfq_nice_start_level() {
+    assert_eq!(MlfqScheduler::base_level(-20), 0);
+    assert_eq!(MlfqScheduler::base_level(0), 0);
+    assert_eq!(MlfqScheduler::base_level(1), 1);
+    assert_eq!(MlfqScheduler::base_level(NICE_MAX), LEVELS - 1);
+    serial_println!("MLFQ nice levels...[ok]");
+}
@@ -0,0 +1,688 @@
+//! Kernel threads and the preemptive scheduler.
+//!
+//! Each thread owns a kernel stack, when a thread is switched out the callee
+//! saved registers are pushed on its stack and the stack pointer is stored in
+//! its `Thread`. Switching back pops them off again. Which thread runs next
+//! is decided by a pluggable `Scheduler` policy.
+
+pub mod cfs;
+pub mod mlfq;
+pub mod scheduler;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+use self::mlfq::MlfqScheduler;
+use self::scheduler::clamp_nice;
+pub use self::scheduler::{Nice, SchedEntity, Scheduler, NICE_DEFAULT, NICE_MAX, NICE_MIN};
+use crate::address_space::{self, AddressSpace};
+use crate::gdt;
+use crate::lock_class;
+use crate::println;
+use crate::process::Pid;
+use crate::sync::lockdep::{self, HeldLocks};
+use crate::sync::{IrqSpinLock, WaitQueue};
+use crate::syscall;
+use crate::time;
+
+const KERNEL_STACK_SIZE: usize = 4096 * 4;
+// Interrupts are disabled until the new thread enables them in `thread_entry`
+const INITIAL_RFLAGS: u64 = 0x2;
+
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global ham_dos_switch_context
+.global ham_dos_thread_trampoline
+
+// fn(old_stack_pointer: *mut u64 [rdi], new_stack_pointer: u64 [rsi])
+ham_dos_switch_context:
+    pushfq
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    popfq
+    ret
+
+// First thing a new thread runs, r13 holds the entry function and r12 its argument
+ham_dos_thread_trampoline:
+    mov rdi, r12
+    call r13
+    ud2
+.att_syntax
+"#
+);
+
+extern "C" {
+    fn ham_dos_switch_context(old_stack_pointer: *mut u64, new_stack_pointer: u64);
+    fn ham_dos_thread_trampoline();
+}
+
+type ThreadEntry = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> ThreadId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    /// Sleeping until the given tick
+    Sleeping(u64),
+    /// Waiting for `wake_up`, e.g. on a `WaitQueue`
+    Blocked,
+    /// Waiting for `wake_up` until the given tick at the latest
+    BlockedUntil(u64),
+    /// Exited, the thread is freed by the next `schedule` call
+    Dead,
+}
+
+impl fmt::Display for ThreadState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ThreadState::Ready => "ready",
+            ThreadState::Running => "running",
+            ThreadState::Sleeping(_) => "sleeping",
+            ThreadState::Blocked | ThreadState::BlockedUntil(_) => "blocked",
+            ThreadState::Dead => "dead",
+        };
+        f.pad(name)
+    }
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    state: ThreadState,
+    sched: SchedEntity,
+    /// Saved stack pointer while the thread isn't running
+    stack_pointer: u64,
+    /// `None` for the boot thread which runs on the bootloader's stack
+    stack: Option<Box<[u8]>>,
+    /// `None` for kernel threads, they run on the kernel's page tables
+    address_space: Option<Arc<AddressSpace>>,
+    process: Option<Pid>,
+    cpu_ticks: u64,
+    switches: u64,
+    /// Spin locks held while the thread is switched out, for the lock validator
+    held_locks: HeldLocks,
+}
+
+impl Thread {
+    /// Wraps the code that's currently running (i.e. `kernel_main`)
+    fn from_current(name: &str) -> Box<Thread> {
+        Box::new(Thread {
+            id: ThreadId::new(),
+            name: String::from(name),
+            state: ThreadState::Running,
+            sched: SchedEntity::new(NICE_DEFAULT),
+            stack_pointer: 0,
+            stack: None,
+            address_space: None,
+            process: None,
+            cpu_ticks: 0,
+            switches: 0,
+            held_locks: HeldLocks::new(),
+        })
+    }
+
+    fn new_kernel(name: &str, nice: Nice, entry: ThreadEntry) -> Box<Thread> {
+        let mut stack = vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice();
+        let stack_top = (stack.as_mut_ptr() as u64 + KERNEL_STACK_SIZE as u64) & !0xF;
+
+        // Build the frame `ham_dos_switch_context` pops, returning into the trampoline
+        let entry = Box::into_raw(Box::new(entry));
+        let initial_frame: [u64; 8] = [
+            0,                                         // r15
+            0,                                         // r14
+            thread_entry as usize as u64,              // r13
+            entry as u64,                              // r12
+            0,                                         // rbx
+            0,                                         // rbp
+            INITIAL_RFLAGS,                            // rflags
+            ham_dos_thread_trampoline as usize as u64, // return address
+        ];
+        let stack_pointer = stack_top - (initial_frame.len() * 8) as u64;
+        unsafe {
+            (stack_pointer as *mut [u64; 8]).write(initial_frame);
+        }
+
+        Box::new(Thread {
+            id: ThreadId::new(),
+            name: String::from(name),
+            state: ThreadState::Ready,
+            sched: SchedEntity::new(nice),
+            stack_pointer,
+            stack: Some(stack),
+            address_space: None,
+            process: None,
+            cpu_ticks: 0,
+            switches: 0,
+            held_locks: HeldLocks::new(),
+        })
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    /// The stack the CPU switches to when the thread is interrupted in ring 3
+    fn kernel_stack_top(&self) -> Option<VirtAddr> {
+        self.stack.as_ref().map(|stack| {
+            let top = stack.as_ptr() as u64 + stack.len() as u64;
+            VirtAddr::new(top & !0xF)
+        })
+    }
+}
+
+extern "C" fn thread_entry(entry: *mut ThreadEntry) -> ! {
+    // We got here from `schedule`, which runs with interrupts disabled
+    interrupts::enable();
+    let entry = unsafe { Box::from_raw(entry) };
+    (*entry)();
+    exit();
+}
+
+struct TaskManager {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    current: Option<ThreadId>,
+    /// Runs when nothing else is runnable, never queued in the scheduler
+    idle: Option<ThreadId>,
+    scheduler: Box<dyn Scheduler>,
+    /// A woken up thread should preempt the running one on the next tick
+    need_resched: bool,
+}
+
+lazy_static! {
+    /// Woken up whenever a thread exits, for `join`
+    static ref EXITED: WaitQueue = WaitQueue::new();
+}
+
+lazy_static! {
+    static ref TASKS: IrqSpinLock<TaskManager> = IrqSpinLock::with_class(TaskManager {
+        threads: BTreeMap::new(),
+        current: None,
+        idle: None,
+        scheduler: Box::new(MlfqScheduler::new()),
+        need_resched: false,
+    }, lock_class!("TASKS"));
+}
+
+/// Everything `schedule` needs to switch threads once `TASKS` is unlocked,
+/// the threads are boxed and aren't freed while interrupts are disabled
+struct Switch {
+    old_stack_pointer: *mut u64,
+    new_stack_pointer: u64,
+    old_held_locks: *mut HeldLocks,
+    new_held_locks: *const HeldLocks,
+    /// `None` switches to the kernel's page tables
+    new_level_4_frame: Option<PhysFrame>,
+    new_kernel_stack: Option<VirtAddr>,
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+impl TaskManager {
+    fn add_ready(&mut self, thread: Box<Thread>) {
+        let id = thread.id;
+        self.threads.insert(id, thread);
+        if let Some(thread) = self.threads.get_mut(&id) {
+            self.scheduler.enqueue(id, &mut thread.sched);
+        }
+    }
+
+    fn current_thread(&mut self) -> &mut Thread {
+        let id = self.current.expect("Tasks aren't initialized");
+        self.threads.get_mut(&id).expect("Current thread is missing")
+    }
+
+    /// Frees threads that exited, the running thread is still using its stack
+    fn reap(&mut self) {
+        let current = self.current;
+        let dead: Vec<ThreadId> = self
+            .threads
+            .values()
+            .filter(|thread| thread.state == ThreadState::Dead && Some(thread.id) != current)
+            .map(|thread| thread.id)
+            .collect();
+
+        for id in dead {
+            self.threads.remove(&id);
+        }
+    }
+
+    /// Picks the next thread, `None` if the current thread keeps running
+    fn pick_switch(&mut self) -> Option<Switch> {
+        self.need_resched = false;
+        self.reap();
+        let current_id = self.current?;
+        let idle = self.idle;
+
+        let current = self.threads.get_mut(&current_id)?;
+        if current.state == ThreadState::Running {
+            current.state = ThreadState::Ready;
+            if Some(current_id) != idle {
+                self.scheduler.enqueue(current_id, &mut current.sched);
+            }
+        }
+
+        let next_id = self.scheduler.pick_next().or(idle)?;
+        let next = self.threads.get_mut(&next_id)?;
+        next.state = ThreadState::Running;
+        next.sched.ran_ticks = 0;
+        if next_id == current_id {
+            return None;
+        }
+
+        next.switches += 1;
+        let new_stack_pointer = next.stack_pointer;
+        let new_held_locks = &next.held_locks as *const HeldLocks;
+        let new_level_4_frame = next.address_space.as_ref().map(|space| space.level_4_frame());
+        let new_kernel_stack = next.kernel_stack_top();
+        let current = self.threads.get_mut(&current_id)?;
+        self.current = Some(next_id);
+
+        Some(Switch {
+            old_stack_pointer: &mut current.stack_pointer,
+            new_stack_pointer,
+            old_held_locks: &mut current.held_locks,
+            new_held_locks,
+            new_level_4_frame,
+            new_kernel_stack,
+        })
+    }
+
+    /// Accounts a timer tick, returns `true` if the running thread should be preempted
+    fn tick(&mut self, now: u64) -> bool {
+        let mut woke_up = false;
+        for thread in self.threads.values_mut() {
+            match thread.state {
+                ThreadState::Sleeping(until) | ThreadState::BlockedUntil(until) if until <= now => {
+                    thread.state = ThreadState::Ready;
+                    self.scheduler.enqueue(thread.id, &mut thread.sched);
+                    woke_up = true;
+                }
+                _ => {}
+            }
+        }
+
+        let idle = self.idle;
+        let scheduler = &mut self.scheduler;
+        let current = match self.current {
+            Some(id) => self.threads.get_mut(&id).expect("Current thread is missing"),
+            None => return false,
+        };
+
+        current.cpu_ticks += 1;
+        if Some(current.id) == idle {
+            return !scheduler.is_empty();
+        }
+
+        current.sched.ran_ticks += 1;
+        let expired = scheduler.tick(&mut current.sched);
+        let need_resched = self.need_resched || woke_up;
+        expired || (need_resched && scheduler.check_preempt(&current.sched))
+    }
+
+    /// Makes a blocked thread runnable again, returns `false` if it wasn't blocked
+    fn wake_up(&mut self, id: ThreadId) -> bool {
+        let thread = match self.threads.get_mut(&id) {
+            Some(thread) => thread,
+            None => return false,
+        };
+        match thread.state {
+            ThreadState::Blocked | ThreadState::BlockedUntil(_) => {}
+            _ => return false,
+        }
+
+        thread.state = ThreadState::Ready;
+        self.scheduler.enqueue(id, &mut thread.sched);
+        self.need_resched = true;
+        true
+    }
+}
+
+/// Turns the running code into the "main" thread and creates the idle thread
+pub fn init() {
+    let main = Thread::from_current("main");
+    let idle = Thread::new_kernel(
+        "idle",
+        NICE_MAX,
+        Box::new(|| {
+            crate::hlt_loop();
+        }),
+    );
+
+    {
+        let mut tasks = TASKS.lock();
+        tasks.current = Some(main.id);
+        tasks.threads.insert(main.id, main);
+        tasks.idle = Some(idle.id);
+        tasks.threads.insert(idle.id, idle);
+    }
+
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+pub fn spawn<F>(name: &str, entry: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_with_nice(name, NICE_DEFAULT, entry)
+}
+
+pub fn spawn_with_nice<F>(name: &str, nice: Nice, entry: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    let thread = Thread::new_kernel(name, nice, Box::new(entry));
+    let id = thread.id;
+    TASKS.lock().add_ready(thread);
+    id
+}
+
+/// Like `spawn`, but the thread only starts running once `wake_up` is
+/// called for it, so the caller can record its id first
+pub fn spawn_blocked<F>(name: &str, entry: F) -> ThreadId
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut thread = Thread::new_kernel(name, NICE_DEFAULT, Box::new(entry));
+    let id = thread.id;
+    thread.state = ThreadState::Blocked;
+    TASKS.lock().threads.insert(id, thread);
+    id
+}
+
+/// Switches to the next thread, must be called with interrupts disabled
+fn schedule() {
+    let switch = TASKS.lock().pick_switch();
+    if let Some(switch) = switch {
+        unsafe {
+            lockdep::switch_held_locks(&mut *switch.old_held_locks, &*switch.new_held_locks);
+            match switch.new_level_4_frame {
+                Some(frame) => address_space::activate_level_4_frame(frame),
+                None => address_space::activate_kernel(),
+            }
+            if let Some(stack_top) = switch.new_kernel_stack {
+                gdt::set_kernel_stack(stack_top);
+                syscall::set_kernel_stack(stack_top);
+            }
+            ham_dos_switch_context(switch.old_stack_pointer, switch.new_stack_pointer);
+        }
+    }
+}
+
+/// Gives up the CPU to the next runnable thread
+pub fn yield_now() {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+
+    interrupts::without_interrupts(schedule);
+}
+
+/// Terminates the running thread
+pub fn exit() -> ! {
+    interrupts::disable();
+    TASKS.lock().current_thread().state = ThreadState::Dead;
+    EXITED.wake_all();
+    schedule();
+    unreachable!("A dead thread was scheduled");
+}
+
+/// Blocks until the thread exited, returns right away for unknown threads
+pub fn join(id: ThreadId) {
+    EXITED.wait_until(|| match TASKS.lock().threads.get(&id) {
+        Some(thread) => thread.state == ThreadState::Dead,
+        None => true,
+    });
+}
+
+/// Gives the running thread its own address space and switches to it,
+/// it's freed when the thread exits and nobody else uses it
+pub fn set_address_space(space: Arc<AddressSpace>) {
+    interrupts::without_interrupts(|| {
+        let mut tasks = TASKS.lock();
+        let thread = tasks.current_thread();
+        unsafe { space.activate() };
+        thread.address_space = Some(space);
+    });
+}
+
+/// Makes the running thread the one executing a process
+pub fn set_process(pid: Pid) {
+    TASKS.lock().current_thread().process = Some(pid);
+}
+
+/// Returns the process the running thread belongs to, `None` for kernel threads
+pub fn current_process() -> Option<Pid> {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return None;
+    }
+
+    TASKS.lock().current_thread().process
+}
+
+/// Returns the address space of the running thread, `None` for kernel threads
+pub fn current_address_space() -> Option<Arc<AddressSpace>> {
+    TASKS.lock().current_thread().address_space.clone()
+}
+
+pub fn sleep_ms(ms: u64) {
+    sleep_ticks(time::ms_to_ticks(ms));
+}
+
+pub fn sleep_ticks(ticks: u64) {
+    let until = time::ticks() + ticks;
+    if !INITIALIZED.load(Ordering::Acquire) {
+        while time::ticks() < until {
+            x86_64::instructions::hlt();
+        }
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        TASKS.lock().current_thread().state = ThreadState::Sleeping(until);
+        schedule();
+    });
+}
+
+/// Blocks the running thread until `wake_up` is called for it.
+///
+/// Must be called with interrupts disabled, so that the caller can register
+/// the thread somewhere (e.g. a `WaitQueue`) and block without a wake up
+/// sneaking in between. Returns right away if threads aren't initialized yet,
+/// so callers have to re-check their wait condition in a loop.
+pub fn block_current() {
+    debug_assert!(!interrupts::are_enabled());
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+
+    TASKS.lock().current_thread().state = ThreadState::Blocked;
+    schedule();
+}
+
+/// Like `block_current`, but the thread is woken up at tick `deadline` if
+/// nobody called `wake_up` before
+pub fn block_current_until(deadline: u64) {
+    debug_assert!(!interrupts::are_enabled());
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+
+    TASKS.lock().current_thread().state = ThreadState::BlockedUntil(deadline);
+    schedule();
+}
+
+/// Makes a thread that called `block_current` runnable again,
+/// returns `false` if the thread wasn't blocked
+pub fn wake_up(id: ThreadId) -> bool {
+    TASKS.lock().wake_up(id)
+}
+
+/// Called by the timer interrupt handler after the EOI was sent, may switch
+/// to another thread
+pub fn timer_tick() {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let preempt = TASKS.lock().tick(time::ticks());
+    if preempt {
+        schedule();
+    }
+}
+
+/// Whether the thread exists and didn't exit
+pub fn is_alive(id: ThreadId) -> bool {
+    TASKS
+        .lock()
+        .threads
+        .get(&id)
+        .map_or(false, |thread| thread.state != ThreadState::Dead)
+}
+
+pub fn current_id() -> Option<ThreadId> {
+    TASKS.lock().current
+}
+
+pub fn set_nice(id: ThreadId, nice: Nice) {
+    let mut tasks = TASKS.lock();
+    let TaskManager {
+        threads, scheduler, ..
+    } = &mut *tasks;
+
+    if let Some(thread) = threads.get_mut(&id) {
+        let queued = thread.state == ThreadState::Ready;
+        if queued {
+            scheduler.remove(id);
+        }
+
+        thread.sched.nice = clamp_nice(nice);
+        // Let the MLFQ recompute the start level
+        thread.sched.epoch = u64::max_value();
+        if queued {
+            scheduler.enqueue(id, &mut thread.sched);
+        }
+    }
+}
+
+/// Replaces the scheduling policy, runnable threads are moved to the new one
+pub fn set_scheduler(mut new_scheduler: Box<dyn Scheduler>) {
+    let mut tasks = TASKS.lock();
+    let TaskManager {
+        threads,
+        scheduler,
+        idle,
+        ..
+    } = &mut *tasks;
+
+    for thread in threads.values_mut() {
+        if thread.state == ThreadState::Ready && Some(thread.id) != *idle {
+            new_scheduler.enqueue(thread.id, &mut thread.sched);
+        }
+    }
+
+    *scheduler = new_scheduler;
+}
+
+pub fn scheduler_name() -> &'static str {
+    TASKS.lock().scheduler.name()
+}
+
+/// Snapshot of a thread used for task listings
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    pub id: ThreadId,
+    pub name: String,
+    pub state: ThreadState,
+    pub nice: Nice,
+    pub cpu_ms: u64,
+    pub switches: u64,
+}
+
+impl fmt::Display for TaskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>4} {:<16} {:<9} {:>4} {:>10} {:>8}",
+            self.id, self.name, self.state, self.nice, self.cpu_ms, self.switches
+        )
+    }
+}
+
+pub fn task_list() -> Vec<TaskInfo> {
+    TASKS
+        .lock()
+        .threads
+        .values()
+        .map(|thread| TaskInfo {
+            id: thread.id,
+            name: thread.name.clone(),
+            state: thread.state,
+            nice: thread.sched.nice,
+            cpu_ms: thread.cpu_ticks * time::TICK_MS,
+            switches: thread.switches,
+        })
+        .collect()
+}
+
+pub fn print_task_list() {
+    println!("Scheduler: {}", scheduler_name());
+    println!(
+        "{:>4} {:<16} {:<9} {:>4} {:>10} {:>8}",
+        "TID", "NAME", "STATE", "NICE", "CPU(ms)", "SWITCHES"
+    );
+    for task in task_list() {
+        println!("{}", task);
+    }
+}
@@ -0,0 +1,147 @@
+use alloc::collections::VecDeque;
+
+use super::ThreadId;
+
+/// Unix style niceness, lower values get more CPU time
+pub type Nice = i8;
+
+pub const NICE_MIN: Nice = -20;
+pub const NICE_MAX: Nice = 19;
+pub const NICE_DEFAULT: Nice = 0;
+
+/// Load weight of a nice 0 thread
+pub const NICE_0_WEIGHT: u64 = 1024;
+
+// Each nice level is roughly 10% more/less CPU time than its neighbour
+// (same table as Linux's `sched_prio_to_weight`)
+const NICE_TO_WEIGHT: [u64; 40] = [
+    /* -20 */ 88761, 71755, 56483, 46273, 36291, //
+    /* -15 */ 29154, 23254, 18705, 14949, 11916, //
+    /* -10 */ 9548, 7620, 6100, 4904, 3906, //
+    /*  -5 */ 3121, 2501, 1991, 1586, 1277, //
+    /*   0 */ 1024, 820, 655, 526, 423, //
+    /*   5 */ 335, 272, 215, 172, 137, //
+    /*  10 */ 110, 87, 70, 56, 45, //
+    /*  15 */ 36, 29, 23, 18, 15, //
+];
+
+pub fn nice_to_weight(nice: Nice) -> u64 {
+    NICE_TO_WEIGHT[(clamp_nice(nice) - NICE_MIN) as usize]
+}
+
+pub fn clamp_nice(nice: Nice) -> Nice {
+    if nice < NICE_MIN {
+        NICE_MIN
+    } else if nice > NICE_MAX {
+        NICE_MAX
+    } else {
+        nice
+    }
+}
+
+/// Per thread bookkeeping shared by all the scheduling policies, every
+/// policy only touches the fields it cares about
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SchedEntity {
+    pub nice: Nice,
+    /// Ticks the thread ran since it was last picked, maintained by the
+    /// task module before `Scheduler::tick` is called
+    pub ran_ticks: u64,
+    /// Weighted run time (fair scheduler)
+    pub vruntime: u64,
+    /// Queue the thread belongs to (multi-level feedback queue)
+    pub level: usize,
+    /// Ticks consumed on the current level (multi-level feedback queue)
+    pub level_ticks: u64,
+    /// Priority boost the level was computed in, `u64::max_value()` until
+    /// the thread is first queued (multi-level feedback queue)
+    pub epoch: u64,
+}
+
+impl SchedEntity {
+    pub fn new(nice: Nice) -> SchedEntity {
+        SchedEntity {
+            nice: clamp_nice(nice),
+            ran_ticks: 0,
+            vruntime: 0,
+            level: 0,
+            level_ticks: 0,
+            epoch: u64::max_value(),
+        }
+    }
+}
+
+/// A scheduling policy. The policy only owns the run queue, thread
+/// states and context switching are handled by the task module.
+///
+/// All the methods are called with interrupts disabled.
+pub trait Scheduler: Send {
+    fn name(&self) -> &'static str;
+
+    /// Makes a thread runnable
+    fn enqueue(&mut self, id: ThreadId, entity: &mut SchedEntity);
+
+    /// Removes the thread that should run next from the run queue
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    /// Removes a runnable thread from the run queue
+    fn remove(&mut self, id: ThreadId);
+
+    /// Number of runnable threads waiting in the run queue
+    fn len(&self) -> usize;
+
+    /// Accounts one timer tick to the running thread, returns `true`
+    /// when it used up its time slice and should be preempted
+    fn tick(&mut self, current: &mut SchedEntity) -> bool;
+
+    /// Returns `true` if a queued thread should preempt the running thread
+    /// right away (e.g. after an input handling thread was woken up)
+    fn check_preempt(&self, _current: &SchedEntity) -> bool {
+        false
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+const ROUND_ROBIN_QUANTUM_TICKS: u64 = 5;
+
+/// Plain round robin with a fixed time slice, ignores nice values
+pub struct RoundRobin {
+    queue: VecDeque<ThreadId>,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin {
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round-robin"
+    }
+
+    fn enqueue(&mut self, id: ThreadId, _entity: &mut SchedEntity) {
+        self.queue.push_back(id);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.queue.pop_front()
+    }
+
+    fn remove(&mut self, id: ThreadId) {
+        self.queue.retain(|&queued| queued != id);
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn tick(&mut self, current: &mut SchedEntity) -> bool {
+        current.ran_ticks >= ROUND_ROBIN_QUANTUM_TICKS
+    }
+}
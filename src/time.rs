@@ -0,0 +1,49 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::port::Port;
+
+/// Frequency the PIT is programmed to, each timer interrupt is one tick
+pub const TIMER_HZ: u64 = 100;
+pub const TICK_MS: u64 = 1000 / TIMER_HZ;
+
+// https://wiki.osdev.org/Programmable_Interval_Timer
+const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+const PORT_PIT_CHANNEL_0: u16 = 0x40;
+const PORT_PIT_COMMAND: u16 = 0x43;
+// Channel 0, lobyte/hibyte access, mode 3 (square wave), binary
+const CMD_CHANNEL_0_SQUARE_WAVE: u8 = 0b0011_0110;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Programs channel 0 of the PIT to fire IRQ0 `TIMER_HZ` times per second
+pub fn init() {
+    let divisor = (PIT_FREQUENCY_HZ / TIMER_HZ) as u16;
+    let mut command_port: Port<u8> = Port::new(PORT_PIT_COMMAND);
+    let mut data_port: Port<u8> = Port::new(PORT_PIT_CHANNEL_0);
+
+    unsafe {
+        command_port.write(CMD_CHANNEL_0_SQUARE_WAVE);
+        data_port.write((divisor & 0xFF) as u8);
+        data_port.write((divisor >> 8) as u8);
+    }
+}
+
+/// Called by the timer interrupt handler
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Timer ticks since `init`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime_ms() -> u64 {
+    ticks() * TICK_MS
+}
+
+/// Converts milliseconds to ticks, rounding up so that a non zero
+/// duration always waits for at least one tick
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms + TICK_MS - 1) / TICK_MS
+}
@@ -0,0 +1,167 @@
+//! Entry stubs for the interrupts that may change the registers of the code
+//! they interrupted.
+//!
+//! `x86-interrupt` handlers only see rip, rsp and rflags, delivering a
+//! signal needs all registers. These stubs save them in a `SyscallFrame`
+//! like the syscall entry does, call `ham_dos_trap_dispatch` and load them
+//! back from the frame before `iretq`. They work for ring 0 as well, the
+//! CPU always pushes ss and rsp in long mode.
+
+use core::mem;
+
+use x86_64::structures::idt::{HandlerFunc, HandlerFuncWithErrCode, PageFaultHandlerFunc};
+
+use crate::interrupts;
+use crate::signal;
+use crate::syscall::{self, SyscallFrame};
+use crate::user;
+
+pub const DIVIDE_ERROR_VECTOR: u64 = 0;
+pub const INVALID_OPCODE_VECTOR: u64 = 6;
+pub const GENERAL_PROTECTION_FAULT_VECTOR: u64 = 13;
+pub const PAGE_FAULT_VECTOR: u64 = 14;
+pub const TIMER_VECTOR: u64 = interrupts::PIC_1_OFFSET as u64;
+pub const INT80_VECTOR: u64 = 0x80;
+
+global_asm!(
+    r#"
+.intel_syntax noprefix
+
+// Exceptions without an error code get a 0 so that every stub has the same
+// layout: the frame, the error code and what the CPU pushed (rip, cs,
+// rflags, rsp, ss). The 6 words the CPU pushed plus the 18 of the frame
+// keep the stack 16 byte aligned for the call.
+.macro TRAP_ENTRY name, vector, error_code
+.global \name
+\name:
+.if \error_code == 0
+    push 0
+.endif
+    push qword ptr [rsp + 32]
+    push qword ptr [rsp + 16]
+    push qword ptr [rsp + 40]
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push r8
+    push r9
+    push r10
+    push r11
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    mov rsi, \vector
+    mov rdx, [rsp + 144]
+    mov rcx, [rsp + 160]
+    call ham_dos_trap_dispatch
+    // The dispatcher may have changed where the code continues
+    mov rax, [rsp + 128]
+    mov [rsp + 152], rax
+    mov rax, [rsp + 120]
+    mov [rsp + 168], rax
+    mov rax, [rsp + 136]
+    mov [rsp + 176], rax
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    add rsp, 32
+    iretq
+.endm
+
+TRAP_ENTRY ham_dos_divide_error_entry, 0, 0
+TRAP_ENTRY ham_dos_invalid_opcode_entry, 6, 0
+TRAP_ENTRY ham_dos_general_protection_fault_entry, 13, 1
+TRAP_ENTRY ham_dos_page_fault_entry, 14, 1
+TRAP_ENTRY ham_dos_timer_entry, 32, 0
+TRAP_ENTRY ham_dos_int80_entry, 0x80, 0
+.att_syntax
+"#
+);
+
+extern "C" {
+    fn ham_dos_divide_error_entry();
+    fn ham_dos_invalid_opcode_entry();
+    fn ham_dos_general_protection_fault_entry();
+    fn ham_dos_page_fault_entry();
+    fn ham_dos_timer_entry();
+    fn ham_dos_int80_entry();
+}
+
+/// The stubs aren't `x86-interrupt` functions, but they're entered the same way
+unsafe fn entry_as<F>(stub: unsafe extern "C" fn()) -> F {
+    mem::transmute_copy(&stub)
+}
+
+pub fn divide_error_handler() -> HandlerFunc {
+    unsafe { entry_as(ham_dos_divide_error_entry) }
+}
+
+pub fn invalid_opcode_handler() -> HandlerFunc {
+    unsafe { entry_as(ham_dos_invalid_opcode_entry) }
+}
+
+pub fn general_protection_fault_handler() -> HandlerFuncWithErrCode {
+    unsafe { entry_as(ham_dos_general_protection_fault_entry) }
+}
+
+pub fn page_fault_handler() -> PageFaultHandlerFunc {
+    unsafe { entry_as(ham_dos_page_fault_entry) }
+}
+
+pub fn timer_handler() -> HandlerFunc {
+    unsafe { entry_as(ham_dos_timer_entry) }
+}
+
+/// The `int 0x80` handler, it has to be installed in the IDT with DPL 3
+pub fn int80_handler() -> HandlerFunc {
+    unsafe { entry_as(ham_dos_int80_entry) }
+}
+
+#[no_mangle]
+extern "C" fn ham_dos_trap_dispatch(
+    frame: &mut SyscallFrame,
+    vector: u64,
+    error_code: u64,
+    code_segment: u64,
+) {
+    let from_user = code_segment & 3 == 3;
+    interrupts::count(vector as u8);
+    match vector {
+        DIVIDE_ERROR_VECTOR => interrupts::divide_error(frame, from_user),
+        INVALID_OPCODE_VECTOR => interrupts::invalid_opcode(frame, from_user),
+        GENERAL_PROTECTION_FAULT_VECTOR => {
+            interrupts::general_protection_fault(frame, error_code, from_user)
+        }
+        PAGE_FAULT_VECTOR => interrupts::page_fault(frame, error_code, from_user),
+        TIMER_VECTOR => interrupts::timer_interrupt(),
+        INT80_VECTOR => {
+            // Delivers the signals itself
+            syscall::handle(frame);
+            return;
+        }
+        _ => panic!("No trap handler for vector {}", vector),
+    }
+
+    if from_user {
+        signal::deliver_pending(frame);
+        frame.rflags = user::sanitize_rflags(frame.rflags);
+    }
+}
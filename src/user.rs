@@ -0,0 +1,282 @@
+//! Running code in ring 3.
+//!
+//! A user thread is a kernel thread that switched to its own `AddressSpace`
+//! and dropped to ring 3 with `iretq`. Interrupts and exceptions bring it
+//! back to its kernel stack, which the scheduler stores in the TSS.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::address_space::{self, AddressSpace, MapError, USER_SPACE_END, USER_SPACE_START};
+use crate::elf::{self, ElfError};
+use crate::gdt;
+use crate::lock_class;
+use crate::memory;
+use crate::println;
+use crate::process;
+use crate::signal::{self, Signal};
+use crate::sync::IrqSpinLock;
+use crate::syscall::SyscallFrame;
+use crate::task::{self, ThreadId};
+
+/// Where flat binaries are loaded
+pub const USER_CODE_START: u64 = USER_SPACE_START + 0x40_0000;
+/// The page below the end of user space is left unmapped as a guard
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - 4096;
+pub const USER_STACK_SIZE: usize = 4096 * 4;
+
+// Interrupts enabled, reserved bit 1 set
+const USER_RFLAGS: u64 = 0x202;
+// The status flags, DF and TF, user code can't change anything else
+const USER_RFLAGS_MASK: u64 = 0xDD5;
+
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global ham_dos_return_to_user
+
+// fn(context: *const SyscallFrame [rdi], code_selector [rsi], data_selector [rdx]) -> !
+// Builds the frame iretq pops (ss, rsp, rflags, cs, rip) and loads the
+// registers from `context`
+ham_dos_return_to_user:
+    push rdx
+    push qword ptr [rdi + 136]
+    push qword ptr [rdi + 120]
+    push rsi
+    push qword ptr [rdi + 128]
+    mov ds, dx
+    mov es, dx
+    mov r15, [rdi]
+    mov r14, [rdi + 8]
+    mov r13, [rdi + 16]
+    mov r12, [rdi + 24]
+    mov rbx, [rdi + 32]
+    mov rbp, [rdi + 40]
+    mov r11, [rdi + 48]
+    mov r10, [rdi + 56]
+    mov r9, [rdi + 64]
+    mov r8, [rdi + 72]
+    mov rcx, [rdi + 80]
+    mov rdx, [rdi + 88]
+    mov rsi, [rdi + 96]
+    mov rax, [rdi + 112]
+    mov rdi, [rdi + 104]
+    iretq
+.att_syntax
+"#
+);
+
+extern "C" {
+    fn ham_dos_return_to_user(context: *const SyscallFrame, code: u64, data: u64) -> !;
+}
+
+static FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+/// A user thread outside of a process that the thread which spawned it can
+/// still `wait` for
+struct Waitable {
+    spawner: ThreadId,
+    /// Set once the thread exited
+    code: Option<i32>,
+}
+
+/// User threads that weren't waited for yet, dropped once their spawner is
+/// gone since nobody else knows about them
+static EXIT_CODES: IrqSpinLock<BTreeMap<ThreadId, Waitable>> =
+    IrqSpinLock::with_class(BTreeMap::new(), lock_class!("EXIT_CODES"));
+
+/// Exit code of a thread outside of a process killed because of an exception
+pub const FAULT_EXIT_CODE: i32 = -1;
+
+/// Clears the flags user code isn't allowed to set
+pub fn sanitize_rflags(rflags: u64) -> u64 {
+    (rflags & USER_RFLAGS_MASK) | USER_RFLAGS
+}
+
+/// The registers of a program that's just starting, all zero
+pub fn initial_context(entry: VirtAddr, stack_pointer: VirtAddr) -> SyscallFrame {
+    SyscallFrame {
+        rip: entry.as_u64(),
+        rsp: stack_pointer.as_u64(),
+        rflags: USER_RFLAGS,
+        ..SyscallFrame::default()
+    }
+}
+
+/// Drops the running thread to ring 3 with the given registers, its
+/// address space has to be set with `task::set_address_space` before
+pub fn return_to_user(context: &SyscallFrame) -> ! {
+    let mut context = context.clone();
+    context.rflags = sanitize_rflags(context.rflags);
+    let code = u64::from(gdt::user_code_selector().0);
+    let data = u64::from(gdt::user_data_selector().0);
+    unsafe { ham_dos_return_to_user(&context, code, data) }
+}
+
+pub fn enter_user_mode(entry: VirtAddr, stack_pointer: VirtAddr) -> ! {
+    return_to_user(&initial_context(entry, stack_pointer))
+}
+
+/// Maps `size` bytes of fresh zeroed memory starting at `start`, nothing
+/// stays mapped if it fails
+pub fn map_region(
+    space: &AddressSpace,
+    start: VirtAddr,
+    size: usize,
+    flags: PageTableFlags,
+) -> Result<(), MapError> {
+    if size == 0 {
+        return Ok(());
+    }
+    let start_addr = start.as_u64();
+    if start_addr < USER_SPACE_START || size as u64 > USER_SPACE_END - start_addr {
+        return Err(MapError::NotUserAddress);
+    }
+
+    let first = Page::containing_address(start);
+    let last = Page::containing_address(start + (size - 1));
+    for page in Page::range_inclusive(first, last) {
+        if let Err(error) = space.map_new_page(page, flags) {
+            for mapped in Page::range(first, page) {
+                if let Some(frame) = space.unmap_page(mapped) {
+                    memory::deallocate_frame(frame);
+                }
+            }
+            return Err(error);
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates an address space holding a flat binary and a stack
+pub fn load_flat_binary(code: &[u8]) -> Result<AddressSpace, MapError> {
+    let space = AddressSpace::new()?;
+    // Read only and executable
+    map_region(
+        &space,
+        VirtAddr::new(USER_CODE_START),
+        code.len(),
+        PageTableFlags::empty(),
+    )?;
+    space.write_bytes(VirtAddr::new(USER_CODE_START), code)?;
+
+    map_region(
+        &space,
+        VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE as u64),
+        USER_STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+
+    Ok(space)
+}
+
+/// Starts a thread that runs in ring 3 in the given address space
+pub fn spawn_in(
+    name: &str,
+    space: Arc<AddressSpace>,
+    entry: VirtAddr,
+    stack_top: VirtAddr,
+) -> ThreadId {
+    // Blocked until it's recorded, it could exit before that otherwise
+    let id = task::spawn_blocked(name, move || {
+        task::set_address_space(space);
+        enter_user_mode(entry, stack_top);
+    });
+
+    if let Some(spawner) = task::current_id() {
+        let mut exit_codes = EXIT_CODES.lock();
+        exit_codes.retain(|_, waitable| task::is_alive(waitable.spawner));
+        exit_codes.insert(id, Waitable { spawner, code: None });
+    }
+    task::wake_up(id);
+    id
+}
+
+/// Runs a flat binary in ring 3 on a new thread, starting at its first byte
+pub fn spawn_flat_binary(name: &str, code: &[u8]) -> Result<ThreadId, MapError> {
+    let space = Arc::new(load_flat_binary(code)?);
+    Ok(spawn_in(
+        name,
+        space,
+        VirtAddr::new(USER_CODE_START),
+        VirtAddr::new(USER_STACK_TOP),
+    ))
+}
+
+/// Runs an ELF executable in ring 3 on a new thread
+pub fn spawn_elf(
+    name: &str,
+    data: &[u8],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<ThreadId, ElfError> {
+    let (space, entry, stack_pointer) = elf::load_executable(data, argv, envp)?;
+    Ok(spawn_in(name, Arc::new(space), entry, stack_pointer))
+}
+
+/// Terminates the running user thread, `wait` returns the code. Ends the
+/// whole process if the thread belongs to one.
+pub fn exit(code: i32) -> ! {
+    if process::current_pid().is_some() {
+        process::exit(code);
+    }
+
+    if let Some(id) = task::current_id() {
+        if let Some(waitable) = EXIT_CODES.lock().get_mut(&id) {
+            waitable.code = Some(code);
+        }
+    }
+    task::exit();
+}
+
+/// Blocks until a user thread exited and returns its exit code
+pub fn wait(id: ThreadId) -> Option<i32> {
+    task::join(id);
+    EXIT_CODES.lock().remove(&id).and_then(|waitable| waitable.code)
+}
+
+/// Handles a write to a copy-on-write page of the running thread, returns
+/// `false` if `addr` isn't one
+pub fn resolve_copy_on_write(addr: VirtAddr) -> bool {
+    if !address_space::is_user_address(addr) {
+        return false;
+    }
+
+    match task::current_address_space() {
+        Some(space) => space.resolve_copy_on_write(Page::containing_address(addr)) == Ok(true),
+        None => false,
+    }
+}
+
+/// Handles an exception raised by user code instead of taking the kernel
+/// down. Processes get `signal`, which is delivered before they return to
+/// ring 3, other user threads are terminated.
+pub fn handle_exception(exception: &str, signal: Signal, frame: &SyscallFrame, error_code: u64) {
+    FAULTS.fetch_add(1, Ordering::SeqCst);
+    if process::current_pid().is_some() {
+        signal::force(signal);
+        return;
+    }
+
+    if let Some(id) = task::current_id() {
+        println!(
+            "USER EXCEPTION: {} in thread {}, error code {:#x}",
+            exception, id, error_code
+        );
+    }
+    println!(
+        "Instruction Pointer: {:#x}, Stack Pointer: {:#x}",
+        frame.rip, frame.rsp
+    );
+    exit(FAULT_EXIT_CODE);
+}
+
+/// How many exceptions user code raised
+pub fn fault_count() -> usize {
+    FAULTS.load(Ordering::SeqCst)
+}
@@ -1,9 +1,10 @@
 use core::fmt;
 
-use spin::Mutex;
-
 use lazy_static::lazy_static;
 
+use crate::lock_class;
+use crate::sync::IrqSpinLock;
+
 const VGA_BUFFER_ADDR: usize = 0xB8000;
 const DEFAULT_TEXT_ATTR: u8 = 0x07;
 // VGA buffer address
@@ -12,11 +13,11 @@ const SCREEN_HEIGHT: usize = 25;
 const BACKSPACE: u8 = 8;
 const LINE_FEED: u8 = 10;
 lazy_static! {
-    pub static ref VGA_WRITER: Mutex<VgaBuffer> = Mutex::new(VgaBuffer {
+    pub static ref VGA_WRITER: IrqSpinLock<VgaBuffer> = IrqSpinLock::with_class(VgaBuffer {
         row: 0,
         col: 0,
         all_screen_attr: ScreenCharAttr { val: DEFAULT_TEXT_ATTR }   // Default light grey
-    });
+    }, lock_class!("VGA_WRITER"));
 }
 
 pub struct VgaBuffer {
@@ -204,11 +205,9 @@ fn is_printable(byte: u8) -> bool {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        VGA_WRITER.lock().write_fmt(args).unwrap();
-    });
+    crate::log::write(args);
+    VGA_WRITER.lock().write_fmt(args).unwrap();
 }
 
 #[macro_export]
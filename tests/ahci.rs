@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::block::ahci::{self, AhciDisk};
+use ham_dos::block::{BlockDevice, BlockError};
+use ham_dos::fs::devfs;
+use ham_dos::fs::ext2::Ext2Fs;
+use ham_dos::fs::{self, FileType};
+use ham_dos::{address_space, allocator, memory, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    fs::init();
+    devfs::init();
+    ahci::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+// Attached to ports 0 and 2 by the test arguments in Cargo.toml
+static IMAGE_1K: &[u8] = include_bytes!("images/ext2-1024.img");
+static IMAGE_4K: &[u8] = include_bytes!("images/ext2-4096.img");
+
+fn disk(name: &str) -> Arc<AhciDisk> {
+    ahci::disks()
+        .into_iter()
+        .find(|disk| disk.name() == name)
+        .expect("Missing disk")
+}
+
+#[test_case]
+fn test_disks() {
+    serial_print!("test_disks... ");
+    let disks = ahci::disks();
+    assert_eq!(disks.len(), 2);
+    for (disk, &(name, port)) in disks.iter().zip([("sda", 0), ("sdb", 2)].iter()) {
+        assert_eq!(disk.name(), name);
+        assert_eq!(disk.port(), port);
+        assert_eq!(disk.model(), "QEMU HARDDISK");
+        assert_eq!(disk.block_count(), 2048);
+        assert!(disk.is_interrupt_driven());
+    }
+
+    let metadata = fs::metadata("/dev/sdb").expect("Failed to get the metadata");
+    assert_eq!(metadata.file_type, FileType::BlockDevice);
+    assert_eq!(metadata.size, 1024 * 1024);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_read() {
+    serial_print!("test_read... ");
+    // More sectors at a time than one command transfers
+    let mut buffer = vec![0; 300 * 512];
+    for &(name, image) in [("sda", IMAGE_1K), ("sdb", IMAGE_4K)].iter() {
+        let mut sector = 0;
+        while sector < 2048 {
+            let length = buffer.len().min((2048 - sector) * 512);
+            let expected = &image[sector * 512..sector * 512 + length];
+            let buffer = &mut buffer[..length];
+            disk(name).read_blocks(sector as u64, buffer).expect("Failed to read");
+            assert!(&buffer[..] == expected);
+            sector += length / 512;
+        }
+
+        let mut sector = [0; 512];
+        disk(name).read_blocks(5, &mut sector).expect("Failed to read");
+        assert_eq!(&sector[..], &image[5 * 512..6 * 512]);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_write() {
+    serial_print!("test_write... ");
+    let disk = disk("sda");
+    let mut original = vec![0; 200 * 512];
+    disk.read_blocks(1800, &mut original).expect("Failed to read");
+
+    let pattern: Vec<u8> = (0..200 * 512).map(|index| (index * 17 + 3) as u8).collect();
+    disk.write_blocks(1800, &pattern).expect("Failed to write");
+    let mut buffer = vec![0; 200 * 512];
+    disk.read_blocks(1800, &mut buffer).expect("Failed to read");
+    assert!(buffer == pattern);
+
+    disk.write_blocks(1800, &original).expect("Failed to write");
+    disk.read_blocks(1800, &mut buffer).expect("Failed to read");
+    assert!(buffer == original);
+
+    let mut sectors = [0; 1024];
+    assert_eq!(disk.read_blocks(2047, &mut sectors), Err(BlockError::OutOfRange));
+    assert_eq!(disk.write_blocks(0, &sectors[..10]), Err(BlockError::BadBuffer));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_ext2() {
+    serial_print!("test_ext2... ");
+    fs::create_dir("/ext2").expect("Failed to create the mount point");
+    for &name in ["sda", "sdb"].iter() {
+        let device = devfs::block_device(name).expect("Failed to find the disk");
+        let ext2 = Ext2Fs::new(device).expect("Failed to open");
+        fs::mount("/ext2", ext2).expect("Failed to mount");
+        assert_eq!(fs::read("/ext2/hello.txt"), Ok(b"Hello from ext2!\n".to_vec()));
+        let big = fs::read("/ext2/big.bin").expect("Failed to read");
+        assert_eq!(big.len(), 300 * 1024);
+        fs::unmount("/ext2").expect("Failed to unmount");
+    }
+    serial_println!("[ok]");
+}
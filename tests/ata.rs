@@ -0,0 +1,167 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::block::ata::{self, AtaDrive, DriveKind};
+use ham_dos::block::{BlockDevice, BlockError};
+use ham_dos::fs::devfs;
+use ham_dos::fs::ext2::Ext2Fs;
+use ham_dos::fs::{self, FileType};
+use ham_dos::{address_space, allocator, interrupts, memory, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    fs::init();
+    devfs::init();
+    ata::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+// Attached as the slaves of both channels by the test arguments in
+// Cargo.toml
+static IMAGE_1K: &[u8] = include_bytes!("images/ext2-1024.img");
+static IMAGE_4K: &[u8] = include_bytes!("images/ext2-4096.img");
+
+fn drive(name: &str) -> Arc<AtaDrive> {
+    ata::drives()
+        .into_iter()
+        .find(|drive| drive.name() == name)
+        .expect("Missing drive")
+}
+
+#[test_case]
+fn test_drives() {
+    serial_print!("test_drives... ");
+    // The boot image, the two test images and QEMU's empty CD-ROM drive
+    assert_eq!(ata::drives().len(), 4);
+    assert_eq!(drive("hda").kind(), DriveKind::Ata);
+    for &name in ["hdb", "hdd"].iter() {
+        let drive = drive(name);
+        assert_eq!(drive.kind(), DriveKind::Ata);
+        assert_eq!(drive.model(), "QEMU HARDDISK");
+        assert_eq!(drive.block_size(), 512);
+        assert_eq!(drive.block_count(), 2048);
+        assert!(!drive.is_read_only());
+    }
+
+    let cdrom = drive("hdc");
+    assert_eq!(cdrom.kind(), DriveKind::Atapi);
+    assert_eq!(cdrom.block_size(), 2048);
+    assert_eq!(cdrom.block_count(), 0);
+    assert!(cdrom.is_read_only());
+
+    let metadata = fs::metadata("/dev/hdb").expect("Failed to get the metadata");
+    assert_eq!(metadata.file_type, FileType::BlockDevice);
+    assert_eq!(metadata.size, 1024 * 1024);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_read() {
+    serial_print!("test_read... ");
+    let count = |vector| {
+        interrupts::counts()
+            .into_iter()
+            .find(|&(counted, _)| counted == vector)
+            .map_or(0, |(_, count)| count)
+    };
+    let (primary, secondary) = (count(46), count(47));
+
+    // More sectors at a time than one command transfers
+    let mut buffer = vec![0; 384 * 512];
+    for &(name, image) in [("hdb", IMAGE_1K), ("hdd", IMAGE_4K)].iter() {
+        let mut sector = 0;
+        while sector < 2048 {
+            let length = buffer.len().min((2048 - sector) * 512);
+            let expected = &image[sector * 512..sector * 512 + length];
+            let buffer = &mut buffer[..length];
+            drive(name).read_blocks(sector as u64, buffer).expect("Failed to read");
+            assert!(&buffer[..] == expected);
+            sector += length / 512;
+        }
+
+        let mut sector = [0; 512];
+        drive(name).read_blocks(3, &mut sector).expect("Failed to read");
+        assert_eq!(&sector[..], &image[3 * 512..4 * 512]);
+    }
+
+    // A sector each
+    assert!(count(46) >= primary + 2049);
+    assert!(count(47) >= secondary + 2049);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_write() {
+    serial_print!("test_write... ");
+    let drive = drive("hdb");
+    let mut original = vec![0; 3 * 512];
+    drive.read_blocks(2000, &mut original).expect("Failed to read");
+
+    let pattern: Vec<u8> = (0..3 * 512).map(|index| (index * 13) as u8).collect();
+    drive.write_blocks(2000, &pattern).expect("Failed to write");
+    let mut buffer = vec![0; 3 * 512];
+    drive.read_blocks(2000, &mut buffer).expect("Failed to read");
+    assert_eq!(buffer, pattern);
+
+    drive.write_blocks(2000, &original).expect("Failed to write");
+    drive.read_blocks(2000, &mut buffer).expect("Failed to read");
+    assert_eq!(buffer, original);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_errors() {
+    serial_print!("test_errors... ");
+    let disk = drive("hdb");
+    let mut buffer = [0; 1024];
+    assert_eq!(disk.read_blocks(2047, &mut buffer), Err(BlockError::OutOfRange));
+    assert_eq!(disk.read_blocks(0, &mut buffer[..100]), Err(BlockError::BadBuffer));
+
+    let cdrom = drive("hdc");
+    let mut block = [0; 2048];
+    assert_eq!(cdrom.read_blocks(0, &mut block), Err(BlockError::OutOfRange));
+    assert_eq!(cdrom.write_blocks(0, &block), Err(BlockError::OutOfRange));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_ext2() {
+    serial_print!("test_ext2... ");
+    fs::create_dir("/ext2").expect("Failed to create the mount point");
+    for &name in ["hdb", "hdd"].iter() {
+        let device = devfs::block_device(name).expect("Failed to find the drive");
+        let ext2 = Ext2Fs::new(device).expect("Failed to open");
+        fs::mount("/ext2", ext2).expect("Failed to mount");
+        assert_eq!(fs::read("/ext2/hello.txt"), Ok(b"Hello from ext2!\n".to_vec()));
+        assert_eq!(fs::read("/ext2/nested/deep/file.txt"), Ok(b"Deep down\n".to_vec()));
+        fs::unmount("/ext2").expect("Failed to unmount");
+    }
+    serial_println!("[ok]");
+}
@@ -0,0 +1,190 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::block::queue::RequestQueue;
+use ham_dos::block::ram::RamDisk;
+use ham_dos::block::{self, BlockDevice, BlockError, Disk, IoStats, SECTOR_SIZE};
+use ham_dos::fs::fat::{self, FatFs, FatType};
+use ham_dos::fs::{self, devfs, FileType};
+use ham_dos::{address_space, allocator, memory, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    fs::init();
+    devfs::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+/// A disk of 64 sectors, each filled with its number
+fn numbered_disk() -> Arc<RamDisk> {
+    let image: Vec<u8> = (0..64 * SECTOR_SIZE).map(|index| (index / SECTOR_SIZE) as u8).collect();
+    RamDisk::with_contents(&image, false).expect("Failed to create the disk")
+}
+
+fn sector_of(device: &dyn BlockDevice, block: u64) -> [u8; SECTOR_SIZE] {
+    let mut sector = [0; SECTOR_SIZE];
+    device.read_blocks(block, &mut sector).expect("Failed to read");
+    sector
+}
+
+#[test_case]
+fn test_queue() {
+    serial_print!("test_queue... ");
+    let ram = numbered_disk();
+    let mut queue = RequestQueue::new();
+    for &block in [5, 3, 4, 10, 6].iter() {
+        queue.read(block, 1, SECTOR_SIZE);
+    }
+    queue.write(20, vec![7; 2 * SECTOR_SIZE]);
+    queue.write(22, vec![8; SECTOR_SIZE]);
+    assert_eq!(queue.len(), 7);
+
+    let mut stats = IoStats::default();
+    let done = queue.run(&*ram, &mut stats).expect("Failed to run the queue");
+    let blocks: Vec<u64> = done.iter().map(|request| request.block).collect();
+    assert_eq!(blocks, [3, 4, 5, 6, 10, 20, 22]);
+    assert!(done[2].data == vec![5; SECTOR_SIZE]);
+    // 3 to 6, 10 and 20 to 22
+    assert_eq!((stats.reads, stats.writes, stats.merged), (2, 1, 4));
+    assert_eq!((stats.blocks_read, stats.blocks_written), (5, 3));
+    assert_eq!(sector_of(&*ram, 22)[0], 8);
+
+    let mut queue = RequestQueue::new();
+    queue.write(63, vec![0; 2 * SECTOR_SIZE]);
+    assert_eq!(queue.run(&*ram, &mut stats).err(), Some(BlockError::OutOfRange));
+    let mut queue = RequestQueue::new();
+    queue.write(3, vec![0; 100]);
+    assert_eq!(queue.run(&*ram, &mut stats).err(), Some(BlockError::BadBuffer));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_cache() {
+    serial_print!("test_cache... ");
+    let ram = numbered_disk();
+    let disk = Disk::with_cache_size("test", ram.clone(), 8 * SECTOR_SIZE);
+    assert_eq!(disk.cache_capacity(), 8);
+
+    let mut buffer = vec![0; 4 * SECTOR_SIZE];
+    disk.read_blocks(0, &mut buffer).expect("Failed to read");
+    assert_eq!(buffer[3 * SECTOR_SIZE], 3);
+    disk.read_blocks(1, &mut buffer[..SECTOR_SIZE]).expect("Failed to read");
+    let stats = disk.stats();
+    assert_eq!((stats.hits, stats.misses, stats.reads), (1, 4, 1));
+
+    // Written blocks stay in the cache until they're written back
+    disk.write_blocks(10, &[0xAA; 2 * SECTOR_SIZE]).expect("Failed to write");
+    assert_eq!(disk.dirty_blocks(), 2);
+    assert_eq!(sector_of(&*ram, 10)[0], 10);
+    assert_eq!(sector_of(&disk, 10)[0], 0xAA);
+
+    // Large reads bypass the cache, but see the dirty blocks
+    let mut large = vec![0; 16 * SECTOR_SIZE];
+    disk.read_blocks(0, &mut large).expect("Failed to read");
+    assert_eq!((large[10 * SECTOR_SIZE], large[12 * SECTOR_SIZE]), (0xAA, 12));
+    assert_eq!(disk.cached_blocks(), 6);
+
+    // Evicting a dirty block writes all of them back at once
+    for block in 20..27 {
+        disk.read_blocks(block, &mut buffer[..SECTOR_SIZE]).expect("Failed to read");
+    }
+    assert_eq!(disk.cached_blocks(), 8);
+    assert_eq!(disk.dirty_blocks(), 0);
+    assert_eq!(sector_of(&*ram, 11)[0], 0xAA);
+    let stats = disk.stats();
+    assert_eq!((stats.writes, stats.written_back), (1, 2));
+
+    // Large writes go to the device and update what's cached
+    disk.write_blocks(20, &[9; 8 * SECTOR_SIZE]).expect("Failed to write");
+    assert_eq!(sector_of(&disk, 21)[0], 9);
+    assert_eq!(sector_of(&*ram, 27)[0], 9);
+    assert_eq!(disk.dirty_blocks(), 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_write_back() {
+    serial_print!("test_write_back... ");
+    let ram = numbered_disk();
+    let disk = Disk::new("test", ram.clone());
+    for &block in [33, 31, 30, 32, 40].iter() {
+        disk.write_blocks(block, &[block as u8 + 100; SECTOR_SIZE]).expect("Failed to write");
+    }
+    assert_eq!(disk.dirty_blocks(), 5);
+    assert_eq!(disk.stats().writes, 0);
+
+    disk.flush().expect("Failed to flush");
+    assert_eq!(disk.dirty_blocks(), 0);
+    let stats = disk.stats();
+    assert_eq!((stats.writes, stats.merged, stats.flushes), (2, 3, 1));
+    for &block in [30, 31, 32, 33, 40].iter() {
+        assert_eq!(sector_of(&*ram, block)[0], block as u8 + 100);
+    }
+
+    // Dropping a disk writes what's dirty back
+    disk.write_blocks(50, &[1; SECTOR_SIZE]).expect("Failed to write");
+    drop(disk);
+    assert_eq!(sector_of(&*ram, 50)[0], 1);
+
+    let read_only = RamDisk::with_contents(&[0; SECTOR_SIZE], true).expect("Failed to create");
+    let disk = Disk::new("test", read_only);
+    assert_eq!(disk.write_blocks(0, &[0; SECTOR_SIZE]), Err(BlockError::ReadOnly));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_register() {
+    serial_print!("test_register... ");
+    let ram = RamDisk::new(1440 * 1024);
+    let disk = block::register("ram0", ram.clone()).expect("Failed to register");
+    assert!(block::disks().iter().any(|registered| Arc::ptr_eq(registered, &disk)));
+    let metadata = fs::metadata("/dev/ram0").expect("Failed to get the metadata");
+    assert_eq!(metadata.file_type, FileType::BlockDevice);
+    assert_eq!(metadata.size, 1440 * 1024);
+
+    // A filesystem on the disk goes through the cache
+    let device = devfs::block_device("ram0").expect("Failed to find the disk");
+    fat::format(&*device, FatType::Fat12).expect("Failed to format");
+    let fat = FatFs::new(device).expect("Failed to open");
+    fs::create_dir("/fat").expect("Failed to create the mount point");
+    fs::mount("/fat", fat).expect("Failed to mount");
+    fs::write("/fat/cached.txt", b"Cached").expect("Failed to write");
+    assert!(disk.dirty_blocks() > 0);
+    assert!(disk.stats().hits > 0);
+
+    fs::sync().expect("Failed to sync");
+    assert_eq!(disk.dirty_blocks(), 0);
+    fs::unmount("/fat").expect("Failed to unmount");
+    let fat = FatFs::new(ram).expect("Failed to open");
+    fs::mount("/fat", fat).expect("Failed to mount");
+    assert_eq!(fs::read("/fat/cached.txt"), Ok(b"Cached".to_vec()));
+    fs::unmount("/fat").expect("Failed to unmount");
+    serial_println!("[ok]");
+}
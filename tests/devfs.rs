@@ -0,0 +1,208 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use ham_dos::block::ram::RamDisk;
+use ham_dos::block::BlockDevice;
+use ham_dos::fs::devfs::{self, CharDevice};
+use ham_dos::fs::{self, FileType, FsError, FsResult, SeekFrom};
+use ham_dos::fs::{O_RDONLY, O_RDWR, O_WRONLY};
+use ham_dos::{address_space, allocator, input, memory, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    fs::init();
+    devfs::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .expect("Failed to list")
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+/// Counts what's written to it, reads give the count so far
+struct Counter(AtomicUsize);
+
+impl CharDevice for Counter {
+    fn read(&self, buffer: &mut [u8]) -> FsResult<usize> {
+        buffer[0] = self.0.load(Ordering::Relaxed) as u8;
+        Ok(1)
+    }
+
+    fn write(&self, buffer: &[u8]) -> FsResult<usize> {
+        self.0.fetch_add(buffer.len(), Ordering::Relaxed);
+        Ok(buffer.len())
+    }
+}
+
+#[test_case]
+fn test_layout() {
+    serial_print!("test_layout... ");
+    let entries = names("/dev");
+    for name in ["console", "input", "null", "ttyS0", "zero"].iter() {
+        assert!(entries.iter().any(|entry| entry == name));
+    }
+    assert_eq!(names("/dev/input"), ["keyboard0", "mouse0"]);
+
+    let metadata = fs::metadata("/dev/ttyS0").expect("Failed to get the metadata");
+    assert_eq!(metadata.file_type, FileType::CharDevice);
+    assert_eq!(metadata.size, 0);
+    let input = fs::metadata("/dev/input").expect("Failed to get the metadata");
+    assert_eq!(input.file_type, FileType::Directory);
+    assert_eq!(fs::metadata("/dev/missing").err(), Some(FsError::NotFound));
+    assert_eq!(fs::metadata("/dev/null/file").err(), Some(FsError::NotADirectory));
+    assert_eq!(fs::write("/dev/new", b"data"), Err(FsError::ReadOnly));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_null_and_zero() {
+    serial_print!("test_null_and_zero... ");
+    let null = fs::open("/dev/null", O_RDWR).expect("Failed to open");
+    assert_eq!(null.write(b"gone"), Ok(4));
+    assert_eq!(null.read(&mut [1; 8]), Ok(0));
+
+    let zero = fs::open("/dev/zero", O_RDONLY).expect("Failed to open");
+    let mut buffer = [1; 100];
+    assert_eq!(zero.read(&mut buffer), Ok(100));
+    assert_eq!(&buffer[..], &[0; 100][..]);
+    assert_eq!(fs::truncate("/dev/zero", 0), Err(FsError::InvalidArgument));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_serial_port() {
+    // Written through the device file instead of `serial_print!`
+    let serial = fs::open("/dev/ttyS0", O_WRONLY).expect("Failed to open");
+    let text = b"test_serial_port... ";
+    assert_eq!(serial.write(text), Ok(text.len()));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_input() {
+    serial_print!("test_input... ");
+    let mouse = fs::open("/dev/input/mouse0", O_RDONLY).expect("Failed to open");
+    let keyboard = fs::open("/dev/input/keyboard0", O_RDONLY).expect("Failed to open");
+    // Drop what the devices sent while they were set up
+    mouse.read(&mut vec![0; input::MOUSE.len()]).expect("Failed to read");
+    keyboard.read(&mut vec![0; input::KEYBOARD.len()]).expect("Failed to read");
+
+    // What the mouse interrupt handler queues for a packet
+    let packet = [0x09, 0x05, 0xFE, 0x00];
+    input::MOUSE.push(&packet);
+    let mut buffer = [0; 16];
+    assert_eq!(mouse.read(&mut buffer), Ok(4));
+    assert_eq!(buffer[..4], packet);
+    assert!(input::MOUSE.is_empty());
+
+    // A pressed and released
+    input::KEYBOARD.push(&[0x1E, 0x9E]);
+    assert_eq!(keyboard.read(&mut buffer[..1]), Ok(1));
+    assert_eq!(buffer[0], 0x1E);
+    assert_eq!(keyboard.read(&mut buffer), Ok(1));
+    assert_eq!(buffer[0], 0x9E);
+    assert_eq!(keyboard.write(b"x"), Err(FsError::WrongMode));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_register() {
+    serial_print!("test_register... ");
+    let counter = Arc::new(Counter(AtomicUsize::new(0)));
+    devfs::register_char("test/nested/counter", counter.clone()).expect("Failed to register");
+    assert_eq!(
+        devfs::register_char("test/nested/counter", counter.clone()),
+        Err(FsError::AlreadyExists)
+    );
+    assert_eq!(
+        devfs::register_char("test/nested/counter/below", counter.clone()),
+        Err(FsError::NotADirectory)
+    );
+    assert_eq!(devfs::register_char("../escape", counter.clone()), Err(FsError::InvalidArgument));
+    assert_eq!(names("/dev/test"), ["nested"]);
+
+    let file = fs::open("/dev/test/nested/counter", O_RDWR).expect("Failed to open");
+    assert_eq!(file.write(b"12345"), Ok(5));
+    assert_eq!(file.write(b"678"), Ok(3));
+    let mut count = [0];
+    assert_eq!(file.read(&mut count), Ok(1));
+    assert_eq!(count[0], 8);
+
+    // Gone from /dev, the open file still works
+    devfs::unregister("test/nested/counter").expect("Failed to unregister");
+    assert_eq!(fs::metadata("/dev/test").err(), Some(FsError::NotFound));
+    assert_eq!(devfs::unregister("test/nested/counter"), Err(FsError::NotFound));
+    assert_eq!(file.write(b"9"), Ok(1));
+    assert_eq!(counter.0.load(Ordering::Relaxed), 9);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_block_device() {
+    serial_print!("test_block_device... ");
+    let disk = RamDisk::new(64 * 1024);
+    devfs::register_block("ram0", disk.clone()).expect("Failed to register");
+    assert!(devfs::devices().iter().any(|name| name == "ram0"));
+    let metadata = fs::metadata("/dev/ram0").expect("Failed to get the metadata");
+    assert_eq!(metadata.file_type, FileType::BlockDevice);
+    assert_eq!(metadata.size, 64 * 1024);
+
+    // Not aligned to sectors
+    let file = fs::open("/dev/ram0", O_RDWR).expect("Failed to open");
+    file.seek(SeekFrom::Start(1000)).expect("Failed to seek");
+    assert_eq!(file.write(b"across two sectors"), Ok(18));
+    let mut sectors = vec![0; 1024];
+    disk.read_blocks(1, &mut sectors).expect("Failed to read the disk");
+    assert_eq!(&sectors[1000 - 512..1000 - 512 + 18], b"across two sectors");
+
+    file.seek(SeekFrom::Start(1000)).expect("Failed to seek");
+    let mut buffer = [0; 18];
+    assert_eq!(file.read(&mut buffer), Ok(18));
+    assert_eq!(&buffer, b"across two sectors");
+
+    // Only up to the end
+    file.seek(SeekFrom::End(-4)).expect("Failed to seek");
+    assert_eq!(file.write(b"too long"), Ok(4));
+    assert_eq!(file.write(b"more"), Err(FsError::NoSpace));
+    assert_eq!(file.read(&mut buffer), Ok(0));
+
+    assert!(devfs::block_device("ram0").is_ok());
+    assert_eq!(devfs::block_device("null").err(), Some(FsError::InvalidArgument));
+    drop(file);
+    devfs::unregister("ram0").expect("Failed to unregister");
+    assert_eq!(devfs::block_device("ram0").err(), Some(FsError::NotFound));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::elf::{ElfError, ElfFile};
+use ham_dos::task;
+use ham_dos::{address_space, allocator, memory, programs, serial_print, serial_println, user};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_run_embedded_program() {
+    serial_print!("test_run_embedded_program... ");
+    let id = user::spawn_elf(
+        "hello",
+        programs::HELLO,
+        &["hello", "first", "second"],
+        &["HOME=/"],
+    )
+    .expect("Failed to load the program");
+
+    let code = user::wait(id);
+    serial_print!("exit code {:?} ", code);
+    assert_eq!(code, Some(43));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_invalid_files_are_rejected() {
+    serial_print!("test_invalid_files_are_rejected... ");
+    assert_eq!(
+        ElfFile::parse(&programs::HELLO[..32]).err(),
+        Some(ElfError::TooShort)
+    );
+
+    let mut bad_magic: Vec<u8> = programs::HELLO.to_vec();
+    bad_magic[1] = b'X';
+    assert_eq!(ElfFile::parse(&bad_magic).err(), Some(ElfError::BadMagic));
+
+    let mut not_64_bit = programs::HELLO.to_vec();
+    not_64_bit[4] = 1;
+    assert_eq!(
+        ElfFile::parse(&not_64_bit).err(),
+        Some(ElfError::UnsupportedFormat)
+    );
+
+    // Point the first segment at kernel memory
+    let mut kernel_segment = programs::HELLO.to_vec();
+    let first_header = 64;
+    kernel_segment[first_header + 16..first_header + 24]
+        .copy_from_slice(&0x4444_4444_0000u64.to_le_bytes());
+    assert_eq!(
+        ElfFile::parse(&kernel_segment).err(),
+        Some(ElfError::BadSegment)
+    );
+    serial_println!("[ok]");
+}
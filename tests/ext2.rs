@@ -0,0 +1,249 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::block::ram::RamDisk;
+use ham_dos::fs::ext2::Ext2Fs;
+use ham_dos::fs::{self, FileSystem, FileType, FsError, SeekFrom};
+use ham_dos::fs::{O_CREAT, O_RDONLY, O_WRONLY};
+use ham_dos::{address_space, allocator, memory, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    fs::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+// Built by tests/images/make_ext2.sh, with 1 KiB and with 4 KiB blocks
+static IMAGE_1K: &[u8] = include_bytes!("images/ext2-1024.img");
+static IMAGE_4K: &[u8] = include_bytes!("images/ext2-4096.img");
+
+/// When the files of the images were last modified
+const MODIFIED: u64 = 1_600_000_000_000;
+
+/// Mounts a RAM disk holding `image` on `/ext2`
+fn mount_ext2(image: &[u8]) -> Arc<Ext2Fs> {
+    let disk = RamDisk::with_contents(image, true).expect("Failed to create the disk");
+    let ext2 = Ext2Fs::new(disk).expect("Failed to open");
+    fs::create_dir("/ext2").expect("Failed to create the mount point");
+    fs::mount("/ext2", ext2.clone()).expect("Failed to mount");
+    ext2
+}
+
+fn unmount_ext2() {
+    fs::unmount("/ext2").expect("Failed to unmount");
+    fs::remove_dir("/ext2").expect("Failed to remove the mount point");
+}
+
+/// The names in the directory `path`, sorted
+fn names(path: &str) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(path)
+        .expect("Failed to list")
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    names
+}
+
+fn pattern(length: usize) -> Vec<u8> {
+    (0..length).map(|index| (index * 7 + index / 251) as u8).collect()
+}
+
+#[test_case]
+fn test_mount() {
+    serial_print!("test_mount... ");
+    for &(image, block_size) in [(IMAGE_1K, 1024), (IMAGE_4K, 4096)].iter() {
+        let ext2 = mount_ext2(image);
+        assert_eq!(ext2.name(), "ext2");
+        assert_eq!(ext2.block_size(), block_size);
+        assert_eq!(ext2.label(), format!("hamdos-{}", block_size).as_str());
+
+        let root = fs::metadata("/ext2").expect("Failed to get the metadata");
+        assert_eq!(root.inode, 2);
+        assert_eq!(root.file_type, FileType::Directory);
+        assert_eq!(root.modified, MODIFIED);
+        let names = names("/ext2");
+        for name in ["big.bin", "docs", "empty", "hello.txt", "lost+found", "nested"].iter() {
+            assert!(names.iter().any(|entry| entry == name));
+        }
+        assert!(!names.iter().any(|entry| entry == "." || entry == ".."));
+        unmount_ext2();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_files() {
+    serial_print!("test_files... ");
+    for &image in [IMAGE_1K, IMAGE_4K].iter() {
+        mount_ext2(image);
+        assert_eq!(fs::read("/ext2/hello.txt"), Ok(b"Hello from ext2!\n".to_vec()));
+        assert_eq!(fs::read("/ext2/docs/readme.txt"), Ok(b"Read me\n".to_vec()));
+        assert_eq!(fs::read("/ext2/empty"), Ok(Vec::new()));
+
+        let metadata = fs::metadata("/ext2/hello.txt").expect("Failed to get the metadata");
+        assert_eq!(metadata.size, 17);
+        assert_eq!(metadata.file_type, FileType::Regular);
+        assert_eq!(metadata.links, 1);
+        assert_eq!(metadata.modified, MODIFIED);
+
+        // Direct, indirect and with 1 KiB blocks doubly indirect blocks
+        let expected = pattern(300 * 1024);
+        assert_eq!(fs::read("/ext2/big.bin").as_ref(), Ok(&expected));
+        let file = fs::open("/ext2/big.bin", O_RDONLY).expect("Failed to open");
+        file.seek(SeekFrom::Start(273_000)).expect("Failed to seek");
+        let mut buffer = vec![0; 5000];
+        assert_eq!(file.read(&mut buffer), Ok(5000));
+        assert_eq!(&buffer[..], &expected[273_000..278_000]);
+        drop(file);
+        unmount_ext2();
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_holes() {
+    serial_print!("test_holes... ");
+    mount_ext2(IMAGE_1K);
+    // Only the last block is stored, behind a triply indirect block
+    let end = 70 * 1024 * 1024;
+    let file = fs::open("/ext2/sparse.bin", O_RDONLY).expect("Failed to open");
+    assert_eq!(file.metadata().map(|metadata| metadata.size), Ok(end + 9));
+
+    let mut buffer = [1; 20];
+    assert_eq!(file.read(&mut buffer), Ok(20));
+    assert_eq!(buffer, [0; 20]);
+    file.seek(SeekFrom::Start(end - 11)).expect("Failed to seek");
+    assert_eq!(file.read(&mut buffer), Ok(20));
+    assert_eq!(&buffer[..11], &[0; 11]);
+    assert_eq!(&buffer[11..], b"far away\n");
+    assert_eq!(file.read(&mut buffer), Ok(0));
+    drop(file);
+    unmount_ext2();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_directories() {
+    serial_print!("test_directories... ");
+    mount_ext2(IMAGE_1K);
+    // The entries take more than one block
+    let expected: Vec<String> = (0..100).map(|index| format!("file-{:02}", index)).collect();
+    assert_eq!(names("/ext2/many"), expected);
+    assert_eq!(fs::read("/ext2/many/file-42"), Ok(b"file-42\n".to_vec()));
+
+    assert_eq!(fs::read("/ext2/docs/nested/deep/file.txt"), Ok(b"Deep down\n".to_vec()));
+    assert_eq!(names("/ext2/docs"), ["nested", "readme.txt"]);
+    let long_name = format!("/ext2/long-{:0250}", 0);
+    assert_eq!(fs::metadata(&long_name).map(|metadata| metadata.size), Ok(0));
+
+    assert_eq!(fs::read("/ext2/missing").err(), Some(FsError::NotFound));
+    assert_eq!(fs::read_dir("/ext2/hello.txt").err(), Some(FsError::NotADirectory));
+    assert_eq!(fs::read("/ext2/hello.txt/file").err(), Some(FsError::NotADirectory));
+    unmount_ext2();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_symlinks() {
+    serial_print!("test_symlinks... ");
+    for &image in [IMAGE_1K, IMAGE_4K].iter() {
+        mount_ext2(image);
+        // Stored in the inode
+        assert_eq!(fs::read_link("/ext2/hello-link"), Ok(String::from("hello.txt")));
+        assert_eq!(fs::read("/ext2/hello-link"), Ok(b"Hello from ext2!\n".to_vec()));
+        let link = fs::symlink_metadata("/ext2/hello-link").expect("Failed to get the metadata");
+        assert_eq!(link.file_type, FileType::Symlink);
+        assert_eq!(link.size, 9);
+        let target = fs::metadata("/ext2/hello-link").expect("Failed to get the metadata");
+        assert_eq!(target.file_type, FileType::Regular);
+
+        // Stored in a block
+        let far = "docs/./nested/./deep/../deep/./././././././././././././file.txt";
+        assert_eq!(fs::read_link("/ext2/far-link"), Ok(String::from(far)));
+        assert_eq!(fs::read("/ext2/far-link"), Ok(b"Deep down\n".to_vec()));
+
+        // A link to a directory in the middle of a path
+        assert_eq!(fs::read("/ext2/nested/deep/file.txt"), Ok(b"Deep down\n".to_vec()));
+        assert_eq!(names("/ext2/nested"), ["deep"]);
+        assert_eq!(fs::read_link("/ext2/hello.txt"), Err(FsError::InvalidArgument));
+        unmount_ext2();
+    }
+
+    mount_ext2(IMAGE_1K);
+    assert_eq!(fs::read("/ext2/loop").err(), Some(FsError::SymlinkLoop));
+    assert_eq!(fs::read_link("/ext2/loop"), Ok(String::from("loop")));
+    assert_eq!(fs::read("/ext2/broken").err(), Some(FsError::NotFound));
+    assert!(fs::symlink_metadata("/ext2/broken").is_ok());
+    unmount_ext2();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_read_only() {
+    serial_print!("test_read_only... ");
+    mount_ext2(IMAGE_1K);
+    assert_eq!(fs::write("/ext2/hello.txt", b"changed"), Err(FsError::ReadOnly));
+    assert_eq!(fs::write("/ext2/new.txt", b"new"), Err(FsError::ReadOnly));
+    let file = fs::open("/ext2/hello.txt", O_WRONLY).expect("Failed to open");
+    assert_eq!(file.write(b"changed"), Err(FsError::ReadOnly));
+    drop(file);
+    assert_eq!(fs::open("/ext2/new.txt", O_WRONLY | O_CREAT).err(), Some(FsError::ReadOnly));
+    assert_eq!(fs::create_dir("/ext2/new"), Err(FsError::ReadOnly));
+    assert_eq!(fs::remove_file("/ext2/hello.txt"), Err(FsError::ReadOnly));
+    assert_eq!(fs::remove_dir("/ext2/docs/nested/deep"), Err(FsError::ReadOnly));
+    assert_eq!(fs::rename("/ext2/hello.txt", "/ext2/moved.txt"), Err(FsError::ReadOnly));
+    assert_eq!(fs::read("/ext2/hello.txt"), Ok(b"Hello from ext2!\n".to_vec()));
+    unmount_ext2();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_bad_images() {
+    serial_print!("test_bad_images... ");
+    let open = |image: &[u8]| {
+        let disk = RamDisk::with_contents(image, true).expect("Failed to create the disk");
+        Ext2Fs::new(disk).map(|_| ())
+    };
+    assert_eq!(open(&[0; 4096]), Err(FsError::InvalidArgument));
+
+    // An incompatible feature that isn't supported, like extents
+    let mut image = IMAGE_1K.to_vec();
+    image[1024 + 96] |= 0x40;
+    assert_eq!(open(&image), Err(FsError::NotSupported));
+
+    // More blocks than the device has
+    let mut image = IMAGE_1K.to_vec();
+    image[1024 + 5] = 0x10;
+    assert_eq!(open(&image), Err(FsError::Io));
+    serial_println!("[ok]");
+}
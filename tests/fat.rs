@@ -0,0 +1,390 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::block::ram::RamDisk;
+use ham_dos::block::{BlockDevice, SECTOR_SIZE};
+use ham_dos::fs::fat::{self, FatFs, FatType, Problem};
+use ham_dos::fs::{self, FileSystem, FileType, FsError, SeekFrom};
+use ham_dos::fs::{O_APPEND, O_CREAT, O_RDONLY, O_WRONLY};
+use ham_dos::{address_space, allocator, memory, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    fs::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+/// A floppy, the smallest FAT16 and a FAT32 a bit over the smallest
+const VOLUMES: [(FatType, u64); 3] = [
+    (FatType::Fat12, 1440 * 1024),
+    (FatType::Fat16, 16 * 1024 * 1024),
+    (FatType::Fat32, 40 * 1024 * 1024),
+];
+
+/// Formats a new RAM disk and mounts it on `/fat`
+fn mount_fat(fat_type: FatType, size: u64) -> (Arc<RamDisk>, Arc<FatFs>) {
+    let disk = RamDisk::new(size);
+    fat::format(&*disk, fat_type).expect("Failed to format");
+    let fat = FatFs::new(disk.clone()).expect("Failed to open");
+    fs::create_dir("/fat").expect("Failed to create the mount point");
+    fs::mount("/fat", fat.clone()).expect("Failed to mount");
+    (disk, fat)
+}
+
+/// Unmounts `/fat` and checks the filesystem left on the disk
+fn unmount_fat(disk: &Arc<RamDisk>) {
+    fs::unmount("/fat").expect("Failed to unmount");
+    fs::remove_dir("/fat").expect("Failed to remove the mount point");
+    assert_eq!(fat::check(disk.clone()), Ok(Vec::new()));
+}
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .expect("Failed to list")
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+fn pattern(length: usize) -> Vec<u8> {
+    (0..length).map(|index| (index * 7 + index / 251) as u8).collect()
+}
+
+#[test_case]
+fn test_format() {
+    serial_print!("test_format... ");
+    for &(fat_type, size) in VOLUMES.iter() {
+        let (disk, fat) = mount_fat(fat_type, size);
+        assert_eq!(fat.fat_type(), fat_type);
+        assert_eq!(fat.name(), "vfat");
+        assert!(fat.free_bytes() > size * 9 / 10);
+        assert_eq!(names("/fat"), Vec::<String>::new());
+        drop(fat);
+        unmount_fat(&disk);
+    }
+
+    // Too small for FAT16 and FAT32
+    let floppy = RamDisk::new(1440 * 1024);
+    assert_eq!(fat::format(&*floppy, FatType::Fat16), Err(FsError::InvalidArgument));
+    assert_eq!(fat::format(&*floppy, FatType::Fat32), Err(FsError::InvalidArgument));
+    // Not formatted
+    assert_eq!(FatFs::new(floppy).err(), Some(FsError::InvalidArgument));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_files() {
+    serial_print!("test_files... ");
+    for &(fat_type, size) in VOLUMES.iter() {
+        let (disk, fat) = mount_fat(fat_type, size);
+        let free = fat.free_bytes();
+        let cluster_size = fat.cluster_size() as usize;
+
+        fs::write("/fat/EMPTY", b"").expect("Failed to write");
+        assert_eq!(fs::read("/fat/EMPTY"), Ok(Vec::new()));
+        let data = pattern(cluster_size * 5 + 123);
+        fs::write("/fat/DATA.BIN", &data).expect("Failed to write");
+        assert_eq!(fs::read("/fat/DATA.BIN"), Ok(data.clone()));
+
+        let file = fs::open("/fat/DATA.BIN", O_WRONLY | O_APPEND).expect("Failed to open");
+        assert_eq!(file.write(b"tail"), Ok(4));
+        assert_eq!(file.metadata().map(|metadata| metadata.size), Ok(data.len() as u64 + 4));
+        drop(file);
+
+        // Writing past the end fills the gap with zeros
+        let file = fs::open("/fat/EMPTY", O_WRONLY).expect("Failed to open");
+        file.seek(SeekFrom::Start(cluster_size as u64 * 2 + 10)).expect("Failed to seek");
+        assert_eq!(file.write(b"x"), Ok(1));
+        drop(file);
+        let mut expected = vec![0; cluster_size * 2 + 11];
+        expected[cluster_size * 2 + 10] = b'x';
+        assert_eq!(fs::read("/fat/EMPTY"), Ok(expected));
+
+        fs::truncate("/fat/DATA.BIN", 10).expect("Failed to truncate");
+        assert_eq!(fs::read("/fat/DATA.BIN"), Ok(data[..10].to_vec()));
+        fs::truncate("/fat/DATA.BIN", 20).expect("Failed to truncate");
+        let mut grown = data[..10].to_vec();
+        grown.resize(20, 0);
+        assert_eq!(fs::read("/fat/DATA.BIN"), Ok(grown));
+
+        fs::remove_file("/fat/DATA.BIN").expect("Failed to remove");
+        fs::remove_file("/fat/EMPTY").expect("Failed to remove");
+        assert_eq!(fat.free_bytes(), free);
+        drop(fat);
+        unmount_fat(&disk);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_long_names() {
+    serial_print!("test_long_names... ");
+    let (disk, fat) = mount_fat(FatType::Fat16, 16 * 1024 * 1024);
+    let long = "A rather long name with spaces.text";
+    fs::write(&format!("/fat/{}", long), b"long").expect("Failed to write");
+    fs::write("/fat/lower.txt", b"lower").expect("Failed to write");
+    fs::write("/fat/UPPER.TXT", b"upper").expect("Failed to write");
+    fs::write("/fat/Ünïcödé.txt", b"unicode").expect("Failed to write");
+
+    let mut listed = names("/fat");
+    listed.sort();
+    assert_eq!(listed, [long, "UPPER.TXT", "lower.txt", "Ünïcödé.txt"]);
+
+    // Case doesn't matter and the short names work too
+    assert_eq!(fs::read("/fat/a RATHER long NAME with SPACES.TEXT"), Ok(b"long".to_vec()));
+    assert_eq!(fs::read("/fat/ARATHE~1.TEX"), Ok(b"long".to_vec()));
+    assert_eq!(fs::read("/fat/LOWER.TXT"), Ok(b"lower".to_vec()));
+    assert_eq!(fs::write("/fat/upper.txt", b"again"), Ok(()));
+    assert_eq!(fs::read("/fat/UPPER.TXT"), Ok(b"again".to_vec()));
+
+    // Names that need a numeric tail get different ones
+    for index in 0..20 {
+        fs::write(&format!("/fat/Similar name {}.txt", index), b"").expect("Failed to write");
+    }
+    assert_eq!(names("/fat").len(), 24);
+    assert_eq!(fs::read("/fat/SIMILA~2.TXT"), Ok(Vec::new()));
+
+    assert_eq!(fs::write("/fat/a:b", b""), Err(FsError::InvalidArgument));
+    assert_eq!(fs::write("/fat/trailing.", b""), Err(FsError::InvalidArgument));
+    let too_long: String = core::iter::repeat('a').take(256).collect();
+    assert_eq!(fs::write(&format!("/fat/{}", too_long), b""), Err(FsError::NameTooLong));
+    let longest = &too_long[..255];
+    fs::write(&format!("/fat/{}", longest), b"").expect("Failed to write");
+    assert!(names("/fat").iter().any(|name| name == longest));
+
+    drop(fat);
+    unmount_fat(&disk);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_directories() {
+    serial_print!("test_directories... ");
+    for &(fat_type, size) in VOLUMES.iter() {
+        let (disk, fat) = mount_fat(fat_type, size);
+        fs::create_dir_all("/fat/one/two/three").expect("Failed to create");
+        fs::write("/fat/one/two/file", b"file").expect("Failed to write");
+        assert_eq!(
+            fs::metadata("/fat/one/two").map(|metadata| metadata.file_type),
+            Ok(FileType::Directory)
+        );
+        assert_eq!(fs::metadata("/fat/one").map(|metadata| metadata.links), Ok(3));
+
+        // Enough entries to need more clusters
+        for index in 0..100 {
+            fs::write(&format!("/fat/one/entry number {}", index), b"").expect("Failed to write");
+        }
+        assert_eq!(names("/fat/one").len(), 101);
+        assert_eq!(fs::remove_dir("/fat/one"), Err(FsError::DirectoryNotEmpty));
+        for index in 0..100 {
+            fs::remove_file(&format!("/fat/one/entry number {}", index)).expect("Failed to remove");
+        }
+
+        fs::rename("/fat/one/two", "/fat/moved").expect("Failed to rename");
+        assert_eq!(fs::read("/fat/moved/file"), Ok(b"file".to_vec()));
+        assert_eq!(names("/fat/one"), Vec::<String>::new());
+        fs::rename("/fat/moved/file", "/fat/Renamed File").expect("Failed to rename");
+        assert_eq!(names("/fat/moved"), ["three"]);
+        fs::write("/fat/other", b"other").expect("Failed to write");
+        fs::rename("/fat/other", "/fat/renamed file").expect("Failed to rename");
+        assert_eq!(fs::read("/fat/Renamed File"), Ok(b"other".to_vec()));
+        assert_eq!(
+            fs::rename("/fat/moved", "/fat/Renamed File"),
+            Err(FsError::NotADirectory)
+        );
+
+        fs::remove_dir("/fat/moved/three").expect("Failed to remove");
+        fs::remove_dir("/fat/moved").expect("Failed to remove");
+        fs::remove_dir("/fat/one").expect("Failed to remove");
+        assert_eq!(names("/fat"), ["renamed file"]);
+        drop(fat);
+        unmount_fat(&disk);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_removed_file_stays_open() {
+    serial_print!("test_removed_file_stays_open... ");
+    let (disk, fat) = mount_fat(FatType::Fat12, 1440 * 1024);
+    let free = fat.free_bytes();
+    let data = pattern(10000);
+    fs::write("/fat/open", &data).expect("Failed to write");
+
+    let file = fs::open("/fat/open", O_RDONLY).expect("Failed to open");
+    fs::remove_file("/fat/open").expect("Failed to remove");
+    assert_eq!(fs::metadata("/fat/open").err(), Some(FsError::NotFound));
+    // The entry can be reused while the clusters are still taken
+    fs::write("/fat/open", b"new").expect("Failed to write");
+    assert!(fat.free_bytes() < free);
+
+    let mut buffer = vec![0; data.len()];
+    assert_eq!(file.read(&mut buffer), Ok(data.len()));
+    assert_eq!(buffer, data);
+    drop(file);
+    fs::remove_file("/fat/open").expect("Failed to remove");
+    assert_eq!(fat.free_bytes(), free);
+    drop(fat);
+    unmount_fat(&disk);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_full_volume() {
+    serial_print!("test_full_volume... ");
+    let (disk, fat) = mount_fat(FatType::Fat12, 256 * 1024);
+    let free = fat.free_bytes();
+    let file = fs::open("/fat/big", O_WRONLY | O_CREAT).expect("Failed to create");
+    let chunk = [0x5A; 4096];
+    let mut written = 0;
+    loop {
+        match file.write(&chunk) {
+            Ok(count) => written += count as u64,
+            Err(error) => {
+                assert_eq!(error, FsError::NoSpace);
+                break;
+            }
+        }
+    }
+    assert_eq!(written, free);
+    assert_eq!(fat.free_bytes(), 0);
+    assert_eq!(fs::create_dir("/fat/more"), Err(FsError::NoSpace));
+    drop(file);
+
+    fs::remove_file("/fat/big").expect("Failed to remove");
+    assert_eq!(fat.free_bytes(), free);
+    drop(fat);
+    unmount_fat(&disk);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_remount() {
+    serial_print!("test_remount... ");
+    for &(fat_type, size) in VOLUMES.iter() {
+        let (disk, fat) = mount_fat(fat_type, size);
+        fs::create_dir("/fat/Kept Directory").expect("Failed to create");
+        fs::write("/fat/Kept Directory/kept file.txt", b"kept").expect("Failed to write");
+        let free = fat.free_bytes();
+        drop(fat);
+        unmount_fat(&disk);
+
+        let fat = FatFs::new(disk.clone()).expect("Failed to open");
+        assert_eq!(fat.fat_type(), fat_type);
+        assert_eq!(fat.free_bytes(), free);
+        fs::create_dir("/fat").expect("Failed to create the mount point");
+        fs::mount("/fat", fat).expect("Failed to mount");
+        assert_eq!(fs::read("/fat/kept directory/KEPT FILE.TXT"), Ok(b"kept".to_vec()));
+        unmount_fat(&disk);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_read_only() {
+    serial_print!("test_read_only... ");
+    let disk = RamDisk::new(128 * 1024);
+    fat::format(&*disk, FatType::Fat12).expect("Failed to format");
+    let fat = FatFs::new(disk.clone()).expect("Failed to open");
+    fs::create_dir("/fat").expect("Failed to create the mount point");
+    fs::mount("/fat", fat).expect("Failed to mount");
+    fs::write("/fat/file", b"file").expect("Failed to write");
+    unmount_fat(&disk);
+
+    let mut image = vec![0; disk.block_count() as usize * SECTOR_SIZE];
+    disk.read_blocks(0, &mut image).expect("Failed to read");
+    let copy = RamDisk::with_contents(&image, true).expect("Failed to copy");
+    fs::create_dir("/fat").expect("Failed to create the mount point");
+    fs::mount("/fat", FatFs::new(copy.clone()).expect("Failed to open")).expect("Failed to mount");
+    assert_eq!(fs::read("/fat/file"), Ok(b"file".to_vec()));
+    assert_eq!(fs::write("/fat/file", b""), Err(FsError::ReadOnly));
+    assert_eq!(fs::create_dir("/fat/dir"), Err(FsError::ReadOnly));
+    unmount_fat(&copy);
+    serial_println!("[ok]");
+}
+
+/// Byte position of the FAT copy `copy` of a FAT12 or FAT16 volume
+fn fat_position(disk: &RamDisk, copy: u64) -> u64 {
+    let mut boot = [0; SECTOR_SIZE];
+    disk.read_blocks(0, &mut boot).expect("Failed to read");
+    let reserved = u64::from(boot[14]) | u64::from(boot[15]) << 8;
+    let fat_size = u64::from(boot[22]) | u64::from(boot[23]) << 8;
+    (reserved + copy * fat_size) * SECTOR_SIZE as u64
+}
+
+/// Changes `bytes` of `disk` at `position`
+fn patch(disk: &RamDisk, position: u64, bytes: &[u8]) {
+    let mut sector = [0; SECTOR_SIZE];
+    let block = position / SECTOR_SIZE as u64;
+    let offset = (position % SECTOR_SIZE as u64) as usize;
+    disk.read_blocks(block, &mut sector).expect("Failed to read");
+    sector[offset..offset + bytes.len()].copy_from_slice(bytes);
+    disk.write_blocks(block, &sector).expect("Failed to write");
+}
+
+#[test_case]
+fn test_check() {
+    serial_print!("test_check... ");
+    let (disk, fat) = mount_fat(FatType::Fat16, 16 * 1024 * 1024);
+    let cluster_size = fat.cluster_size() as usize;
+    fs::write("/fat/FIRST", &pattern(cluster_size * 2)).expect("Failed to write");
+    fs::write("/fat/SECOND", &pattern(cluster_size)).expect("Failed to write");
+    drop(fat);
+    unmount_fat(&disk);
+
+    // FIRST has clusters 2 and 3, SECOND has 4
+    let first_fat = fat_position(&disk, 0);
+    let second_fat = fat_position(&disk, 1);
+    // A lost cluster in the first copy only
+    patch(&disk, first_fat + 100 * 2, &[0xFF, 0xFF]);
+    let problems = fat::check(disk.clone()).expect("Failed to check");
+    assert_eq!(
+        problems,
+        [Problem::FatCopiesDiffer { copy: 1 }, Problem::LostClusters { count: 1 }]
+    );
+    patch(&disk, second_fat + 100 * 2, &[0xFF, 0xFF]);
+
+    // FIRST continues into SECOND
+    patch(&disk, first_fat + 3 * 2, &[4, 0]);
+    patch(&disk, second_fat + 3 * 2, &[4, 0]);
+    let problems = fat::check(disk.clone()).expect("Failed to check");
+    assert!(problems.contains(&Problem::LostClusters { count: 1 }));
+    assert!(problems.iter().any(|problem| match problem {
+        Problem::CrossLinked { cluster: 4, .. } => true,
+        _ => false,
+    }));
+    assert!(problems.iter().any(|problem| match problem {
+        Problem::SizeMismatch { size, clusters: 3, .. } => *size as usize == cluster_size * 2,
+        _ => false,
+    }));
+    serial_println!("[ok]");
+}
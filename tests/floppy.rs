@@ -0,0 +1,135 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::block::{floppy, BlockDevice, BlockError, SECTOR_SIZE};
+use ham_dos::fs::fat::{FatFs, FatType};
+use ham_dos::fs::{self, devfs, FileType};
+use ham_dos::{address_space, allocator, memory, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    fs::init();
+    devfs::init();
+    floppy::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+// Inserted into the first drive by the test arguments in Cargo.toml, built
+// by images/make_floppy.py
+static IMAGE: &[u8] = include_bytes!("images/floppy.img");
+
+/// Contents of BIG.BIN
+fn pattern(length: usize) -> Vec<u8> {
+    (0..length).map(|i| ((i * 7 + i / 251) % 256) as u8).collect()
+}
+
+#[test_case]
+fn test_drive() {
+    serial_print!("test_drive... ");
+    let drive = floppy::drive().expect("No floppy drive");
+    assert_eq!(drive.name(), "fd0");
+    assert_eq!(drive.block_count(), 2880);
+    assert_eq!(drive.block_size(), SECTOR_SIZE);
+
+    let metadata = fs::metadata("/dev/fd0").expect("Failed to get the metadata");
+    assert_eq!(metadata.file_type, FileType::BlockDevice);
+    assert_eq!(metadata.size, 1440 * 1024);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_read() {
+    serial_print!("test_read... ");
+    let drive = floppy::drive().expect("No floppy drive");
+    // Two and a half tracks from the middle of one, in a few transfers each
+    let mut buffer = vec![0; 45 * SECTOR_SIZE];
+    for &block in [0, 10, 1000, 2880 - 45].iter() {
+        drive.read_blocks(block, &mut buffer).expect("Failed to read");
+        let start = block as usize * SECTOR_SIZE;
+        assert!(buffer[..] == IMAGE[start..start + buffer.len()]);
+    }
+    assert!(drive.motor_on());
+
+    assert_eq!(drive.read_blocks(2880, &mut buffer[..SECTOR_SIZE]), Err(BlockError::OutOfRange));
+    assert_eq!(drive.read_blocks(0, &mut buffer[..100]), Err(BlockError::BadBuffer));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_write() {
+    serial_print!("test_write... ");
+    let drive = floppy::drive().expect("No floppy drive");
+    // Crosses from the first head to the second and on to the next cylinder
+    let block = 30;
+    let written: Vec<u8> = (0..12 * SECTOR_SIZE).map(|i| (i % 253) as u8).collect();
+    drive.write_blocks(block, &written).expect("Failed to write");
+
+    let mut read = vec![0; written.len()];
+    drive.read_blocks(block, &mut read).expect("Failed to read");
+    assert!(read == written);
+
+    let start = block as usize * SECTOR_SIZE;
+    drive.write_blocks(block, &IMAGE[start..start + written.len()]).expect("Failed to restore");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_fat() {
+    serial_print!("test_fat... ");
+    let device = devfs::block_device("fd0").expect("Failed to find the floppy");
+    let fat = FatFs::new(device).expect("Failed to open");
+    assert_eq!(fat.fat_type(), FatType::Fat12);
+    fs::create_dir("/floppy").expect("Failed to create the mount point");
+    fs::mount("/floppy", fat).expect("Failed to mount");
+
+    assert_eq!(fs::read("/floppy/HELLO.TXT"), Ok(b"Hello from a floppy!\n".to_vec()));
+    assert!(fs::read("/floppy/BIG.BIN") == Ok(pattern(100000)));
+
+    fs::write("/floppy/NEW.TXT", b"Written to a floppy").expect("Failed to write");
+    fs::sync().expect("Failed to sync");
+    assert_eq!(fs::read("/floppy/NEW.TXT"), Ok(b"Written to a floppy".to_vec()));
+    fs::unmount("/floppy").expect("Failed to unmount");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_motor() {
+    serial_print!("test_motor... ");
+    let drive = floppy::drive().expect("No floppy drive");
+    let mut sector = [0; SECTOR_SIZE];
+    drive.read_blocks(0, &mut sector).expect("Failed to read");
+    assert!(drive.motor_on());
+
+    // Turned off a few seconds after the last transfer
+    task::sleep_ms(5000);
+    assert!(!drive.motor_on());
+    drive.read_blocks(1, &mut sector).expect("Failed to read");
+    assert!(sector[..] == IMAGE[SECTOR_SIZE..2 * SECTOR_SIZE]);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::address_space::{AddressSpace, COPY_ON_WRITE};
+use ham_dos::process;
+use ham_dos::task;
+use ham_dos::{address_space, allocator, memory, programs, serial_print, serial_println, user};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_fork_copies_on_write() {
+    serial_print!("test_fork_copies_on_write... ");
+    let addr = VirtAddr::new(user::USER_CODE_START);
+    let page = Page::containing_address(addr);
+    let parent = AddressSpace::new().expect("Failed to create an address space");
+    let frame = parent
+        .map_new_page(page, PageTableFlags::WRITABLE)
+        .expect("Failed to map");
+    parent.write_bytes(addr, &[1]).expect("Failed to write");
+
+    let child = parent.fork().expect("Failed to fork");
+    assert_eq!(memory::frame_ref_count(frame), 2);
+    let (_, flags) = parent.translate(addr).expect("Parent lost the page");
+    assert!(flags.contains(COPY_ON_WRITE));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+
+    assert_eq!(child.resolve_copy_on_write(page), Ok(true));
+    let (copy, flags) = child.translate(addr).expect("Child lost the page");
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    assert_ne!(copy, frame.start_address());
+    assert_eq!(memory::frame_ref_count(frame), 1);
+
+    let mut byte = [0];
+    child.read_bytes(addr, &mut byte).expect("Failed to read");
+    assert_eq!(byte, [1]);
+
+    // The last user doesn't need to copy
+    assert_eq!(parent.resolve_copy_on_write(page), Ok(true));
+    assert_eq!(parent.translate(addr).map(|(phys, _)| phys), Some(frame.start_address()));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_forked_child_does_not_change_parent() {
+    serial_print!("test_forked_child_does_not_change_parent... ");
+    let pid = process::spawn("fork_test", programs::FORK_TEST, &["fork_test"], &[])
+        .expect("Failed to spawn");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 0)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_exec_in_forked_child() {
+    serial_print!("test_exec_in_forked_child... ");
+    let pid = process::spawn("exec_test", programs::EXEC_TEST, &["exec_test"], &[])
+        .expect("Failed to spawn");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 0)));
+    serial_println!("[ok]");
+}
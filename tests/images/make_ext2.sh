@@ -0,0 +1,59 @@
+#!/bin/sh
+# Builds the ext2 images tests/ext2.rs reads, needs mke2fs from e2fsprogs
+# 1.43 or later and python3. The images are committed, run this again after
+# changing what's on them.
+set -e
+cd "$(dirname "$0")"
+
+tree=$(mktemp -d)
+trap 'rm -rf "$tree"' EXIT
+
+# Same bytes as `pattern` in the tests
+pattern() {
+    python3 -c "import sys; sys.stdout.buffer.write(bytes((i * 7 + i // 251) % 256 for i in range($2)))" > "$1"
+}
+
+# One image, $1 is the block size
+build() {
+    rm -rf "$tree/root"
+    mkdir -p "$tree/root/docs/nested/deep"
+    cd "$tree/root"
+
+    printf 'Hello from ext2!\n' > hello.txt
+    printf 'Read me\n' > docs/readme.txt
+    printf 'Deep down\n' > docs/nested/deep/file.txt
+    : > empty
+    # Direct, indirect and, with 1 KiB blocks, doubly indirect blocks
+    pattern big.bin 307200
+    ln -s hello.txt hello-link
+    ln -s docs/nested nested
+    # Too long to be stored in the inode
+    ln -s docs/./nested/./deep/../deep/./././././././././././././file.txt far-link
+
+    if [ "$1" = 1024 ]; then
+        # Only the last block is stored, it's behind a triply indirect block
+        python3 -c "
+f = open('sparse.bin', 'wb')
+f.seek(70 * 1024 * 1024)
+f.write(b'far away\n')"
+        # More entries than fit in one directory block
+        mkdir many
+        for i in $(seq -w 0 99); do
+            printf 'file-%s\n' "$i" > "many/file-$i"
+        done
+        touch "long-$(printf '%0250d' 0)"
+        ln -s loop loop
+        ln -s missing broken
+    fi
+
+    find . -exec touch -h -d @1600000000 {} +
+    cd - > /dev/null
+
+    rm -f "ext2-$1.img"
+    E2FSPROGS_FAKE_TIME=1600000000 mke2fs -q -t ext2 -b "$1" -L "hamdos-$1" \
+        -U 6d1f2d2e-5b44-4f43-9a3e-00000000"$1" -E hash_seed=0b2d6a31-0c47-4e0a-8f1b-2f4c1e9d7a55 \
+        -d "$tree/root" "ext2-$1.img" 1M
+}
+
+build 1024
+build 4096
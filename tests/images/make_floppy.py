@@ -0,0 +1,82 @@
+#!/usr/bin/env python3
+# Builds the 1.44 MB FAT12 floppy image tests/floppy.rs reads. The image is
+# committed, run this again after changing what's on it.
+import os
+import struct
+
+SECTORS = 2880
+SECTOR_SIZE = 512
+FAT_SECTORS = 9
+ROOT_ENTRIES = 224
+FIRST_FAT = 1
+ROOT = FIRST_FAT + 2 * FAT_SECTORS
+DATA = ROOT + ROOT_ENTRIES * 32 // SECTOR_SIZE
+
+# 2020-09-13 12:26:40, like the ext2 images
+DATE = ((2020 - 1980) << 9) | (9 << 5) | 13
+TIME = (12 << 11) | (26 << 5) | (40 // 2)
+
+
+def pattern(length):
+    """Same bytes as `pattern` in the tests"""
+    return bytes((i * 7 + i // 251) % 256 for i in range(length))
+
+
+FILES = [
+    (b"HELLO   TXT", b"Hello from a floppy!\n"),
+    (b"BIG     BIN", pattern(100000)),
+]
+
+image = bytearray(SECTORS * SECTOR_SIZE)
+
+# Not bootable: the code asks the BIOS for the next boot device
+boot = struct.pack(
+    "<3s8sHBHBHHBHHHIIBBBI11s8s",
+    b"\xEB\x3C\x90", b"HAMDOS  ", SECTOR_SIZE, 1, FIRST_FAT, 2, ROOT_ENTRIES,
+    SECTORS, 0xF0, FAT_SECTORS, 18, 2, 0, 0, 0, 0, 0x29, 0x1440_2020,
+    b"HAMDOS     ", b"FAT12   ",
+)
+image[:len(boot)] = boot
+image[0x3E:0x42] = b"\xCD\x18\xEB\xFE"
+image[510:512] = b"\x55\xAA"
+
+fat = bytearray(FAT_SECTORS * SECTOR_SIZE)
+
+
+def set_entry(cluster, value):
+    offset = cluster * 3 // 2
+    if cluster % 2 == 0:
+        fat[offset] = value & 0xFF
+        fat[offset + 1] = (fat[offset + 1] & 0xF0) | (value >> 8)
+    else:
+        fat[offset] = (fat[offset] & 0x0F) | ((value << 4) & 0xF0)
+        fat[offset + 1] = value >> 4
+
+
+set_entry(0, 0xFF0)
+set_entry(1, 0xFFF)
+
+cluster = 2
+for index, (name, data) in enumerate(FILES):
+    clusters = (len(data) + SECTOR_SIZE - 1) // SECTOR_SIZE
+    first = cluster
+    for n in range(clusters):
+        set_entry(cluster, cluster + 1 if n + 1 < clusters else 0xFFF)
+        start = (DATA + cluster - 2) * SECTOR_SIZE
+        chunk = data[n * SECTOR_SIZE:(n + 1) * SECTOR_SIZE]
+        image[start:start + len(chunk)] = chunk
+        cluster += 1
+
+    entry = struct.pack(
+        "<11sBBBHHHHHHHI", name, 0x20, 0, 0, TIME, DATE, DATE, 0, TIME, DATE, first, len(data)
+    )
+    offset = ROOT * SECTOR_SIZE + index * 32
+    image[offset:offset + 32] = entry
+
+for copy in range(2):
+    start = (FIRST_FAT + copy * FAT_SECTORS) * SECTOR_SIZE
+    image[start:start + len(fat)] = fat
+
+path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "floppy.img")
+with open(path, "wb") as file:
+    file.write(image)
@@ -0,0 +1,159 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::fs::initrd::{self, EntryKind, InitrdError, ARCHIVE};
+use ham_dos::fs::{self, FileType};
+use ham_dos::{address_space, allocator, memory, process, programs, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    fs::init();
+    initrd::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+/// Appends a USTAR entry like `build.rs` does
+fn append_entry(archive: &mut Vec<u8>, name: &str, type_flag: u8, data: &[u8]) {
+    let octal = |field: &mut [u8], mut value: usize| {
+        let digits = field.len() - 1;
+        for index in (0..digits).rev() {
+            field[index] = b'0' + (value % 8) as u8;
+            value /= 8;
+        }
+    };
+
+    let mut header = [0; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[124..136], data.len());
+    header[156] = type_flag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[148..156].copy_from_slice(b"        ");
+    let checksum = header.iter().map(|&byte| byte as usize).sum();
+    octal(&mut header[148..155], checksum);
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize((archive.len() + 511) / 512 * 512, 0);
+}
+
+#[test_case]
+fn test_archive_entries() {
+    serial_print!("test_archive_entries... ");
+    let entries: Vec<_> = initrd::entries(ARCHIVE)
+        .collect::<Result<_, _>>()
+        .expect("Invalid archive");
+    let find = |path| entries.iter().find(|entry| entry.path == path);
+
+    let bin = find("bin/").expect("No bin directory");
+    assert_eq!(bin.kind, EntryKind::Directory);
+    let hello = find("bin/hello").expect("No hello program");
+    assert_eq!(hello.kind, EntryKind::File);
+    assert_eq!(hello.data, programs::HELLO);
+    assert_eq!(
+        find("etc/motd").map(|entry| entry.data),
+        Some(&include_bytes!("../initrd/etc/motd")[..])
+    );
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_unpacked_at_boot() {
+    serial_print!("test_unpacked_at_boot... ");
+    assert_eq!(
+        fs::metadata("/etc").map(|metadata| metadata.file_type),
+        Ok(FileType::Directory)
+    );
+    assert_eq!(
+        fs::read("/etc/hostname").as_ref().map(|data| &data[..]),
+        Ok(&include_bytes!("../initrd/etc/hostname")[..])
+    );
+    assert_eq!(
+        fs::read("/bin/signal_test").as_ref().map(|data| &data[..]),
+        Ok(programs::SIGNAL_TEST)
+    );
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_broken_archives() {
+    serial_print!("test_broken_archives... ");
+    let mut archive = Vec::new();
+    append_entry(&mut archive, "file", b'0', b"contents");
+    assert_eq!(initrd::entries(&archive).count(), 1);
+
+    // Without the end of the data
+    assert_eq!(
+        initrd::entries(&archive[..515]).next().map(|entry| entry.err()),
+        Some(Some(InitrdError::Truncated))
+    );
+    archive[0] = b'F';
+    assert_eq!(
+        initrd::entries(&archive).next().map(|entry| entry.err()),
+        Some(Some(InitrdError::BadChecksum))
+    );
+    archive[257] = 0;
+    assert_eq!(
+        initrd::unpack(&archive, "/"),
+        Err(InitrdError::BadHeader)
+    );
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_unpack_stays_in_the_target() {
+    serial_print!("test_unpack_stays_in_the_target... ");
+    let mut archive = Vec::new();
+    append_entry(&mut archive, "/absolute/file", b'0', b"inside");
+    append_entry(&mut archive, "link", b'2', b"");
+    fs::create_dir("/target").expect("Failed to create");
+    assert_eq!(initrd::unpack(&archive, "/target"), Ok(1));
+    assert_eq!(
+        fs::read("/target/absolute/file").as_ref().map(|data| &data[..]),
+        Ok(&b"inside"[..])
+    );
+
+    let mut escaping = Vec::new();
+    append_entry(&mut escaping, "../escaped", b'0', b"outside");
+    assert_eq!(initrd::unpack(&escaping, "/target"), Err(InitrdError::BadHeader));
+    assert!(fs::metadata("/escaped").is_err());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_exec_from_the_initrd() {
+    serial_print!("test_exec_from_the_initrd... ");
+    let pid = process::spawn(
+        "exec_test",
+        programs::EXEC_TEST,
+        &["exec_test", "/bin/hello"],
+        &[],
+    )
+    .expect("Failed to spawn");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 0)));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,160 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::channel::{Channel, ChannelError, MAX_MESSAGE_SIZE};
+use ham_dos::file::File;
+use ham_dos::pipe::{self, PIPE_CAPACITY};
+use ham_dos::syscall::SyscallError;
+use ham_dos::{address_space, allocator, memory, process, programs, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_pipe_end_of_file() {
+    serial_print!("test_pipe_end_of_file... ");
+    let (reader, writer) = pipe::pipe();
+    assert_eq!(writer.write(b"hello"), Ok(5));
+    drop(writer);
+
+    let mut buffer = [0; 16];
+    assert_eq!(reader.read(&mut buffer), Ok(5));
+    assert_eq!(&buffer[..5], b"hello");
+    assert_eq!(reader.read(&mut buffer), Ok(0));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_pipe_broken() {
+    serial_print!("test_pipe_broken... ");
+    let (reader, writer) = pipe::pipe();
+    drop(reader);
+    assert_eq!(writer.write(b"lost"), Err(SyscallError::BrokenPipe));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_pipe_blocks_writer_when_full() {
+    serial_print!("test_pipe_blocks_writer_when_full... ");
+    let (reader, writer) = pipe::pipe();
+    // More than fits, the writer has to wait for the reader
+    const SIZE: usize = PIPE_CAPACITY * 3 + 100;
+
+    let thread = task::spawn("pipe writer", move || {
+        let data: Vec<u8> = (0..SIZE).map(|index| index as u8).collect();
+        assert_eq!(writer.write(&data), Ok(SIZE));
+    });
+
+    let mut received = Vec::new();
+    let mut buffer = [0; 512];
+    loop {
+        let count = reader.read(&mut buffer).expect("Failed to read");
+        if count == 0 {
+            break;
+        }
+        received.extend_from_slice(&buffer[..count]);
+    }
+    task::join(thread);
+
+    assert_eq!(received.len(), SIZE);
+    assert!(received
+        .iter()
+        .enumerate()
+        .all(|(index, &byte)| byte == index as u8));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_channel_keeps_messages_apart() {
+    serial_print!("test_channel_keeps_messages_apart... ");
+    let channel = Channel::new(4);
+    channel.send(b"first").expect("Failed to send");
+    channel.send(b"second").expect("Failed to send");
+    assert_eq!(channel.len(), 2);
+
+    let mut small = [0; 3];
+    assert_eq!(channel.read(&mut small), Err(SyscallError::MessageTooLong));
+    assert_eq!(channel.receive(), Ok(Vec::from(&b"first"[..])));
+    assert_eq!(channel.receive(), Ok(Vec::from(&b"second"[..])));
+    assert_eq!(channel.try_receive(), Err(ChannelError::Empty));
+
+    let too_long = vec![0; MAX_MESSAGE_SIZE + 1];
+    assert_eq!(channel.try_send(&too_long), Err(ChannelError::MessageTooLong));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_channel_is_bounded() {
+    serial_print!("test_channel_is_bounded... ");
+    let channel = Channel::new(2);
+    assert_eq!(channel.try_send(&[1]), Ok(()));
+    assert_eq!(channel.try_send(&[2]), Ok(()));
+    assert_eq!(channel.try_send(&[3]), Err(ChannelError::Full));
+
+    // Blocks until the main thread makes room
+    let sender = channel.clone();
+    let thread = task::spawn("channel sender", move || {
+        for message in 3..10u8 {
+            sender.send(&[message]).expect("Failed to send");
+        }
+    });
+
+    for expected in 1..10u8 {
+        let message = channel.receive().expect("Failed to receive");
+        assert_eq!(message, vec![expected]);
+    }
+    task::join(thread);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_channel_handles() {
+    serial_print!("test_channel_handles... ");
+    let channel = Channel::new(1);
+    let id = channel.id();
+    let opened = Channel::open(id).expect("Failed to open the channel");
+    assert!(Arc::ptr_eq(&channel, &opened));
+
+    drop(channel);
+    drop(opened);
+    assert!(Channel::open(id).is_none());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_pipe_and_channel_from_user_mode() {
+    serial_print!("test_pipe_and_channel_from_user_mode... ");
+    let pid = process::spawn("pipe_test", programs::PIPE_TEST, &["pipe_test"], &[])
+        .expect("Failed to spawn");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 0)));
+    serial_println!("[ok]");
+}
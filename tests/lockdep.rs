@@ -0,0 +1,71 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use ham_dos::interrupts::InterruptContext;
+use ham_dos::sync::{lockdep, IrqSpinLock, SpinLock};
+use ham_dos::{lock_class, serial_print, serial_println};
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info);
+}
+
+#[test_case]
+fn test_order_inversion_is_reported() {
+    serial_print!("test_order_inversion_is_reported... ");
+    static FIRST: SpinLock<()> = SpinLock::new((), lock_class!("FIRST"));
+    static SECOND: SpinLock<()> = SpinLock::new((), lock_class!("SECOND"));
+    let reports = lockdep::reports();
+
+    {
+        let _first = FIRST.lock();
+        let _second = SECOND.lock();
+    }
+    assert_eq!(lockdep::reports(), reports);
+
+    // Never deadlocks here, but would if two threads did both at once
+    {
+        let _second = SECOND.lock();
+        let _first = FIRST.lock();
+    }
+    assert_eq!(lockdep::reports(), reports + 1);
+
+    // Only reported once
+    {
+        let _second = SECOND.lock();
+        let _first = FIRST.lock();
+    }
+    assert_eq!(lockdep::reports(), reports + 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_irq_unsafe_lock_in_interrupt_context() {
+    serial_print!("test_irq_unsafe_lock_in_interrupt_context... ");
+    static PLAIN: SpinLock<u32> = SpinLock::new(0, lock_class!("PLAIN"));
+    static IRQ_SAFE: IrqSpinLock<u32> = IrqSpinLock::with_class(0, lock_class!("IRQ_SAFE"));
+    let reports = lockdep::reports();
+
+    *PLAIN.lock() += 1;
+    *IRQ_SAFE.lock() += 1;
+    assert_eq!(lockdep::reports(), reports);
+
+    {
+        let _context = InterruptContext::enter();
+        *IRQ_SAFE.lock() += 1;
+        assert_eq!(lockdep::reports(), reports);
+        *PLAIN.lock() += 1;
+    }
+    assert_eq!(lockdep::reports(), reports + 1);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,182 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![feature(asm)]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use ham_dos::block::ahci;
+use ham_dos::block::{BlockDevice, SECTOR_SIZE};
+use ham_dos::fs::{self, devfs};
+use ham_dos::interrupts::{self, DYNAMIC_IRQS, LEGACY_IRQS};
+use ham_dos::pci::msi::{self, Mode, MsiError};
+use ham_dos::pci::{self, Device};
+use ham_dos::{address_space, allocator, apic, memory, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    apic::init();
+    task::init();
+    fs::init();
+    devfs::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+// Added by the test arguments in Cargo.toml, it only has MSI-X
+const VIRTIO_RNG: (u16, u16) = (0x1AF4, 0x1005);
+// Only has MSI
+const AHCI: (u16, u16) = (0x8086, 0x2922);
+
+fn device(id: (u16, u16)) -> Device {
+    pci::devices()
+        .into_iter()
+        .find(|device| (device.vendor_id, device.device_id) == id)
+        .expect("Missing device")
+}
+
+/// How often `vector` was raised
+fn raised(vector: u8) -> u64 {
+    interrupts::counts()
+        .into_iter()
+        .find(|&(raised, _)| raised == vector)
+        .map_or(0, |(_, count)| count)
+}
+
+static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+fn count_interrupt() {
+    HANDLED.fetch_add(1, Ordering::SeqCst);
+}
+
+fn other_interrupt() {}
+
+#[test_case]
+fn test_apic() {
+    serial_print!("test_apic... ");
+    assert!(apic::is_enabled());
+    // The only CPU
+    assert_eq!(apic::id(), 0);
+    // Legacy interrupts still come through the PICs
+    let ticks = ham_dos::time::ticks();
+    task::sleep_ms(50);
+    assert!(ham_dos::time::ticks() > ticks);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_irqs() {
+    serial_print!("test_irqs... ");
+    let irq = interrupts::allocate_irq().expect("No IRQ");
+    assert_eq!(irq, LEGACY_IRQS);
+    let vector = interrupts::irq_vector(irq);
+    assert_eq!(vector, 0x50);
+    assert_eq!(interrupts::vector_name(vector), "MSI");
+    assert!(interrupts::register_irq_handler(irq, count_interrupt));
+
+    unsafe { asm!("int $$0x50" :::: "volatile") };
+    assert_eq!(HANDLED.load(Ordering::SeqCst), 1);
+    assert_eq!(raised(vector), 1);
+
+    // All the others, then there are none left
+    let others: Vec<u8> = (1..DYNAMIC_IRQS)
+        .map(|_| interrupts::allocate_irq().expect("No IRQ"))
+        .collect();
+    assert_eq!(interrupts::allocate_irq(), None);
+    interrupts::free_irq(irq);
+    // Freed IRQs take no handlers and drop the ones they had
+    assert!(!interrupts::register_irq_handler(irq, other_interrupt));
+    assert_eq!(interrupts::allocate_irq(), Some(irq));
+    unsafe { asm!("int $$0x50" :::: "volatile") };
+    assert_eq!(HANDLED.load(Ordering::SeqCst), 1);
+    interrupts::free_irq(irq);
+    others.into_iter().for_each(interrupts::free_irq);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_msix() {
+    serial_print!("test_msix... ");
+    const ENTRY_DATA: usize = 0x8;
+    const ENTRY_CONTROL: usize = 0xC;
+
+    let rng = device(VIRTIO_RNG);
+    let address = rng.address;
+    assert_eq!(msi::capabilities(address), (false, true));
+    assert_eq!(msi::enable_msi(address, count_interrupt), Err(MsiError::NotSupported));
+
+    let irqs = msi::enable_msix(&rng, &[count_interrupt, other_interrupt])
+        .expect("Failed to enable MSI-X");
+    assert_eq!(irqs.len(), 2);
+    assert_eq!(msi::mode(address), Some((Mode::Msix, irqs.clone())));
+    assert_eq!(msi::enable(&rng, count_interrupt), Err(MsiError::AlreadyEnabled));
+
+    let capability = address.find_capability(0x11).expect("No MSI-X capability");
+    assert!(address.read_u16(capability + 2) & 1 << 15 != 0);
+    assert!(address.read_u16(pci::COMMAND) & pci::COMMAND_INTERRUPT_DISABLE != 0);
+
+    // The entries hold the vectors of the IRQs, unmasked
+    let table = address.read(capability + 4);
+    let base = rng.map_bar((table & 0x7) as usize).expect("Failed to map the table");
+    let entries = base.as_u64() as usize + (table & !0x7) as usize;
+    for (entry, &irq) in irqs.iter().enumerate() {
+        let entry = entries + entry * 16;
+        let data = unsafe { ptr::read_volatile((entry + ENTRY_DATA) as *const u32) };
+        let control = unsafe { ptr::read_volatile((entry + ENTRY_CONTROL) as *const u32) };
+        assert_eq!((data, control), (u32::from(interrupts::irq_vector(irq)), 0));
+    }
+
+    msi::disable(address);
+    assert_eq!(msi::mode(address), None);
+    assert!(address.read_u16(capability + 2) & 1 << 15 == 0);
+    assert!(address.read_u16(pci::COMMAND) & pci::COMMAND_INTERRUPT_DISABLE == 0);
+    // The IRQs are free again
+    assert_eq!(interrupts::allocate_irq(), Some(irqs[0]));
+    interrupts::free_irq(irqs[0]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_ahci_msi() {
+    serial_print!("test_ahci_msi... ");
+    let address = device(AHCI).address;
+    assert_eq!(msi::capabilities(address), (true, false));
+
+    ahci::init();
+    let vector = match msi::mode(address) {
+        Some((Mode::Msi, irqs)) => interrupts::irq_vector(irqs[0]),
+        other => panic!("The controller doesn't use MSI: {:?}", other),
+    };
+    let disk = ahci::disks().into_iter().next().expect("No disk");
+    assert!(disk.is_interrupt_driven());
+
+    // Each command interrupts on the vector
+    let before = raised(vector);
+    let mut buffer = [0; 4 * SECTOR_SIZE];
+    disk.read_blocks(0, &mut buffer).expect("Failed to read");
+    disk.read_blocks(8, &mut buffer).expect("Failed to read");
+    assert!(raised(vector) >= before + 2);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,256 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::block::partition::{self, PartitionType, TableError};
+use ham_dos::block::partition::{GPT_BASIC_DATA, GPT_LINUX_FILESYSTEM};
+use ham_dos::block::partition::{MBR_EXTENDED, MBR_FAT12, MBR_LINUX, MBR_PROTECTIVE};
+use ham_dos::block::ram::RamDisk;
+use ham_dos::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use ham_dos::fs::fat::{self, FatType};
+use ham_dos::fs::{self, devfs, FileType};
+use ham_dos::{address_space, allocator, memory, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    fs::init();
+    devfs::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+static IMAGE_1K: &[u8] = include_bytes!("images/ext2-1024.img");
+static IMAGE_4K: &[u8] = include_bytes!("images/ext2-4096.img");
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Sets the entry `index` of the MBR or EBR at `sector`
+fn set_mbr_entry(device: &dyn BlockDevice, sector: u64, index: usize, entry: (u8, u32, u32)) {
+    let mut mbr = [0; SECTOR_SIZE];
+    device.read_blocks(sector, &mut mbr).expect("Failed to read");
+    let at = 446 + index * 16;
+    mbr[at + 4] = entry.0;
+    write_u32(&mut mbr, at + 8, entry.1);
+    write_u32(&mut mbr, at + 12, entry.2);
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    device.write_blocks(sector, &mbr).expect("Failed to write");
+}
+
+/// Writes a GPT header at `header` with 128 entries at `entries`
+fn write_gpt(device: &dyn BlockDevice, header: u64, entries: u64, partitions: &[Vec<u8>]) {
+    let mut table = vec![0; 128 * 128];
+    for (index, partition) in partitions.iter().enumerate() {
+        table[index * 128..index * 128 + partition.len()].copy_from_slice(partition);
+    }
+    device.write_blocks(entries, &table).expect("Failed to write");
+
+    let total = device.block_count();
+    let mut sector = [0; SECTOR_SIZE];
+    sector[..8].copy_from_slice(b"EFI PART");
+    write_u32(&mut sector, 8, 0x0001_0000);
+    write_u32(&mut sector, 12, 92);
+    write_u64(&mut sector, 24, header);
+    write_u64(&mut sector, 32, if header == 1 { total - 1 } else { 1 });
+    write_u64(&mut sector, 40, 34);
+    write_u64(&mut sector, 48, total - 34);
+    write_u64(&mut sector, 72, entries);
+    write_u32(&mut sector, 80, 128);
+    write_u32(&mut sector, 84, 128);
+    write_u32(&mut sector, 88, partition::crc32(&table));
+    let checksum = partition::crc32(&sector[..92]);
+    write_u32(&mut sector, 16, checksum);
+    device.write_blocks(header, &sector).expect("Failed to write");
+}
+
+/// A GPT entry of `kind` from `first` to `last`
+fn gpt_entry(kind: [u8; 16], first: u64, last: u64, name: &str) -> Vec<u8> {
+    let mut entry = vec![0; 128];
+    entry[..16].copy_from_slice(&kind);
+    write_u64(&mut entry, 32, first);
+    write_u64(&mut entry, 40, last);
+    for (index, unit) in name.encode_utf16().enumerate() {
+        entry[56 + index * 2..58 + index * 2].copy_from_slice(&unit.to_le_bytes());
+    }
+    entry
+}
+
+fn write_image(device: &dyn BlockDevice, image: &[u8]) {
+    for (index, chunk) in image.chunks(32 * SECTOR_SIZE).enumerate() {
+        device.write_blocks(index as u64 * 32, chunk).expect("Failed to write");
+    }
+}
+
+#[test_case]
+fn test_crc32() {
+    serial_print!("test_crc32... ");
+    assert_eq!(partition::crc32(b""), 0);
+    assert_eq!(partition::crc32(b"123456789"), 0xCBF4_3926);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_mbr() {
+    serial_print!("test_mbr... ");
+    // A FAT12 primary partition and an ext2 logical one after an empty one
+    let ram = RamDisk::new(8192 * SECTOR_SIZE as u64);
+    set_mbr_entry(&*ram, 0, 0, (MBR_FAT12, 2048, 2880));
+    set_mbr_entry(&*ram, 0, 1, (MBR_EXTENDED, 5120, 3072));
+    set_mbr_entry(&*ram, 5120, 0, (0, 0, 0));
+    set_mbr_entry(&*ram, 5120, 1, (MBR_EXTENDED, 16, 2100));
+    set_mbr_entry(&*ram, 5136, 0, (MBR_LINUX, 1, 2048));
+
+    let disk = block::register("ram0", ram.clone()).expect("Failed to register");
+    let partitions = partition::scan("ram0", disk).expect("Failed to scan");
+    let names: Vec<&str> = partitions.iter().map(|partition| partition.name()).collect();
+    assert_eq!(names, ["ram0p1", "ram0p6"]);
+    let (fat_partition, ext2_partition) = (&partitions[0], &partitions[1]);
+    assert_eq!(fat_partition.kind(), PartitionType::Mbr(MBR_FAT12));
+    assert_eq!((fat_partition.start(), fat_partition.block_count()), (2048, 2880));
+    assert_eq!((ext2_partition.start(), ext2_partition.block_count()), (5137, 2048));
+
+    let metadata = fs::metadata("/dev/ram0p1").expect("Failed to get the metadata");
+    assert_eq!(metadata.file_type, FileType::BlockDevice);
+    assert_eq!(metadata.size, 2880 * SECTOR_SIZE as u64);
+
+    // Partitions can't reach past their end
+    let mut sectors = [0; 2 * SECTOR_SIZE];
+    assert_eq!(fat_partition.read_blocks(2879, &mut sectors), Err(BlockError::OutOfRange));
+    ext2_partition.write_blocks(0, &[0xEE; SECTOR_SIZE]).expect("Failed to write");
+    let mut sector = [0; SECTOR_SIZE];
+    ram.read_blocks(5137, &mut sector).expect("Failed to read");
+    assert!(sector.iter().all(|&byte| byte == 0));
+    block::sync_all().expect("Failed to sync");
+    ram.read_blocks(5137, &mut sector).expect("Failed to read");
+    assert!(sector.iter().all(|&byte| byte == 0xEE));
+
+    // Both filesystems are mounted by type
+    fat::format(&**fat_partition, FatType::Fat12).expect("Failed to format");
+    write_image(&**ext2_partition, IMAGE_1K);
+    let path = partition::mount(fat_partition).expect("Failed to mount");
+    assert_eq!(path, Some(String::from("/mnt/ram0p1")));
+    fs::write("/mnt/ram0p1/file.txt", b"On a partition").expect("Failed to write");
+    assert_eq!(fs::read("/mnt/ram0p1/file.txt"), Ok(b"On a partition".to_vec()));
+    let path = partition::mount(ext2_partition).expect("Failed to mount");
+    assert_eq!(path, Some(String::from("/mnt/ram0p6")));
+    assert_eq!(fs::read("/mnt/ram0p6/hello.txt"), Ok(b"Hello from ext2!\n".to_vec()));
+    fs::unmount("/mnt/ram0p1").expect("Failed to unmount");
+    fs::unmount("/mnt/ram0p6").expect("Failed to unmount");
+
+    // Scanning again registers nothing new
+    let disk = devfs::block_device("ram0").expect("Failed to find the disk");
+    assert!(partition::scan("ram0", disk).expect("Failed to scan").is_empty());
+    assert_eq!(partition::partitions().len(), 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_bad_mbr() {
+    serial_print!("test_bad_mbr... ");
+    let ram = RamDisk::new(4096 * SECTOR_SIZE as u64);
+    assert_eq!(partition::read_table(&*ram), Ok(Vec::new()));
+
+    // Past the end of the disk
+    set_mbr_entry(&*ram, 0, 0, (MBR_LINUX, 2048, 4096));
+    assert_eq!(partition::read_table(&*ram), Err(TableError::Invalid));
+
+    // Overlapping
+    set_mbr_entry(&*ram, 0, 0, (MBR_LINUX, 2048, 1024));
+    set_mbr_entry(&*ram, 0, 3, (MBR_FAT12, 3000, 100));
+    assert_eq!(partition::read_table(&*ram), Err(TableError::Invalid));
+
+    // A chain of logical partitions that loops
+    set_mbr_entry(&*ram, 0, 3, (MBR_EXTENDED, 3072, 1024));
+    set_mbr_entry(&*ram, 3072, 0, (MBR_LINUX, 1, 10));
+    set_mbr_entry(&*ram, 3072, 1, (MBR_EXTENDED, 0, 10));
+    assert_eq!(partition::read_table(&*ram), Err(TableError::Invalid));
+    set_mbr_entry(&*ram, 3072, 1, (0, 0, 0));
+    let entries = partition::read_table(&*ram).expect("Failed to read the table");
+    assert_eq!(entries.len(), 2);
+    assert_eq!((entries[1].number, entries[1].start), (5, 3073));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_gpt() {
+    serial_print!("test_gpt... ");
+    let ram = RamDisk::new(6144 * SECTOR_SIZE as u64);
+    let total = ram.block_count();
+    set_mbr_entry(&*ram, 0, 0, (MBR_PROTECTIVE, 1, total as u32 - 1));
+    let partitions = [
+        gpt_entry(GPT_LINUX_FILESYSTEM.0, 2048, 4095, "root"),
+        Vec::new(),
+        gpt_entry(GPT_BASIC_DATA.0, 4096, 4195, "data"),
+    ];
+    write_gpt(&*ram, 1, 2, &partitions);
+    write_gpt(&*ram, total - 1, total - 33, &partitions);
+
+    let entries = partition::read_table(&*ram).expect("Failed to read the table");
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].kind, PartitionType::Gpt(GPT_LINUX_FILESYSTEM));
+    assert_eq!((entries[0].number, entries[0].start, entries[0].block_count), (1, 2048, 2048));
+    assert_eq!(entries[0].label, "root");
+    assert_eq!((entries[1].number, entries[1].label.as_str()), (3, "data"));
+    assert_eq!(
+        alloc::format!("{}", GPT_LINUX_FILESYSTEM),
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+    );
+
+    // The backup is used when the primary is damaged
+    let mut sector = [0; SECTOR_SIZE];
+    ram.read_blocks(2, &mut sector).expect("Failed to read");
+    sector[40] ^= 1;
+    ram.write_blocks(2, &sector).expect("Failed to write");
+    assert_eq!(partition::read_table(&*ram).as_ref(), Ok(&entries));
+    ram.read_blocks(total - 1, &mut sector).expect("Failed to read");
+    sector[48] ^= 1;
+    ram.write_blocks(total - 1, &sector).expect("Failed to write");
+    assert_eq!(partition::read_table(&*ram), Err(TableError::BadChecksum));
+    write_gpt(&*ram, 1, 2, &partitions);
+
+    // ext2 is mounted by the type GUID
+    let disk: Arc<dyn BlockDevice> = ram.clone();
+    let partitions = partition::scan("gpt", disk).expect("Failed to scan");
+    assert_eq!(partitions[0].name(), "gpt1");
+    write_image(&*partitions[0], IMAGE_4K);
+    let path = partition::mount(&partitions[0]).expect("Failed to mount");
+    assert_eq!(path, Some(String::from("/mnt/gpt1")));
+    assert_eq!(fs::read("/mnt/gpt1/hello.txt"), Ok(b"Hello from ext2!\n".to_vec()));
+    fs::unmount("/mnt/gpt1").expect("Failed to unmount");
+
+    // Basic data partitions are expected to be FAT
+    assert!(partition::mount(&partitions[1]).is_err());
+    serial_println!("[ok]");
+}
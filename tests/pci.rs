@@ -0,0 +1,189 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use ham_dos::pci::{self, Address, Bar, Device, DeviceId, Driver};
+use ham_dos::{acpi, address_space, allocator, memory, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    pci::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+// Devices of QEMU's default machine, and the AHCI controller of the test
+// arguments in Cargo.toml
+const HOST_BRIDGE: (u16, u16) = (0x8086, 0x1237);
+const IDE: (u16, u16) = (0x8086, 0x7010);
+const VGA: (u16, u16) = (0x1234, 0x1111);
+const AHCI: (u16, u16) = (0x8086, 0x2922);
+
+fn device(id: (u16, u16)) -> Device {
+    pci::devices()
+        .into_iter()
+        .find(|device| (device.vendor_id, device.device_id) == id)
+        .expect("Missing device")
+}
+
+#[test_case]
+fn test_devices() {
+    serial_print!("test_devices... ");
+    let host = device(HOST_BRIDGE);
+    assert_eq!(host.address, Address::new(0, 0, 0));
+    assert_eq!(host.class, (0x06, 0x00, 0x00));
+    assert_eq!(host.class_name(), "host bridge");
+
+    let ide = device(IDE);
+    assert_eq!(ide.class_name(), "IDE controller");
+    assert_eq!(ide.bars[4].map(|bar| bar.size()), Some(16));
+    match ide.bars[4] {
+        Some(Bar::Io { .. }) => {}
+        other => panic!("Not an I/O BAR: {:?}", other),
+    }
+
+    let vga = device(VGA);
+    match vga.bars[0] {
+        Some(Bar::Memory {
+            size, prefetchable, ..
+        }) => assert_eq!((size, prefetchable), (16 << 20, true)),
+        other => panic!("Not a memory BAR: {:?}", other),
+    }
+
+    let ahci = device(AHCI);
+    assert_eq!(ahci.class_name(), "AHCI controller");
+    assert_eq!(pci::find_class((0x01, 0x06, 0x01)), [ahci.address]);
+    assert_eq!(ahci.bars[5].map(|bar| bar.size()), Some(0x1000));
+
+    // Sorted by address, the way the buses were scanned
+    let devices = pci::devices();
+    assert!(devices.windows(2).all(|pair| pair[0].address < pair[1].address));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_config_space() {
+    serial_print!("test_config_space... ");
+    let vga = device(VGA).address;
+    assert_eq!(vga.read(pci::VENDOR_ID), 0x1111_1234);
+    assert_eq!(vga.read_u16(pci::DEVICE_ID), 0x1111);
+    assert_eq!(vga.class(), (0x03, 0x00, 0x00));
+
+    // The interrupt line is only a note for drivers, any value sticks
+    let line = vga.interrupt_line();
+    vga.write_u16(pci::INTERRUPT_LINE, 0x010B);
+    assert_eq!(vga.read_u8(pci::INTERRUPT_LINE), 0x0B);
+    vga.write_u16(pci::INTERRUPT_LINE, 0x0100 | u16::from(line));
+    assert_eq!(vga.interrupt_line(), line);
+
+    // Past the end of what the ports reach, without ECAM
+    if pci::ecam_regions_in_use().is_empty() {
+        assert_eq!(vga.read(0x100), !0);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_acpi() {
+    serial_print!("test_acpi... ");
+    let tables = acpi::tables();
+    assert!(tables.contains(b"FACP"));
+    assert!(tables.contains(b"APIC"));
+    let fadt = acpi::find_table(b"FACP").expect("Missing FADT");
+    assert_eq!(&fadt[..4], b"FACP");
+    assert_eq!(acpi::read_u32(fadt, 4) as usize, fadt.len());
+    assert_eq!(acpi::find_table(b"NONE"), None);
+
+    // QEMU's default machine has no MCFG, its chipset has no ECAM
+    assert_eq!(acpi::find_table(b"MCFG").is_some(), !pci::ecam_regions_in_use().is_empty());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_map_bar() {
+    serial_print!("test_map_bar... ");
+    const HBA_VERSION: usize = 0x10;
+
+    let ahci = device(AHCI);
+    let registers = ahci.map_bar(5).expect("Failed to map the registers");
+    let version = registers.as_u64() as usize + HBA_VERSION;
+    let version = unsafe { ptr::read_volatile(version as *const u32) };
+    assert_eq!(version, 0x0001_0000);
+    // Mapping it again finds it mapped
+    assert_eq!(ahci.map_bar(5), Some(registers));
+    assert_eq!(ahci.map_bar(4), None);
+    serial_println!("[ok]");
+}
+
+static PROBED: AtomicUsize = AtomicUsize::new(0);
+
+fn take(_device: &Device) -> bool {
+    PROBED.fetch_add(1, Ordering::SeqCst);
+    true
+}
+
+fn refuse(_device: &Device) -> bool {
+    PROBED.fetch_add(1, Ordering::SeqCst);
+    false
+}
+
+static VGA_DRIVER: Driver = Driver {
+    name: "test-vga",
+    ids: &[DeviceId::Id(0x1234, 0x1111)],
+    probe: take,
+};
+
+static DISPLAY_DRIVER: Driver = Driver {
+    name: "test-display",
+    ids: &[DeviceId::Class(0x03, 0x00)],
+    probe: take,
+};
+
+static BRIDGE_DRIVER: Driver = Driver {
+    name: "test-bridge",
+    ids: &[DeviceId::Interface(0x06, 0x00, 0x00)],
+    probe: refuse,
+};
+
+#[test_case]
+fn test_drivers() {
+    serial_print!("test_drivers... ");
+    let vga = device(VGA);
+    assert!(DeviceId::Class(0x03, 0x00).matches(&vga));
+    assert!(!DeviceId::Id(0x1234, 0x1112).matches(&vga));
+
+    assert_eq!(pci::register_driver(&VGA_DRIVER), 1);
+    assert_eq!(pci::driver_of(vga.address), Some("test-vga"));
+    // Already taken, it's not even asked
+    assert_eq!(pci::register_driver(&DISPLAY_DRIVER), 0);
+    assert_eq!(PROBED.load(Ordering::SeqCst), 1);
+
+    // A device the driver turned down stays free
+    let host = device(HOST_BRIDGE);
+    assert_eq!(pci::register_driver(&BRIDGE_DRIVER), 0);
+    assert_eq!(PROBED.load(Ordering::SeqCst), 2);
+    assert_eq!(pci::driver_of(host.address), None);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::process::{self, ProcessState};
+use ham_dos::syscall::SyscallError;
+use ham_dos::task;
+use ham_dos::{address_space, allocator, memory, programs, serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_spawn_and_wait() {
+    serial_print!("test_spawn_and_wait... ");
+    let mut pids = Vec::new();
+    for argc in 1..4 {
+        let argv = ["hello", "a", "b"];
+        let pid = process::spawn("hello", programs::HELLO, &argv[..argc], &["HOME=/"])
+            .expect("Failed to spawn");
+        pids.push((pid, argc));
+    }
+
+    // Collect them in reverse order
+    for &(pid, argc) in pids.iter().rev() {
+        assert_eq!(process::wait(Some(pid)), Ok((pid, 40 + argc as i32)));
+    }
+    assert_eq!(process::wait(None), Err(SyscallError::NoChildren));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_zombies_until_reaped() {
+    serial_print!("test_zombies_until_reaped... ");
+    let pid = process::spawn("hello", programs::HELLO, &["hello"], &["HOME=/"])
+        .expect("Failed to spawn");
+
+    let state = |pid| {
+        process::process_list()
+            .into_iter()
+            .find(|process| process.pid == pid)
+            .map(|process| process.state)
+    };
+    while state(pid) == Some(ProcessState::Running) {
+        task::sleep_ms(10);
+    }
+
+    assert_eq!(state(pid), Some(ProcessState::Zombie(41)));
+    let zombie = process::process_list()
+        .into_iter()
+        .find(|process| process.pid == pid)
+        .expect("The zombie is missing");
+    assert_eq!(zombie.open_files, 0);
+    assert_eq!(zombie.parent, None);
+
+    assert_eq!(process::wait(None), Ok((pid, 41)));
+    assert_eq!(state(pid), None);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,202 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::fs::procfs;
+use ham_dos::fs::{self, FileType, FsError, O_RDONLY};
+use ham_dos::{address_space, allocator, interrupts, memory, task, time};
+use ham_dos::{println, serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    fs::init();
+    procfs::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+fn read(path: &str) -> String {
+    String::from_utf8(fs::read(path).expect("Failed to read")).expect("Not UTF-8")
+}
+
+/// The number after `name` in /proc/meminfo
+fn meminfo(name: &str) -> usize {
+    let text = read("/proc/meminfo");
+    let line = text
+        .lines()
+        .find(|line| line.starts_with(name))
+        .expect("Missing line");
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    assert_eq!(fields.len(), 3);
+    assert_eq!(fields[2], "kB");
+    fields[1].parse().expect("Not a number")
+}
+
+#[test_case]
+fn test_files() {
+    serial_print!("test_files... ");
+    let names: Vec<String> = fs::read_dir("/proc")
+        .expect("Failed to list")
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    let files = ["diskstats", "interrupts", "log", "meminfo", "memmap", "mounts", "ps2", "tasks"];
+    for name in files.iter() {
+        assert!(names.iter().any(|entry| entry == name));
+    }
+
+    let metadata = fs::metadata("/proc/mounts").expect("Failed to get the metadata");
+    assert_eq!(metadata.file_type, FileType::Regular);
+    assert_eq!(metadata.size, read("/proc/mounts").len() as u64);
+    assert!(read("/proc/mounts").lines().any(|line| line == "/proc procfs"));
+    assert_eq!(fs::write("/proc/uptime", b"0"), Err(FsError::ReadOnly));
+    assert_eq!(fs::write("/proc/new", b"0"), Err(FsError::ReadOnly));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_uptime() {
+    serial_print!("test_uptime... ");
+    // Opened before it changes, the file keeps what it had then
+    let file = fs::open("/proc/uptime", O_RDONLY).expect("Failed to open");
+    let parse = |text: &str| -> u64 {
+        let (seconds, ms) = text.trim_end().split_at(text.find('.').expect("No point"));
+        seconds.parse::<u64>().unwrap() * 1000 + ms[1..].parse::<u64>().unwrap()
+    };
+    let before = parse(&read("/proc/uptime"));
+    let start = time::ticks();
+    while time::ticks() < start + 3 {
+        x86_64::instructions::hlt();
+    }
+    let after = parse(&read("/proc/uptime"));
+    assert!(after > before);
+
+    let mut buffer = [0; 32];
+    let length = file.read(&mut buffer).expect("Failed to read");
+    assert!(parse(core::str::from_utf8(&buffer[..length]).unwrap()) <= before);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_interrupts() {
+    serial_print!("test_interrupts... ");
+    let text = read("/proc/interrupts");
+    let timer = text
+        .lines()
+        .find(|line| line.ends_with(" timer"))
+        .expect("No timer interrupts");
+    let fields: Vec<&str> = timer.split_whitespace().collect();
+    assert_eq!(fields[0], "32");
+    assert!(fields[1].parse::<u64>().unwrap() > 0);
+
+    // Counted as the breakpoint handler runs
+    let count = || {
+        interrupts::counts()
+            .into_iter()
+            .find(|&(vector, _)| vector == 3)
+            .map_or(0, |(_, count)| count)
+    };
+    let before = count();
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(count(), before + 1);
+    assert!(read("/proc/interrupts").contains(" breakpoint"));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_memory() {
+    serial_print!("test_memory... ");
+    let total = meminfo("MemTotal:");
+    let used = meminfo("MemUsed:");
+    assert!(used > 0 && used < total);
+    assert_eq!(meminfo("MemFree:"), total - used);
+    assert_eq!(meminfo("HeapTotal:"), allocator::HEAP_SIZE / 1024);
+
+    let frames: Vec<_> = (0..16)
+        .map(|_| memory::allocate_frame().expect("Out of frames"))
+        .collect();
+    assert_eq!(meminfo("MemUsed:"), used + 16 * 4);
+    for frame in frames {
+        memory::deallocate_frame(frame);
+    }
+    assert_eq!(meminfo("MemUsed:"), used);
+
+    // The usable regions add up to the total
+    let usable: u64 = read("/proc/memmap")
+        .lines()
+        .filter(|line| line.ends_with(" Usable"))
+        .map(|line| {
+            let range = line.split(' ').next().unwrap();
+            let (start, end) = range.split_at(range.find('-').unwrap());
+            let parse = |text: &str| u64::from_str_radix(&text[2..], 16).unwrap();
+            parse(&end[1..]) - parse(start)
+        })
+        .sum();
+    assert_eq!(usable / 1024, total as u64);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_tasks_and_devices() {
+    serial_print!("test_tasks_and_devices... ");
+    let tasks = read("/proc/tasks");
+    assert!(tasks.starts_with("scheduler: "));
+    assert!(tasks.lines().nth(1).unwrap().trim_start().starts_with("TID"));
+    assert!(read("/proc/processes").trim_start().starts_with("PID"));
+
+    let ps2 = read("/proc/ps2");
+    let ports: Vec<&str> = ps2.lines().collect();
+    assert_eq!(ports.len(), 2);
+    assert!(ports[0].starts_with("port 1: "));
+    assert!(ports[0].contains("Keyboard"));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_log() {
+    serial_print!("test_log... ");
+    println!("procfs log marker");
+    assert!(read("/proc/log").contains("procfs log marker\n"));
+    serial_println!("[ok]");
+}
+
+fn answer() -> String {
+    String::from("42\n")
+}
+
+#[test_case]
+fn test_register() {
+    serial_print!("test_register... ");
+    procfs::register("answer", answer).expect("Failed to register");
+    assert_eq!(procfs::register("answer", answer), Err(FsError::AlreadyExists));
+    assert_eq!(procfs::register("a/b", answer), Err(FsError::InvalidArgument));
+    assert_eq!(read("/proc/answer"), "42\n");
+
+    procfs::unregister("answer").expect("Failed to unregister");
+    assert_eq!(fs::read("/proc/answer").err(), Some(FsError::NotFound));
+    assert_eq!(procfs::unregister("answer"), Err(FsError::NotFound));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use ham_dos::task::cfs::FairScheduler;
+use ham_dos::task::{self, ThreadState};
+use ham_dos::{allocator, memory, serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    task::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+static COUNTERS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+#[test_case]
+fn test_threads_run_and_exit() {
+    serial_print!("test_threads_run_and_exit... ");
+    static DONE: AtomicU64 = AtomicU64::new(0);
+    for _ in 0..3 {
+        task::spawn("worker", || {
+            DONE.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    while DONE.load(Ordering::SeqCst) != 3 {
+        task::yield_now();
+    }
+
+    // Let the dead threads get reaped
+    task::yield_now();
+    let dead = task::task_list()
+        .iter()
+        .filter(|task| task.state == ThreadState::Dead)
+        .count();
+    assert!(dead <= 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_preemption_and_accounting() {
+    serial_print!("test_preemption_and_accounting... ");
+    task::set_scheduler(Box::new(FairScheduler::new()));
+
+    // Neither of them yields, only the timer interrupt can switch between them
+    for (index, nice) in [0i8, 5].iter().enumerate() {
+        task::spawn_with_nice("spinner", *nice, move || loop {
+            COUNTERS[index].fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    task::sleep_ms(500);
+    assert!(COUNTERS[0].load(Ordering::Relaxed) > 0);
+    assert!(COUNTERS[1].load(Ordering::Relaxed) > 0);
+
+    let spinners: alloc::vec::Vec<_> = task::task_list()
+        .into_iter()
+        .filter(|task| task.name == "spinner")
+        .collect();
+    assert_eq!(spinners.len(), 2);
+    // nice 0 weighs about 3 times more than nice 5
+    assert!(spinners[0].cpu_ms > spinners[1].cpu_ms);
+    task::print_task_list();
+    serial_println!("[ok]");
+}
@@ -0,0 +1,153 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::address_space::{AddressSpace, SHARED};
+use ham_dos::shm::{self, ShmError};
+use ham_dos::{address_space, allocator, memory, process, programs, task};
+use ham_dos::{serial_print, serial_println};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+fn map(space: &AddressSpace, object: &shm::SharedMemory) -> VirtAddr {
+    let start = space.reserve_region(object.size()).expect("No address space left");
+    object.map(space, start, true).expect("Failed to map");
+    start
+}
+
+#[test_case]
+fn test_writes_are_shared() {
+    serial_print!("test_writes_are_shared... ");
+    let object = shm::open_or_create("writes", 5000).expect("Failed to create");
+    assert_eq!(object.size(), 8192);
+
+    let first = AddressSpace::new().expect("Failed to create an address space");
+    let second = AddressSpace::new().expect("Failed to create an address space");
+    let (a, b) = (map(&first, &object), map(&second, &object));
+
+    first.write_bytes(a + 4094u64, b"shared").expect("Failed to write");
+    let mut buffer = [0; 6];
+    second.read_bytes(b + 4094u64, &mut buffer).expect("Failed to read");
+    assert_eq!(&buffer, b"shared");
+
+    shm::unlink("writes").expect("Failed to unlink");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_frames_are_freed_with_the_last_reference() {
+    serial_print!("test_frames_are_freed_with_the_last_reference... ");
+    let object = shm::open_or_create("refs", 4096).expect("Failed to create");
+    let frame = object.frames()[0];
+
+    let first = AddressSpace::new().expect("Failed to create an address space");
+    let second = AddressSpace::new().expect("Failed to create an address space");
+    let a = map(&first, &object);
+    map(&second, &object);
+    drop(object);
+    assert_eq!(memory::frame_ref_count(frame), 3);
+
+    // The address space goes away like the one of an exiting process
+    drop(second);
+    assert_eq!(memory::frame_ref_count(frame), 2);
+    shm::unlink("refs").expect("Failed to unlink");
+    assert_eq!(memory::frame_ref_count(frame), 1);
+    assert!(shm::open("refs").is_none());
+
+    shm::unmap(&first, a, 4096).expect("Failed to unmap");
+    assert!(first.translate(a).is_none());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_open_checks_the_size() {
+    serial_print!("test_open_checks_the_size... ");
+    assert_eq!(shm::open_or_create("size", 0).err(), Some(ShmError::InvalidSize));
+    assert_eq!(
+        shm::open_or_create("size", shm::MAX_SIZE + 1).err(),
+        Some(ShmError::InvalidSize)
+    );
+
+    let object = shm::open_or_create("size", 4096).expect("Failed to create");
+    let again = shm::open_or_create("size", 100).expect("Failed to open");
+    assert_eq!(object.frames(), again.frames());
+    assert_eq!(shm::open_or_create("size", 8192).err(), Some(ShmError::InvalidSize));
+
+    shm::unlink("size").expect("Failed to unlink");
+    assert_eq!(shm::unlink("size"), Err(ShmError::NotFound));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_fork_keeps_memory_shared() {
+    serial_print!("test_fork_keeps_memory_shared... ");
+    let object = shm::open_or_create("fork", 4096).expect("Failed to create");
+    let parent = AddressSpace::new().expect("Failed to create an address space");
+    let start = map(&parent, &object);
+
+    let child = parent.fork().expect("Failed to fork");
+    let (_, flags) = child.translate(start).expect("Not mapped in the child");
+    assert!(flags.contains(PageTableFlags::WRITABLE | SHARED));
+    assert_eq!(memory::frame_ref_count(object.frames()[0]), 3);
+
+    child.write_bytes(start, &[42]).expect("Failed to write");
+    let mut buffer = [0];
+    parent.read_bytes(start, &mut buffer).expect("Failed to read");
+    assert_eq!(buffer, [42]);
+
+    shm::unlink("fork").expect("Failed to unlink");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_unmap_only_takes_shared_memory() {
+    serial_print!("test_unmap_only_takes_shared_memory... ");
+    let space = AddressSpace::new().expect("Failed to create an address space");
+    let start = space.reserve_region(4096).expect("No address space left");
+    let page: Page = Page::containing_address(start);
+    space
+        .map_new_page(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        .expect("Failed to map");
+
+    assert_eq!(shm::unmap(&space, start, 4096), Err(ShmError::NotMapped));
+    assert_eq!(shm::unmap(&space, start + 1u64, 4096), Err(ShmError::InvalidSize));
+    assert!(space.translate(start).is_some());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_shared_memory_from_user_mode() {
+    serial_print!("test_shared_memory_from_user_mode... ");
+    let pid = process::spawn("shm_test", programs::SHM_TEST, &["shm_test"], &[])
+        .expect("Failed to spawn");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 0)));
+    assert!(shm::open("counter").is_none());
+    serial_println!("[ok]");
+}
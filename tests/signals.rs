@@ -0,0 +1,136 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::signal::{self, SignalAction, SignalState};
+use ham_dos::syscall::SyscallError;
+use ham_dos::{address_space, allocator, console, memory, process, programs, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+/// Exit code of a process the signal terminated
+fn killed_by(signal: signal::Signal) -> i32 {
+    128 + signal as i32
+}
+
+#[test_case]
+fn test_signal_state() {
+    serial_print!("test_signal_state... ");
+    let mut state = SignalState::new();
+    let handler = SignalAction::Handler {
+        handler: 0x2000_0040_0000,
+        restorer: 0x2000_0040_0010,
+    };
+
+    state.set_blocked(1 << signal::SIGUSR1 | 1 << signal::SIGKILL);
+    assert_eq!(state.blocked(), 1 << signal::SIGUSR1);
+    state.raise(signal::SIGUSR1);
+    assert!(!state.has_deliverable());
+    assert_eq!(state.take(), None);
+
+    state.raise(signal::SIGTERM);
+    assert_eq!(state.take(), Some((signal::SIGTERM, SignalAction::Default)));
+
+    assert_eq!(
+        state.set_action(signal::SIGKILL, SignalAction::Ignore),
+        Err(SyscallError::InvalidArgument)
+    );
+    assert_eq!(
+        state.set_action(signal::SIGINT, handler),
+        Ok(SignalAction::Default)
+    );
+
+    // Ignored by default
+    state.raise(signal::SIGCHLD);
+    assert!(!state.has_deliverable());
+
+    state.force(signal::SIGUSR1);
+    assert!(state.has_deliverable());
+
+    let child = state.fork();
+    assert_eq!(child.pending(), 0);
+    assert_eq!(child.action(signal::SIGINT), handler);
+
+    state.exec();
+    assert_eq!(state.action(signal::SIGINT), SignalAction::Default);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_signal_handlers() {
+    serial_print!("test_signal_handlers... ");
+    let pid = process::spawn("signal_test", programs::SIGNAL_TEST, &["signal_test"], &[])
+        .expect("Failed to spawn");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 0)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_divide_error_raises_sigfpe() {
+    serial_print!("test_divide_error_raises_sigfpe... ");
+    let pid = process::spawn("divide_error", programs::DIVIDE_ERROR, &["divide_error"], &[])
+        .expect("Failed to spawn");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, killed_by(signal::SIGFPE))));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_signal_terminates_running_program() {
+    serial_print!("test_signal_terminates_running_program... ");
+    let pid = process::spawn("spin", programs::SPIN, &["spin"], &[]).expect("Failed to spawn");
+    task::sleep_ms(50);
+    assert_eq!(process::send_signal(pid, signal::SIGTERM), Ok(()));
+    assert_eq!(process::wait(Some(pid)), Ok((pid, killed_by(signal::SIGTERM))));
+    assert_eq!(
+        process::send_signal(pid, signal::SIGTERM),
+        Err(SyscallError::NoSuchProcess)
+    );
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_signal_interrupts_blocking_read() {
+    serial_print!("test_signal_interrupts_blocking_read... ");
+    let pid = process::spawn("spin", programs::SPIN, &["spin", "read"], &[])
+        .expect("Failed to spawn");
+    task::sleep_ms(50);
+    assert_eq!(process::send_signal(pid, signal::SIGKILL), Ok(()));
+    assert_eq!(process::wait(Some(pid)), Ok((pid, killed_by(signal::SIGKILL))));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_ctrl_c_interrupts_foreground_process() {
+    serial_print!("test_ctrl_c_interrupts_foreground_process... ");
+    let pid = process::spawn("spin", programs::SPIN, &["spin"], &[]).expect("Failed to spawn");
+    console::set_foreground(Some(pid));
+    task::sleep_ms(20);
+    console::interrupt();
+    assert_eq!(process::wait(Some(pid)), Ok((pid, killed_by(signal::SIGINT))));
+    console::set_foreground(None);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,152 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use ham_dos::sync::{Condvar, Mutex, RwLock, Semaphore, WaitQueue};
+use ham_dos::{allocator, memory, serial_print, serial_println, task, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    task::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_mutex_contention() {
+    serial_print!("test_mutex_contention... ");
+    const THREADS: usize = 4;
+    const ROUNDS: usize = 200;
+
+    let counter = Arc::new(Mutex::new(0usize));
+    let done = Arc::new(Semaphore::new(0));
+    for _ in 0..THREADS {
+        let counter = counter.clone();
+        let done = done.clone();
+        task::spawn("incrementer", move || {
+            for _ in 0..ROUNDS {
+                let mut value = counter.lock();
+                let old = *value;
+                // Give the others a chance to run while the lock is held
+                task::yield_now();
+                *value = old + 1;
+            }
+            done.release();
+        });
+    }
+
+    for _ in 0..THREADS {
+        done.acquire();
+    }
+    assert_eq!(*counter.lock(), THREADS * ROUNDS);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_condvar_producer_consumer() {
+    serial_print!("test_condvar_producer_consumer... ");
+    let queue = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
+    let sum = Arc::new(AtomicUsize::new(0));
+    let finished = Arc::new(Semaphore::new(0));
+
+    {
+        let queue = queue.clone();
+        let sum = sum.clone();
+        let finished = finished.clone();
+        task::spawn("consumer", move || {
+            let (items, available) = &*queue;
+            loop {
+                let mut items = available.wait_while(items.lock(), |items| items.is_empty());
+                match items.pop_front() {
+                    Some(0) => break,
+                    Some(item) => sum.fetch_add(item, Ordering::SeqCst),
+                    None => unreachable!(),
+                };
+            }
+            finished.release();
+        });
+    }
+
+    let (items, available) = &*queue;
+    for item in (1..=100).chain(core::iter::once(0)) {
+        items.lock().push_back(item);
+        available.notify_one();
+        if item % 10 == 0 {
+            task::sleep_ms(10);
+        }
+    }
+
+    finished.acquire();
+    assert_eq!(sum.load(Ordering::SeqCst), 5050);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_rwlock_readers_share() {
+    serial_print!("test_rwlock_readers_share... ");
+    let lock = RwLock::new(5);
+    {
+        let first = lock.read();
+        let second = lock.read();
+        assert_eq!(*first + *second, 10);
+    }
+
+    *lock.write() += 1;
+    assert_eq!(*lock.read(), 6);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_wait_queue_deadline() {
+    serial_print!("test_wait_queue_deadline... ");
+    let queue = Arc::new(WaitQueue::new());
+    let flag = Arc::new(AtomicBool::new(false));
+
+    // Nobody wakes it up
+    let start = time::ticks();
+    assert!(!queue.wait_until_deadline(|| false, start + 5));
+    assert!(time::ticks() >= start + 5);
+    assert!(queue.is_empty());
+
+    {
+        let queue = queue.clone();
+        let flag = flag.clone();
+        task::spawn("waker", move || {
+            task::sleep_ms(20);
+            flag.store(true, Ordering::SeqCst);
+            queue.wake_all();
+        });
+    }
+    let deadline = time::ticks() + time::ms_to_ticks(5000);
+    assert!(queue.wait_until_deadline(|| flag.load(Ordering::SeqCst), deadline));
+    assert!(time::ticks() < deadline);
+
+    // The condition turns true as the deadline wakes it up, it mustn't be
+    // left in the queue for the next wake up
+    let deadline = time::ticks() + 5;
+    assert!(queue.wait_until_deadline(|| time::ticks() >= deadline, deadline));
+    assert!(queue.is_empty());
+    assert!(!queue.wake_one());
+    serial_println!("[ok]");
+}
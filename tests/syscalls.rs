@@ -0,0 +1,135 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::task;
+use ham_dos::address_space::{AddressSpace, MapError, USER_SPACE_END};
+use ham_dos::{address_space, allocator, memory, serial_print, serial_println, user};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+/// Where the program stores the syscall results
+const RESULTS: u64 = user::USER_CODE_START + 0x1_0000;
+
+// movabs rbx, RESULTS
+// write(1, "hello\n", 6) -> [rbx]
+// write(1, <kernel heap>, 4) -> [rbx + 8]
+// getpid() -> [rbx + 16]
+// mmap(0, 8192, PROT_READ | PROT_WRITE) -> [rbx + 24], then stores 42 in its second page
+// getpid() through int 0x80 -> [rbx + 32]
+// syscall 999 -> [rbx + 40]
+// rbx -> [rbx + 48], it has to survive the syscalls
+// exit(0)
+const PROGRAM: [u8; 154] = [
+    0x48, 0xBB, 0x00, 0x00, 0x41, 0x00, 0x00, 0x20, 0x00, 0x00, 0x48, 0x8D,
+    0x35, 0x83, 0x00, 0x00, 0x00, 0xB8, 0x01, 0x00, 0x00, 0x00, 0xBF, 0x01,
+    0x00, 0x00, 0x00, 0xBA, 0x06, 0x00, 0x00, 0x00, 0x0F, 0x05, 0x48, 0x89,
+    0x03, 0xB8, 0x01, 0x00, 0x00, 0x00, 0xBF, 0x01, 0x00, 0x00, 0x00, 0x48,
+    0xBE, 0x00, 0x00, 0x44, 0x44, 0x44, 0x44, 0x00, 0x00, 0xBA, 0x04, 0x00,
+    0x00, 0x00, 0x0F, 0x05, 0x48, 0x89, 0x43, 0x08, 0xB8, 0x04, 0x00, 0x00,
+    0x00, 0x0F, 0x05, 0x48, 0x89, 0x43, 0x10, 0xB8, 0x05, 0x00, 0x00, 0x00,
+    0x31, 0xFF, 0xBE, 0x00, 0x20, 0x00, 0x00, 0xBA, 0x03, 0x00, 0x00, 0x00,
+    0x0F, 0x05, 0x48, 0x89, 0x43, 0x18, 0x48, 0xC7, 0x80, 0x00, 0x10, 0x00,
+    0x00, 0x2A, 0x00, 0x00, 0x00, 0xB8, 0x04, 0x00, 0x00, 0x00, 0xCD, 0x80,
+    0x48, 0x89, 0x43, 0x20, 0xB8, 0xE7, 0x03, 0x00, 0x00, 0x0F, 0x05, 0x48,
+    0x89, 0x43, 0x28, 0x48, 0x89, 0x5B, 0x30, 0xB8, 0x02, 0x00, 0x00, 0x00,
+    0x31, 0xFF, 0x0F, 0x05, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x0A,
+];
+
+fn read_u64(space: &address_space::AddressSpace, addr: u64) -> u64 {
+    let mut bytes = [0; 8];
+    space
+        .read_bytes(VirtAddr::new(addr), &mut bytes)
+        .expect("Reading user memory failed");
+    u64::from_le_bytes(bytes)
+}
+
+#[test_case]
+fn test_syscalls() {
+    serial_print!("test_syscalls... ");
+    let space = user::load_flat_binary(&PROGRAM).expect("Failed to load the program");
+    user::map_region(
+        &space,
+        VirtAddr::new(RESULTS),
+        4096,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )
+    .expect("Failed to map the results");
+    let space = Arc::new(space);
+
+    let id = user::spawn_in(
+        "syscalls",
+        space.clone(),
+        VirtAddr::new(user::USER_CODE_START),
+        VirtAddr::new(user::USER_STACK_TOP),
+    );
+    task::join(id);
+
+    assert_eq!(read_u64(&space, RESULTS), 6);
+    // -EFAULT
+    assert_eq!(read_u64(&space, RESULTS + 8) as i64, -14);
+    assert_eq!(read_u64(&space, RESULTS + 16), id.as_u64());
+
+    let mapped = read_u64(&space, RESULTS + 24);
+    assert!(address_space::is_user_address(VirtAddr::new(mapped)));
+    assert_eq!(read_u64(&space, mapped + 4096), 42);
+
+    assert_eq!(read_u64(&space, RESULTS + 32), id.as_u64());
+    // -ENOSYS
+    assert_eq!(read_u64(&space, RESULTS + 40) as i64, -38);
+    assert_eq!(read_u64(&space, RESULTS + 48), RESULTS);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_huge_regions() {
+    serial_print!("test_huge_regions... ");
+    let space = AddressSpace::new().expect("Failed to create an address space");
+    let start = space.reserve_region(4096).expect("No address space left");
+
+    // Sizes that would wrap around or leave user space are refused
+    assert_eq!(space.reserve_region(usize::max_value()), Err(MapError::NotUserAddress));
+    assert_eq!(space.reserve_region(1 << 39), Err(MapError::NotUserAddress));
+    let last_page = VirtAddr::new(USER_SPACE_END - 4096);
+    let flags = PageTableFlags::WRITABLE;
+    assert_eq!(
+        user::map_region(&space, last_page, usize::max_value(), flags),
+        Err(MapError::NotUserAddress)
+    );
+    assert_eq!(user::map_region(&space, last_page, 8192, flags), Err(MapError::NotUserAddress));
+
+    // The failed reservations didn't use up any address space
+    let next = space.reserve_region(4096).expect("No address space left");
+    assert_eq!(next, start + 4096u64);
+    space.release_region(next, 4096);
+    assert_eq!(space.reserve_region(1), Ok(next));
+    serial_println!("[ok]");
+}
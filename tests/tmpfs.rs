@@ -0,0 +1,360 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::fs::tmpfs::TmpFs;
+use ham_dos::fs::{self, FileType, FsError, SeekFrom, O_APPEND, O_CREAT, O_EXCL, O_RDONLY};
+use ham_dos::fs::{O_RDWR, O_TRUNC, O_WRONLY};
+use ham_dos::sync::Semaphore;
+use ham_dos::{address_space, allocator, memory, process, programs, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+    fs::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+fn write_file(path: &str, data: &[u8]) {
+    let file = fs::open(path, O_WRONLY | O_CREAT | O_TRUNC).expect("Failed to create");
+    assert_eq!(file.write(data), Ok(data.len()));
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    let file = fs::open(path, O_RDONLY).expect("Failed to open");
+    let mut data = Vec::new();
+    let mut buffer = [0; 512];
+    loop {
+        let count = file.read(&mut buffer).expect("Failed to read");
+        if count == 0 {
+            return data;
+        }
+        data.extend_from_slice(&buffer[..count]);
+    }
+}
+
+fn names(path: &str) -> Vec<String> {
+    fs::read_dir(path)
+        .expect("Failed to list")
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+/// Mounts a tmpfs of its own on a new directory, so that its usage can be
+/// checked
+fn mount_tmpfs(path: &str) -> Arc<TmpFs> {
+    let tmpfs = TmpFs::new();
+    fs::create_dir(path).expect("Failed to create the mount point");
+    fs::mount(path, tmpfs.clone()).expect("Failed to mount");
+    tmpfs
+}
+
+fn unmount_tmpfs(path: &str) {
+    fs::unmount(path).expect("Failed to unmount");
+    fs::remove_dir(path).expect("Failed to remove the mount point");
+}
+
+#[test_case]
+fn test_regular_files() {
+    serial_print!("test_regular_files... ");
+    write_file("/file", b"hello");
+    assert_eq!(read_file("/file"), b"hello");
+    assert_eq!(
+        fs::open("/file", O_RDWR | O_CREAT | O_EXCL).err(),
+        Some(FsError::AlreadyExists)
+    );
+
+    let file = fs::open("/file", O_WRONLY | O_APPEND).expect("Failed to open");
+    assert_eq!(file.write(b" world"), Ok(6));
+    assert_eq!(read_file("/file"), b"hello world");
+
+    // Writing past the end leaves a hole of zeros
+    let file = fs::open("/file", O_RDWR).expect("Failed to open");
+    assert_eq!(file.seek(SeekFrom::Start(10_000)), Ok(10_000));
+    assert_eq!(file.write(b"end"), Ok(3));
+    let data = read_file("/file");
+    assert_eq!(data.len(), 10_003);
+    assert!(data[11..10_000].iter().all(|&byte| byte == 0));
+    assert_eq!(&data[10_000..], b"end");
+
+    write_file("/file", b"new");
+    assert_eq!(read_file("/file"), b"new");
+    fs::remove_file("/file").expect("Failed to remove");
+    assert_eq!(fs::metadata("/file").err(), Some(FsError::NotFound));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_truncate() {
+    serial_print!("test_truncate... ");
+    let tmpfs = mount_tmpfs("/truncate");
+    write_file("/truncate/file", &[7; 9000]);
+    assert_eq!(tmpfs.used_bytes(), 3 * 4096);
+
+    fs::truncate("/truncate/file", 10).expect("Failed to truncate");
+    assert_eq!(tmpfs.used_bytes(), 4096);
+    assert_eq!(fs::metadata("/truncate/file").map(|m| m.size), Ok(10));
+
+    // The bytes after the old end come back as zeros
+    let file = fs::open("/truncate/file", O_RDWR).expect("Failed to open");
+    file.set_len(20).expect("Failed to grow");
+    let data = read_file("/truncate/file");
+    assert_eq!(&data[..10], &[7; 10]);
+    assert_eq!(&data[10..], &[0; 10]);
+    assert_eq!(fs::truncate("/truncate", 0), Err(FsError::IsADirectory));
+
+    drop(file);
+    fs::remove_file("/truncate/file").expect("Failed to remove");
+    assert_eq!(tmpfs.used_bytes(), 0);
+    unmount_tmpfs("/truncate");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_directories() {
+    serial_print!("test_directories... ");
+    fs::create_dir("/dir").expect("Failed to create");
+    fs::create_dir("/dir/b").expect("Failed to create");
+    write_file("/dir/a", b"");
+    write_file("/dir/c", b"");
+    assert_eq!(fs::create_dir("/dir/a"), Err(FsError::AlreadyExists));
+    assert_eq!(fs::create_dir("/missing/x"), Err(FsError::NotFound));
+    assert_eq!(names("/dir"), ["a", "b", "c"]);
+
+    let metadata = fs::metadata("/dir").expect("Failed to stat");
+    assert_eq!(metadata.file_type, FileType::Directory);
+    assert_eq!(metadata.links, 3);
+
+    assert_eq!(fs::remove_dir("/dir"), Err(FsError::DirectoryNotEmpty));
+    assert_eq!(fs::remove_file("/dir/b"), Err(FsError::IsADirectory));
+    assert_eq!(fs::remove_dir("/dir/a"), Err(FsError::NotADirectory));
+    assert_eq!(fs::remove_dir("/"), Err(FsError::Busy));
+    fs::remove_dir("/dir/b").expect("Failed to remove");
+    fs::remove_file("/dir/a").expect("Failed to remove");
+    fs::remove_file("/dir/c").expect("Failed to remove");
+    fs::remove_dir("/dir").expect("Failed to remove");
+    assert_eq!(fs::metadata("/").map(|m| m.links), Ok(2));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_rename() {
+    serial_print!("test_rename... ");
+    fs::create_dir("/from").expect("Failed to create");
+    fs::create_dir("/to").expect("Failed to create");
+    write_file("/from/file", b"moved");
+    write_file("/to/old", b"replaced");
+
+    fs::rename("/from/file", "/from/file").expect("Failed to rename to itself");
+    fs::rename("/from/file", "/to/old").expect("Failed to rename");
+    assert_eq!(read_file("/to/old"), b"moved");
+    assert!(names("/from").is_empty());
+
+    // Directories move with their contents
+    fs::create_dir("/from/sub").expect("Failed to create");
+    write_file("/from/sub/inner", b"inner");
+    fs::rename("/from/sub", "/to/sub").expect("Failed to rename");
+    assert_eq!(read_file("/to/sub/inner"), b"inner");
+    assert_eq!(fs::metadata("/from").map(|m| m.links), Ok(2));
+    assert_eq!(fs::metadata("/to").map(|m| m.links), Ok(3));
+
+    assert_eq!(fs::rename("/to", "/to/sub/x"), Err(FsError::InvalidArgument));
+    assert_eq!(fs::rename("/from", "/to"), Err(FsError::DirectoryNotEmpty));
+    assert_eq!(fs::rename("/to/old", "/from"), Err(FsError::IsADirectory));
+    assert_eq!(fs::rename("/from", "/to/old"), Err(FsError::NotADirectory));
+    assert_eq!(fs::rename("/missing", "/x"), Err(FsError::NotFound));
+
+    mount_tmpfs("/other");
+    assert_eq!(fs::rename("/to/old", "/other/old"), Err(FsError::CrossDevice));
+    assert_eq!(fs::rename("/other", "/elsewhere"), Err(FsError::Busy));
+    fs::unmount("/other").expect("Failed to unmount");
+
+    fs::remove_file("/to/sub/inner").expect("Failed to remove");
+    fs::remove_dir("/to/sub").expect("Failed to remove");
+    fs::remove_file("/to/old").expect("Failed to remove");
+    fs::remove_dir("/to").expect("Failed to remove");
+    fs::remove_dir("/from").expect("Failed to remove");
+    fs::remove_dir("/other").expect("Failed to remove");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_timestamps() {
+    serial_print!("test_timestamps... ");
+    write_file("/time", b"0");
+    let created = fs::metadata("/time").expect("Failed to stat");
+    assert_eq!(created.modified, created.changed);
+
+    task::sleep_ms(30);
+    read_file("/time");
+    let read = fs::metadata("/time").expect("Failed to stat");
+    assert!(read.accessed > created.accessed);
+    assert_eq!(read.modified, created.modified);
+
+    task::sleep_ms(30);
+    fs::open("/time", O_WRONLY)
+        .expect("Failed to open")
+        .write(b"1")
+        .expect("Failed to write");
+    let written = fs::metadata("/time").expect("Failed to stat");
+    assert!(written.modified > read.modified);
+    assert_eq!(written.changed, written.modified);
+
+    task::sleep_ms(30);
+    fs::rename("/time", "/time2").expect("Failed to rename");
+    let renamed = fs::metadata("/time2").expect("Failed to stat");
+    assert!(renamed.changed > written.changed);
+    assert_eq!(renamed.modified, written.modified);
+    fs::remove_file("/time2").expect("Failed to remove");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_removed_file_stays_open() {
+    serial_print!("test_removed_file_stays_open... ");
+    let tmpfs = mount_tmpfs("/open");
+    write_file("/open/file", b"still here");
+    let file = fs::open("/open/file", O_RDONLY).expect("Failed to open");
+    fs::remove_file("/open/file").expect("Failed to remove");
+    assert_eq!(fs::metadata("/open/file").err(), Some(FsError::NotFound));
+    assert_eq!(file.metadata().map(|m| m.links), Ok(0));
+
+    let mut buffer = [0; 16];
+    assert_eq!(file.read(&mut buffer), Ok(10));
+    assert_eq!(&buffer[..10], b"still here");
+    assert_eq!(tmpfs.inode_count(), 2);
+    drop(file);
+    assert_eq!(tmpfs.inode_count(), 1);
+    assert_eq!(tmpfs.used_bytes(), 0);
+    unmount_tmpfs("/open");
+    serial_println!("[ok]");
+}
+
+const STRESS_FILES: usize = 1000;
+
+#[test_case]
+fn test_create_and_delete_many_files() {
+    serial_print!("test_create_and_delete_many_files... ");
+    let tmpfs = mount_tmpfs("/many");
+    for round in 0..3 {
+        for index in 0..STRESS_FILES {
+            let path = format!("/many/file{}", index);
+            if index % 10 == 0 {
+                write_file(&path, path.as_bytes());
+            } else {
+                fs::open(&path, O_WRONLY | O_CREAT | O_EXCL).expect("Failed to create");
+            }
+        }
+        assert_eq!(names("/many").len(), STRESS_FILES);
+        assert_eq!(tmpfs.inode_count(), STRESS_FILES + 1);
+        assert_eq!(read_file("/many/file990"), b"/many/file990");
+
+        // Every other round removes them in reverse order
+        for index in 0..STRESS_FILES {
+            let index = if round % 2 == 0 { index } else { STRESS_FILES - 1 - index };
+            fs::remove_file(&format!("/many/file{}", index)).expect("Failed to remove");
+        }
+        assert!(names("/many").is_empty());
+        assert_eq!(tmpfs.inode_count(), 1);
+        assert_eq!(tmpfs.used_bytes(), 0);
+    }
+    unmount_tmpfs("/many");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_deep_directories() {
+    serial_print!("test_deep_directories... ");
+    let tmpfs = mount_tmpfs("/deep");
+    let mut path = String::from("/deep");
+    for level in 0..64 {
+        path.push_str(&format!("/{}", level));
+        fs::create_dir(&path).expect("Failed to create");
+    }
+    write_file(&format!("{}/leaf", path), b"leaf");
+    assert_eq!(tmpfs.inode_count(), 66);
+
+    fs::remove_file(&format!("{}/leaf", path)).expect("Failed to remove");
+    while path != "/deep" {
+        fs::remove_dir(&path).expect("Failed to remove");
+        let end = path.rfind('/').unwrap();
+        path.truncate(end);
+    }
+    assert_eq!(tmpfs.inode_count(), 1);
+    unmount_tmpfs("/deep");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_concurrent_create_and_delete() {
+    serial_print!("test_concurrent_create_and_delete... ");
+    const THREADS: usize = 4;
+    let tmpfs = mount_tmpfs("/concurrent");
+    let done = Arc::new(Semaphore::new(0));
+    for thread in 0..THREADS {
+        let done = done.clone();
+        task::spawn("tmpfs stress", move || {
+            let directory = format!("/concurrent/{}", thread);
+            fs::create_dir(&directory).expect("Failed to create");
+            for index in 0..200 {
+                let path = format!("{}/{}", directory, index);
+                write_file(&path, path.as_bytes());
+                // Renamed into a directory shared by all threads and back
+                let shared = format!("/concurrent/{}-{}", thread, index);
+                fs::rename(&path, &shared).expect("Failed to rename");
+                fs::rename(&shared, &path).expect("Failed to rename");
+            }
+            for index in 0..200 {
+                fs::remove_file(&format!("{}/{}", directory, index)).expect("Failed to remove");
+            }
+            fs::remove_dir(&directory).expect("Failed to remove");
+            done.release();
+        });
+    }
+    for _ in 0..THREADS {
+        done.acquire();
+    }
+
+    assert!(names("/concurrent").is_empty());
+    assert_eq!(tmpfs.inode_count(), 1);
+    assert_eq!(tmpfs.used_bytes(), 0);
+    unmount_tmpfs("/concurrent");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_files_from_user_mode() {
+    serial_print!("test_files_from_user_mode... ");
+    let pid = process::spawn("fs_test", programs::FS_TEST, &["fs_test"], &[])
+        .expect("Failed to spawn");
+    assert_eq!(process::wait(Some(pid)), Ok((pid, 0)));
+    assert_eq!(fs::metadata("/fs_test").err(), Some(FsError::NotFound));
+    serial_println!("[ok]");
+}
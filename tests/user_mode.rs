@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::task;
+use ham_dos::{address_space, allocator, memory, serial_print, serial_println, user};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_privileged_instruction_is_killed() {
+    serial_print!("test_privileged_instruction_is_killed... ");
+    // hlt; jmp $
+    const PROGRAM: [u8; 3] = [0xF4, 0xEB, 0xFE];
+    let faults = user::fault_count();
+
+    let id = user::spawn_flat_binary("hlt", &PROGRAM).expect("Failed to load the program");
+    task::join(id);
+    assert_eq!(user::fault_count(), faults + 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_kernel_memory_is_not_accessible() {
+    serial_print!("test_kernel_memory_is_not_accessible... ");
+    // mov rax, [0x4444_4444_0000] (the kernel heap); jmp $
+    const PROGRAM: [u8; 12] = [
+        0x48, 0xA1, 0x00, 0x00, 0x44, 0x44, 0x44, 0x44, 0x00, 0x00, 0xEB, 0xFE,
+    ];
+    let faults = user::fault_count();
+
+    let id = user::spawn_flat_binary("peek", &PROGRAM).expect("Failed to load the program");
+    task::join(id);
+    assert_eq!(user::fault_count(), faults + 1);
+    serial_println!("[ok]");
+}
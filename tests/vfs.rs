@@ -0,0 +1,262 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(ham_dos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use ham_dos::fs::{self, path, DirEntry, FileSystem, FileType, FsError, FsResult, Inode};
+use ham_dos::fs::{Metadata, SeekFrom, O_APPEND, O_CREAT, O_DIRECTORY, O_RDONLY, O_RDWR};
+use ham_dos::{address_space, allocator, memory, task};
+use ham_dos::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    ham_dos::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    memory::init_frame_allocator(frame_allocator);
+    address_space::init();
+    task::init();
+
+    fs::mount("/", StaticFs::new()).expect("Failed to mount the root");
+    test_main();
+    ham_dos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    ham_dos::test_panic_handler(info)
+}
+
+/// A read only filesystem with a fixed tree: `/hello`, `/docs/readme` and
+/// the empty directory `/mnt`
+struct StaticFs {
+    root: Arc<StaticInode>,
+}
+
+enum Node {
+    File(&'static [u8]),
+    Directory(Vec<(&'static str, Arc<StaticInode>)>),
+}
+
+struct StaticInode {
+    number: u64,
+    node: Node,
+}
+
+impl StaticFs {
+    fn new() -> Arc<StaticFs> {
+        let file = |number, data| Arc::new(StaticInode { number, node: Node::File(data) });
+        let directory = |number, entries| {
+            Arc::new(StaticInode {
+                number,
+                node: Node::Directory(entries),
+            })
+        };
+
+        let docs = directory(2, vec![("readme", file(3, &b"read me\n"[..]))]);
+        let root = directory(
+            1,
+            vec![
+                ("hello", file(4, &b"hello world"[..])),
+                ("docs", docs),
+                ("mnt", directory(5, Vec::new())),
+            ],
+        );
+        Arc::new(StaticFs { root })
+    }
+}
+
+impl FileSystem for StaticFs {
+    fn name(&self) -> &str {
+        "static"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+impl Inode for StaticInode {
+    fn metadata(&self) -> FsResult<Metadata> {
+        let (size, file_type) = match &self.node {
+            Node::File(data) => (data.len() as u64, FileType::Regular),
+            Node::Directory(entries) => (entries.len() as u64, FileType::Directory),
+        };
+        Ok(Metadata {
+            inode: self.number,
+            size,
+            file_type,
+            links: 1,
+            accessed: 0,
+            modified: 0,
+            changed: 0,
+        })
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> FsResult<usize> {
+        let data = match &self.node {
+            Node::File(data) => data,
+            Node::Directory(_) => return Err(FsError::IsADirectory),
+        };
+        if offset >= data.len() as u64 {
+            return Ok(0);
+        }
+
+        let rest = &data[offset as usize..];
+        let count = rest.len().min(buffer.len());
+        buffer[..count].copy_from_slice(&rest[..count]);
+        Ok(count)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        match &self.node {
+            Node::Directory(entries) => entries
+                .iter()
+                .find(|(entry, _)| *entry == name)
+                .map(|(_, inode)| inode.clone() as Arc<dyn Inode>)
+                .ok_or(FsError::NotFound),
+            Node::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read_dir(&self, index: usize) -> FsResult<Option<DirEntry>> {
+        let entries = match &self.node {
+            Node::Directory(entries) => entries,
+            Node::File(_) => return Err(FsError::NotADirectory),
+        };
+        match entries.get(index) {
+            Some((name, inode)) => {
+                let metadata = inode.metadata()?;
+                Ok(Some(DirEntry {
+                    inode: metadata.inode,
+                    file_type: metadata.file_type,
+                    name: (*name).into(),
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[test_case]
+fn test_normalize() {
+    serial_print!("test_normalize... ");
+    assert_eq!(path::normalize("/", "/a/b").as_ref().map(|p| p.as_str()), Ok("/a/b"));
+    assert_eq!(path::normalize("/a", "b/./c/").as_ref().map(|p| p.as_str()), Ok("/a/b/c"));
+    assert_eq!(path::normalize("/a/b", "../c").as_ref().map(|p| p.as_str()), Ok("/a/c"));
+    assert_eq!(path::normalize("/", "../../..").as_ref().map(|p| p.as_str()), Ok("/"));
+    assert_eq!(path::normalize("/a", "//x//y").as_ref().map(|p| p.as_str()), Ok("/x/y"));
+    assert_eq!(path::normalize("/", ""), Err(FsError::NotFound));
+
+    let long_name: Vec<u8> = vec![b'x'; path::MAX_NAME + 1];
+    let long_name = core::str::from_utf8(&long_name).unwrap();
+    assert_eq!(path::normalize("/", long_name), Err(FsError::NameTooLong));
+
+    assert_eq!(path::split_last("/a/b"), Some(("/a", "b")));
+    assert_eq!(path::split_last("/a"), Some(("/", "a")));
+    assert_eq!(path::split_last("/"), None);
+    assert_eq!(path::strip_ancestor("/mnt/x", "/mnt"), Some("/x"));
+    assert_eq!(path::strip_ancestor("/mnt", "/mnt"), Some(""));
+    assert_eq!(path::strip_ancestor("/mntx", "/mnt"), None);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_read_and_seek() {
+    serial_print!("test_read_and_seek... ");
+    let file = fs::open("/docs/../hello", O_RDONLY).expect("Failed to open");
+    assert_eq!(file.path(), "/hello");
+
+    let mut buffer = [0; 5];
+    assert_eq!(file.read(&mut buffer), Ok(5));
+    assert_eq!(&buffer, b"hello");
+    assert_eq!(file.seek(SeekFrom::Current(1)), Ok(6));
+    assert_eq!(file.read(&mut buffer), Ok(5));
+    assert_eq!(&buffer, b"world");
+    assert_eq!(file.read(&mut buffer), Ok(0));
+
+    assert_eq!(file.seek(SeekFrom::End(-5)), Ok(6));
+    assert_eq!(file.seek(SeekFrom::Current(-7)), Err(FsError::InvalidArgument));
+    assert_eq!(file.seek(SeekFrom::Start(100)), Ok(100));
+    assert_eq!(file.read(&mut buffer), Ok(0));
+    assert_eq!(file.write(b"x"), Err(FsError::WrongMode));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_open_errors() {
+    serial_print!("test_open_errors... ");
+    assert_eq!(fs::open("/missing", O_RDONLY).err(), Some(FsError::NotFound));
+    assert_eq!(fs::open("/hello/x", O_RDONLY).err(), Some(FsError::NotADirectory));
+    assert_eq!(fs::open("/docs", O_RDWR).err(), Some(FsError::IsADirectory));
+    assert_eq!(fs::open("/hello", O_RDONLY | O_DIRECTORY).err(), Some(FsError::NotADirectory));
+    assert_eq!(fs::open("/new", O_RDWR | O_CREAT).err(), Some(FsError::ReadOnly));
+    assert_eq!(fs::open("/hello", 3).err(), Some(FsError::InvalidArgument));
+
+    // Writing is up to the filesystem, this one can't
+    let file = fs::open("/hello", O_RDWR | O_APPEND).expect("Failed to open");
+    assert_eq!(file.write(b"!"), Err(FsError::ReadOnly));
+    assert_eq!(fs::create_dir("/docs/new"), Err(FsError::ReadOnly));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_stat_and_read_dir() {
+    serial_print!("test_stat_and_read_dir... ");
+    let metadata = fs::metadata("/docs/readme").expect("Failed to stat");
+    assert_eq!(metadata.inode, 3);
+    assert_eq!(metadata.size, 8);
+    assert_eq!(metadata.file_type, FileType::Regular);
+
+    let names: Vec<_> = fs::read_dir("/")
+        .expect("Failed to list")
+        .into_iter()
+        .map(|entry| (entry.name, entry.file_type))
+        .collect();
+    assert_eq!(names.len(), 3);
+    assert_eq!(names[0], ("hello".into(), FileType::Regular));
+    assert_eq!(names[1], ("docs".into(), FileType::Directory));
+
+    let directory = fs::open("/docs", O_RDONLY).expect("Failed to open");
+    assert_eq!(directory.read(&mut [0; 4]), Err(FsError::IsADirectory));
+    assert!(directory.read_dir().expect("Failed to read").is_some());
+    assert_eq!(directory.read_dir(), Ok(None));
+    assert_eq!(directory.seek(SeekFrom::Start(0)), Ok(0));
+    assert!(directory.read_dir().expect("Failed to read").is_some());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_mount_points() {
+    serial_print!("test_mount_points... ");
+    assert_eq!(fs::mount("/hello", StaticFs::new()), Err(FsError::NotADirectory));
+    assert_eq!(fs::mount("/nowhere", StaticFs::new()), Err(FsError::NotFound));
+    fs::mount("/mnt", StaticFs::new()).expect("Failed to mount");
+    assert_eq!(fs::mount("/mnt/", StaticFs::new()), Err(FsError::Busy));
+
+    // Paths below the mount point are on the mounted filesystem, `..`
+    // leads back out of it
+    assert_eq!(fs::metadata("/mnt").map(|metadata| metadata.inode), Ok(1));
+    assert_eq!(fs::metadata("/mnt/docs/readme").map(|metadata| metadata.size), Ok(8));
+    assert_eq!(fs::metadata("/mnt/..").map(|metadata| metadata.size), Ok(3));
+    assert_eq!(fs::read_dir("/mnt").map(|entries| entries.len()), Ok(3));
+
+    let file = fs::open("/mnt/hello", O_RDONLY).expect("Failed to open");
+    assert_eq!(fs::unmount("/mnt"), Err(FsError::Busy));
+    drop(file);
+    fs::unmount("/mnt").expect("Failed to unmount");
+    assert_eq!(fs::read_dir("/mnt").map(|entries| entries.len()), Ok(0));
+    assert_eq!(fs::unmount("/mnt"), Err(FsError::InvalidArgument));
+    serial_println!("[ok]");
+}